@@ -2,6 +2,7 @@
 use std::ops::{Add, Mul};
 use std::marker::Sized;
 use std::cmp::Ordering;
+use precision::Float;
 
 pub trait VectorSpace : Add + Sized {
     type Field: Mul<Self>;
@@ -21,11 +22,18 @@ pub trait Group : Mul + Sized {
 pub trait GroupAction<T> : Group + Mul<T> {}
 
 
+// Approximate equality of floats, for use where rounding makes an
+// exact comparison meaningless
+pub fn approx_eq(x: Float, y: Float, tolerance: Float) -> bool {
+    (x - y).abs() <= tolerance
+}
+
+
 #[derive(PartialEq, PartialOrd)]
-pub struct Real(f64);
+pub struct Real(Float);
 
 impl Real {
-    pub fn from_float(x : f64) -> Option<Real> {
+    pub fn from_float(x : Float) -> Option<Real> {
         if x.is_nan() { None } else { Some(Real(x)) }
     }
 
@@ -38,7 +46,8 @@ impl Eq for Real {}
 
 impl Ord for Real {
     fn cmp(&self, other : &Real) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        // Real can never hold NaN, so the partial ordering is total
+        self.partial_cmp(other).unwrap_or(Ordering::Equal)
     }
 }
 
@@ -55,5 +64,11 @@ mod tests {
         assert!(x!=y);
         assert!(x==x);
     }
+
+    #[test]
+    fn test_approximate_equality() {
+        assert!(approx_eq(0.1 + 0.2, 0.3, 1e-12));
+        assert!(!approx_eq(0.1, 0.2, 1e-12));
+    }
 }
         
@@ -0,0 +1,364 @@
+// animation - values which change from frame to frame, given by keys
+// at some frames and interpolated between them.
+//
+// Tracks hold the first key's value before it and the last key's
+// after it. Bezier tracks pass through their keys, with control points
+// set from the neighbouring keys (Catmull-Rom), so that they ease in
+// and out at the first and last keys. Rotations are interpolated on
+// the sphere of unit quaternions, either by slerp or by Bezier curves
+// built from slerps.
+use image::Rgb;
+
+use precision::Float;
+use precision::float::consts::PI;
+use vector3d::Vec3;
+use space_algebra::{SO3, UnitQuaternion};
+use scene::Light;
+use camera::{CameraSpec, CameraMotion};
+use shapes::{Transform, Motion};
+use error::{Error, Result};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Interpolation {
+    Linear,
+    Bezier,
+    Slerp,
+}
+
+impl Interpolation {
+    pub fn by_name(name: &str) -> Option<Interpolation> {
+        match name {
+            "linear" => Some(Interpolation::Linear),
+            "bezier" => Some(Interpolation::Bezier),
+            "slerp" => Some(Interpolation::Slerp),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Interpolation::Linear => "linear",
+            Interpolation::Bezier => "bezier",
+            Interpolation::Slerp => "slerp",
+        }
+    }
+}
+
+// Values which tracks can interpolate
+pub trait Animatable: Copy {
+    fn supports(interpolation: Interpolation) -> bool;
+
+    // The value a fraction t of the way from self to other
+    fn towards(self, other: Self, t: Float) -> Self;
+
+    // self moved by s times the difference from a to b, for placing
+    // control points
+    fn offset(self, a: Self, b: Self, s: Float) -> Self;
+}
+
+impl Animatable for Float {
+    fn supports(interpolation: Interpolation) -> bool {
+        interpolation != Interpolation::Slerp
+    }
+
+    fn towards(self, other: Float, t: Float) -> Float {
+        self + (other - self) * t
+    }
+
+    fn offset(self, a: Float, b: Float, s: Float) -> Float {
+        self + (b - a) * s
+    }
+}
+
+impl Animatable for Vec3 {
+    fn supports(interpolation: Interpolation) -> bool {
+        interpolation != Interpolation::Slerp
+    }
+
+    fn towards(self, other: Vec3, t: Float) -> Vec3 {
+        self.lerp(other, t)
+    }
+
+    fn offset(self, a: Vec3, b: Vec3, s: Float) -> Vec3 {
+        self + s * (b - a)
+    }
+}
+
+impl Animatable for UnitQuaternion {
+    fn supports(interpolation: Interpolation) -> bool {
+        interpolation != Interpolation::Linear
+    }
+
+    fn towards(self, other: UnitQuaternion, t: Float) -> UnitQuaternion {
+        self.slerp(other, t)
+    }
+
+    // Turn by the given fraction of the rotation taking a to b, the
+    // shorter way round
+    fn offset(self, a: UnitQuaternion, b: UnitQuaternion, s: Float) -> UnitQuaternion {
+        let (axis, angle) = (b * a.conjugate()).to_axis_angle();
+        let angle = if angle > PI {
+            angle - 2.0 * PI
+        } else {
+            angle
+        };
+        UnitQuaternion::from_axis_angle(axis, angle * s).map_or(self, |turn| turn * self)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Track<T> {
+    interpolation: Interpolation,
+    // Frames and the values at them, in order of frame
+    keys: Vec<(Float, T)>,
+}
+
+impl<T: Animatable> Track<T> {
+    pub fn new(interpolation: Interpolation, keys: Vec<(Float, T)>) -> Result<Track<T>> {
+        if !T::supports(interpolation) {
+            return Err(Error::Animation(format!("cannot interpolate these values by {}",
+                                                interpolation.name())));
+        }
+        if keys.is_empty() {
+            return Err(Error::Animation("track has no keys".to_string()));
+        }
+        if keys.windows(2).any(|pair| !(pair[0].0 < pair[1].0)) {
+            return Err(Error::Animation("keys must be in order of frame".to_string()));
+        }
+        Ok(Track {
+            interpolation: interpolation,
+            keys: keys,
+        })
+    }
+
+    // A track which never changes
+    pub fn constant(value: T) -> Track<T> {
+        Track {
+            interpolation: Interpolation::Bezier,
+            keys: vec![(0.0, value)],
+        }
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    pub fn keys(&self) -> &[(Float, T)] {
+        &self.keys
+    }
+
+    pub fn at(&self, frame: Float) -> T {
+        let keys = &self.keys;
+        let last = keys.len() - 1;
+        if frame <= keys[0].0 {
+            return keys[0].1;
+        }
+        if frame >= keys[last].0 {
+            return keys[last].1;
+        }
+
+        let i = keys.iter().rposition(|k| k.0 <= frame).unwrap_or(0);
+        let ((f0, p0), (f1, p1)) = (keys[i], keys[i + 1]);
+        let t = (frame - f0) / (f1 - f0);
+        match self.interpolation {
+            Interpolation::Linear | Interpolation::Slerp => p0.towards(p1, t),
+            Interpolation::Bezier => {
+                // Tangents follow the line through the neighbouring
+                // keys, shortened to suit the length of this segment
+                let c0 = if i == 0 {
+                    p0
+                } else {
+                    let (f, p) = keys[i - 1];
+                    p0.offset(p, p1, (f1 - f0) / (3.0 * (f1 - f)))
+                };
+                let c1 = if i + 1 == last {
+                    p1
+                } else {
+                    let (f, p) = keys[i + 2];
+                    p1.offset(p, p0, (f1 - f0) / (3.0 * (f - f0)))
+                };
+
+                // de Casteljau's construction
+                let (a, b, c) = (p0.towards(c0, t), c0.towards(c1, t), c1.towards(p1, t));
+                let (d, e) = (a.towards(b, t), b.towards(c, t));
+                d.towards(e, t)
+            }
+        }
+    }
+}
+
+// The value of a track at a frame, or the given value if there is no
+// track
+pub fn value_at<T: Animatable>(track: &Option<Track<T>>, frame: Float, otherwise: T) -> T {
+    track.as_ref().map_or(otherwise, |t| t.at(frame))
+}
+
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct CameraTracks {
+    pub position: Option<Track<Vec3>>,
+    pub rotation: Option<Track<UnitQuaternion>>,
+    pub fov: Option<Track<Float>>,
+}
+
+impl CameraTracks {
+    pub fn pose(&self, camera: &CameraSpec, frame: Float) -> CameraSpec {
+        CameraSpec {
+            position: value_at(&self.position, frame, camera.position),
+            orientation: value_at(&self.rotation, frame, camera.orientation),
+            fov: value_at(&self.fov, frame, camera.fov),
+            ..*camera
+        }
+    }
+}
+
+// A camera following its tracks while the shutter is open. Its field
+// of view is held where the tracks put it at each frame.
+pub struct CameraPath {
+    pub camera: CameraSpec,
+    pub tracks: CameraTracks,
+}
+
+impl CameraMotion for CameraPath {
+    fn pose(&self, time: Float) -> (Vec3, SO3) {
+        let posed = self.tracks.pose(&self.camera, time);
+        (posed.position, SO3::from(posed.orientation))
+    }
+}
+
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct LightTracks {
+    pub position: Option<Track<Vec3>>,
+    // Channels in 0-255, as lights give them
+    pub colour: Option<Track<Vec3>>,
+}
+
+impl LightTracks {
+    pub fn pose(&self, light: &Light, frame: Float) -> Light {
+        let c = |i: usize| light.colour.data[i] as Float;
+        let Vec3(r, g, b) = value_at(&self.colour, frame, Vec3(c(0), c(1), c(2)));
+        let channel = |c: Float| c.round().max(0.0).min(255.0) as u8;
+        Light {
+            position: value_at(&self.position, frame, light.position),
+            colour: Rgb([channel(r), channel(g), channel(b)]),
+            intensity: light.intensity,
+            group: light.group.clone(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct ObjectTracks {
+    pub translation: Option<Track<Vec3>>,
+    pub rotation: Option<Track<UnitQuaternion>>,
+    pub scale: Option<Track<Float>>,
+    // How far the object moves each frame, from where it is at frame
+    // zero, on top of any translate track
+    pub velocity: Option<Vec3>,
+}
+
+impl ObjectTracks {
+    pub fn transform(&self, frame: Float) -> Transform {
+        let identity = Transform::identity();
+        let drift = self.velocity.map_or(Vec3::zero(), |v| frame * v);
+        Transform {
+            translation: value_at(&self.translation, frame, identity.translation) + drift,
+            rotation: value_at(&self.rotation, frame, identity.rotation),
+            scale: value_at(&self.scale, frame, identity.scale),
+        }
+    }
+}
+
+impl Motion for ObjectTracks {
+    fn transform_at(&self, time: Float) -> Transform {
+        self.transform(time)
+    }
+}
+
+// Everything animated in a scene. Lights and objects are known by
+// their place in the scene's lists.
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct Animation {
+    pub camera: CameraTracks,
+    pub lights: Vec<(usize, LightTracks)>,
+    pub objects: Vec<(usize, ObjectTracks)>,
+}
+
+impl Animation {
+    pub fn is_empty(&self) -> bool {
+        self.camera == CameraTracks::default() && self.lights.is_empty() &&
+        self.objects.is_empty()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use precision::float;
+
+    #[test]
+    fn test_tracks_hold_their_ends() {
+        let track = Track::new(Interpolation::Linear, vec![(10.0, 1.0), (20.0, 3.0)]).unwrap();
+        assert!(track.at(0.0) == 1.0 && track.at(30.0) == 3.0);
+        assert!(track.at(15.0) == 2.0);
+        assert!(Track::constant(4.0).at(-7.0) == 4.0);
+    }
+
+    #[test]
+    fn test_bezier_tracks_pass_through_keys_and_ease() {
+        let tolerance = float::EPSILON.sqrt();
+        let keys = vec![(0.0, Vec3(0.0, 0.0, 0.0)), (10.0, Vec3(5.0, 1.0, 0.0)),
+                        (30.0, Vec3(10.0, 0.0, 2.0))];
+        let track = Track::new(Interpolation::Bezier, keys.clone()).unwrap();
+        for &(frame, value) in keys.iter() {
+            assert!(track.at(frame).approx_eq(value, tolerance));
+        }
+        // Easing out of the first key, so slower than linear
+        assert!(track.at(1.0).0 < 0.5);
+        // Smooth through the middle key
+        let before = track.at(10.0) - track.at(9.99);
+        let after = track.at(10.01) - track.at(10.0);
+        assert!((before - after).norm() < 0.001);
+    }
+
+    #[test]
+    fn test_rotations_slerp() {
+        let tolerance = 0.001;
+        let z = Vec3(0.0, 0.0, 1.0);
+        let turn = |angle| UnitQuaternion::from_axis_angle(z, angle).unwrap();
+        let track = Track::new(Interpolation::Slerp, vec![(0.0, turn(0.0)), (4.0, turn(2.0))])
+            .unwrap();
+        let x = track.at(1.0) * Vec3(1.0, 0.0, 0.0);
+        assert!(x.approx_eq(Vec3((0.5 as Float).cos(), (0.5 as Float).sin(), 0.0), tolerance));
+
+        let keys = vec![(0.0, turn(0.0)), (4.0, turn(1.0)), (8.0, turn(2.0))];
+        let bezier = Track::new(Interpolation::Bezier, keys).unwrap();
+        let (_, angle) = bezier.at(4.0).to_axis_angle();
+        assert!((angle - 1.0).abs() < tolerance);
+        let (_, angle) = bezier.at(6.0).to_axis_angle();
+        assert!(angle > 1.0 && angle < 2.0);
+    }
+
+    #[test]
+    fn test_bad_tracks_are_refused() {
+        assert!(Track::new(Interpolation::Slerp, vec![(0.0, 1.0)]).is_err());
+        assert!(Track::new(Interpolation::Linear, vec![(0.0, UnitQuaternion::identity())])
+            .is_err());
+        assert!(Track::<Float>::new(Interpolation::Linear, vec![]).is_err());
+        assert!(Track::new(Interpolation::Linear, vec![(2.0, 1.0), (1.0, 2.0)]).is_err());
+    }
+
+    #[test]
+    fn test_lights_change_colour() {
+        let light = Light::new(Vec3::zero(), Rgb([0, 0, 0]));
+        let tracks = LightTracks {
+            position: None,
+            colour: Some(Track::new(Interpolation::Linear,
+                                    vec![(0.0, Vec3(0.0, 0.0, 0.0)),
+                                         (2.0, Vec3(255.0, 100.0, 0.0))])
+                .unwrap()),
+        };
+        assert!(tracks.pose(&light, 1.0).colour == Rgb([128, 50, 0]));
+        assert!(tracks.pose(&light, 1.0).position == Vec3::zero());
+    }
+}
@@ -0,0 +1,225 @@
+// aov - arbitrary output variables: extra images rendered alongside
+// the beauty image, for reworking a render in compositing
+use image::{RgbImage, ImageBuffer};
+
+use precision::Float;
+use precision::float;
+use vector3d::Vec3;
+use ray::ShadeCell;
+use shade::Lighting;
+use framebuffer::Framebuffer;
+use error::Result;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum Pass {
+    // Distance along the primary ray, infinite where nothing is struck
+    Depth,
+    // World space position of the point struck
+    Position,
+    // Shading normal, with components in [-1, 1]
+    Normal,
+    // Base colour of the material struck
+    Albedo,
+    // The parts of the local lighting, in [0, 1] for full white
+    Emission,
+    Ambient,
+    Diffuse,
+    Specular,
+    // Light arriving by reflection off other surfaces
+    Reflection,
+    // One more than the index of the object in the scene, so that zero
+    // marks the background
+    ObjectId,
+    // One more than the id of the material struck, so that materials
+    // not numbered are told from the background
+    MaterialId,
+}
+
+pub const ALL_PASSES: [Pass; 11] = [Pass::Depth, Pass::Position, Pass::Normal, Pass::Albedo,
+                                    Pass::Emission, Pass::Ambient, Pass::Diffuse, Pass::Specular,
+                                    Pass::Reflection, Pass::ObjectId, Pass::MaterialId];
+
+impl Pass {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Pass::Depth => "depth",
+            Pass::Position => "position",
+            Pass::Normal => "normal",
+            Pass::Albedo => "albedo",
+            Pass::Emission => "emission",
+            Pass::Ambient => "ambient",
+            Pass::Diffuse => "diffuse",
+            Pass::Specular => "specular",
+            Pass::Reflection => "reflection",
+            Pass::ObjectId => "object_id",
+            Pass::MaterialId => "material_id",
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        match *self {
+            Pass::Depth | Pass::ObjectId | Pass::MaterialId => 1,
+            _ => 3,
+        }
+    }
+
+    // The value of the pass where nothing is struck
+    fn background(&self) -> Float {
+        match *self {
+            Pass::Depth => float::INFINITY,
+            _ => 0.0,
+        }
+    }
+}
+
+// What is known about the point seen through a pixel, from which each
+// pass takes its value
+pub struct Sample<'a> {
+    pub cell: ShadeCell<'a>,
+    pub lighting: Lighting,
+    // On the same scale as the lighting
+    pub reflection: [Float; 3],
+}
+
+impl<'a> Sample<'a> {
+    fn value(&self, pass: Pass) -> Vec<Float> {
+        let ShadeCell(p, n, _, m, strike) = self.cell;
+        let vector = |v: Vec3| vec![v.i(), v.j(), v.k()];
+        let colour = |c: [Float; 3]| vec![c[0] / 255.0, c[1] / 255.0, c[2] / 255.0];
+        match pass {
+            Pass::Depth => vec![strike.distance],
+            Pass::Position => vector(p),
+            Pass::Normal => vector(n),
+            Pass::Albedo => m.pbr().base_colour.to_vec(),
+            Pass::Emission => colour(self.lighting.emission),
+            Pass::Ambient => colour(self.lighting.ambient),
+            Pass::Diffuse => colour(self.lighting.diffuse),
+            Pass::Specular => colour(self.lighting.specular),
+            Pass::Reflection => colour(self.reflection),
+            Pass::ObjectId => vec![(strike.object + 1) as Float],
+            Pass::MaterialId => vec![(m.id() + 1) as Float],
+        }
+    }
+}
+
+// The light from each named group of lights, rendered separately for
+// relighting in compositing. A last buffer holds the rest of the
+// light: from ungrouped lights and emissive surfaces, and ambient
+// light and emission. Together the buffers sum to the beauty image.
+pub struct LightGroups {
+    names: Vec<String>,
+    buffers: Vec<Framebuffer>,
+}
+
+pub const UNGROUPED: &'static str = "ungrouped";
+
+impl LightGroups {
+    pub fn new(width: u32, height: u32, names: Vec<String>) -> LightGroups {
+        LightGroups {
+            buffers: (0..names.len() + 1).map(|_| Framebuffer::new(width, height, 3)).collect(),
+            names: names,
+        }
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Framebuffer> {
+        self.names.iter().position(|n| n == name).map(|i| &self.buffers[i])
+    }
+
+    pub fn ungrouped(&self) -> &Framebuffer {
+        &self.buffers[self.names.len()]
+    }
+
+    // Record the light reaching a pixel from each group in turn, then
+    // the rest, on the scale of colour channels
+    pub fn put(&mut self, x: u32, y: u32, split: &[[Float; 3]]) {
+        for (fb, c) in self.buffers.iter_mut().zip(split.iter()) {
+            fb.put(x, y, &[c[0] / 255.0, c[1] / 255.0, c[2] / 255.0]);
+        }
+    }
+
+    // Recombine the groups with each scaled by a tint, as a compositor
+    // would to change the colour and brightness of its lights
+    pub fn relight<F>(&self, tint: F) -> Framebuffer
+        where F: Fn(&str) -> [Float; 3]
+    {
+        let mut out = self.ungrouped().clone();
+        for (name, fb) in self.names.iter().zip(self.buffers.iter()) {
+            let t = tint(name);
+            for y in 0..fb.height() {
+                for x in 0..fb.width() {
+                    let (c, o) = (fb.get(x, y), out.get_mut(x, y));
+                    for i in 0..3 {
+                        o[i] += t[i] * c[i];
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    pub fn beauty(&self) -> RgbImage {
+        self.relight(|_| [1.0; 3]).to_rgb_image()
+    }
+
+    // Write the beauty image as <stem>.png, and each group beside it
+    // as a float map named <stem>.light.<group>.pfm
+    pub fn save(&self, stem: &str) -> Result<()> {
+        self.beauty().save(format!("{}.png", stem))?;
+        for (name, fb) in self.names.iter().map(|n| n.as_str()).chain(Some(UNGROUPED))
+            .zip(self.buffers.iter()) {
+            fb.save_pfm(format!("{}.light.{}.pfm", stem, name))?;
+        }
+        Ok(())
+    }
+}
+
+
+// A beauty image with the passes rendered alongside it
+pub struct RenderPasses {
+    pub beauty: RgbImage,
+    passes: Vec<(Pass, Framebuffer)>,
+}
+
+impl RenderPasses {
+    pub fn new(width: u32, height: u32, passes: &[Pass]) -> RenderPasses {
+        let mut wanted: Vec<Pass> = Vec::new();
+        for &pass in passes.iter() {
+            if !wanted.contains(&pass) {
+                wanted.push(pass);
+            }
+        }
+
+        RenderPasses {
+            beauty: ImageBuffer::new(width, height),
+            passes: wanted.into_iter()
+                .map(|pass| {
+                    (pass, Framebuffer::filled(width, height, pass.channels(), pass.background()))
+                })
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, pass: Pass) -> Option<&Framebuffer> {
+        self.passes.iter().find(|&&(p, _)| p == pass).map(|&(_, ref fb)| fb)
+    }
+
+    pub fn record(&mut self, x: u32, y: u32, sample: &Sample) {
+        for &mut (pass, ref mut fb) in self.passes.iter_mut() {
+            fb.put(x, y, &sample.value(pass));
+        }
+    }
+
+    // Write the beauty image as <stem>.png, and each pass beside it as
+    // a float map named <stem>.<pass>.pfm
+    pub fn save(&self, stem: &str) -> Result<()> {
+        self.beauty.save(format!("{}.png", stem))?;
+        for &(pass, ref fb) in self.passes.iter() {
+            fb.save_pfm(format!("{}.{}.pfm", stem, pass.name()))?;
+        }
+        Ok(())
+    }
+}
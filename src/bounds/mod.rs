@@ -0,0 +1,105 @@
+// bounds - axis aligned bounding boxes
+use precision::Float;
+use vector3d::Vec3;
+use ray::Ray;
+use packet::{Lanes, Mask, RayPacket};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(corner1: Vec3, corner2: Vec3) -> Aabb {
+        Aabb {
+            min: corner1.min(corner2),
+            max: corner1.max(corner2),
+        }
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn contains(&self, p: Vec3) -> bool {
+        p.min(self.min) == self.min && p.max(self.max) == self.max
+    }
+
+    // The slab test: clip the ray against the pair of planes bounding
+    // each axis in turn. Gives the distances along the ray at which it
+    // enters and leaves the box, if it does so ahead of the origin.
+    pub fn intersect(&self, ray: &Ray) -> Option<(Float, Float)> {
+        let inv = Vec3(1.0 / ray.direction.i(),
+                       1.0 / ray.direction.j(),
+                       1.0 / ray.direction.k());
+        let t0 = (self.min - ray.origin).component_mul(inv);
+        let t1 = (self.max - ray.origin).component_mul(inv);
+
+        let t_enter = t0.min(t1).max_component().max(0.0);
+        let t_exit = t0.max(t1).min_component();
+
+        if t_enter <= t_exit { Some((t_enter, t_exit)) } else { None }
+    }
+
+    pub fn intersect_packet(&self, packet: &RayPacket) -> Mask {
+        let one = Lanes::splat(1.0);
+        let slab = |min: Float, max: Float, o: Lanes, d: Lanes| {
+            let inv = one / d;
+            let t0 = (Lanes::splat(min) - o) * inv;
+            let t1 = (Lanes::splat(max) - o) * inv;
+            (t0.min(t1), t0.max(t1))
+        };
+
+        let (o, d) = (packet.origin, packet.direction);
+        let (x0, x1) = slab(self.min.i(), self.max.i(), o.x, d.x);
+        let (y0, y1) = slab(self.min.j(), self.max.j(), o.y, d.y);
+        let (z0, z1) = slab(self.min.k(), self.max.k(), o.z, d.z);
+
+        let t_enter = x0.max(y0).max(z0).max(Lanes::splat(0.0));
+        let t_exit = x1.min(y1).min(z1);
+        t_enter.le(t_exit)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packet::WIDTH;
+
+    #[test]
+    fn test_ray_enters_and_leaves_box() {
+        let aabb = Aabb::new(Vec3(1.0, 1.0, 1.0), Vec3(-1.0, -1.0, -1.0));
+        let ray = Ray::new(Vec3(0.0, 0.0, -5.0), Vec3(0.0, 0.0, 1.0));
+
+        assert!(aabb.intersect(&ray) == Some((4.0, 6.0)));
+        assert!(aabb.contains(Vec3(0.5, -0.5, 1.0)));
+    }
+
+    #[test]
+    fn test_ray_misses_box_behind_it() {
+        let aabb = Aabb::new(Vec3(1.0, 1.0, 1.0), Vec3(-1.0, -1.0, -1.0));
+        let ray = Ray::new(Vec3(0.0, 0.0, 5.0), Vec3(0.0, 0.0, 1.0));
+
+        assert!(aabb.intersect(&ray).is_none());
+    }
+
+    #[test]
+    fn test_packet_box_test_agrees_with_scalar() {
+        let aabb = Aabb::new(Vec3(1.0, 1.0, 1.0), Vec3(-1.0, -1.0, -1.0));
+        let o = Vec3(0.0, 0.0, -5.0);
+        let rays = [Ray::new(o, Vec3(0.0, 0.0, 1.0)),
+                    Ray::new(o, Vec3(0.5, 0.0, 1.0)),
+                    Ray::new(o, Vec3(0.1, -0.1, 1.0)),
+                    Ray::new(o, Vec3(0.0, 0.0, -1.0))];
+        let hits = aabb.intersect_packet(&RayPacket::new(rays));
+
+        for i in 0..WIDTH {
+            assert!(hits.lane(i) == aabb.intersect(&rays[i]).is_some());
+        }
+    }
+}
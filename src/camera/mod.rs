@@ -1,53 +1,69 @@
 // camera - module for describing camera operations
+use precision::Float;
 use vector3d::Vec3;
 use algebra::InnerProductSpace;
-use space_algebra::SO3;
+use space_algebra::{SO3, UnitQuaternion};
 use ray::Ray;
+use packet::{RayPacket, WIDTH};
+use error::{Error, Result};
 
-use std::f64;
-use std::f64::consts::PI;
 use std::ops::{Add, Mul};
 
+#[cfg(test)]
+use precision::float;
+#[cfg(test)]
+use precision::float::consts::PI;
+
 #[derive(Clone,Copy,PartialEq)]
 pub struct CameraBuilder {
     canvas_x: u32,
     canvas_y: u32,
-    field_of_view: f64,
+    field_of_view: Float,
     position: Vec3,
     orientation: SO3,
 }
 
+// Where a moving camera is and which way it faces at a time, for
+// blurring its motion while the shutter is open
+pub trait CameraMotion: Send + Sync {
+    fn pose(&self, time: Float) -> (Vec3, SO3);
+}
+
 pub struct Camera {
     canvas_x: u32,
     canvas_y: u32,
-    width: f64,
-    height: f64,
+    width: Float,
+    height: Float,
     position: Vec3,
     orientation: SO3,
+    // The times the shutter opens and closes
+    shutter: (Float, Float),
+    motion: Option<Box<CameraMotion>>,
 }
 
 impl CameraBuilder {
-    pub fn new(x: u32, y: u32, fov: f64) -> CameraBuilder {
-        if fov <= 0.0 || fov >= 180.0 {
-            panic!("CameraBuilder: field of view too big or too small: {0}",
-                   fov);
+    pub fn new(x: u32, y: u32, fov: Float) -> Result<CameraBuilder> {
+        if !(fov > 0.0 && fov < 180.0) {
+            Err(Error::FieldOfView(fov))
+        } else if x == 0 || y == 0 {
+            Err(Error::CanvasSize(x, y))
         } else {
-            CameraBuilder {
+            Ok(CameraBuilder {
                 canvas_x: x,
                 canvas_y: y,
                 field_of_view: fov,
                 position: Vec3::zero(),
                 orientation: SO3::identity(),
-            }
+            })
         }
     }
 
-    fn _get_world_height(&self) -> f64 {
+    fn _get_world_height(&self) -> Float {
         2.0 * (self.field_of_view / 2.0).tan()
     }
 
-    fn _get_world_width(&self) -> f64 {
-        (self.canvas_x as f64) / (self.canvas_y as f64) * self._get_world_height()
+    fn _get_world_width(&self) -> Float {
+        (self.canvas_x as Float) / (self.canvas_y as Float) * self._get_world_height()
     }
 
 
@@ -60,35 +76,127 @@ impl CameraBuilder {
             height: self._get_world_height(),
             position: self.position,
             orientation: self.orientation,
+            shutter: (0.0, 0.0),
+            motion: None,
         }
     }
 }
 
 
 impl Camera {
+    // Keep the shutter open from one time to another, so that rays are
+    // spread over the interval and anything moving is blurred
+    pub fn with_shutter(self, open: Float, close: Float) -> Camera {
+        Camera { shutter: (open, close), ..self }
+    }
+
+    // Move the camera while the shutter is open, in place of its fixed
+    // position and orientation
+    pub fn with_motion(self, motion: Box<CameraMotion>) -> Camera {
+        Camera { motion: Some(motion), ..self }
+    }
+
+    pub fn shutter(&self) -> (Float, Float) {
+        self.shutter
+    }
+
+    fn _get_pose(&self, time: Float) -> (Vec3, SO3) {
+        match self.motion {
+            Some(ref motion) => motion.pose(time),
+            None => (self.position, self.orientation),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.canvas_x
+    }
 
-    fn _get_zero_ray_direction(&self, x: u32, y: u32) -> Vec3 {
-        assert!(x >= 0);
-        assert!(x < self.canvas_x);
-        assert!(y >= 0);
-        assert!(y < self.canvas_y);
+    pub fn height(&self) -> u32 {
+        self.canvas_y
+    }
+
+    fn _get_zero_ray_direction(&self, x: u32, y: u32, dx: Float, dy: Float) -> Result<Vec3> {
+        if x >= self.canvas_x || y >= self.canvas_y {
+            return Err(Error::PixelOutOfRange {
+                x: x,
+                y: y,
+                width: self.canvas_x,
+                height: self.canvas_y,
+            });
+        }
+
+        let x_step = self.width / (self.canvas_x as Float);
+        let y_step = self.height / (self.canvas_y as Float);
+
+        Ok(Vec3(x_step * ((x as Float) + dx - (self.canvas_x as Float) / 2.0),
+                y_step * ((y as Float) + dy - (self.canvas_y as Float) / 2.0),
+                1.0))
+    }
+
+    pub fn get_direction_through_pixel(&self, x: u32, y: u32) -> Result<Vec3> {
+        self._get_zero_ray_direction(x, y, 0.0, 0.0).map(|d| self.orientation * d)
+    }
+
+    // A ray through a point offset from the usual one through a pixel,
+    // for taking several samples over the pixel. Offsets are in pixels.
+    // The ray is taken in the middle of the shutter interval.
+    pub fn get_ray_through_subpixel(&self, x: u32, y: u32, dx: Float, dy: Float) -> Result<Ray> {
+        self.get_ray_through_subpixel_at(x, y, dx, dy, 0.5)
+    }
+
+    // As get_ray_through_subpixel, taken a fraction u of the way
+    // through the shutter interval, from the camera where it is then
+    pub fn get_ray_through_subpixel_at(&self, x: u32, y: u32, dx: Float, dy: Float, u: Float)
+                                       -> Result<Ray> {
+        let (open, close) = self.shutter;
+        let time = open + u * (close - open);
+        self._get_zero_ray_direction(x, y, dx, dy).map(|d| {
+            let (position, orientation) = self._get_pose(time);
+            Ray {
+                origin: position,
+                direction: orientation * d,
+                time: time,
+            }
+        })
+    }
 
-        let x_step = self.width / (self.canvas_x as f64);
-        let y_step = self.height / (self.canvas_y as f64);
+    pub fn get_ray_through_pixel(&self, x: u32, y: u32) -> Result<Ray> {
+        self.get_ray_through_subpixel(x, y, 0.0, 0.0)
+    }
 
-        Vec3(x_step * ((x as f64) - (self.canvas_x as f64) / 2.0),
-             y_step * ((y as f64) - (self.canvas_y as f64) / 2.0),
-             1.0)
+    // Rays through several pixels at once, for tracing as a packet.
+    // Neighbouring pixels give coherent rays which trace best together.
+    pub fn get_packet_through_pixels(&self, pixels: [(u32, u32); WIDTH]) -> Result<RayPacket> {
+        let ray = |i: usize| self.get_ray_through_pixel(pixels[i].0, pixels[i].1);
+        Ok(RayPacket::new([ray(0)?, ray(1)?, ray(2)?, ray(3)?]))
     }
+}
+
+// Where the camera sits and how it is turned; the size of the image
+// is left to whoever renders it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CameraSpec {
+    pub position: Vec3,
+    pub orientation: UnitQuaternion,
+    pub fov: Float,
+    // The fraction of a frame the shutter stays open for
+    pub shutter: Float,
+}
 
-    pub fn get_direction_through_pixel(&self, x: u32, y: u32) -> Vec3 {
-        self.orientation * self._get_zero_ray_direction(x, y)
+impl CameraSpec {
+    pub fn build(&self, width: u32, height: u32) -> Result<Camera> {
+        let builder = CameraBuilder::new(width, height, self.fov)?;
+        Ok((SO3::from(self.orientation) * builder + self.position).build())
     }
+}
 
-    pub fn get_ray_through_pixel(&self, x: u32, y: u32) -> Ray {
-        Ray {
-            origin: self.position,
-            direction: self.get_direction_through_pixel(x, y),
+impl Default for CameraSpec {
+    fn default() -> CameraSpec {
+        CameraSpec {
+            position: Vec3::zero(),
+            orientation: UnitQuaternion::identity(),
+            fov: 45.0,
+            shutter: 0.0,
         }
     }
 }
@@ -122,25 +230,80 @@ impl Add<Vec3> for CameraBuilder {
 
 #[test]
 fn test_get_zero_ray_direction() {
-    let camera = CameraBuilder::new(100, 100, 90.0).build();
-    assert!(camera.get_direction_through_pixel(50, 50) == Vec3(0.0, 0.0, 1.0));
+    let camera = CameraBuilder::new(100, 100, 90.0).unwrap().build();
+    assert!(camera.get_direction_through_pixel(50, 50).unwrap() == Vec3(0.0, 0.0, 1.0));
 }
 
 #[test]
 fn test_get_rotated_camera_direction() {
-    let precamera = SO3::rotation_y(PI / 2.0) * CameraBuilder::new(100, 100, 90.0);
+    let precamera = SO3::rotation_y(PI / 2.0) * CameraBuilder::new(100, 100, 90.0).unwrap();
     let camera = precamera.build();
-    let tolerance = 0.00000001;
-    let v = camera.get_direction_through_pixel(50, 50) - Vec3(-1.0, 0.0, 0.0);
-    assert!(v.dot(v) < tolerance);
+    let tolerance = float::EPSILON.sqrt();
+    let v = camera.get_direction_through_pixel(50, 50).unwrap();
+    assert!(v.approx_eq(Vec3(-1.0, 0.0, 0.0), tolerance));
 }
 
 #[test]
 fn test_get_ray_through_pixel() {
-    let camera = (CameraBuilder::new(100, 100, 90.0) + Vec3(1.0, 0.0, 0.0)).build();
+    let camera = (CameraBuilder::new(100, 100, 90.0).unwrap() + Vec3(1.0, 0.0, 0.0)).build();
     let expected = Ray {
         origin: Vec3(1.0, 0.0, 0.0),
         direction: Vec3(0.0, 0.0, 1.0),
+        time: 0.0,
     };
-    assert!(expected == camera.get_ray_through_pixel(50, 50));
+    assert!(expected == camera.get_ray_through_pixel(50, 50).unwrap());
+}
+
+#[test]
+fn test_subpixel_rays_are_offset_by_fractions_of_pixels() {
+    let camera = CameraBuilder::new(100, 100, 90.0).unwrap().build();
+    let centre = camera.get_ray_through_subpixel(50, 50, 0.0, 0.0).unwrap();
+    let right = camera.get_ray_through_subpixel(50, 50, 1.0, 0.0).unwrap();
+
+    assert!(centre == camera.get_ray_through_pixel(50, 50).unwrap());
+    assert!(right.direction == camera.get_ray_through_pixel(51, 50).unwrap().direction);
+    assert!(camera.get_ray_through_subpixel(100, 0, -0.5, 0.0).is_err());
+}
+
+#[test]
+fn test_rays_spread_over_the_shutter() {
+    struct Sliding;
+    impl CameraMotion for Sliding {
+        fn pose(&self, time: Float) -> (Vec3, SO3) {
+            (Vec3(time, 0.0, 0.0), SO3::identity())
+        }
+    }
+
+    let camera = CameraBuilder::new(100, 100, 90.0).unwrap().build().with_shutter(2.0, 3.0);
+    assert!(camera.get_ray_through_pixel(50, 50).unwrap().time == 2.5);
+    assert!(camera.get_ray_through_subpixel_at(50, 50, 0.0, 0.0, 0.0).unwrap().time == 2.0);
+
+    let moving = camera.with_motion(Box::new(Sliding));
+    let ray = moving.get_ray_through_subpixel_at(50, 50, 0.0, 0.0, 1.0).unwrap();
+    assert!(ray.origin == Vec3(3.0, 0.0, 0.0) && ray.time == 3.0);
+}
+
+#[test]
+fn test_bad_field_of_view_is_an_error() {
+    assert!(CameraBuilder::new(100, 100, 180.0).is_err());
+    assert!(CameraBuilder::new(100, 100, 0.0).is_err());
+}
+
+#[test]
+fn test_pixel_outside_canvas_is_an_error() {
+    let camera = CameraBuilder::new(100, 100, 90.0).unwrap().build();
+    assert!(camera.get_ray_through_pixel(100, 50).is_err());
+    assert!(camera.get_ray_through_pixel(50, 100).is_err());
+}
+
+#[test]
+fn test_get_packet_through_pixels() {
+    let camera = CameraBuilder::new(100, 100, 90.0).unwrap().build();
+    let pixels = [(0, 0), (99, 0), (0, 99), (50, 50)];
+    let packet = camera.get_packet_through_pixels(pixels).unwrap();
+
+    for i in 0..WIDTH {
+        assert!(packet.ray(i) == camera.get_ray_through_pixel(pixels[i].0, pixels[i].1).unwrap());
+    }
+    assert!(camera.get_packet_through_pixels([(0, 0), (0, 0), (0, 0), (0, 100)]).is_err());
 }
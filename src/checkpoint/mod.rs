@@ -0,0 +1,382 @@
+// checkpoint - saving a render as it goes, so that one which is killed
+// can carry on where it was, and so that renders of the same scene
+// made apart can be added together. A checkpoint holds the job, the
+// sum of each pixel's samples so far and how many there were.
+//
+// A checkpoint file is the job as it is sent to workers, followed by
+//
+//     region full | cropped <x> <y> <width> <height> | padded ...
+//     pixels
+//
+// then the sums of the region's pixels and their counts, row by row,
+// as little endian 64 bit floats and 32 bit integers.
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use precision::Float;
+use camera::Camera;
+use scene::Scene;
+use shade::Shader;
+use raytrace::Raytracer;
+use parallel::{Region, Tile, region_tiles};
+use framebuffer::Framebuffer;
+use distributed::Job;
+use error::{Error, Result};
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Checkpoint {
+    // The samples in its options are how many each pixel should reach
+    pub job: Job,
+    area: Tile,
+    sums: Framebuffer,
+    counts: Vec<u32>,
+}
+
+impl Checkpoint {
+    // A render of the job not yet begun
+    pub fn new(job: Job) -> Result<Checkpoint> {
+        let area = job.options.region.pixels(job.width, job.height)?;
+        Ok(Checkpoint {
+            job: job,
+            area: area,
+            sums: Framebuffer::new(area.width, area.height, 3),
+            counts: vec![0; (area.width * area.height) as usize],
+        })
+    }
+
+    // How many samples each pixel of the region has, row by row
+    pub fn counts(&self) -> &[u32] {
+        &self.counts
+    }
+
+    // The fewest samples any pixel has
+    pub fn samples_done(&self) -> u32 {
+        self.counts.iter().cloned().min().unwrap_or(0)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.samples_done() >= self.job.options.samples
+    }
+
+    // The mean of each pixel's samples so far, as render_parallel
+    // gives it
+    pub fn image(&self) -> Result<Framebuffer> {
+        let mut means = self.sums.clone();
+        for y in 0..self.area.height {
+            for x in 0..self.area.width {
+                let count = self.count(x, y).max(1) as Float;
+                for c in means.get_mut(x, y).iter_mut() {
+                    *c /= count;
+                }
+            }
+        }
+        self.job.options.region.place(self.job.width, self.job.height, &means)
+    }
+
+    fn count(&self, x: u32, y: u32) -> u32 {
+        self.counts[(y * self.area.width + x) as usize]
+    }
+
+    // Add the samples of another render of the same job. The two must
+    // have been seeded differently, or they would hold the same samples.
+    // Afterwards each pixel should reach the samples of both.
+    pub fn merge(&mut self, other: &Checkpoint) -> Result<()> {
+        let (a, b) = (&self.job, &other.job);
+        let (o, p) = (&a.options, &b.options);
+        if a.scene != b.scene || a.width != b.width || a.height != b.height ||
+           a.shader != b.shader || a.frame != b.frame || o.depth != p.depth ||
+           o.sampler != p.sampler || o.region != p.region {
+            return Err(Error::Checkpoint("checkpoints are of different renders".to_string()));
+        }
+        if o.seed == p.seed {
+            return Err(Error::Checkpoint(format!("both checkpoints are seeded with {}, so \
+                                                  their samples are the same",
+                                                 o.seed)));
+        }
+        self.job.options.samples += p.samples;
+        for y in 0..self.area.height {
+            for x in 0..self.area.width {
+                let i = (y * self.area.width + x) as usize;
+                self.counts[i] += other.counts[i];
+                for (s, &t) in self.sums.get_mut(x, y).iter_mut().zip(other.sums.get(x, y)) {
+                    *s += t;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        self.job.write(out)?;
+        match self.job.options.region {
+            Region::Full => writeln!(out, "region full")?,
+            Region::Cropped(r) => {
+                writeln!(out, "region cropped {} {} {} {}", r.x, r.y, r.width, r.height)?
+            }
+            Region::Padded(r) => {
+                writeln!(out, "region padded {} {} {} {}", r.x, r.y, r.width, r.height)?
+            }
+        }
+        writeln!(out, "pixels")?;
+        self.sums.write_raw(out)?;
+        let mut bytes = Vec::with_capacity(self.counts.len() * 4);
+        for &n in self.counts.iter() {
+            bytes.extend_from_slice(&n.to_le_bytes());
+        }
+        out.write_all(&bytes)?;
+        Ok(out.flush()?)
+    }
+
+    pub fn read<R: BufRead>(input: &mut R) -> Result<Checkpoint> {
+        let mut job = Job::read(input).map_err(|e| match e {
+                Error::Protocol(message) => Error::Checkpoint(message),
+                e => e,
+            })?;
+        job.options.region = read_region(input)?;
+        if read_line(input)? != "pixels" {
+            return Err(Error::Checkpoint("expected pixels".to_string()));
+        }
+
+        let mut checkpoint = Checkpoint::new(job)?;
+        let area = checkpoint.area;
+        checkpoint.sums = Framebuffer::read_raw(input, area.width, area.height, 3)?;
+        let mut bytes = vec![0; checkpoint.counts.len() * 4];
+        input.read_exact(&mut bytes)?;
+        for (n, b) in checkpoint.counts.iter_mut().zip(bytes.chunks(4)) {
+            *n = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        }
+        Ok(checkpoint)
+    }
+
+    // Write the checkpoint beside the path and then move it there, so
+    // that being killed while saving leaves the last one whole
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        {
+            let mut out = BufWriter::new(File::create(&partial)?);
+            self.write(&mut out)?;
+        }
+        Ok(fs::rename(&partial, path)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Checkpoint> {
+        Checkpoint::read(&mut BufReader::new(File::open(path)?))
+    }
+}
+
+fn read_line<R: BufRead>(input: &mut R) -> Result<String> {
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    Ok(line.trim_end().to_string())
+}
+
+fn read_region<R: BufRead>(input: &mut R) -> Result<Region> {
+    let line = read_line(input)?;
+    let words: Vec<&str> = line.split(' ').collect();
+    let tile = || -> Result<Tile> {
+        let n = words[2..].iter()
+            .map(|w| w.parse().map_err(|_| Error::Checkpoint(format!("bad region '{}'", line))))
+            .collect::<Result<Vec<u32>>>()?;
+        match n.as_slice() {
+            &[x, y, width, height] => Ok(Tile::new(x, y, width, height)),
+            _ => Err(Error::Checkpoint(format!("bad region '{}'", line))),
+        }
+    };
+    match (words.get(0).cloned(), words.get(1).cloned()) {
+        (Some("region"), Some("full")) if words.len() == 2 => Ok(Region::Full),
+        (Some("region"), Some("cropped")) => Ok(Region::Cropped(tile()?)),
+        (Some("region"), Some("padded")) => Ok(Region::Padded(tile()?)),
+        _ => Err(Error::Checkpoint(format!("expected a region, got '{}'", line))),
+    }
+}
+
+impl<S: Shader + Sync> Raytracer<S> {
+    // Carry on the checkpoint's render in passes of at most the given
+    // number of samples a pixel, until every pixel has as many as the
+    // job asks for, handing the checkpoint to after_pass after each.
+    // Each sample is the one render_parallel would take, so a render
+    // which is stopped and resumed comes out the same as one which is
+    // not, give or take rounding.
+    pub fn render_checkpointed<F>(&self, camera: &Camera, scene: &Scene,
+                                  checkpoint: &mut Checkpoint, pass: u32, mut after_pass: F)
+                                  -> Result<()>
+        where F: FnMut(&Checkpoint) -> Result<()>
+    {
+        let options = checkpoint.job.options;
+        let area = checkpoint.area;
+        while !checkpoint.is_complete() {
+            // Each tile carries on from where its pixels are. Should
+            // they not all be at the same place, its pixels go alone.
+            let mut batch = Vec::new();
+            for tile in region_tiles(area, options.tile_size) {
+                let (x, y) = (tile.x - area.x, tile.y - area.y);
+                let first = checkpoint.count(x, y);
+                let even = (0..tile.height)
+                    .all(|j| (0..tile.width).all(|i| checkpoint.count(x + i, y + j) == first));
+                let pieces = if even {
+                    vec![(tile, first)]
+                } else {
+                    region_tiles(tile, 1)
+                        .into_iter()
+                        .map(|p| (p, checkpoint.count(p.x - area.x, p.y - area.y)))
+                        .collect()
+                };
+                for (piece, first) in pieces {
+                    if first < options.samples {
+                        batch.push((piece, first..options.samples.min(first + pass.max(1))));
+                    }
+                }
+            }
+
+            let rendered = self.render_batch(camera, scene, &options, &batch, |_, _| ())?;
+            for (&(piece, ref samples), pixels) in batch.iter().zip(rendered) {
+                let weight = samples.len() as Float;
+                for j in 0..piece.height {
+                    for i in 0..piece.width {
+                        let (x, y) = (piece.x - area.x + i, piece.y - area.y + j);
+                        checkpoint.counts[(y * area.width + x) as usize] += samples.len() as u32;
+                        for (s, &p) in checkpoint.sums.get_mut(x, y).iter_mut()
+                            .zip(pixels.get(i, j)) {
+                            *s += p * weight;
+                        }
+                    }
+                }
+            }
+            after_pass(checkpoint)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parallel::RenderOptions;
+    use sampler::SamplerKind;
+
+    const SCENE: &'static str = "
+        camera position 0 0 0 rotate 0 0 0 fov 90
+        ambient 40 40 40
+        light 2 -4 0
+        sphere 0 0 5 radius 1.5
+        sphere 2 1 6 radius 1
+    ";
+
+    fn job(options: RenderOptions) -> Job {
+        Job {
+            scene: SCENE.to_string(),
+            width: 16,
+            height: 12,
+            shader: "phong".to_string(),
+            frame: 0.0,
+            options: options.with_tile_size(5),
+        }
+    }
+
+    fn assert_close(a: &Framebuffer, b: &Framebuffer) {
+        assert!(a.width() == b.width() && a.height() == b.height());
+        for y in 0..a.height() {
+            for x in 0..a.width() {
+                for (p, q) in a.get(x, y).iter().zip(b.get(x, y)) {
+                    assert!((p - q).abs() < 1e-4);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_stopped_renders_resume_where_they_were() {
+        let job = job(RenderOptions::new().with_samples(5).with_seed(4));
+        let (camera, scene, tracer) = job.prepare().unwrap();
+        let whole = tracer.render_parallel(&camera, &scene, &job.options).unwrap();
+
+        // Stop after the first pass, as if killed, keeping what was saved
+        let mut saved = Vec::new();
+        let mut checkpoint = Checkpoint::new(job.clone()).unwrap();
+        let stopped = tracer.render_checkpointed(&camera, &scene, &mut checkpoint, 2, |c| {
+            c.write(&mut saved)?;
+            Err(Error::Checkpoint("stop".to_string()))
+        });
+        assert!(stopped.is_err());
+
+        let mut resumed = Checkpoint::read(&mut saved.as_slice()).unwrap();
+        assert!(resumed.samples_done() == 2 && !resumed.is_complete());
+        let mut passes = 0;
+        tracer.render_checkpointed(&camera, &scene, &mut resumed, 2, |_| Ok(passes += 1))
+            .unwrap();
+        assert!(passes == 2);
+        assert!(resumed.counts().iter().all(|&n| n == 5));
+        assert_close(&resumed.image().unwrap(), &whole);
+    }
+
+    #[test]
+    fn test_checkpoints_round_trip() {
+        let region = Region::Padded(Tile::new(2, 3, 7, 5));
+        let job = job(RenderOptions::new()
+            .with_samples(3)
+            .with_sampler(SamplerKind::Stratified)
+            .with_region(region));
+        let (camera, scene, tracer) = job.prepare().unwrap();
+        let mut checkpoint = Checkpoint::new(job).unwrap();
+        tracer.render_checkpointed(&camera, &scene, &mut checkpoint, 1, |_| Ok(())).unwrap();
+
+        let mut bytes = Vec::new();
+        checkpoint.write(&mut bytes).unwrap();
+        assert!(Checkpoint::read(&mut bytes.as_slice()).unwrap() == checkpoint);
+        assert!(Checkpoint::read(&mut &bytes[..bytes.len() - 1]).is_err());
+        assert!(checkpoint.image().unwrap().width() == 16);
+    }
+
+    #[test]
+    fn test_uneven_counts_are_evened_out() {
+        let job = job(RenderOptions::new().with_samples(4));
+        let (camera, scene, tracer) = job.prepare().unwrap();
+        let whole = tracer.render_parallel(&camera, &scene, &job.options).unwrap();
+
+        let mut checkpoint = Checkpoint::new(job.clone()).unwrap();
+        let first = tracer.render_samples(&camera, &scene, &job.options, Tile::new(3, 3, 1, 1),
+                                          0..3)
+            .unwrap();
+        checkpoint.sums.put(3, 3, &[3.0 * first.get(0, 0)[0],
+                                    3.0 * first.get(0, 0)[1],
+                                    3.0 * first.get(0, 0)[2]]);
+        checkpoint.counts[3 * 16 + 3] = 3;
+        tracer.render_checkpointed(&camera, &scene, &mut checkpoint, 8, |_| Ok(())).unwrap();
+        assert!(checkpoint.counts().iter().all(|&n| n == 4));
+        assert_close(&checkpoint.image().unwrap(), &whole);
+    }
+
+    #[test]
+    fn test_merged_renders_hold_both() {
+        let a = job(RenderOptions::new().with_samples(2).with_seed(1));
+        let b = job(RenderOptions::new().with_samples(3).with_seed(2));
+        let (camera, scene, tracer) = a.prepare().unwrap();
+        let mut first = Checkpoint::new(a.clone()).unwrap();
+        let mut second = Checkpoint::new(b.clone()).unwrap();
+        tracer.render_checkpointed(&camera, &scene, &mut first, 8, |_| Ok(())).unwrap();
+        tracer.render_checkpointed(&camera, &scene, &mut second, 8, |_| Ok(())).unwrap();
+        let one = tracer.render_parallel(&camera, &scene, &a.options).unwrap();
+        let two = tracer.render_parallel(&camera, &scene, &b.options).unwrap();
+
+        let mut merged = first.clone();
+        merged.merge(&second).unwrap();
+        assert!(merged.job.options.samples == 5 && merged.is_complete());
+        let image = merged.image().unwrap();
+        for y in 0..12 {
+            for x in 0..16 {
+                for k in 0..3 {
+                    let mean = (2.0 * one.get(x, y)[k] + 3.0 * two.get(x, y)[k]) / 5.0;
+                    assert!((image.get(x, y)[k] - mean).abs() < 1e-4);
+                }
+            }
+        }
+
+        assert!(first.clone().merge(&first).is_err());
+        let other = Checkpoint::new(Job { width: 8, ..b }).unwrap();
+        assert!(first.merge(&other).is_err());
+    }
+}
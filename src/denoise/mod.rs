@@ -0,0 +1,271 @@
+// denoise - smoothing noisy renders while keeping their edges, guided
+// by the auxiliary passes rendered alongside them
+use precision::Float;
+use framebuffer::Framebuffer;
+use aov::{Pass, RenderPasses};
+
+// The auxiliary images which tell the filter where edges lie. Any may
+// be left out, at the cost of blurring the edges only it shows.
+#[derive(Clone, Copy, Default)]
+pub struct Guides<'a> {
+    pub albedo: Option<&'a Framebuffer>,
+    pub normal: Option<&'a Framebuffer>,
+    pub depth: Option<&'a Framebuffer>,
+}
+
+impl<'a> Guides<'a> {
+    pub fn from_passes(passes: &'a RenderPasses) -> Guides<'a> {
+        Guides {
+            albedo: passes.get(Pass::Albedo),
+            normal: passes.get(Pass::Normal),
+            depth: passes.get(Pass::Depth),
+        }
+    }
+}
+
+// An edge-avoiding a-trous wavelet filter. Each iteration blurs with
+// a 5x5 B-spline kernel whose taps spread twice as far apart as the
+// last, so wide areas are smoothed in few steps, while each tap is
+// weighted down by how much the guides and colour differ from the
+// centre pixel.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Denoiser {
+    pub iterations: u32,
+    // How far colours may differ, in [0, 1], before counting as an
+    // edge; halved at each iteration as the noise is smoothed away
+    pub colour_sigma: Float,
+    pub albedo_sigma: Float,
+    // Exponent on the cosine between normals
+    pub normal_power: Float,
+    // Relative difference in depth, per pixel of distance
+    pub depth_sigma: Float,
+}
+
+const KERNEL: [Float; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+impl Denoiser {
+    pub fn new() -> Denoiser {
+        Denoiser {
+            iterations: 5,
+            colour_sigma: 0.5,
+            albedo_sigma: 0.1,
+            normal_power: 64.0,
+            depth_sigma: 0.05,
+        }
+    }
+
+    pub fn denoise(&self, noisy: &Framebuffer, guides: &Guides) -> Framebuffer {
+        let mut image = noisy.clone();
+        for i in 0..self.iterations {
+            let sigma = self.colour_sigma / (1 << i) as Float;
+            image = self.step(&image, guides, 1 << i, sigma);
+        }
+        image
+    }
+
+    fn step(&self, image: &Framebuffer, guides: &Guides, spacing: i64, colour_sigma: Float)
+            -> Framebuffer {
+        let (width, height) = (image.width() as i64, image.height() as i64);
+        let channels = image.channels();
+        let mut out = Framebuffer::new(image.width(), image.height(), channels);
+
+        for y in 0..height {
+            for x in 0..width {
+                let p = (x as u32, y as u32);
+                let centre = image.get(p.0, p.1);
+                let mut total = vec![0.0; channels];
+                let mut weights = 0.0;
+
+                for (j, ky) in KERNEL.iter().enumerate() {
+                    for (i, kx) in KERNEL.iter().enumerate() {
+                        let qx = x + (i as i64 - 2) * spacing;
+                        let qy = y + (j as i64 - 2) * spacing;
+                        if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                            continue;
+                        }
+                        let q = (qx as u32, qy as u32);
+                        let (dx, dy) = (qx - x, qy - y);
+                        let distance = ((dx * dx + dy * dy) as Float).sqrt();
+
+                        let sample = image.get(q.0, q.1);
+                        let w = kx * ky *
+                                gaussian(centre, sample, colour_sigma) *
+                                self.guide_weight(guides, p, q, distance);
+                        for c in 0..channels {
+                            total[c] += w * sample[c];
+                        }
+                        weights += w;
+                    }
+                }
+
+                // Guides can still weigh out every tap, the centre's
+                // included, in which case the pixel is kept as it was
+                if weights > 0.0 {
+                    for c in total.iter_mut() {
+                        *c /= weights;
+                    }
+                    out.put(p.0, p.1, &total);
+                } else {
+                    out.put(p.0, p.1, centre);
+                }
+            }
+        }
+        out
+    }
+
+    fn guide_weight(&self, guides: &Guides, p: (u32, u32), q: (u32, u32), distance: Float)
+                    -> Float {
+        let mut w = 1.0;
+        if let Some(albedo) = guides.albedo {
+            w *= gaussian(albedo.get(p.0, p.1), albedo.get(q.0, q.1), self.albedo_sigma);
+        }
+        if let Some(normal) = guides.normal {
+            let (a, b) = (normal.get(p.0, p.1), normal.get(q.0, q.1));
+            let dot = |u: &[Float], v: &[Float]| {
+                u.iter().zip(v.iter()).map(|(x, y)| x * y).sum::<Float>()
+            };
+            // Pixels which see nothing have no normal to compare
+            let lengths = (dot(a, a).sqrt(), dot(b, b).sqrt());
+            if lengths.0 > 0.0 && lengths.1 > 0.0 {
+                let cos = dot(a, b) / (lengths.0 * lengths.1);
+                w *= cos.max(0.0).powf(self.normal_power);
+            }
+        }
+        if let Some(depth) = guides.depth {
+            let (a, b) = (depth.get(p.0, p.1)[0], depth.get(q.0, q.1)[0]);
+            // Pixels which see nothing are infinitely deep
+            w *= if a.is_infinite() || b.is_infinite() {
+                if a == b { 1.0 } else { 0.0 }
+            } else {
+                let scale = self.depth_sigma * a.abs().max(b.abs()) * distance.max(1.0);
+                if scale > 0.0 { (-(a - b).abs() / scale).exp() } else { 1.0 }
+            };
+        }
+        w
+    }
+}
+
+impl Default for Denoiser {
+    fn default() -> Denoiser {
+        Denoiser::new()
+    }
+}
+
+fn gaussian(a: &[Float], b: &[Float], sigma: Float) -> Float {
+    let d2 = a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum::<Float>();
+    (-d2 / (sigma * sigma).max(1e-12)).exp()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+    use vector3d::Vec3;
+    use aov::ALL_PASSES;
+    use camera::CameraBuilder;
+    use ray::Shadable;
+    use raytrace::Raytracer;
+    use scene::{Scene, Light, AmbientLight};
+    use shade::PhongShader;
+    use shapes::Sphere;
+
+    // Repeatable noise in [-0.5, 0.5)
+    fn noise(x: u32, y: u32) -> Float {
+        let mut h = x.wrapping_mul(73856093) ^ y.wrapping_mul(19349663);
+        h = (h ^ (h >> 13)).wrapping_mul(0x5bd1e995);
+        (h >> 8) as Float / (1 << 24) as Float - 0.5
+    }
+
+    // A left half darker than the right, with noise over both
+    fn halves(width: u32, height: u32, amount: Float) -> Framebuffer {
+        let mut fb = Framebuffer::new(width, height, 3);
+        for y in 0..height {
+            for x in 0..width {
+                let base = if x < width / 2 { 0.2 } else { 0.8 };
+                let v = base + amount * noise(x, y);
+                fb.put(x, y, &[v, v, v]);
+            }
+        }
+        fb
+    }
+
+    fn normals(width: u32, height: u32) -> Framebuffer {
+        let mut fb = Framebuffer::new(width, height, 3);
+        for y in 0..height {
+            for x in 0..width {
+                let n = if x < width / 2 { [0.0, 0.0, 1.0] } else { [1.0, 0.0, 0.0] };
+                fb.put(x, y, &n);
+            }
+        }
+        fb
+    }
+
+    // Mean squared difference from the clean image
+    fn error(a: &Framebuffer, b: &Framebuffer) -> Float {
+        let mut total = 0.0;
+        for y in 0..a.height() {
+            for x in 0..a.width() {
+                total += (a.get(x, y)[0] - b.get(x, y)[0]).powi(2);
+            }
+        }
+        total / (a.width() * a.height()) as Float
+    }
+
+    #[test]
+    fn test_denoising_reduces_noise_but_keeps_edges() {
+        let (clean, noisy) = (halves(32, 16, 0.0), halves(32, 16, 0.3));
+        let n = normals(32, 16);
+        let guides = Guides { normal: Some(&n), ..Guides::default() };
+
+        let denoised = Denoiser::new().denoise(&noisy, &guides);
+
+        assert!(error(&denoised, &clean) < error(&noisy, &clean) / 4.0);
+        // Either side of the edge stays close to its own level
+        assert!((denoised.get(15, 8)[0] - 0.2).abs() < 0.1);
+        assert!((denoised.get(16, 8)[0] - 0.8).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_flat_images_are_left_alone() {
+        let flat = Framebuffer::filled(8, 8, 3, 0.5);
+        let denoised = Denoiser::new().denoise(&flat, &Guides::default());
+        for y in 0..8 {
+            for x in 0..8 {
+                for &c in denoised.get(x, y).iter() {
+                    assert!((c - 0.5).abs() < 1e-6);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_background_in_rendered_guides_stays_finite() {
+        let scene = Scene {
+            ambient_light: AmbientLight { colour: Rgb([40; 3]) },
+            objects: vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 5.0), 1.0)) as Box<Shadable>],
+            lights: vec![Light::new(Vec3(2.0, -4.0, 0.0), Rgb([255; 3]))],
+            volumes: Vec::new(),
+        };
+        let camera = CameraBuilder::new(12, 8, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let passes = tracer.render_passes(2, &camera, &scene, &ALL_PASSES).unwrap();
+        let beauty = Framebuffer::from_rgb_image(&passes.beauty);
+        let normal = passes.get(Pass::Normal);
+        // The background has a zero normal
+        assert!(normal.unwrap().get(0, 0).iter().all(|&c| c == 0.0));
+
+        for guides in [Guides::from_passes(&passes),
+                       Guides { normal: normal, ..Guides::default() }].iter() {
+            let denoised = Denoiser::new().denoise(&beauty, guides);
+            for y in 0..8 {
+                for x in 0..12 {
+                    assert!(denoised.get(x, y).iter().all(|c| c.is_finite()));
+                }
+            }
+            for (a, b) in denoised.get(0, 0).iter().zip(beauty.get(0, 0).iter()) {
+                assert!((a - b).abs() < 1e-3);
+            }
+        }
+    }
+}
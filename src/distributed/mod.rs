@@ -0,0 +1,751 @@
+// distributed - rendering across processes, and machines, over TCP. A
+// coordinator splits the frame into tiles and ranges of samples and
+// hands them out to workers as they finish their last, then merges
+// the mean colours they send back, weighted by how many samples each
+// took. Work given to a worker which dies or stops answering goes to
+// another. Samples are drawn as they are for a render on one machine,
+// so the image differs from that only by rounding.
+//
+// The protocol is lines of text, except for pixels. The coordinator
+// sends the job:
+//
+//     yars-job 3
+//     width 800
+//     height 600
+//     shader phong
+//     depth 2
+//     samples 16
+//     seed 0
+//     sampler sobol
+//     tile-size 16
+//     frame 1
+//     scene <length in bytes>
+//     <the scene file>
+//
+// which the worker answers with READY, or ERROR and a message. Then
+// for each assignment the coordinator sends
+//
+//     TILE <x> <y> <width> <height> <first sample> <samples>
+//
+// and the worker answers with PIXELS and the counters from rendering
+// them (as stats::Counters::to_words gives them), then the mean red,
+// green and blue of each pixel, row by row, as little endian 64 bit
+// floats; or with ERROR and a message. END closes the connection.
+// Until it answers, a worker sends WORKING every HEARTBEAT, so that
+// the coordinator can tell a slow worker from a dead one.
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Condvar, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use precision::Float;
+use scene_file;
+use camera::Camera;
+use scene::Scene;
+use shade::{self, Shader};
+use raytrace::Raytracer;
+use parallel::{RenderOptions, Region, Tile, region_tiles};
+use framebuffer::Framebuffer;
+use sampler::SamplerKind;
+use stats::{self, Counters};
+use error::{Error, Result};
+
+const VERSION: u32 = 3;
+
+// How often a worker busy with a job says so
+pub const HEARTBEAT: Duration = Duration::from_secs(5);
+
+// The longest scene file a worker accepts
+const MAX_SCENE_LENGTH: usize = 64 << 20;
+
+// The most pixels in a frame a worker accepts, and the widest tiles.
+// Workers hold only a tile's pixels at once, so these bound what one
+// job can make them allocate.
+const MAX_FRAME_PIXELS: u32 = 1 << 28;
+const MAX_TILE_SIZE: u32 = 1024;
+
+// Everything a worker needs to render its share of a frame
+#[derive(Clone, PartialEq, Debug)]
+pub struct Job {
+    // The text of a scene file
+    pub scene: String,
+    pub width: u32,
+    pub height: u32,
+    // One of shade::SHADER_NAMES
+    pub shader: String,
+    // The frame of the scene's animation to render
+    pub frame: Float,
+    // The threads are the worker's own, and the region is the
+    // coordinator's, so neither is sent
+    pub options: RenderOptions,
+}
+
+// A range of samples for a tile of the frame
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Assignment {
+    pub tile: Tile,
+    pub first_sample: u32,
+    pub samples: u32,
+}
+
+// The region cut into tiles, and the samples of each into runs of at
+// most the given length
+pub fn assignments(region: Tile, tile_size: u32, samples: u32, chunk: u32) -> Vec<Assignment> {
+    let chunk = chunk.max(1);
+    let mut assignments = Vec::new();
+    for first in (0..samples.max(1)).step_by(chunk as usize) {
+        for tile in region_tiles(region, tile_size) {
+            assignments.push(Assignment {
+                tile: tile,
+                first_sample: first,
+                samples: chunk.min(samples.max(1) - first),
+            });
+        }
+    }
+    assignments
+}
+
+impl Job {
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        let o = &self.options;
+        writeln!(out, "yars-job {}\nwidth {}\nheight {}\nshader {}",
+               VERSION, self.width, self.height, self.shader)?;
+        writeln!(out, "depth {}\nsamples {}\nseed {}\nsampler {}\ntile-size {}",
+               o.depth, o.samples, o.seed, o.sampler.name(), o.tile_size)?;
+        writeln!(out, "frame {}\nscene {}", self.frame, self.scene.len())?;
+        out.write_all(self.scene.as_bytes())?;
+        Ok(out.flush()?)
+    }
+
+    pub fn read<R: BufRead>(input: &mut R) -> Result<Job> {
+        let version: u32 = parse(&expect(input, "yars-job")?)?;
+        if version != VERSION {
+            return Err(Error::Protocol(format!("unknown job version {}", version)));
+        }
+        let width: u32 = parse(&expect(input, "width")?)?;
+        let height = parse(&expect(input, "height")?)?;
+        let shader = expect(input, "shader")?;
+        let depth = parse(&expect(input, "depth")?)?;
+        let samples = parse(&expect(input, "samples")?)?;
+        let seed = parse(&expect(input, "seed")?)?;
+        let sampler = expect(input, "sampler")?;
+        let sampler = SamplerKind::from_name(&sampler)
+            .ok_or_else(|| Error::Protocol(format!("unknown sampler '{}'", sampler)))?;
+        let tile_size: u32 = parse(&expect(input, "tile-size")?)?;
+        let frame = parse(&expect(input, "frame")?)?;
+        let length: usize = parse(&expect(input, "scene")?)?;
+        if length > MAX_SCENE_LENGTH {
+            return Err(Error::Protocol(format!("scene of {} bytes is too long", length)));
+        }
+        let mut scene = vec![0; length];
+        input.read_exact(&mut scene)?;
+        let scene = String::from_utf8(scene)
+            .map_err(|_| Error::Protocol("scene is not UTF-8".to_string()))?;
+        // Checked once the whole job is read, so the coordinator gets the error
+        if width.checked_mul(height).map_or(true, |n| n > MAX_FRAME_PIXELS) {
+            return Err(Error::Protocol(format!("frame of {}x{} is too large", width, height)));
+        }
+        if tile_size == 0 || tile_size > MAX_TILE_SIZE {
+            return Err(Error::Protocol(format!("tile size {} is out of range", tile_size)));
+        }
+
+        Ok(Job {
+            scene: scene,
+            width: width,
+            height: height,
+            shader: shader,
+            frame: frame,
+            options: RenderOptions::new()
+                .with_depth(depth)
+                .with_samples(samples)
+                .with_seed(seed)
+                .with_sampler(sampler)
+                .with_tile_size(tile_size),
+        })
+    }
+
+    // The camera, scene and tracer to render the job with
+    pub fn prepare(&self) -> Result<(Camera, Scene, Raytracer<Box<Shader + Send + Sync>>)> {
+        let file = scene_file::parse(&self.scene)?.at_frame(self.frame);
+        let camera = file.build_camera(self.width, self.height)?;
+        let shader = shade::by_name(&self.shader, self.options.depth)
+            .ok_or_else(|| Error::Protocol(format!("unknown shader '{}'", self.shader)))?;
+        Ok((camera, file.scene, Raytracer::from_shader(shader)))
+    }
+}
+
+fn read_line<R: BufRead>(input: &mut R) -> Result<String> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(Error::Protocol("connection closed".to_string()));
+    }
+    Ok(line.trim_end().to_string())
+}
+
+// The rest of a line which must begin with the given word
+fn expect<R: BufRead>(input: &mut R, word: &str) -> Result<String> {
+    let line = read_line(input)?;
+    let mut parts = line.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(w), Some(rest)) if w == word => Ok(rest.to_string()),
+        _ => Err(Error::Protocol(format!("expected '{}', got '{}'", word, line))),
+    }
+}
+
+fn parse<T: ::std::str::FromStr>(word: &str) -> Result<T> {
+    word.parse().map_err(|_| Error::Protocol(format!("expected a number, got '{}'", word)))
+}
+
+fn write_pixels<W: Write>(out: &mut W, pixels: &Framebuffer, counters: &Counters)
+                          -> Result<()> {
+    writeln!(out, "PIXELS {}", counters.to_words())?;
+    pixels.write_raw(out)?;
+    Ok(out.flush()?)
+}
+
+// Answer coordinators connecting to the listener, each on its own
+// thread, rendering with the given number of threads (None for one a
+// processor). This returns only if the listener fails.
+pub fn serve(listener: TcpListener, threads: Option<usize>) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        thread::spawn(move || serve_connection(stream, threads));
+    }
+    Ok(())
+}
+
+// Answer one coordinator until it is done or the connection fails
+pub fn serve_connection(stream: TcpStream, threads: Option<usize>) -> Result<()> {
+    let mut out = stream.try_clone()?;
+    let mut input = BufReader::new(stream);
+    let job = match Job::read(&mut input) {
+        Ok(job) => job,
+        Err(e) => {
+            writeln!(out, "ERROR {}", e)?;
+            return Err(e);
+        }
+    };
+
+    let (camera, scene, tracer) = match with_heartbeat(&out, || job.prepare())? {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            writeln!(out, "ERROR {}", e)?;
+            return Err(e);
+        }
+    };
+    let options = match threads {
+        Some(n) => job.options.with_threads(n),
+        None => job.options,
+    };
+    out.write_all(b"READY\n")?;
+
+    loop {
+        let line = read_line(&mut input)?;
+        if line == "END" {
+            return Ok(());
+        }
+        let tile = expect(&mut line.as_bytes(), "TILE").and_then(|a| parse_tile(&a, &job));
+        let (tile, samples) = match tile {
+            Ok(a) => (a.tile, a.first_sample..a.first_sample + a.samples),
+            Err(e) => {
+                writeln!(out, "ERROR {}", e)?;
+                return Err(e);
+            }
+        };
+        stats::take();
+        let rendered = with_heartbeat(&out, || {
+            tracer.render_samples(&camera, &scene, &options, tile, samples)
+        })?;
+        match rendered {
+            Ok(pixels) => write_pixels(&mut out, &pixels, &stats::take())?,
+            Err(e) => writeln!(out, "ERROR {}", e)?,
+        }
+    }
+}
+
+// An assignment, which must be a tile no larger than the job's, lie
+// within its frame and take only the job's samples
+fn parse_tile(words: &str, job: &Job) -> Result<Assignment> {
+    let n = words.split(' ').map(parse).collect::<Result<Vec<u32>>>()?;
+    match n.as_slice() {
+        &[x, y, width, height, first, samples] if samples > 0 => {
+            let size = job.options.tile_size;
+            if width > size || height > size {
+                return Err(Error::Protocol(format!("tile larger than {} in '{}'", size, words)));
+            }
+            let tile = Region::Cropped(Tile::new(x, y, width, height))
+                .pixels(job.width, job.height)?;
+            let end = first.checked_add(samples);
+            if end.map_or(true, |end| end > job.options.samples.max(1)) {
+                return Err(Error::Protocol(format!("samples out of range in '{}'", words)));
+            }
+            Ok(Assignment {
+                tile: tile,
+                first_sample: first,
+                samples: samples,
+            })
+        }
+        _ => Err(Error::Protocol(format!("bad tile '{}'", words))),
+    }
+}
+
+// Do the work, sending WORKING down the stream every HEARTBEAT until
+// it is done
+fn with_heartbeat<T, F: FnOnce() -> T>(out: &TcpStream, work: F) -> Result<T> {
+    let mut beat = out.try_clone()?;
+    let (done, waiting) = mpsc::channel::<()>();
+    Ok(thread::scope(|s| {
+        s.spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = waiting.recv_timeout(HEARTBEAT) {
+                if beat.write_all(b"WORKING\n").is_err() {
+                    break;
+                }
+            }
+        });
+        let result = work();
+        drop(done);
+        result
+    }))
+}
+
+// One connection to a worker, ready for assignments
+struct Connection {
+    input: BufReader<TcpStream>,
+    out: TcpStream,
+}
+
+impl Connection {
+    fn open(address: &str, job: &Job, timeout: Duration) -> Result<Connection> {
+        let mut last = Error::Protocol(format!("'{}' names no address", address));
+        for addr in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    let mut connection = Connection {
+                        input: BufReader::new(stream.try_clone()?),
+                        out: stream,
+                    };
+                    job.write(&mut connection.out)?;
+                    connection.reply()?;
+                    return Ok(connection);
+                }
+                Err(e) => last = Error::Io(e),
+            }
+        }
+        Err(last)
+    }
+
+    // Read the line which begins an answer, turning ERROR into an error
+    // and passing over the worker's heartbeats
+    fn reply(&mut self) -> Result<String> {
+        let mut line = read_line(&mut self.input)?;
+        while line == "WORKING" {
+            line = read_line(&mut self.input)?;
+        }
+        if line.starts_with("ERROR") {
+            return Err(Error::Worker(line["ERROR".len()..].trim().to_string()));
+        }
+        Ok(line)
+    }
+
+    fn render(&mut self, a: &Assignment) -> Result<(Framebuffer, Counters)> {
+        writeln!(self.out, "TILE {} {} {} {} {} {}", a.tile.x, a.tile.y, a.tile.width,
+               a.tile.height, a.first_sample, a.samples)?;
+        let reply = self.reply()?;
+        match reply.splitn(2, ' ').collect::<Vec<_>>().as_slice() {
+            &["PIXELS", counters] => {
+                let counters = Counters::from_words(counters)?;
+                let pixels = Framebuffer::read_raw(&mut self.input, a.tile.width, a.tile.height,
+                                                   3)?;
+                Ok((pixels, counters))
+            }
+            _ => Err(Error::Protocol(format!("expected pixels, got '{}'", reply))),
+        }
+    }
+
+    fn close(mut self) {
+        self.out.write_all(b"END\n").unwrap_or(());
+    }
+}
+
+// Hands out a job's assignments to workers, by address. An address may
+// appear more than once, to keep a worker with many processors busy.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Coordinator {
+    pub workers: Vec<String>,
+    // How long to wait for a worker to connect, or to send anything,
+    // before giving its work to another. Workers send a heartbeat while
+    // they render, so this should be longer than HEARTBEAT but need
+    // not cover the longest assignment.
+    pub timeout: Duration,
+    // The most samples given out in one assignment
+    pub samples_per_assignment: u32,
+}
+
+// What the coordinator's threads share
+struct Progress {
+    queue: VecDeque<Assignment>,
+    remaining: usize,
+    alive: usize,
+    failure: Option<Error>,
+    sums: Framebuffer,
+    counts: Vec<u32>,
+    // What the workers counted while rendering
+    counted: Counters,
+}
+
+impl Coordinator {
+    pub fn new(workers: Vec<String>) -> Coordinator {
+        Coordinator {
+            workers: workers,
+            timeout: Duration::from_secs(30),
+            samples_per_assignment: 16,
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Coordinator {
+        Coordinator { timeout: timeout, ..self }
+    }
+
+    pub fn with_samples_per_assignment(self, samples: u32) -> Coordinator {
+        Coordinator { samples_per_assignment: samples.max(1), ..self }
+    }
+
+    // The mean of each pixel's samples over the job's region, as
+    // render_parallel gives it
+    pub fn render(&self, job: &Job) -> Result<Framebuffer> {
+        self.render_with_progress(job, |_, _| ())
+    }
+
+    // As render, telling the callback how many assignments of how many
+    // are done as each finishes. What the workers count is added to
+    // the counts of this thread.
+    pub fn render_with_progress<F>(&self, job: &Job, progress: F) -> Result<Framebuffer>
+        where F: Fn(usize, usize) + Sync
+    {
+        let region = job.options.region.pixels(job.width, job.height)?;
+        let work = assignments(region, job.options.tile_size, job.options.samples,
+                               self.samples_per_assignment);
+        let total = work.len();
+        let state = Mutex::new(Progress {
+            queue: work.into_iter().collect(),
+            remaining: total,
+            alive: self.workers.len(),
+            failure: if self.workers.is_empty() { Some(Error::NoWorkers) } else { None },
+            sums: Framebuffer::new(region.width, region.height, 3),
+            counts: vec![0; (region.width * region.height) as usize],
+            counted: Counters::new(),
+        });
+        let changed = Condvar::new();
+
+        thread::scope(|s| {
+            for address in self.workers.iter() {
+                let (state, changed, progress) = (&state, &changed, &progress);
+                s.spawn(move || {
+                    self.drive(address, job, region, state, changed, total, progress)
+                });
+            }
+        });
+
+        let Progress { failure, sums, counts, counted, .. } = state.into_inner().unwrap();
+        stats::add(&counted);
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        let mut means = sums;
+        for y in 0..region.height {
+            for x in 0..region.width {
+                let count = counts[(y * region.width + x) as usize].max(1) as Float;
+                for c in means.get_mut(x, y).iter_mut() {
+                    *c /= count;
+                }
+            }
+        }
+        job.options.region.place(job.width, job.height, &means)
+    }
+
+    // Keep one worker busy until the work is done, or it or another
+    // fails for good
+    fn drive<F>(&self, address: &str, job: &Job, region: Tile, state: &Mutex<Progress>,
+                changed: &Condvar, total: usize, progress: &F)
+        where F: Fn(usize, usize) + Sync
+    {
+        let mut connection = match Connection::open(address, job, self.timeout) {
+            Ok(connection) => connection,
+            Err(e) => return lose(state, changed, None, e),
+        };
+        loop {
+            // Wait for work, which may come back from a worker which dies
+            let assignment = {
+                let mut shared = state.lock().unwrap();
+                loop {
+                    if shared.failure.is_some() || shared.remaining == 0 {
+                        break None;
+                    }
+                    if let Some(a) = shared.queue.pop_front() {
+                        break Some(a);
+                    }
+                    shared = changed.wait(shared).unwrap();
+                }
+            };
+            let a = match assignment {
+                Some(a) => a,
+                None => return connection.close(),
+            };
+            let (pixels, counted) = match connection.render(&a) {
+                Ok(rendered) => rendered,
+                Err(e) => return lose(state, changed, Some(a), e),
+            };
+
+            let mut shared = state.lock().unwrap();
+            let weight = a.samples as Float;
+            for y in 0..a.tile.height {
+                for x in 0..a.tile.width {
+                    let (rx, ry) = (a.tile.x + x - region.x, a.tile.y + y - region.y);
+                    shared.counts[(ry * region.width + rx) as usize] += a.samples;
+                    for (s, &p) in shared.sums.get_mut(rx, ry).iter_mut().zip(pixels.get(x, y)) {
+                        *s += p * weight;
+                    }
+                }
+            }
+            shared.counted.add(&counted);
+            shared.remaining -= 1;
+            progress(total - shared.remaining, total);
+            changed.notify_all();
+        }
+    }
+}
+
+// Give up on a worker, handing back the assignment it had
+fn lose(state: &Mutex<Progress>, changed: &Condvar, assignment: Option<Assignment>,
+        error: Error) {
+    let mut shared = state.lock().unwrap();
+    if let Some(a) = assignment {
+        shared.queue.push_front(a);
+    }
+    shared.alive -= 1;
+    if shared.failure.is_none() {
+        match error {
+            // A worker which cannot render the job will fail the same
+            // way everywhere
+            Error::Worker(_) => shared.failure = Some(error),
+            _ if shared.alive == 0 && shared.remaining > 0 => {
+                shared.failure = Some(Error::NoWorkers)
+            }
+            _ => (),
+        }
+    }
+    changed.notify_all();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use parallel::Region;
+
+    const SCENE: &'static str = "
+        camera position 0 0 0 rotate 0 0 0 fov 90
+        ambient 40 40 40
+        light 2 -4 0
+        sphere 0 0 5 radius 1.5
+        sphere 2 1 6 radius 1
+    ";
+
+    fn job(options: RenderOptions) -> Job {
+        Job {
+            scene: SCENE.to_string(),
+            width: 16,
+            height: 12,
+            shader: "phong".to_string(),
+            frame: 0.0,
+            options: options,
+        }
+    }
+
+    // A worker on a free port of this machine, serving until the tests end
+    fn worker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, Some(2)));
+        address.to_string()
+    }
+
+    fn local(job: &Job) -> Framebuffer {
+        let (camera, scene, tracer) = job.prepare().unwrap();
+        tracer.render_parallel(&camera, &scene, &job.options).unwrap()
+    }
+
+    fn assert_close(a: &Framebuffer, b: &Framebuffer) {
+        assert!(a.width() == b.width() && a.height() == b.height());
+        for y in 0..a.height() {
+            for x in 0..a.width() {
+                for (p, q) in a.get(x, y).iter().zip(b.get(x, y)) {
+                    assert!((p - q).abs() < 1e-4);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_assignments_cover_every_sample_of_every_pixel() {
+        let work = assignments(Tile::new(1, 1, 10, 7), 4, 10, 4);
+        assert!(work.len() == 18);
+        assert!(work.iter().map(|a| a.tile.width * a.tile.height * a.samples).sum::<u32>() == 700);
+        assert!(work[17] == Assignment {
+            tile: Tile::new(9, 5, 2, 3),
+            first_sample: 8,
+            samples: 2,
+        });
+    }
+
+    #[test]
+    fn test_jobs_cross_the_wire() {
+        let job = job(RenderOptions::new()
+            .with_depth(3)
+            .with_samples(9)
+            .with_seed(4)
+            .with_sampler(SamplerKind::Halton)
+            .with_tile_size(5));
+        let job = Job { frame: 2.5, ..job };
+        let mut bytes = Vec::new();
+        job.write(&mut bytes).unwrap();
+        assert!(Job::read(&mut bytes.as_slice()).unwrap() == job);
+        assert!(Job::read(&mut &bytes[..bytes.len() - 1]).is_err());
+
+        // Scenes too long to hold are refused before being read
+        let text = String::from_utf8(bytes).unwrap();
+        let at = text.find("scene ").unwrap();
+        let huge = format!("{}scene {}\n", &text[..at], usize::MAX);
+        match Job::read(&mut huge.as_bytes()) {
+            Err(Error::Protocol(ref message)) if message.contains("too long") => (),
+            other => panic!("expected the scene to be refused, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_workers_refuse_tiles_outside_the_job() {
+        let address = worker();
+        let job = job(RenderOptions::new().with_samples(4).with_tile_size(8));
+        for tile in ["TILE 10 0 7 2 0 1", "TILE 4294967295 0 2 2 0 1", "TILE 0 0 0 3 0 1",
+                     "TILE 0 0 2 2 4294967295 2", "TILE 0 0 2 2 3 2", "TILE 0 0 9 2 0 1",
+                     "TILE 0 0 2 2"].iter() {
+            let stream = TcpStream::connect(&address).unwrap();
+            let mut input = BufReader::new(stream.try_clone().unwrap());
+            job.write(&mut &stream).unwrap();
+            assert!(read_line(&mut input).unwrap() == "READY");
+            writeln!(&stream, "{}", tile).unwrap();
+            assert!(read_line(&mut input).unwrap().starts_with("ERROR"));
+        }
+
+        // Jobs with frames or tiles too large to hold are refused whole
+        let huge = Job { width: u32::MAX, height: u32::MAX, ..job.clone() };
+        let wide = Job { width: 1 << 15, height: 1 << 14, ..job.clone() };
+        let tiles = Job { options: job.options.with_tile_size(1 << 20), ..job.clone() };
+        for job in [huge, wide, tiles].iter() {
+            let stream = TcpStream::connect(&address).unwrap();
+            let mut input = BufReader::new(stream.try_clone().unwrap());
+            job.write(&mut &stream).unwrap();
+            assert!(read_line(&mut input).unwrap().starts_with("ERROR"));
+        }
+    }
+
+    #[test]
+    fn test_slow_workers_are_kept_by_their_heartbeat() {
+        // This one takes longer than the timeout to answer, but says it
+        // is working in the meantime
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let slow = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut input = BufReader::new(stream.try_clone().unwrap());
+            Job::read(&mut input).unwrap();
+            (&stream).write_all(b"READY\n").unwrap();
+            read_line(&mut input).unwrap();
+            for _ in 0..4 {
+                thread::sleep(Duration::from_millis(100));
+                (&stream).write_all(b"WORKING\n").unwrap();
+            }
+            (&stream).write_all(b"ERROR still here\n").unwrap();
+        });
+
+        let coordinator = Coordinator::new(vec![slow]).with_timeout(Duration::from_millis(250));
+        match coordinator.render(&job(RenderOptions::new())) {
+            Err(Error::Worker(ref message)) if message == "still here" => (),
+            other => panic!("expected the worker's answer, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_workers_render_as_one_machine_would() {
+        let job = job(RenderOptions::new().with_samples(5).with_seed(2).with_tile_size(5));
+        let coordinator = Coordinator::new(vec![worker(), worker()])
+            .with_samples_per_assignment(2);
+        stats::take();
+        let image = coordinator.render(&job).unwrap();
+        let remote = stats::take();
+        assert_close(&image, &local(&job));
+        assert!(remote == stats::take());
+
+        let region = Region::Cropped(Tile::new(3, 2, 9, 7));
+        let job = Job { options: job.options.with_region(region), ..job };
+        assert_close(&coordinator.render(&job).unwrap(), &local(&job));
+    }
+
+    #[test]
+    fn test_work_moves_from_workers_which_die() {
+        // This one hangs up after its first assignment
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let flaky = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut input = BufReader::new(stream.try_clone().unwrap());
+            Job::read(&mut input).unwrap();
+            (&stream).write_all(b"READY\n").unwrap();
+            read_line(&mut input).unwrap();
+        });
+
+        let job = job(RenderOptions::new().with_samples(3));
+        let done = Mutex::new(0);
+        let image = Coordinator::new(vec![flaky, worker()])
+            .with_samples_per_assignment(1)
+            .render_with_progress(&job, |n, total| {
+                *done.lock().unwrap() = n;
+                assert!(n <= total);
+            })
+            .unwrap();
+        assert_close(&image, &local(&job));
+        assert!(done.into_inner().unwrap() == 3);
+    }
+
+    #[test]
+    fn test_renders_fail_without_workers() {
+        // Free ports, with nothing listening on them
+        let dead: Vec<SocketAddr> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap())
+            .collect();
+        let coordinator = Coordinator::new(dead.iter().map(|a| a.to_string()).collect());
+        match coordinator.render(&job(RenderOptions::new())) {
+            Err(Error::NoWorkers) => (),
+            other => panic!("expected no workers, got {:?}", other.map(|_| ())),
+        }
+        match Coordinator::new(vec![]).render(&job(RenderOptions::new())) {
+            Err(Error::NoWorkers) => (),
+            other => panic!("expected no workers, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_jobs_workers_cannot_render_fail() {
+        let job = Job { shader: "toon".to_string(), ..job(RenderOptions::new()) };
+        match Coordinator::new(vec![worker()]).render(&job) {
+            Err(Error::Worker(ref message)) if message.contains("toon") => (),
+            other => panic!("expected the worker to refuse, got {:?}", other.map(|_| ())),
+        }
+    }
+}
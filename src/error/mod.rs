@@ -0,0 +1,113 @@
+// error - errors which can arise when building scenes and rendering
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+use image::ImageError;
+use precision::Float;
+
+#[derive(Debug)]
+pub enum Error {
+    DegeneratePlane,
+    DegenerateAxis,
+    DegenerateTriangle,
+    DegenerateQuad,
+    MeshIndex(usize),
+    FieldOfView(Float),
+    CanvasSize(u32, u32),
+    PixelOutOfRange { x: u32, y: u32, width: u32, height: u32 },
+    RegionOutOfFrame {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        frame_width: u32,
+        frame_height: u32,
+    },
+    SceneFile { line: usize, message: String },
+    // Float maps hold one or three channels, not this many
+    FloatMapChannels(usize),
+    // Something other than the distributed protocol came down the wire
+    Protocol(String),
+    // A worker could not render what it was given
+    Worker(String),
+    // Every worker died, or none were given, with work left to do
+    NoWorkers,
+    // A checkpoint which cannot be read, or added to another
+    Checkpoint(String),
+    // A keyframe track which cannot be followed
+    Animation(String),
+    Io(io::Error),
+    Image(ImageError),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::DegeneratePlane => write!(f, "plane defined with zero or parallel vectors"),
+            Error::DegenerateAxis => write!(f, "rotation defined about a zero axis"),
+            Error::DegenerateTriangle => write!(f, "triangle defined with collinear vertices"),
+            Error::DegenerateQuad => write!(f, "quad defined with parallel edges"),
+            Error::MeshIndex(i) => write!(f, "mesh face refers to missing vertex {}", i),
+            Error::FieldOfView(fov) => {
+                write!(f, "field of view too big or too small: {}", fov)
+            }
+            Error::CanvasSize(x, y) => write!(f, "canvas has zero area: {}x{}", x, y),
+            Error::PixelOutOfRange { x, y, width, height } => {
+                write!(f, "pixel ({}, {}) lies outside {}x{} canvas", x, y, width, height)
+            }
+            Error::RegionOutOfFrame { x, y, width, height, frame_width, frame_height } => {
+                write!(f, "region {}x{} at ({}, {}) does not lie within {}x{} frame",
+                       width, height, x, y, frame_width, frame_height)
+            }
+            Error::SceneFile { line, ref message } => {
+                write!(f, "scene file line {}: {}", line, message)
+            }
+            Error::FloatMapChannels(n) => {
+                write!(f, "cannot write a {} channel image as a float map", n)
+            }
+            Error::Protocol(ref message) => write!(f, "protocol error: {}", message),
+            Error::Worker(ref message) => write!(f, "worker failed: {}", message),
+            Error::NoWorkers => write!(f, "no workers left to render"),
+            Error::Checkpoint(ref message) => write!(f, "checkpoint: {}", message),
+            Error::Animation(ref message) => write!(f, "animation: {}", message),
+            Error::Io(ref e) => write!(f, "i/o error: {}", e),
+            Error::Image(ref e) => write!(f, "image error: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Image(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(e: ImageError) -> Error {
+        Error::Image(e)
+    }
+}
+
+
+#[test]
+fn test_io_errors_convert() {
+    let e: Error = io::Error::new(io::ErrorKind::Other, "oops").into();
+    match e {
+        Error::Io(_) => (),
+        _ => panic!("expected an i/o error"),
+    }
+}
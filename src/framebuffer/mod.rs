@@ -0,0 +1,216 @@
+// framebuffer - images of floating point values, for render passes
+// which do not fit in eight bits a channel
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use image::{Rgb, RgbImage, ImageBuffer};
+use precision::Float;
+use error::{Error, Result};
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    channels: usize,
+    data: Vec<Float>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, channels: usize) -> Framebuffer {
+        Framebuffer::filled(width, height, channels, 0.0)
+    }
+
+    pub fn filled(width: u32, height: u32, channels: usize, value: Float) -> Framebuffer {
+        Framebuffer {
+            width: width,
+            height: height,
+            channels: channels,
+            data: vec![value; width as usize * height as usize * channels],
+        }
+    }
+
+    // Channels scaled from [0, 255] to [0, 1]
+    pub fn from_rgb_image(image: &RgbImage) -> Framebuffer {
+        let mut fb = Framebuffer::new(image.width(), image.height(), 3);
+        for (x, y, p) in image.enumerate_pixels() {
+            let c = |i: usize| p.data[i] as Float / 255.0;
+            fb.put(x, y, &[c(0), c(1), c(2)]);
+        }
+        fb
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn offset(&self, x: u32, y: u32) -> usize {
+        (y as usize * self.width as usize + x as usize) * self.channels
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> &[Float] {
+        let i = self.offset(x, y);
+        &self.data[i..i + self.channels]
+    }
+
+    pub fn get_mut(&mut self, x: u32, y: u32) -> &mut [Float] {
+        let i = self.offset(x, y);
+        &mut self.data[i..i + self.channels]
+    }
+
+    pub fn put(&mut self, x: u32, y: u32, values: &[Float]) {
+        self.get_mut(x, y).copy_from_slice(values);
+    }
+
+    // The given rectangle of the image, which must lie within it
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> Framebuffer {
+        let mut out = Framebuffer::new(width, height, self.channels);
+        for j in 0..height {
+            for i in 0..width {
+                out.put(i, j, self.get(x + i, y + j));
+            }
+        }
+        out
+    }
+
+    // Quantise to eight bits a channel for viewing, taking values in
+    // [0, 1] to the full range. Single channel images become grey.
+    pub fn to_rgb_image(&self) -> RgbImage {
+        let quantise = |x: Float| (255.0 * x.max(0.0).min(1.0)).round() as u8;
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let p = self.get(x, y);
+            let c = |i: usize| quantise(p[if i < self.channels { i } else { 0 }]);
+            Rgb([c(0), c(1), c(2)])
+        })
+    }
+
+    // Write as a portable float map, which keeps full precision and is
+    // read by most compositors. Only one and three channel images can
+    // be written this way.
+    pub fn save_pfm<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.pfm_tag()?;
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_pfm(&mut out)?;
+        Ok(())
+    }
+
+    pub fn write_pfm<W: Write>(&self, out: &mut W) -> Result<()> {
+        let tag = self.pfm_tag()?;
+        // A negative scale marks the data as little endian
+        writeln!(out, "{}\n{} {}\n-1.0", tag, self.width, self.height)?;
+
+        // Rows run from the bottom of the image up
+        for y in (0..self.height).rev() {
+            let start = self.offset(0, y);
+            let row = &self.data[start..start + self.width as usize * self.channels];
+            for &v in row.iter() {
+                out.write_all(&(v as f32).to_bits().to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn pfm_tag(&self) -> Result<&'static str> {
+        match self.channels {
+            1 => Ok("Pf"),
+            3 => Ok("PF"),
+            n => Err(Error::FloatMapChannels(n)),
+        }
+    }
+
+    // Write every value, row by row, as a little endian 64 bit float,
+    // so that it can be read back exactly by read_raw
+    pub fn write_raw<W: Write>(&self, out: &mut W) -> Result<()> {
+        let mut bytes = Vec::with_capacity(self.data.len() * 8);
+        for &v in self.data.iter() {
+            bytes.extend_from_slice(&(v as f64).to_le_bytes());
+        }
+        out.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn read_raw<R: Read>(input: &mut R, width: u32, height: u32, channels: usize)
+                             -> Result<Framebuffer> {
+        let mut fb = Framebuffer::new(width, height, channels);
+        let mut bytes = vec![0; fb.data.len() * 8];
+        input.read_exact(&mut bytes)?;
+        for (v, b) in fb.data.iter_mut().zip(bytes.chunks(8)) {
+            let mut word = [0; 8];
+            word.copy_from_slice(b);
+            *v = f64::from_le_bytes(word) as Float;
+        }
+        Ok(fb)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pixels_round_trip() {
+        let mut fb = Framebuffer::new(3, 2, 3);
+        fb.put(2, 1, &[0.25, 0.5, 0.75]);
+
+        assert!(fb.get(2, 1) == &[0.25, 0.5, 0.75]);
+        assert!(fb.get(1, 1) == &[0.0; 3]);
+        assert!(fb.to_rgb_image().get_pixel(2, 1) == &Rgb([64, 128, 191]));
+        assert!(Framebuffer::from_rgb_image(&fb.to_rgb_image()).to_rgb_image().get_pixel(2, 1) ==
+                &Rgb([64, 128, 191]));
+    }
+
+    #[test]
+    fn test_crop_keeps_the_rectangle() {
+        let mut fb = Framebuffer::new(4, 3, 1);
+        fb.put(2, 1, &[1.0]);
+        let cropped = fb.crop(1, 1, 2, 2);
+        assert!(cropped.width() == 2 && cropped.height() == 2);
+        assert!(cropped.get(1, 0) == &[1.0]);
+        assert!(cropped.get(0, 1) == &[0.0]);
+    }
+
+    #[test]
+    fn test_pfm_header_and_row_order() {
+        let mut fb = Framebuffer::new(2, 2, 1);
+        fb.put(0, 1, &[1.0]);
+        let mut out = Vec::new();
+        fb.write_pfm(&mut out).unwrap();
+
+        let header = b"Pf\n2 2\n-1.0\n";
+        assert!(&out[..header.len()] == &header[..]);
+        assert!(out.len() == header.len() + 4 * 4);
+        // The bottom row comes first
+        assert!(&out[header.len()..header.len() + 4] == &1.0f32.to_bits().to_le_bytes()[..]);
+    }
+
+    #[test]
+    fn test_pfm_refuses_other_channel_counts() {
+        let mut out = Vec::new();
+        match Framebuffer::new(2, 2, 4).write_pfm(&mut out) {
+            Err(Error::FloatMapChannels(4)) => (),
+            _ => panic!("expected a channel count error"),
+        }
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_raw_values_round_trip() {
+        let mut fb = Framebuffer::new(3, 2, 2);
+        fb.put(2, 0, &[0.1, -7.5]);
+        fb.put(0, 1, &[1e6, 0.3]);
+        let mut out = Vec::new();
+        fb.write_raw(&mut out).unwrap();
+        assert!(out.len() == 3 * 2 * 2 * 8);
+        assert!(Framebuffer::read_raw(&mut out.as_slice(), 3, 2, 2).unwrap() == fb);
+        assert!(Framebuffer::read_raw(&mut &out[1..], 3, 2, 2).is_err());
+    }
+}
@@ -1,13 +1,31 @@
 // Library modules for yars-raytracer
 extern crate image;
 
+pub mod precision;
+pub mod error;
+pub mod stats;
 pub mod algebra;
+pub mod warp;
+pub mod sampler;
 pub mod vector3d;
 pub mod space_algebra;
 pub mod ray;
+pub mod packet;
 pub mod camera;
 pub mod materials;
+pub mod bounds;
 pub mod shapes;
 pub mod shade;
 pub mod scene;
+pub mod medium;
+pub mod framebuffer;
+pub mod aov;
 pub mod raytrace;
+pub mod progressive;
+pub mod parallel;
+pub mod denoise;
+pub mod tonemap;
+pub mod scene_file;
+pub mod animation;
+pub mod distributed;
+pub mod checkpoint;
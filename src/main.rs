@@ -1,83 +1,757 @@
 extern crate yars_raytracer;
 extern crate image;
 
-use std::fs::File;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::TcpListener;
 use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
 
-use yars_raytracer::vector3d::Vec3;
-use yars_raytracer::algebra::InnerProductSpace;
-use yars_raytracer::space_algebra::SO3;
-use yars_raytracer::ray::Orientable;
-use yars_raytracer::camera::CameraBuilder;
-use yars_raytracer::scene::{Scene, Light, AmbientLight};
-use yars_raytracer::shade::{Shader, PhongShader};
-use yars_raytracer::materials::Material;
+use yars_raytracer::precision::Float;
+use yars_raytracer::camera::Camera;
+use yars_raytracer::scene::Scene;
+use yars_raytracer::scene_file;
+use yars_raytracer::shade::{self, Shader};
 use yars_raytracer::raytrace::Raytracer;
-use image::{ImageBuffer, ImageRgb8, Rgb, PNG};
+use yars_raytracer::parallel::{RenderOptions, Region, Tile};
+use yars_raytracer::progressive::AdaptiveSettings;
+use yars_raytracer::sampler::SamplerKind;
+use yars_raytracer::tonemap;
+use yars_raytracer::framebuffer::Framebuffer;
+use yars_raytracer::distributed::{self, Coordinator, Job};
+use yars_raytracer::checkpoint::Checkpoint;
+use yars_raytracer::stats::Report;
+use yars_raytracer::error::{Error, Result};
+use image::{ImageRgb8, ImageFormat, PNG, JPEG, PPM};
 
-use yars_raytracer::shapes::{Sphere, Plane};
-use yars_raytracer::ray::{Ray, Shadable, ShadeCell};
+const USAGE: &'static str = "\
+usage: yars [options] [scene file]
+       yars worker --listen ADDRESS [--threads N] [--quiet]
+       yars merge CHECKPOINT CHECKPOINT... --output CHECKPOINT
+
+Renders the scene file, or a built in demonstration scene without one.
+Animated scenes are rendered frame by frame, each to the output path
+numbered by its frame: the run of #s in the path is replaced by the
+frame number, or the number is put before the extension if there are
+none, so out.png becomes out.0001.png, out.0002.png and so on.
+As a worker, renders parts of frames for others run with --workers.
+Merging adds together checkpoints of one scene rendered with different
+seeds, to resume or finish as one.
+
+options:
+  -o, --output PATH        where to write the image (default output.png)
+  -f, --format FORMAT      png, jpeg, ppm or pfm (default: from the output path)
+  -r, --resolution WxH     image size (default: from the scene file, or 800x600)
+      --frames A-B         render frames A to B of the animation, or
+                           --frames N for frame N alone (default: from the
+                           scene file, or a single still image)
+  -s, --samples N          samples per pixel (default 1)
+  -d, --depth N            most rays traced from each pixel, counting
+                           reflections (default 2)
+  -t, --threads N          render threads (default: one per processor)
+      --seed N             seed for the samples (default 0)
+      --sampler NAME       independent, stratified, halton, sobol or
+                           blue-noise (default sobol)
+      --integrator NAME    whitted, or adaptive to take more samples where
+                           the image is noisy, up to --samples, on one thread
+                           with a fixed pattern of samples (default whitted)
+      --threshold X        noise level at which adaptive sampling stops
+                           (default 0.01)
+      --shader NAME        phong, pbr, ao, normal, depth, uv, object-id,
+                           facing or bounces (default phong)
+      --exposure STOPS     brighten or darken the image (default 0)
+      --crop X,Y,W,H       render only this rectangle of the image
+      --pad                place the cropped rectangle in an image of the
+                           full size, black elsewhere
+      --workers A,B,...    render on workers at these addresses, such as
+                           localhost:7878; an address given twice takes
+                           two connections to it
+      --worker-timeout S   seconds to wait for a worker to connect or show
+                           it is still working before giving its work to
+                           another (default 30, and more than 5)
+      --listen ADDRESS     where a worker waits for work
+      --checkpoint PATH    save the render so far to PATH as it goes
+      --checkpoint-every S seconds between checkpoints (default 60)
+      --resume PATH        carry on the render saved at PATH, with the
+                           scene and settings saved there
+  -q, --quiet              print nothing but errors; otherwise, counts of
+                           rays and time spent are printed at the end
+  -h, --help               print this message
+";
+
+// Samples a pixel taken between chances to save a checkpoint
+const CHECKPOINT_PASS: u32 = 1;
+
+// The scene rendered when none is given
+const DEMO_SCENE: &'static str = "
+    camera position 0 -2 0 rotate 0.47 0 0 fov 45
+    resolution 800 600
+    ambient 70 70 70
+    light 4 -4 0 colour 255 255 200
+
+    material slate phong specular 0.01 0.01 0.01 diffuse 0.03 0.03 0.03 \
+        ambient 0.1 0.1 0.1 reflect 0 0 0 shine 2
+    material mat1 phong specular 0.5 0.5 0.5 diffuse 0.5 0.3 0.01 \
+        ambient 0.5 0.3 0.01 reflect 0.1 0.1 0.05 shine 7
+    material mat2 phong specular 0.3 0.2 0.5 diffuse 0.3 0.1 0.5 \
+        ambient 0.3 0.1 0.5 reflect 0.1 0.1 0.1 shine 4
+
+    plane 1 0 0  0 0 1 material slate
+    sphere 0 -1 5 radius 1 material mat1
+    sphere -2 -0.5 4 radius 0.5 material mat2
+";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Format {
+    Image(ImageFormat),
+    Pfm,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Integrator {
+    Whitted,
+    Adaptive,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Args {
+    scene: Option<String>,
+    output: String,
+    format: Format,
+    resolution: Option<(u32, u32)>,
+    frames: Option<(u32, u32)>,
+    render: RenderOptions,
+    integrator: Integrator,
+    threshold: Float,
+    shader: String,
+    exposure: Float,
+    workers: Vec<String>,
+    worker_timeout: u64,
+    checkpoint: Option<String>,
+    checkpoint_every: u64,
+    resume: Option<String>,
+    quiet: bool,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Command {
+    Render(Args),
+    Worker { listen: String, threads: Option<usize>, quiet: bool },
+    Merge { inputs: Vec<String>, output: String },
+    Help,
+}
 
 fn main() {
-    let WIDTH = 800;
-    let HEIGHT = 600;
-    let OUTPUT = "output.png";
+    let args: Vec<String> = env::args().skip(1).collect();
+    match parse_args(&args) {
+        Ok(Command::Help) => print!("{}", USAGE),
+        Ok(Command::Render(args)) => {
+            if let Err(e) = run(&args) {
+                writeln!(io::stderr(), "yars: {}", e).unwrap_or(());
+                process::exit(1);
+            }
+        }
+        Ok(Command::Worker { listen, threads, quiet }) => {
+            if let Err(e) = work(&listen, threads, quiet) {
+                writeln!(io::stderr(), "yars: {}", e).unwrap_or(());
+                process::exit(1);
+            }
+        }
+        Ok(Command::Merge { inputs, output }) => {
+            if let Err(e) = merge(&inputs, &output) {
+                writeln!(io::stderr(), "yars: {}", e).unwrap_or(());
+                process::exit(1);
+            }
+        }
+        Err(message) => {
+            writeln!(io::stderr(), "yars: {}\n\n{}", message, USAGE).unwrap_or(());
+            process::exit(2);
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> std::result::Result<Command, String> {
+    match args.first().map(|a| a.as_str()) {
+        Some("worker") => return parse_worker(&args[1..]),
+        Some("merge") => return parse_merge(&args[1..]),
+        _ => (),
+    }
+    let mut parsed = Args {
+        scene: None,
+        output: "output.png".to_string(),
+        format: Format::Image(PNG),
+        resolution: None,
+        frames: None,
+        render: RenderOptions::new(),
+        integrator: Integrator::Whitted,
+        threshold: 0.01,
+        shader: "phong".to_string(),
+        exposure: 0.0,
+        workers: Vec::new(),
+        worker_timeout: 30,
+        checkpoint: None,
+        checkpoint_every: 60,
+        resume: None,
+        quiet: false,
+    };
+
+    let (mut format, mut crop, mut pad) = (None, None, false);
+    // Options the adaptive integrator has no use for
+    let mut sampling = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().map(|v| v.as_str()).ok_or_else(|| format!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-q" | "--quiet" => parsed.quiet = true,
+            "-o" | "--output" => parsed.output = value()?.to_string(),
+            "-f" | "--format" => format = Some(parse_format(value()?)?),
+            "-r" | "--resolution" => parsed.resolution = Some(parse_resolution(value()?)?),
+            "--frames" => parsed.frames = Some(parse_frames(value()?)?),
+            "-s" | "--samples" => {
+                parsed.render = parsed.render.with_samples(number(arg, value()?)?)
+            }
+            "-d" | "--depth" => parsed.render = parsed.render.with_depth(number(arg, value()?)?),
+            "-t" | "--threads" => {
+                parsed.render = parsed.render.with_threads(number(arg, value()?)?);
+                sampling.push(arg);
+            }
+            "--seed" => {
+                parsed.render = parsed.render.with_seed(number(arg, value()?)?);
+                sampling.push(arg);
+            }
+            "--sampler" => {
+                sampling.push(arg);
+                let name = value()?;
+                let kind = SamplerKind::from_name(name)
+                    .ok_or_else(|| format!("unknown sampler '{}'", name))?;
+                parsed.render = parsed.render.with_sampler(kind);
+            }
+            "--integrator" => {
+                parsed.integrator = match value()? {
+                    "whitted" => Integrator::Whitted,
+                    "adaptive" => Integrator::Adaptive,
+                    other => return Err(format!("unknown integrator '{}'", other)),
+                }
+            }
+            "--threshold" => parsed.threshold = number(arg, value()?)?,
+            "--shader" => {
+                let name = value()?;
+                if shade::by_name(name, 0).is_none() {
+                    return Err(format!("unknown shader '{}'", name));
+                }
+                parsed.shader = name.to_string();
+            }
+            "--exposure" => parsed.exposure = number(arg, value()?)?,
+            "--crop" => crop = Some(parse_crop(value()?)?),
+            "--pad" => pad = true,
+            "--workers" => {
+                parsed.workers = value()?.split(',').map(|w| w.to_string()).collect()
+            }
+            "--worker-timeout" => {
+                // Workers show they are still working once a heartbeat, so a
+                // shorter timeout would give away work that is in hand
+                let timeout = number::<u64>(arg, value()?)?;
+                let heartbeat = distributed::HEARTBEAT.as_secs();
+                if timeout <= heartbeat {
+                    return Err(format!("--worker-timeout must be more than the {} s \
+                                        heartbeat", heartbeat));
+                }
+                parsed.worker_timeout = timeout
+            }
+            "--checkpoint" => parsed.checkpoint = Some(value()?.to_string()),
+            "--checkpoint-every" => parsed.checkpoint_every = number(arg, value()?)?,
+            "--resume" => parsed.resume = Some(value()?.to_string()),
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(format!("unknown option '{}'", flag))
+            }
+            scene => {
+                if parsed.scene.is_some() {
+                    return Err(format!("more than one scene file given: '{}'", scene));
+                }
+                parsed.scene = Some(scene.to_string());
+            }
+        }
+    }
+
+    if let (Integrator::Adaptive, Some(arg)) = (parsed.integrator, sampling.first()) {
+        return Err(format!("the adaptive integrator does not take {}", arg));
+    }
+    if !parsed.workers.is_empty() && parsed.integrator != Integrator::Whitted {
+        return Err("workers render only with the whitted integrator".to_string());
+    }
+    if parsed.checkpoint.is_some() || parsed.resume.is_some() {
+        if parsed.integrator != Integrator::Whitted || !parsed.workers.is_empty() {
+            return Err("checkpoints are only taken with the whitted integrator on this \
+                        machine"
+                .to_string());
+        }
+    }
+    if parsed.resume.is_some() && (parsed.scene.is_some() || parsed.frames.is_some()) {
+        return Err("a resumed render takes its scene from the checkpoint".to_string());
+    }
+    parsed.render = parsed.render.with_region(match crop {
+        None if pad => return Err("--pad needs --crop".to_string()),
+        None => Region::Full,
+        Some(c) if pad => Region::Padded(c),
+        Some(c) => Region::Cropped(c),
+    });
+    parsed.format = match format {
+        Some(f) => f,
+        None => {
+            let extension = Path::new(&parsed.output)
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or("");
+            parse_format(extension).map_err(|_| {
+                    format!("cannot tell the format of '{}'; use --format", parsed.output)
+                })?
+        }
+    };
+    Ok(Command::Render(parsed))
+}
+
+fn parse_worker(args: &[String]) -> std::result::Result<Command, String> {
+    let (mut listen, mut threads, mut quiet) = (None, None, false);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().map(|v| v.as_str()).ok_or_else(|| format!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-q" | "--quiet" => quiet = true,
+            "--listen" => listen = Some(value()?.to_string()),
+            "-t" | "--threads" => threads = Some(number::<usize>(arg, value()?)?.max(1)),
+            other => return Err(format!("a worker does not take '{}'", other)),
+        }
+    }
+    match listen {
+        Some(listen) => Ok(Command::Worker { listen: listen, threads: threads, quiet: quiet }),
+        None => Err("a worker needs --listen".to_string()),
+    }
+}
+
+fn parse_merge(args: &[String]) -> std::result::Result<Command, String> {
+    let (mut inputs, mut output) = (Vec::new(), None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-o" | "--output" => {
+                output = Some(args.next().ok_or_else(|| format!("{} needs a value", arg))?.clone())
+            }
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(format!("merging does not take '{}'", flag))
+            }
+            input => inputs.push(input.to_string()),
+        }
+    }
+    match output {
+        Some(output) if inputs.len() >= 2 => {
+            Ok(Command::Merge {
+                inputs: inputs,
+                output: output,
+            })
+        }
+        Some(_) => Err("merging needs at least two checkpoints".to_string()),
+        None => Err("merging needs --output".to_string()),
+    }
+}
+
+fn number<T: std::str::FromStr>(option: &str, value: &str) -> std::result::Result<T, String> {
+    value.parse().map_err(|_| format!("{} expects a number, not '{}'", option, value))
+}
+
+fn parse_format(name: &str) -> std::result::Result<Format, String> {
+    match name.to_lowercase().as_str() {
+        "png" => Ok(Format::Image(PNG)),
+        "jpg" | "jpeg" => Ok(Format::Image(JPEG)),
+        "ppm" => Ok(Format::Image(PPM)),
+        "pfm" => Ok(Format::Pfm),
+        _ => Err(format!("unknown format '{}'", name)),
+    }
+}
 
-    let camera = (SO3::rotation_x(0.47) * CameraBuilder::new(WIDTH, HEIGHT, 45.0) +
-                  Vec3(0.0, -2.0, 0.0))
-        .build();
+fn parse_resolution(value: &str) -> std::result::Result<(u32, u32), String> {
+    let mut parts = value.split('x');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(w), Some(h), None) => Ok((number("--resolution", w)?, number("--resolution", h)?)),
+        _ => Err(format!("resolution should look like 800x600, not '{}'", value)),
+    }
+}
+
+fn parse_frames(value: &str) -> std::result::Result<(u32, u32), String> {
+    let mut parts = value.splitn(2, '-');
+    let first = number("--frames", parts.next().unwrap_or(""))?;
+    let last = match parts.next() {
+        Some(last) => number("--frames", last)?,
+        None => first,
+    };
+    if last < first {
+        return Err(format!("frames should run forwards, not '{}'", value));
+    }
+    Ok((first, last))
+}
 
-    let mut img = ImageBuffer::new(WIDTH, HEIGHT);
+fn parse_crop(value: &str) -> std::result::Result<Tile, String> {
+    let parts = value.split(',')
+        .map(|p| number("--crop", p))
+        .collect::<std::result::Result<Vec<u32>, String>>()?;
+    match parts.as_slice() {
+        &[x, y, w, h] if w > 0 && h > 0 => Ok(Tile::new(x, y, w, h)),
+        _ => Err(format!("crop should look like X,Y,W,H with W and H positive, not '{}'", value)),
+    }
+}
 
-    // Some test paramters
-    let a_colour = Rgb([255 as u8; 3]);
-    let light = Light {
-        position: Vec3(4.0, -4.0, 0.0),
-        colour: Rgb([255 as u8, 255 as u8, 200 as u8]),
+fn run(args: &Args) -> Result<()> {
+    let mut report = Report::new();
+    let image = match args.resume {
+        Some(ref path) => {
+            let mut checkpoint = report.time("load", || Checkpoint::load(path))?;
+            checkpoint.job.options.threads = args.render.threads;
+            let save_to = args.checkpoint.as_ref().unwrap_or(path);
+            render_checkpointed(checkpoint, save_to, args, &mut report)?
+        }
+        None => {
+            let (job, frames) = report.time("load", || read_job(args))?;
+            match frames_to_render(args, frames)? {
+                None => render_frame(job, &args.output, args, &mut report)?,
+                Some((first, last)) => {
+                    for frame in first..last + 1 {
+                        if !args.quiet {
+                            writeln!(io::stderr(), "frame {}", frame).unwrap_or(());
+                        }
+                        let job = Job { frame: frame as Float, ..job.clone() };
+                        render_frame(job, &frame_path(&args.output, frame), args, &mut report)?;
+                    }
+                }
+            }
+            if !args.quiet {
+                write!(io::stderr(), "{}", report).unwrap_or(());
+            }
+            return Ok(());
+        }
     };
+    report.time("write", || save(&image, &args.output, args))?;
 
-    let slate = Material::new([0.01; 3], [0.03; 3], [0.1; 3], [0.0; 3], 2.0);
+    if !args.quiet {
+        write!(io::stderr(), "{}", report).unwrap_or(());
+    }
+    Ok(())
+}
 
-    let mat1 = Material::new([0.5, 0.5, 0.5],
-                             [0.5, 0.3, 0.01],
-                             [0.5, 0.3, 0.01],
-                             [0.1, 0.1, 0.05],
-                             7.0);
+// The frames given on the command line, or else by the scene file. A
+// checkpoint holds only one frame, so it cannot be kept for several.
+fn frames_to_render(args: &Args, from_file: Option<(u32, u32)>) -> Result<Option<(u32, u32)>> {
+    match args.frames.or(from_file) {
+        Some((first, last)) if first != last && args.checkpoint.is_some() => {
+            Err(Error::Animation("a checkpoint holds only one frame; \
+                                  choose one with --frames".to_string()))
+        }
+        frames => Ok(frames),
+    }
+}
 
-    let mat2 = Material::new([0.3, 0.2, 0.5],
-                             [0.3, 0.1, 0.5],
-                             [0.3, 0.1, 0.5],
-                             [0.1, 0.1, 0.1],
-                             4.0);
+// Render one frame of the job and write it to the output path
+fn render_frame(job: Job, output: &str, args: &Args, report: &mut Report) -> Result<()> {
+    let image = if !args.workers.is_empty() {
+        report.time("render", || render_distributed(&job, args))?
+    } else if let Some(ref path) = args.checkpoint {
+        render_checkpointed(Checkpoint::new(job)?, path, args, report)?
+    } else {
+        let (camera, scene, tracer) = report.time("build", || job.prepare())?;
+        report.time("render", || render(&tracer, &camera, &scene, args))?
+    };
+    report.time("write", || save(&image, output, args))
+}
 
-    let plane =
-        Box::new(Plane::new(Vec3(1.0, 0.0, 0.0), Vec3(0.0, 0.0, 1.0), slate)) as Box<Shadable>;
-    let sphere = Box::new(Sphere::new(Vec3(0.0, -1.0, 5.0), 1.0, mat1)) as Box<Shadable>;
-    let obst = Box::new(Sphere::new(Vec3(-2.0, -0.5, 4.0), 0.5, mat2)) as Box<Shadable>;
-    let scene_objects = vec![plane, sphere, obst];
+// The output path for a frame of an animation
+fn frame_path(output: &str, frame: u32) -> String {
+    match output.find('#') {
+        Some(start) => {
+            let digits = output[start..].chars().take_while(|&c| c == '#').count();
+            format!("{}{:0width$}{}", &output[..start], frame, &output[start + digits..],
+                    width = digits)
+        }
+        None => {
+            let path = Path::new(output);
+            match (path.file_stem(), path.extension()) {
+                (Some(stem), Some(extension)) => {
+                    let name = format!("{}.{:04}.{}", stem.to_string_lossy(), frame,
+                                       extension.to_string_lossy());
+                    path.with_file_name(name).to_string_lossy().into_owned()
+                }
+                _ => format!("{}.{:04}", output, frame),
+            }
+        }
+    }
+}
 
-    let ambient = AmbientLight { colour: Rgb([70; 3]) };
+fn save(image: &Framebuffer, output: &str, args: &Args) -> Result<()> {
+    match args.format {
+        Format::Pfm => tonemap::expose(image, args.exposure).save_pfm(output),
+        Format::Image(format) => {
+            let image = tonemap::apply(image, args.exposure).to_rgb_image();
+            let ref mut fout = File::create(&Path::new(output))?;
+            Ok(ImageRgb8(image).save(fout, format)?)
+        }
+    }
+}
 
-    let scene = Scene {
-        ambient_light: ambient,
-        objects: scene_objects,
-        lights: vec![light],
+// The scene and settings to render, and the frames the scene gives
+// for its animation
+fn read_job(args: &Args) -> Result<(Job, Option<(u32, u32)>)> {
+    let text = match args.scene {
+        Some(ref path) => fs::read_to_string(path)?,
+        None => DEMO_SCENE.to_string(),
+    };
+    let file = scene_file::parse(&text)?;
+    let (width, height) = args.resolution.or(file.resolution).unwrap_or((800, 600));
+    let job = Job {
+        scene: text,
+        width: width,
+        height: height,
+        shader: args.shader.clone(),
+        frame: 0.0,
+        options: args.render,
     };
+    Ok((job, file.frames))
+}
+
+fn render_distributed(job: &Job, args: &Args) -> Result<Framebuffer> {
+    let quiet = args.quiet;
+    let coordinator = Coordinator::new(args.workers.clone())
+        .with_timeout(Duration::from_secs(args.worker_timeout));
+    let image = coordinator.render_with_progress(job, |done, total| {
+        if !quiet {
+            show_progress(done, total);
+        }
+    })?;
+    if !args.quiet {
+        writeln!(io::stderr(), "").unwrap_or(());
+    }
+    Ok(image)
+}
+
+// Carry on the render, saving it to the path every so often and once
+// it is done
+fn render_checkpointed(mut checkpoint: Checkpoint, path: &str, args: &Args,
+                       report: &mut Report)
+                       -> Result<Framebuffer> {
+    let (camera, scene, tracer) = report.time("build", || checkpoint.job.prepare())?;
+    let every = Duration::from_secs(args.checkpoint_every);
+    let total = checkpoint.job.options.samples as usize;
+    let mut saved = Instant::now();
+    report.time("render", || {
+            tracer.render_checkpointed(&camera, &scene, &mut checkpoint, CHECKPOINT_PASS, |c| {
+                if !args.quiet {
+                    show_progress(c.samples_done() as usize, total);
+                }
+                if c.is_complete() || saved.elapsed() >= every {
+                    c.save(path)?;
+                    saved = Instant::now();
+                }
+                Ok(())
+            })
+        })?;
+    if !args.quiet {
+        writeln!(io::stderr(), "").unwrap_or(());
+    }
+    checkpoint.image()
+}
 
-    let tracer = Raytracer::<PhongShader>::from_shader(PhongShader::instance());
+fn merge(inputs: &[String], output: &str) -> Result<()> {
+    let mut merged = Checkpoint::load(&inputs[0])?;
+    for input in inputs[1..].iter() {
+        merged.merge(&Checkpoint::load(input)?)?;
+    }
+    merged.save(output)
+}
+
+// Serve coordinators until killed
+fn work(listen: &str, threads: Option<usize>, quiet: bool) -> Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    if !quiet {
+        writeln!(io::stderr(), "yars: waiting for work on {}", listener.local_addr()?)
+            .unwrap_or(());
+    }
+    distributed::serve(listener, threads)
+}
+
+fn render<S: Shader + Sync>(tracer: &Raytracer<S>, camera: &Camera, scene: &Scene, args: &Args)
+                            -> Result<Framebuffer> {
+    match args.integrator {
+        Integrator::Whitted => {
+            let quiet = args.quiet;
+            let image = tracer.render_parallel_with_progress(camera, scene, &args.render,
+                                                             |done, total| {
+                if !quiet {
+                    show_progress(done, total);
+                }
+            })?;
+            if !args.quiet {
+                writeln!(io::stderr(), "").unwrap_or(());
+            }
+            Ok(image)
+        }
+        Integrator::Adaptive => {
+            let samples = args.render.samples;
+            let settings = AdaptiveSettings::new(samples.min(4), samples, args.threshold);
+            // The whole frame is rendered, and the region taken from it
+            let region = args.render.region;
+            region.pixels(camera.width(), camera.height())?;
+            let image = tracer.render_adaptive(args.render.depth, camera, scene, settings)?;
+            region.extract(&image.mean())
+        }
+    }
+}
 
-    // now do some tracing
+fn show_progress(done: usize, total: usize) {
+    const BAR: usize = 40;
+    let filled = done * BAR / total.max(1);
+    write!(io::stderr(), "\r[{}{}] {:3}%", "#".repeat(filled), " ".repeat(BAR - filled),
+           done * 100 / total.max(1))
+        .unwrap_or(());
+}
 
-    for (x, y, pixel) in img.enumerate_pixels_mut() {
-        let ray = camera.get_ray_through_pixel(x, y);
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        match tracer.trace_to_depth(2, &ray, &scene) {
-            Some(col) => *pixel = col,
-            None => *pixel = Rgb([0 as u8, 0 as u8, 0 as u8]),
+    fn parse(line: &str) -> std::result::Result<Command, String> {
+        let args: Vec<String> = line.split_whitespace().map(|s| s.to_string()).collect();
+        parse_args(&args)
+    }
+
+    fn render_args(line: &str) -> Args {
+        match parse(line) {
+            Ok(Command::Render(args)) => args,
+            other => panic!("expected render arguments, got {:?}", other),
         }
     }
 
-    let ref mut fout = File::create(&Path::new(OUTPUT)).unwrap();
-    let _ = ImageRgb8(img).save(fout, PNG);
+    #[test]
+    fn test_defaults() {
+        let args = render_args("");
+        assert!(args.scene == None);
+        assert!(args.output == "output.png");
+        assert!(args.format == Format::Image(PNG));
+        assert!(args.render == RenderOptions::new());
+        assert!(!args.quiet);
+    }
+
+    #[test]
+    fn test_options_are_read() {
+        let args = render_args("scene.txt -o out.pfm -r 320x240 -s 16 -d 5 -t 3 --seed 7 \
+                                --sampler halton --shader pbr --exposure -1.5 \
+                                --crop 10,20,30,40 -q");
+        assert!(args.scene == Some("scene.txt".to_string()));
+        assert!(args.format == Format::Pfm);
+        assert!(args.resolution == Some((320, 240)));
+        assert!(args.render.with_region(Region::Full) == RenderOptions::new()
+            .with_samples(16)
+            .with_depth(5)
+            .with_threads(3)
+            .with_seed(7)
+            .with_sampler(SamplerKind::Halton));
+        assert!(args.shader == "pbr");
+        assert!(args.exposure == -1.5);
+        assert!(args.render.region == Region::Cropped(Tile::new(10, 20, 30, 40)));
+        assert!(args.quiet);
+    }
+
+    #[test]
+    fn test_bad_options_are_refused() {
+        assert!(parse("--samples lots").is_err());
+        assert!(parse("--depth").is_err());
+        assert!(parse("-r 800by600").is_err());
+        assert!(parse("--crop 1,2,3").is_err());
+        assert!(parse("--pad").is_err());
+        assert!(render_args("--crop 1,2,3,4 --pad").render.region ==
+                Region::Padded(Tile::new(1, 2, 3, 4)));
+        assert!(parse("--shader toon").is_err());
+        assert!(parse("--frobnicate").is_err());
+        assert!(parse("a.txt b.txt").is_err());
+        assert!(parse("-o picture.xyz").is_err());
+        assert!(parse("-o picture.xyz -f jpeg").is_ok());
+        assert!(parse("--help") == Ok(Command::Help));
+        assert!(parse("--workers a:1 --integrator adaptive").is_err());
+        assert!(parse("--integrator adaptive -t 2").is_err());
+        assert!(parse("--seed 3 --integrator adaptive").is_err());
+        assert!(parse("--integrator adaptive --sampler halton").is_err());
+        assert!(parse("--integrator adaptive -s 64 --threshold 0.02").is_ok());
+        assert!(parse("worker").is_err());
+        assert!(parse("worker --listen :7878 -o x.png").is_err());
+        assert!(parse("--checkpoint a.ckpt --integrator adaptive").is_err());
+        assert!(parse("--resume a.ckpt scene.txt").is_err());
+        assert!(parse("merge a.ckpt -o b.ckpt").is_err());
+        assert!(parse("merge a.ckpt b.ckpt").is_err());
+        assert!(parse("--frames 9-2").is_err());
+        assert!(parse("--resume a.ckpt --frames 4").is_err());
+    }
+
+    #[test]
+    fn test_checkpoints_are_kept_for_one_frame_only() {
+        let checkpointed = |line: &str, from_file| {
+            frames_to_render(&render_args(&format!("{} --checkpoint a.ckpt", line)), from_file)
+        };
+        assert!(checkpointed("--frames 1-9", None).is_err());
+        assert!(checkpointed("", Some((1, 9))).is_err());
+        assert!(checkpointed("--frames 4", Some((1, 9))).unwrap() == Some((4, 4)));
+        assert!(checkpointed("", None).unwrap().is_none());
+        assert!(frames_to_render(&render_args("--frames 1-9"), None).unwrap() == Some((1, 9)));
+    }
+
+    #[test]
+    fn test_frames_are_numbered() {
+        assert!(render_args("--frames 3-12").frames == Some((3, 12)));
+        assert!(render_args("--frames 7").frames == Some((7, 7)));
+        assert!(frame_path("out.png", 7) == "out.0007.png");
+        assert!(frame_path("renders/shot_###.pfm", 42) == "renders/shot_042.pfm");
+        assert!(frame_path("out", 12345) == "out.12345");
+    }
+
+    #[test]
+    fn test_workers_are_read() {
+        let args = render_args("--workers localhost:7878,10.0.0.2:7878,localhost:7878");
+        assert!(args.workers == vec!["localhost:7878", "10.0.0.2:7878", "localhost:7878"]);
+        assert!(args.worker_timeout == 30);
+        assert!(render_args("--workers a:1 --worker-timeout 90").worker_timeout == 90);
+        assert!(render_args("--workers a:1 --worker-timeout 6").worker_timeout == 6);
+        assert!(parse("--workers a:1 --worker-timeout 5").is_err());
+        assert!(parse("--workers a:1 --worker-timeout 0").is_err());
+        assert!(parse("worker --listen 0.0.0.0:7878 -t 4 -q") ==
+                Ok(Command::Worker {
+                    listen: "0.0.0.0:7878".to_string(),
+                    threads: Some(4),
+                    quiet: true,
+                }));
+    }
+
+    #[test]
+    fn test_checkpoint_options_are_read() {
+        let args = render_args("--checkpoint night.ckpt --checkpoint-every 300");
+        assert!(args.checkpoint == Some("night.ckpt".to_string()));
+        assert!(args.checkpoint_every == 300);
+        assert!(render_args("--resume night.ckpt").resume == Some("night.ckpt".to_string()));
+        assert!(parse("merge a.ckpt b.ckpt c.ckpt -o all.ckpt") ==
+                Ok(Command::Merge {
+                    inputs: vec!["a.ckpt".to_string(), "b.ckpt".to_string(),
+                                 "c.ckpt".to_string()],
+                    output: "all.ckpt".to_string(),
+                }));
+    }
+
+    #[test]
+    fn test_demo_scene_reads() {
+        let file = scene_file::parse(DEMO_SCENE).unwrap();
+        assert!(file.resolution == Some((800, 600)));
+        assert!(file.scene.objects.len() == 3);
+    }
 }
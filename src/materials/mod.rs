@@ -1,12 +1,46 @@
 // materials.rs - materials for objects
 use image::Rgb;
+use precision::Float;
 
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Material {
-    specular: [f64; 3],
-    diffuse: [f64; 3],
-    ambient: [f64; 3],
-    reflectivity: [f64; 3],
-    shine: f64,
+    specular: [Float; 3],
+    diffuse: [Float; 3],
+    ambient: [Float; 3],
+    reflectivity: [Float; 3],
+    shine: Float,
+    pbr: Option<MetallicRoughness>,
+    emission: [Float; 3],
+    // Tells apart materials given separately, even with the same
+    // settings; zero unless numbered
+    id: u32,
+}
+
+// Parameters for the metallic-roughness workflow. Specular scales
+// the reflectance of dielectrics at normal incidence, with 0.5 giving
+// the usual 4%.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MetallicRoughness {
+    pub base_colour: [Float; 3],
+    pub metallic: Float,
+    pub roughness: Float,
+    pub specular: Float,
+}
+
+impl MetallicRoughness {
+    // Reflectance at normal incidence: dielectrics reflect a small
+    // grey fraction, metals tint their reflections with the base colour
+    pub fn f0(&self) -> [Float; 3] {
+        let dielectric = 0.08 * self.specular;
+        let mix = |c: Float| dielectric + (c - dielectric) * self.metallic;
+        [mix(self.base_colour[0]), mix(self.base_colour[1]), mix(self.base_colour[2])]
+    }
+
+    // Metals have no diffuse component
+    pub fn diffuse_colour(&self) -> [Float; 3] {
+        let scale = |c: Float| c * (1.0 - self.metallic);
+        [scale(self.base_colour[0]), scale(self.base_colour[1]), scale(self.base_colour[2])]
+    }
 }
 
 impl Material {
@@ -18,11 +52,11 @@ impl Material {
                       3.0)
     }
 
-    pub fn new(specularity: [f64; 3],
-               diffusivity: [f64; 3],
-               ambience: [f64; 3],
-               reflect: [f64; 3],
-               shininess: f64)
+    pub fn new(specularity: [Float; 3],
+               diffusivity: [Float; 3],
+               ambience: [Float; 3],
+               reflect: [Float; 3],
+               shininess: Float)
                -> Material {
         Material {
             specular: specularity,
@@ -30,26 +64,103 @@ impl Material {
             ambient: ambience,
             reflectivity: reflect,
             shine: shininess,
+            pbr: None,
+            emission: [0.0; 3],
+            id: 0,
+        }
+    }
+
+    // A physically based material. Phong parameters are derived from
+    // it too, so such materials still render under the Phong shader.
+    pub fn metallic_roughness(base_colour: [Float; 3],
+                              metallic: Float,
+                              roughness: Float,
+                              specular: Float)
+                              -> Material {
+        let clamp = |x: Float| x.max(0.0).min(1.0);
+        let params = MetallicRoughness {
+            base_colour: [clamp(base_colour[0]), clamp(base_colour[1]), clamp(base_colour[2])],
+            metallic: clamp(metallic),
+            roughness: clamp(roughness),
+            specular: clamp(specular),
+        };
+
+        let f0 = params.f0();
+        let diffuse = params.diffuse_colour();
+        let alpha = (params.roughness * params.roughness).max(0.001);
+        let gloss = 1.0 - params.roughness;
+
+        Material {
+            specular: f0,
+            diffuse: diffuse,
+            ambient: diffuse,
+            reflectivity: [f0[0] * gloss, f0[1] * gloss, f0[2] * gloss],
+            // The Blinn-Phong exponent with roughly the same lobe width
+            shine: (2.0 / (alpha * alpha) - 2.0).max(1.0),
+            pbr: Some(params),
+            emission: [0.0; 3],
+            id: 0,
+        }
+    }
+
+    // Make the material glow. The colour is scaled by the strength, so
+    // that a strength of one glows as brightly as a full white light.
+    pub fn with_emission(self, colour: [Float; 3], strength: Float) -> Material {
+        Material {
+            emission: [colour[0] * strength, colour[1] * strength, colour[2] * strength],
+            ..self
+        }
+    }
+
+    pub fn with_id(self, id: u32) -> Material {
+        Material { id: id, ..self }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn emission(&self) -> [Float; 3] {
+        self.emission
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emission.iter().any(|&e| e > 0.0)
+    }
+
+    // The metallic-roughness view of this material. Phong materials
+    // are approximated as dielectrics, with roughness from their shine.
+    pub fn pbr(&self) -> MetallicRoughness {
+        match self.pbr {
+            Some(params) => params,
+            None => {
+                MetallicRoughness {
+                    base_colour: self.diffuse,
+                    metallic: 0.0,
+                    roughness: (2.0 / (self.shine + 2.0)).sqrt().sqrt().min(1.0),
+                    specular: 0.5,
+                }
+            }
         }
     }
 
-    pub fn shine(&self) -> f64 {
+    pub fn shine(&self) -> Float {
         self.shine
     }
 
-    pub fn ambient_refletivity(&self) -> [f64; 3] {
+    pub fn ambient_refletivity(&self) -> [Float; 3] {
         self.ambient
     }
 
-    pub fn diffusive_reflectivity(&self) -> [f64; 3] {
+    pub fn diffusive_reflectivity(&self) -> [Float; 3] {
         self.diffuse
     }
 
-    pub fn specular_reflectiviy(&self) -> [f64; 3] {
+    pub fn specular_reflectiviy(&self) -> [Float; 3] {
         self.specular
     }
 
-    pub fn reflectivity(&self) -> [f64; 3] {
+    pub fn reflectivity(&self) -> [Float; 3] {
         self.reflectivity
     }
 }
@@ -57,3 +168,31 @@ impl Material {
 pub trait HasMaterial {
     fn material(&self) -> &Material;
 }
+
+
+#[test]
+fn test_metals_have_no_diffuse_colour() {
+    let gold = Material::metallic_roughness([1.0, 0.8, 0.3], 1.0, 0.2, 0.5);
+    assert!(gold.pbr().diffuse_colour() == [0.0; 3]);
+    assert!(gold.pbr().f0() == [1.0, 0.8, 0.3]);
+}
+
+#[test]
+fn test_phong_materials_have_a_pbr_view() {
+    let m = Material::plain();
+    let params = m.pbr();
+    assert!(params.base_colour == m.diffusive_reflectivity());
+    assert!(params.metallic == 0.0);
+    assert!(params.roughness > 0.0 && params.roughness <= 1.0);
+}
+
+#[test]
+fn test_emission_scales_with_strength() {
+    let m = Material::plain();
+    let neon = m.with_emission([1.0, 0.5, 0.0], 4.0);
+
+    assert!(!m.is_emissive());
+    assert!(neon.is_emissive());
+    assert!(neon.emission() == [4.0, 2.0, 0.0]);
+    assert!(neon.diffusive_reflectivity() == m.diffusive_reflectivity());
+}
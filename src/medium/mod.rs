@@ -0,0 +1,399 @@
+// medium - participating media, such as fog and haze, which absorb and
+// scatter light all along the rays passing through them rather than
+// only where they strike surfaces.
+//
+// Media are homogeneous, the same throughout the space they fill:
+// either all of it, or the inside of a closed shape. The light they
+// scatter toward the viewer is gathered by marching along each ray,
+// casting a shadow ray to each light at every step, so that whatever
+// blocks a light casts its shadow through the medium as a shaft.
+use image::Rgb;
+
+use precision::Float;
+use precision::float::consts::PI;
+use algebra::InnerProductSpace;
+use vector3d::Vec3;
+use ray::{Ray, Shadable, STRIKE_TOLERANCE};
+use sampler::{hash, to_unit};
+use scene::{Scene, Light};
+use shade::to_floats;
+
+// Rays are marched in this many steps through the media they cross
+pub const MARCH_STEPS: u32 = 48;
+
+// Rays which escape the scene are marched only until the medium has
+// let through this little of the light from further on
+const LAST_LIGHT: Float = 0.001;
+
+// The most times a ray is followed in and out of a shape bounding a
+// medium
+const MAX_CROSSINGS: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Medium {
+    // Coefficients per unit distance, for each colour channel
+    pub absorption: [Float; 3],
+    pub scattering: [Float; 3],
+    // The Henyey-Greenstein asymmetry, in (-1, 1). Positive values
+    // scatter light on in the direction it was going, negative values
+    // back the way it came, and zero evenly in all directions.
+    pub anisotropy: Float,
+}
+
+impl Medium {
+    // The light lost per unit distance, by absorption or scattering
+    pub fn extinction(&self) -> [Float; 3] {
+        let e = |i: usize| self.absorption[i] + self.scattering[i];
+        [e(0), e(1), e(2)]
+    }
+
+    // The fraction of light let through over the given distance
+    pub fn transmittance(&self, distance: Float) -> [Float; 3] {
+        let e = self.extinction();
+        let t = |i: usize| (-e[i] * distance).exp();
+        [t(0), t(1), t(2)]
+    }
+
+    // How far light can go before all but LAST_LIGHT of it is lost,
+    // in the channel which loses it slowest. Channels the medium does
+    // not touch are left out, and a medium touching none reaches no
+    // distance at all.
+    pub fn reach(&self) -> Float {
+        self.extinction()
+            .iter()
+            .filter(|&&e| e > 0.0)
+            .map(|&e| -LAST_LIGHT.ln() / e)
+            .fold(0.0, Float::max)
+    }
+
+    // The share of light scattered between directions at an angle
+    // with the given cosine, per unit solid angle
+    pub fn phase(&self, cos_theta: Float) -> Float {
+        henyey_greenstein(self.anisotropy, cos_theta)
+    }
+}
+
+pub fn henyey_greenstein(g: Float, cos_theta: Float) -> Float {
+    let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+}
+
+pub struct Volume {
+    pub medium: Medium,
+    // The closed shape the medium fills, or None if it fills all space
+    pub bounds: Option<Box<Shadable>>,
+}
+
+impl Volume {
+    pub fn everywhere(medium: Medium) -> Volume {
+        Volume {
+            medium: medium,
+            bounds: None,
+        }
+    }
+
+    pub fn inside(medium: Medium, bounds: Box<Shadable>) -> Volume {
+        Volume {
+            medium: medium,
+            bounds: Some(bounds),
+        }
+    }
+
+    // The stretches of a ray within the medium, as distances along it
+    // up to the given one. A ray is taken to be leaving the bounds
+    // where it meets the surface heading the way the normal points.
+    pub fn spans(&self, ray: &Ray, distance: Float) -> Vec<(Float, Float)> {
+        let distance = distance.min(self.medium.reach());
+        let bounds = match self.bounds {
+            Some(ref bounds) => bounds,
+            None => return vec![(0.0, distance)],
+        };
+
+        let d = ray.direction.normalize();
+        // Leaving without having entered means the ray began inside
+        let (mut spans, mut entered, mut left) = (Vec::new(), None, 0.0);
+        let (mut origin, mut travelled) = (ray.origin, 0.0);
+        for _ in 0..MAX_CROSSINGS {
+            let p = match bounds.intersect(&Ray::new(origin, d).with_time(ray.time)) {
+                Some(p) => p,
+                None => break,
+            };
+            let t = travelled + (p - origin).norm();
+            let leaving = bounds.normal_at(p, ray.time).dot(d) > 0.0;
+            if leaving {
+                spans.push((entered.unwrap_or(left), t.min(distance)));
+                entered = None;
+                left = t;
+            } else if t < distance {
+                entered = Some(t);
+            }
+            if t >= distance {
+                break;
+            }
+            origin = p + STRIKE_TOLERANCE * d;
+            travelled = t + STRIKE_TOLERANCE;
+        }
+        if let Some(t) = entered {
+            spans.push((t, distance));
+        }
+        spans
+    }
+}
+
+// The fraction of light let through by the media in a scene along a
+// ray, over the given distance
+pub fn transmittance(volumes: &[Volume], ray: &Ray, distance: Float) -> [Float; 3] {
+    let mut through = [1.0; 3];
+    for volume in volumes {
+        let length: Float = volume.spans(ray, distance).iter().map(|&(a, b)| b - a).sum();
+        let t = volume.medium.transmittance(length);
+        for i in 0..3 {
+            through[i] *= t[i];
+        }
+    }
+    through
+}
+
+// The lights as they reach p at the given time, each dimmed by the
+// media between it and p
+pub fn dim_lights(volumes: &[Volume], p: Vec3, time: Float, lights: Vec<Light>) -> Vec<Light> {
+    if volumes.is_empty() {
+        return lights;
+    }
+    lights.into_iter()
+        .map(|light| {
+            let to_light = light.position - p;
+            let shadow = Ray::new(p, to_light).with_time(time);
+            let through = transmittance(volumes, &shadow, to_light.norm());
+            light.dimmed(through)
+        })
+        .collect()
+}
+
+// The light reaching the start of a ray through the scene's media,
+// given what was struck and how far away, if anything was. Light from
+// the strike is dimmed by the media on the way, and light from the
+// scene's lights is scattered in along it. Without media the strike
+// is seen as it is.
+pub fn through_media(scene: &Scene, ray: &Ray, struck: Option<(Float, Rgb<u8>)>)
+                     -> Option<Rgb<u8>> {
+    let distance = struck.map_or(Float::INFINITY, |(distance, _)| distance);
+    let (through, scattered) = match march(scene, ray, distance, 1, |_| 0) {
+        Some(marched) => marched,
+        None => return struck.map(|(_, colour)| colour),
+    };
+
+    let behind = struck.map_or([0.0; 3], |(_, colour)| to_floats(colour));
+    let channel = |i: usize| {
+        (behind[i] * through[i] + scattered[0][i]).round().min(255.0) as u8
+    };
+    Some(Rgb([channel(0), channel(1), channel(2)]))
+}
+
+// As through_media, for light split by the named light groups and then
+// everything else, as Raytracer::render_light_groups splits it. Light
+// scattered in from each group's lights goes to that group, and from
+// the ambient light and lights in no group to the rest.
+pub fn split_through_media(scene: &Scene, ray: &Ray, struck: Option<(Float, Vec<[Float; 3]>)>,
+                           names: &[String])
+                           -> Option<Vec<[Float; 3]>> {
+    let distance = struck.as_ref().map_or(Float::INFINITY, |&(distance, _)| distance);
+    let group = |light: &Light| {
+        names.iter().position(|name| light.is_in(name)).unwrap_or(names.len())
+    };
+    let (through, scattered) = match march(scene, ray, distance, names.len() + 1, group) {
+        Some(marched) => marched,
+        None => return struck.map(|(_, split)| split),
+    };
+
+    let behind = struck.map_or(vec![[0.0; 3]; names.len() + 1], |(_, split)| split);
+    Some(behind.iter()
+        .zip(scattered.iter())
+        .map(|(b, s)| {
+            [b[0] * through[0] + s[0], b[1] * through[1] + s[1], b[2] * through[2] + s[2]]
+        })
+        .collect())
+}
+
+// March along a ray through the scene's media up to the given
+// distance, finding the fraction of light let through from the far
+// end, and the light scattered in toward the start. The scattered
+// light is split into the given number of parts, each light's going
+// to the part it is given, and the ambient light's to the last. None
+// if the ray passes through no media.
+fn march<F>(scene: &Scene, ray: &Ray, distance: Float, parts: usize, part_of: F)
+            -> Option<([Float; 3], Vec<[Float; 3]>)>
+    where F: Fn(&Light) -> usize
+{
+    let spans: Vec<Vec<(Float, Float)>> = scene.volumes
+        .iter()
+        .map(|v| v.spans(ray, distance))
+        .collect();
+    let start = spans.iter().flat_map(|s| s.iter()).map(|s| s.0).fold(distance, Float::min);
+    let end = spans.iter().flat_map(|s| s.iter()).map(|s| s.1).fold(0.0, Float::max);
+    if !(end > start) {
+        return None;
+    }
+
+    let d = ray.direction.normalize();
+    let ambient = to_floats(scene.ambient_light.colour);
+    let step = (end - start) / MARCH_STEPS as Float;
+    let offset = march_offset(ray);
+    let (mut through, mut scattered) = ([1.0; 3], vec![[0.0; 3]; parts]);
+    for k in 0..MARCH_STEPS {
+        let t = start + (k as Float + offset) * step;
+        let p = ray.origin + t * d;
+        let here: Vec<&Medium> = scene.volumes
+            .iter()
+            .zip(spans.iter())
+            .filter(|&(_, s)| s.iter().any(|&(a, b)| a <= t && t < b))
+            .map(|(v, _)| &v.medium)
+            .collect();
+        if here.is_empty() {
+            continue;
+        }
+
+        // The light scattered toward the viewer at p, ambient light
+        // scattering the same way whatever the phase function
+        let mut sources = vec![[0.0; 3]; parts];
+        for m in here.iter() {
+            for i in 0..3 {
+                sources[parts - 1][i] += m.scattering[i] * ambient[i];
+            }
+        }
+        for light in scene.lights.iter() {
+            if !light.illuminates(p, ray.time, &scene.objects) {
+                continue;
+            }
+            let to_light = light.position - p;
+            let shadow = Ray::new(p, to_light).with_time(ray.time);
+            let reaching = transmittance(&scene.volumes, &shadow, to_light.norm());
+            let cos_theta = to_light.normalize().dot(d);
+            let colour = light.radiance();
+            let source = &mut sources[part_of(light)];
+            for m in here.iter() {
+                let phase = m.phase(cos_theta);
+                for i in 0..3 {
+                    source[i] += m.scattering[i] * phase * reaching[i] * colour[i];
+                }
+            }
+        }
+
+        // Taking the sources to lie where they were sampled in the step
+        for i in 0..3 {
+            let extinction: Float = here.iter().map(|m| m.extinction()[i]).sum();
+            let before = (-extinction * step * offset).exp();
+            let after = (-extinction * step * (1.0 - offset)).exp();
+            for (s, source) in scattered.iter_mut().zip(sources.iter()) {
+                s[i] += through[i] * before * source[i] * step;
+            }
+            through[i] *= before * after;
+        }
+    }
+    Some((through, scattered))
+}
+
+// How far into each step a ray samples the media it marches through,
+// in [0, 1). Each ray, and so each pixel and each sample of a pixel,
+// draws its own, since sampling every ray at the same depths shows up
+// as bands.
+fn march_offset(ray: &Ray) -> Float {
+    let bits = |x: Float| (x as f32).to_bits();
+    let (o, d) = (ray.origin, ray.direction);
+    to_unit(hash(&[bits(o.i()), bits(o.j()), bits(o.k()), bits(d.i()), bits(d.j()), bits(d.k()),
+                   bits(ray.time)]))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use precision::float;
+    use vector3d::Vec3;
+    use scene::AmbientLight;
+    use shapes::{Sphere, Quad};
+    use materials::Material;
+
+    fn haze() -> Medium {
+        Medium {
+            absorption: [0.0; 3],
+            scattering: [0.1; 3],
+            anisotropy: 0.0,
+        }
+    }
+
+    fn scene(volumes: Vec<Volume>) -> Scene {
+        let dark = AmbientLight { colour: Rgb([0; 3]) };
+        Scene { volumes: volumes, ..Scene::new(dark, Vec::new(), Vec::new()) }
+    }
+
+    #[test]
+    fn test_phase_function_integrates_to_one() {
+        for &g in [-0.5, 0.0, 0.3, 0.8].iter() {
+            // Over the sphere, by the cosine of the angle
+            let n = 10000;
+            let sum: Float = (0..n)
+                .map(|k| -1.0 + 2.0 * (k as Float + 0.5) / n as Float)
+                .map(|c| henyey_greenstein(g, c) * 2.0 * PI * 2.0 / n as Float)
+                .sum();
+            assert!((sum - 1.0).abs() < 0.001);
+        }
+        assert!(henyey_greenstein(0.5, 1.0) > henyey_greenstein(0.5, -1.0));
+    }
+
+    #[test]
+    fn test_bounded_volumes_span_the_inside_of_their_shape() {
+        let tolerance = float::EPSILON.sqrt();
+        let ball = Volume::inside(haze(), Box::new(Sphere::simple(Vec3(0.0, 0.0, 5.0), 1.0)));
+        let spans = ball.spans(&Ray::new(Vec3::zero(), Vec3(0.0, 0.0, 2.0)), 100.0);
+        assert!(spans.len() == 1);
+        assert!((spans[0].0 - 4.0).abs() < tolerance && (spans[0].1 - 6.0).abs() < 0.001);
+
+        // From inside, and stopping short of the far side
+        let spans = ball.spans(&Ray::new(Vec3(0.0, 0.0, 5.0), Vec3(1.0, 0.0, 0.0)), 0.5);
+        assert!(spans == vec![(0.0, 0.5)]);
+        assert!(ball.spans(&Ray::new(Vec3::zero(), Vec3(1.0, 0.0, 0.0)), 100.0).is_empty());
+
+        let through = transmittance(&[ball], &Ray::new(Vec3::zero(), Vec3(0.0, 0.0, 1.0)), 100.0);
+        assert!((through[0] - (-0.2 as Float).exp()).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_fog_dims_what_lies_behind_it() {
+        let fog = scene(vec![Volume::everywhere(Medium { absorption: [0.1; 3], ..haze() })]);
+        let ray = Ray::new(Vec3::zero(), Vec3(0.0, 0.0, 1.0));
+        let seen = through_media(&fog, &ray, Some((10.0, Rgb([200; 3])))).unwrap();
+        assert!((seen.data[0] as Float - 200.0 * (-2.0 as Float).exp()).abs() <= 1.0);
+
+        assert!(through_media(&scene(vec![]), &ray, None).is_none());
+        assert!(through_media(&fog, &ray, None) == Some(Rgb([0; 3])));
+    }
+
+    #[test]
+    fn test_march_offsets_differ_from_ray_to_ray() {
+        let offsets: Vec<Float> = (0..16)
+            .map(|k| march_offset(&Ray::new(Vec3::zero(), Vec3(k as Float * 0.01, 0.0, 1.0))))
+            .collect();
+        assert!(offsets.iter().all(|&u| u >= 0.0 && u < 1.0));
+        assert!(offsets.windows(2).all(|w| w[0] != w[1]));
+        let ray = Ray::new(Vec3::zero(), Vec3(0.0, 0.0, 1.0));
+        assert!(march_offset(&ray) == march_offset(&ray));
+    }
+
+    #[test]
+    fn test_light_shafts_are_shadowed() {
+        let mut scene = scene(vec![Volume::everywhere(haze())]);
+        scene.lights.push(Light::new(Vec3(0.0, 10.0, 0.0), Rgb([255; 3])));
+        // A roof over the left half of the view
+        scene.objects.push(Box::new(Quad::new(Vec3(-20.0, 5.0, 0.0), Vec3(20.0, 0.0, 0.0),
+                                              Vec3(0.0, 0.0, 20.0), Material::plain())
+            .unwrap()));
+
+        let seen = |x: Float| {
+            let ray = Ray::new(Vec3(x, 0.0, 0.0), Vec3(0.0, 0.0, 1.0));
+            through_media(&scene, &ray, None).unwrap().data[0]
+        };
+        assert!(seen(-10.0) == 0);
+        assert!(seen(10.0) > 0);
+    }
+}
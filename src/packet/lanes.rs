@@ -0,0 +1,499 @@
+// lanes - four floats operated on in lockstep
+//
+// On x86_64 the lanes map onto SSE registers (SSE2 is part of the
+// base instruction set there, so no runtime detection is needed);
+// elsewhere we fall back to plain arrays and leave vectorisation to
+// the compiler.
+
+pub use self::imp::{Lanes, Mask};
+
+use precision::Float;
+use std::ops::Neg;
+
+pub const WIDTH: usize = 4;
+
+impl Lanes {
+    pub fn lane(self, i: usize) -> Float {
+        self.to_array()[i]
+    }
+
+    pub fn mul_add(self, a: Lanes, b: Lanes) -> Lanes {
+        self * a + b
+    }
+}
+
+impl Neg for Lanes {
+    type Output = Lanes;
+    fn neg(self) -> Lanes {
+        Lanes::splat(0.0) - self
+    }
+}
+
+impl Mask {
+    pub fn new(lanes: [bool; WIDTH]) -> Mask {
+        let one = |b: bool| if b { 1.0 } else { 0.0 };
+        Lanes::new([one(lanes[0]), one(lanes[1]), one(lanes[2]), one(lanes[3])])
+            .gt(Lanes::splat(0.0))
+    }
+
+    pub fn any(self) -> bool {
+        self.bits() != 0
+    }
+
+    pub fn all(self) -> bool {
+        self.bits() == (1 << WIDTH) - 1
+    }
+
+    pub fn lane(self, i: usize) -> bool {
+        self.bits() & (1 << i) != 0
+    }
+
+    pub fn to_array(self) -> [bool; WIDTH] {
+        [self.lane(0), self.lane(1), self.lane(2), self.lane(3)]
+    }
+}
+
+
+#[cfg(all(target_arch = "x86_64", not(feature = "f32")))]
+mod imp {
+    use std::arch::x86_64::*;
+    use std::ops::{Add, Sub, Mul, Div};
+
+    // Two SSE2 registers of two doubles each
+    #[derive(Clone, Copy, Debug)]
+    pub struct Lanes(__m128d, __m128d);
+
+    #[derive(Clone, Copy, Debug)]
+    pub struct Mask(__m128d, __m128d);
+
+    impl Lanes {
+        #[inline]
+        pub fn splat(x: f64) -> Lanes {
+            unsafe { Lanes(_mm_set1_pd(x), _mm_set1_pd(x)) }
+        }
+
+        #[inline]
+        pub fn new(xs: [f64; 4]) -> Lanes {
+            unsafe { Lanes(_mm_loadu_pd(xs.as_ptr()), _mm_loadu_pd(xs[2..].as_ptr())) }
+        }
+
+        #[inline]
+        pub fn to_array(self) -> [f64; 4] {
+            let mut out = [0.0; 4];
+            unsafe {
+                _mm_storeu_pd(out.as_mut_ptr(), self.0);
+                _mm_storeu_pd(out[2..].as_mut_ptr(), self.1);
+            }
+            out
+        }
+
+        #[inline]
+        pub fn sqrt(self) -> Lanes {
+            unsafe { Lanes(_mm_sqrt_pd(self.0), _mm_sqrt_pd(self.1)) }
+        }
+
+        #[inline]
+        pub fn abs(self) -> Lanes {
+            unsafe {
+                let sign = _mm_set1_pd(-0.0);
+                Lanes(_mm_andnot_pd(sign, self.0), _mm_andnot_pd(sign, self.1))
+            }
+        }
+
+        #[inline]
+        pub fn min(self, rhs: Lanes) -> Lanes {
+            unsafe { Lanes(_mm_min_pd(self.0, rhs.0), _mm_min_pd(self.1, rhs.1)) }
+        }
+
+        #[inline]
+        pub fn max(self, rhs: Lanes) -> Lanes {
+            unsafe { Lanes(_mm_max_pd(self.0, rhs.0), _mm_max_pd(self.1, rhs.1)) }
+        }
+
+        #[inline]
+        pub fn lt(self, rhs: Lanes) -> Mask {
+            unsafe { Mask(_mm_cmplt_pd(self.0, rhs.0), _mm_cmplt_pd(self.1, rhs.1)) }
+        }
+
+        #[inline]
+        pub fn le(self, rhs: Lanes) -> Mask {
+            unsafe { Mask(_mm_cmple_pd(self.0, rhs.0), _mm_cmple_pd(self.1, rhs.1)) }
+        }
+
+        #[inline]
+        pub fn gt(self, rhs: Lanes) -> Mask {
+            rhs.lt(self)
+        }
+
+        #[inline]
+        pub fn ge(self, rhs: Lanes) -> Mask {
+            rhs.le(self)
+        }
+
+        // Take lanes from self where the mask is set, otherwise from other
+        #[inline]
+        pub fn select(mask: Mask, a: Lanes, b: Lanes) -> Lanes {
+            unsafe {
+                Lanes(_mm_or_pd(_mm_and_pd(mask.0, a.0), _mm_andnot_pd(mask.0, b.0)),
+                      _mm_or_pd(_mm_and_pd(mask.1, a.1), _mm_andnot_pd(mask.1, b.1)))
+            }
+        }
+    }
+
+    impl Mask {
+        #[inline]
+        pub fn splat(b: bool) -> Mask {
+            unsafe {
+                let m = if b { _mm_castsi128_pd(_mm_set1_epi64x(-1)) } else { _mm_setzero_pd() };
+                Mask(m, m)
+            }
+        }
+
+        #[inline]
+        pub fn and(self, rhs: Mask) -> Mask {
+            unsafe { Mask(_mm_and_pd(self.0, rhs.0), _mm_and_pd(self.1, rhs.1)) }
+        }
+
+        #[inline]
+        pub fn or(self, rhs: Mask) -> Mask {
+            unsafe { Mask(_mm_or_pd(self.0, rhs.0), _mm_or_pd(self.1, rhs.1)) }
+        }
+
+        #[inline]
+        pub fn not(self) -> Mask {
+            let ones = Mask::splat(true);
+            unsafe { Mask(_mm_xor_pd(self.0, ones.0), _mm_xor_pd(self.1, ones.1)) }
+        }
+
+        #[inline]
+        pub fn bits(self) -> u32 {
+            unsafe { (_mm_movemask_pd(self.0) | (_mm_movemask_pd(self.1) << 2)) as u32 }
+        }
+    }
+
+    macro_rules! lanewise {
+        ($tr:ident, $f:ident, $op:ident) => {
+            impl $tr for Lanes {
+                type Output = Lanes;
+                #[inline]
+                fn $f(self, rhs: Lanes) -> Lanes {
+                    unsafe { Lanes($op(self.0, rhs.0), $op(self.1, rhs.1)) }
+                }
+            }
+        }
+    }
+
+    lanewise!(Add, add, _mm_add_pd);
+    lanewise!(Sub, sub, _mm_sub_pd);
+    lanewise!(Mul, mul, _mm_mul_pd);
+    lanewise!(Div, div, _mm_div_pd);
+}
+
+
+#[cfg(all(target_arch = "x86_64", feature = "f32"))]
+mod imp {
+    use std::arch::x86_64::*;
+    use std::ops::{Add, Sub, Mul, Div};
+
+    // A single SSE register of four singles
+    #[derive(Clone, Copy, Debug)]
+    pub struct Lanes(__m128);
+
+    #[derive(Clone, Copy, Debug)]
+    pub struct Mask(__m128);
+
+    impl Lanes {
+        #[inline]
+        pub fn splat(x: f32) -> Lanes {
+            unsafe { Lanes(_mm_set1_ps(x)) }
+        }
+
+        #[inline]
+        pub fn new(xs: [f32; 4]) -> Lanes {
+            unsafe { Lanes(_mm_loadu_ps(xs.as_ptr())) }
+        }
+
+        #[inline]
+        pub fn to_array(self) -> [f32; 4] {
+            let mut out = [0.0; 4];
+            unsafe { _mm_storeu_ps(out.as_mut_ptr(), self.0) };
+            out
+        }
+
+        #[inline]
+        pub fn sqrt(self) -> Lanes {
+            unsafe { Lanes(_mm_sqrt_ps(self.0)) }
+        }
+
+        #[inline]
+        pub fn abs(self) -> Lanes {
+            unsafe { Lanes(_mm_andnot_ps(_mm_set1_ps(-0.0), self.0)) }
+        }
+
+        #[inline]
+        pub fn min(self, rhs: Lanes) -> Lanes {
+            unsafe { Lanes(_mm_min_ps(self.0, rhs.0)) }
+        }
+
+        #[inline]
+        pub fn max(self, rhs: Lanes) -> Lanes {
+            unsafe { Lanes(_mm_max_ps(self.0, rhs.0)) }
+        }
+
+        #[inline]
+        pub fn lt(self, rhs: Lanes) -> Mask {
+            unsafe { Mask(_mm_cmplt_ps(self.0, rhs.0)) }
+        }
+
+        #[inline]
+        pub fn le(self, rhs: Lanes) -> Mask {
+            unsafe { Mask(_mm_cmple_ps(self.0, rhs.0)) }
+        }
+
+        #[inline]
+        pub fn gt(self, rhs: Lanes) -> Mask {
+            rhs.lt(self)
+        }
+
+        #[inline]
+        pub fn ge(self, rhs: Lanes) -> Mask {
+            rhs.le(self)
+        }
+
+        // Take lanes from self where the mask is set, otherwise from other
+        #[inline]
+        pub fn select(mask: Mask, a: Lanes, b: Lanes) -> Lanes {
+            unsafe { Lanes(_mm_or_ps(_mm_and_ps(mask.0, a.0), _mm_andnot_ps(mask.0, b.0))) }
+        }
+    }
+
+    impl Mask {
+        #[inline]
+        pub fn splat(b: bool) -> Mask {
+            unsafe {
+                Mask(if b { _mm_castsi128_ps(_mm_set1_epi32(-1)) } else { _mm_setzero_ps() })
+            }
+        }
+
+        #[inline]
+        pub fn and(self, rhs: Mask) -> Mask {
+            unsafe { Mask(_mm_and_ps(self.0, rhs.0)) }
+        }
+
+        #[inline]
+        pub fn or(self, rhs: Mask) -> Mask {
+            unsafe { Mask(_mm_or_ps(self.0, rhs.0)) }
+        }
+
+        #[inline]
+        pub fn not(self) -> Mask {
+            unsafe { Mask(_mm_xor_ps(self.0, Mask::splat(true).0)) }
+        }
+
+        #[inline]
+        pub fn bits(self) -> u32 {
+            unsafe { _mm_movemask_ps(self.0) as u32 }
+        }
+    }
+
+    macro_rules! lanewise {
+        ($tr:ident, $f:ident, $op:ident) => {
+            impl $tr for Lanes {
+                type Output = Lanes;
+                #[inline]
+                fn $f(self, rhs: Lanes) -> Lanes {
+                    unsafe { Lanes($op(self.0, rhs.0)) }
+                }
+            }
+        }
+    }
+
+    lanewise!(Add, add, _mm_add_ps);
+    lanewise!(Sub, sub, _mm_sub_ps);
+    lanewise!(Mul, mul, _mm_mul_ps);
+    lanewise!(Div, div, _mm_div_ps);
+}
+
+
+// Portable scalar fallback
+#[cfg(not(target_arch = "x86_64"))]
+mod imp {
+    use precision::Float;
+    use std::ops::{Add, Sub, Mul, Div};
+
+    #[derive(Clone, Copy, Debug)]
+    pub struct Lanes([Float; 4]);
+
+    #[derive(Clone, Copy, Debug)]
+    pub struct Mask([bool; 4]);
+
+    impl Lanes {
+        #[inline]
+        fn zip<F: Fn(Float, Float) -> Float>(self, rhs: Lanes, f: F) -> Lanes {
+            let (a, b) = (self.0, rhs.0);
+            Lanes([f(a[0], b[0]), f(a[1], b[1]), f(a[2], b[2]), f(a[3], b[3])])
+        }
+
+        #[inline]
+        fn compare<F: Fn(Float, Float) -> bool>(self, rhs: Lanes, f: F) -> Mask {
+            let (a, b) = (self.0, rhs.0);
+            Mask([f(a[0], b[0]), f(a[1], b[1]), f(a[2], b[2]), f(a[3], b[3])])
+        }
+
+        #[inline]
+        pub fn splat(x: Float) -> Lanes {
+            Lanes([x; 4])
+        }
+
+        #[inline]
+        pub fn new(xs: [Float; 4]) -> Lanes {
+            Lanes(xs)
+        }
+
+        #[inline]
+        pub fn to_array(self) -> [Float; 4] {
+            self.0
+        }
+
+        #[inline]
+        pub fn sqrt(self) -> Lanes {
+            let a = self.0;
+            Lanes([a[0].sqrt(), a[1].sqrt(), a[2].sqrt(), a[3].sqrt()])
+        }
+
+        #[inline]
+        pub fn abs(self) -> Lanes {
+            let a = self.0;
+            Lanes([a[0].abs(), a[1].abs(), a[2].abs(), a[3].abs()])
+        }
+
+        #[inline]
+        pub fn min(self, rhs: Lanes) -> Lanes {
+            self.zip(rhs, |a, b| if a < b { a } else { b })
+        }
+
+        #[inline]
+        pub fn max(self, rhs: Lanes) -> Lanes {
+            self.zip(rhs, |a, b| if a > b { a } else { b })
+        }
+
+        #[inline]
+        pub fn lt(self, rhs: Lanes) -> Mask {
+            self.compare(rhs, |a, b| a < b)
+        }
+
+        #[inline]
+        pub fn le(self, rhs: Lanes) -> Mask {
+            self.compare(rhs, |a, b| a <= b)
+        }
+
+        #[inline]
+        pub fn gt(self, rhs: Lanes) -> Mask {
+            rhs.lt(self)
+        }
+
+        #[inline]
+        pub fn ge(self, rhs: Lanes) -> Mask {
+            rhs.le(self)
+        }
+
+        #[inline]
+        pub fn select(mask: Mask, a: Lanes, b: Lanes) -> Lanes {
+            let (m, a, b) = (mask.0, a.0, b.0);
+            Lanes([if m[0] { a[0] } else { b[0] },
+                   if m[1] { a[1] } else { b[1] },
+                   if m[2] { a[2] } else { b[2] },
+                   if m[3] { a[3] } else { b[3] }])
+        }
+    }
+
+    impl Mask {
+        #[inline]
+        pub fn splat(b: bool) -> Mask {
+            Mask([b; 4])
+        }
+
+        #[inline]
+        pub fn and(self, rhs: Mask) -> Mask {
+            let (a, b) = (self.0, rhs.0);
+            Mask([a[0] && b[0], a[1] && b[1], a[2] && b[2], a[3] && b[3]])
+        }
+
+        #[inline]
+        pub fn or(self, rhs: Mask) -> Mask {
+            let (a, b) = (self.0, rhs.0);
+            Mask([a[0] || b[0], a[1] || b[1], a[2] || b[2], a[3] || b[3]])
+        }
+
+        #[inline]
+        pub fn not(self) -> Mask {
+            let a = self.0;
+            Mask([!a[0], !a[1], !a[2], !a[3]])
+        }
+
+        #[inline]
+        pub fn bits(self) -> u32 {
+            self.0.iter().enumerate().fold(0, |acc, (i, &b)| if b { acc | (1 << i) } else { acc })
+        }
+    }
+
+    impl Add for Lanes {
+        type Output = Lanes;
+        fn add(self, rhs: Lanes) -> Lanes {
+            self.zip(rhs, |a, b| a + b)
+        }
+    }
+
+    impl Sub for Lanes {
+        type Output = Lanes;
+        fn sub(self, rhs: Lanes) -> Lanes {
+            self.zip(rhs, |a, b| a - b)
+        }
+    }
+
+    impl Mul for Lanes {
+        type Output = Lanes;
+        fn mul(self, rhs: Lanes) -> Lanes {
+            self.zip(rhs, |a, b| a * b)
+        }
+    }
+
+    impl Div for Lanes {
+        type Output = Lanes;
+        fn div(self, rhs: Lanes) -> Lanes {
+            self.zip(rhs, |a, b| a / b)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lane_arithmetic() {
+        let a = Lanes::new([1.0, 2.0, 3.0, 4.0]);
+        let b = Lanes::splat(2.0);
+
+        assert!((a + b).to_array() == [3.0, 4.0, 5.0, 6.0]);
+        assert!((a - b).to_array() == [-1.0, 0.0, 1.0, 2.0]);
+        assert!((a * b).to_array() == [2.0, 4.0, 6.0, 8.0]);
+        assert!((a / b).to_array() == [0.5, 1.0, 1.5, 2.0]);
+        assert!((-a).abs().to_array() == a.to_array());
+        assert!(a.min(b).to_array() == [1.0, 2.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn test_lane_masks() {
+        let a = Lanes::new([1.0, 2.0, 3.0, 4.0]);
+        let b = Lanes::splat(2.5);
+        let m = a.lt(b);
+
+        assert!(m.to_array() == [true, true, false, false]);
+        assert!(m.any() && !m.all());
+        assert!(m.or(m.not()).all());
+        assert!(!m.and(m.not()).any());
+        assert!(Lanes::select(m, a, b).to_array() == [1.0, 2.0, 2.5, 2.5]);
+    }
+}
@@ -0,0 +1,196 @@
+// packet - bundles of coherent rays traced together
+//
+// Rays are stored structure-of-arrays style so that each component
+// of the four origins (and directions) sits in one set of lanes.
+// Shapes may override Intersectable::intersect_packet with a SIMD
+// implementation; anything else falls back to tracing lane by lane.
+
+pub mod lanes;
+
+pub use self::lanes::{Lanes, Mask, WIDTH};
+
+use precision::Float;
+use vector3d::Vec3;
+use ray::{Ray, Shadable, ShadeCell, STRIKE_TOLERANCE};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Vec3Lanes {
+    pub x: Lanes,
+    pub y: Lanes,
+    pub z: Lanes,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RayPacket {
+    pub origin: Vec3Lanes,
+    pub direction: Vec3Lanes,
+    pub time: Lanes,
+}
+
+// The point struck by each ray in a packet, if any
+pub type PacketHits = [Option<Vec3>; WIDTH];
+
+impl Vec3Lanes {
+    pub fn splat(v: Vec3) -> Vec3Lanes {
+        Vec3Lanes {
+            x: Lanes::splat(v.i()),
+            y: Lanes::splat(v.j()),
+            z: Lanes::splat(v.k()),
+        }
+    }
+
+    pub fn new(vs: [Vec3; WIDTH]) -> Vec3Lanes {
+        Vec3Lanes {
+            x: Lanes::new([vs[0].i(), vs[1].i(), vs[2].i(), vs[3].i()]),
+            y: Lanes::new([vs[0].j(), vs[1].j(), vs[2].j(), vs[3].j()]),
+            z: Lanes::new([vs[0].k(), vs[1].k(), vs[2].k(), vs[3].k()]),
+        }
+    }
+
+    pub fn lane(&self, i: usize) -> Vec3 {
+        Vec3(self.x.lane(i), self.y.lane(i), self.z.lane(i))
+    }
+
+    pub fn add(self, rhs: Vec3Lanes) -> Vec3Lanes {
+        Vec3Lanes {
+            x: self.x + rhs.x,
+            y: self.y + rhs.y,
+            z: self.z + rhs.z,
+        }
+    }
+
+    pub fn sub(self, rhs: Vec3Lanes) -> Vec3Lanes {
+        Vec3Lanes {
+            x: self.x - rhs.x,
+            y: self.y - rhs.y,
+            z: self.z - rhs.z,
+        }
+    }
+
+    pub fn scale(self, t: Lanes) -> Vec3Lanes {
+        Vec3Lanes {
+            x: self.x * t,
+            y: self.y * t,
+            z: self.z * t,
+        }
+    }
+
+    pub fn dot(self, rhs: Vec3Lanes) -> Lanes {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z
+    }
+
+    pub fn cross(self, rhs: Vec3Lanes) -> Vec3Lanes {
+        Vec3Lanes {
+            x: self.y * rhs.z - self.z * rhs.y,
+            y: self.z * rhs.x - self.x * rhs.z,
+            z: self.x * rhs.y - self.y * rhs.x,
+        }
+    }
+}
+
+impl RayPacket {
+    pub fn new(rays: [Ray; WIDTH]) -> RayPacket {
+        RayPacket {
+            origin: Vec3Lanes::new([rays[0].origin, rays[1].origin, rays[2].origin,
+                                    rays[3].origin]),
+            direction: Vec3Lanes::new([rays[0].direction, rays[1].direction,
+                                       rays[2].direction, rays[3].direction]),
+            time: Lanes::new([rays[0].time, rays[1].time, rays[2].time, rays[3].time]),
+        }
+    }
+
+    pub fn ray(&self, i: usize) -> Ray {
+        Ray::new(self.origin.lane(i), self.direction.lane(i)).with_time(self.time.lane(i))
+    }
+
+    // Turn distances along each ray into points, for the lanes which hit
+    pub fn points_at(&self, t: Lanes, hit: Mask) -> PacketHits {
+        let p = self.origin.add(self.direction.scale(t));
+        let mut hits = [None; WIDTH];
+        for (i, h) in hits.iter_mut().enumerate() {
+            if hit.lane(i) {
+                *h = Some(p.lane(i));
+            }
+        }
+        hits
+    }
+
+    // Intersect every ray with every object, keeping the nearest strike
+    // beyond the trace tolerance in each lane, exactly as Ray::trace does
+    pub fn trace<'a>(&self, objects: &'a Vec<Box<Shadable>>) -> [Option<ShadeCell<'a>>; WIDTH] {
+        let mut nearest: [Option<(Float, Vec3, usize)>; WIDTH] = [None; WIDTH];
+
+        for (index, object) in objects.iter().enumerate() {
+            let hits = object.intersect_packet(self);
+            for i in 0..WIDTH {
+                if let Some(p) = hits[i] {
+                    let d = (p - self.origin.lane(i)).norm();
+                    let closer = match nearest[i] {
+                        Some((best, _, _)) => d < best,
+                        None => true,
+                    };
+                    if d >= STRIKE_TOLERANCE && closer {
+                        nearest[i] = Some((d, p, index));
+                    }
+                }
+            }
+        }
+
+        let cell = |i: usize| {
+            nearest[i].map(|(_, p, index)| self.ray(i).shade_cell(p, index, &objects[index]))
+        };
+        [cell(0), cell(1), cell(2), cell(3)]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shapes::{Sphere, Plane, Triangle};
+    use materials::Material;
+
+    fn fan_of_rays() -> [Ray; WIDTH] {
+        let o = Vec3(0.0, 0.0, -5.0);
+        [Ray::new(o, Vec3(0.0, 0.0, 1.0)),
+         Ray::new(o, Vec3(0.1, 0.05, 1.0)),
+         Ray::new(o, Vec3(-0.3, 0.2, 1.0)),
+         Ray::new(o, Vec3(0.0, 1.0, 0.0))]
+    }
+
+    #[test]
+    fn test_packet_round_trips_rays() {
+        let rays = fan_of_rays();
+        let packet = RayPacket::new(rays);
+
+        for i in 0..WIDTH {
+            assert!(packet.ray(i) == rays[i]);
+        }
+    }
+
+    #[test]
+    fn test_packet_trace_agrees_with_scalar_trace() {
+        let tolerance = ::precision::float::EPSILON.sqrt();
+        let objects = vec![
+            Box::new(Sphere::simple(Vec3(0.0, 0.0, 0.0), 0.7)) as Box<Shadable>,
+            Box::new(Triangle::new(Vec3(-2.0, -2.0, 1.0), Vec3(2.0, -2.0, 1.0),
+                                   Vec3(0.0, 2.0, 1.0), Material::plain()).unwrap())
+                as Box<Shadable>,
+            Box::new(Plane::new(Vec3(1.0, 0.0, 0.0), Vec3(0.0, 1.0, 0.0), Material::plain())
+                .unwrap()) as Box<Shadable>,
+        ];
+        let rays = fan_of_rays();
+        let cells = RayPacket::new(rays).trace(&objects);
+
+        for i in 0..WIDTH {
+            match (rays[i].trace(&objects), &cells[i]) {
+                (Some(ShadeCell(p, n, _, _, _)), &Some(ShadeCell(q, m, _, _, _))) => {
+                    assert!(p.approx_eq(q, tolerance));
+                    assert!(n.approx_eq(m, tolerance));
+                }
+                (None, &None) => (),
+                _ => panic!("packet and scalar traces disagree in lane {}", i),
+            }
+        }
+    }
+}
@@ -0,0 +1,531 @@
+// parallel - rendering many samples a pixel across threads. The image
+// is split into tiles which threads take up as they finish their last,
+// but each sample is drawn from the seed, its pixel and its index
+// alone, and every pixel sums its samples in index order, so the image
+// is the same to the bit however many threads there are and whatever
+// order the tiles finish in.
+use std::ops::Range;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use image::Rgb;
+
+use precision::Float;
+use camera::Camera;
+use scene::Scene;
+use shade::{Shader, to_floats};
+use raytrace::Raytracer;
+use ray::Ray;
+use packet::{RayPacket, Mask, WIDTH};
+use framebuffer::Framebuffer;
+use sampler::{Sampler, SamplerKind};
+use stats::{self, Counters};
+use error::{Error, Result};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RenderOptions {
+    pub depth: u8,
+    pub samples: u32,
+    // None takes one thread for each processor
+    pub threads: Option<usize>,
+    pub seed: u32,
+    pub sampler: SamplerKind,
+    pub tile_size: u32,
+    pub region: Region,
+}
+
+// Which part of the camera's frame to render. Pixels keep their place
+// in the full frame, so a region renders exactly as it would as part
+// of the whole image.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Region {
+    Full,
+    // An image of the region alone
+    Cropped(Tile),
+    // An image of the full frame, black outside the region
+    Padded(Tile),
+}
+
+impl RenderOptions {
+    pub fn new() -> RenderOptions {
+        RenderOptions {
+            depth: 2,
+            samples: 1,
+            threads: None,
+            seed: 0,
+            sampler: SamplerKind::Sobol,
+            tile_size: 16,
+            region: Region::Full,
+        }
+    }
+
+    pub fn with_depth(self, depth: u8) -> RenderOptions {
+        RenderOptions { depth: depth, ..self }
+    }
+
+    pub fn with_samples(self, samples: u32) -> RenderOptions {
+        RenderOptions { samples: samples.max(1), ..self }
+    }
+
+    pub fn with_threads(self, threads: usize) -> RenderOptions {
+        RenderOptions { threads: Some(threads.max(1)), ..self }
+    }
+
+    pub fn with_seed(self, seed: u32) -> RenderOptions {
+        RenderOptions { seed: seed, ..self }
+    }
+
+    pub fn with_sampler(self, sampler: SamplerKind) -> RenderOptions {
+        RenderOptions { sampler: sampler, ..self }
+    }
+
+    pub fn with_tile_size(self, size: u32) -> RenderOptions {
+        RenderOptions { tile_size: size.max(1), ..self }
+    }
+
+    pub fn with_region(self, region: Region) -> RenderOptions {
+        RenderOptions { region: region, ..self }
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.threads.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions::new()
+    }
+}
+
+// A rectangle of pixels, by its top left corner and size
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    // The pixels to render in a frame of the given size, which must
+    // hold them all
+    pub fn pixels(&self, width: u32, height: u32) -> Result<Tile> {
+        let frame = Tile::full(width, height);
+        let region = match *self {
+            Region::Full => frame,
+            Region::Cropped(r) | Region::Padded(r) => r,
+        };
+        if !frame.contains(&region) || region.width == 0 || region.height == 0 {
+            return Err(Error::RegionOutOfFrame {
+                x: region.x,
+                y: region.y,
+                width: region.width,
+                height: region.height,
+                frame_width: width,
+                frame_height: height,
+            });
+        }
+        Ok(region)
+    }
+
+    // A blank output image, and the pixel of the frame at its corner
+    fn image(&self, width: u32, height: u32) -> ((u32, u32), Framebuffer) {
+        match *self {
+            Region::Cropped(r) => ((r.x, r.y), Framebuffer::new(r.width, r.height, 3)),
+            _ => ((0, 0), Framebuffer::new(width, height, 3)),
+        }
+    }
+
+    // The output image, given the pixels of the region alone
+    pub fn place(&self, width: u32, height: u32, pixels: &Framebuffer) -> Result<Framebuffer> {
+        let region = self.pixels(width, height)?;
+        let (origin, mut image) = self.image(width, height);
+        for y in 0..region.height {
+            for x in 0..region.width {
+                image.put(region.x + x - origin.0, region.y + y - origin.1, pixels.get(x, y));
+            }
+        }
+        Ok(image)
+    }
+
+    // The output image from one of the full frame, for renderers which
+    // cannot be limited to part of it
+    pub fn extract(&self, frame: &Framebuffer) -> Result<Framebuffer> {
+        let region = self.pixels(frame.width(), frame.height())?;
+        let (origin, mut image) = self.image(frame.width(), frame.height());
+        for y in region.y..region.y + region.height {
+            for x in region.x..region.x + region.width {
+                image.put(x - origin.0, y - origin.1, frame.get(x, y));
+            }
+        }
+        Ok(image)
+    }
+}
+
+impl Tile {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Tile {
+        Tile {
+            x: x,
+            y: y,
+            width: width,
+            height: height,
+        }
+    }
+
+    // The whole of an image of the given size
+    pub fn full(width: u32, height: u32) -> Tile {
+        Tile::new(0, 0, width, height)
+    }
+
+    // Tiles reaching past the largest coordinate are out of any frame
+    pub fn contains(&self, other: &Tile) -> bool {
+        let ends = |t: &Tile| (t.x.checked_add(t.width), t.y.checked_add(t.height));
+        match (ends(self), ends(other)) {
+            ((Some(right), Some(bottom)), (Some(other_right), Some(other_bottom))) => {
+                other.x >= self.x && other.y >= self.y && other_right <= right &&
+                other_bottom <= bottom
+            }
+            _ => false,
+        }
+    }
+}
+
+// Square tiles covering the image row by row, cut short at the edges
+pub fn tiles(width: u32, height: u32, size: u32) -> Vec<Tile> {
+    region_tiles(Tile::full(width, height), size)
+}
+
+// As tiles, covering only the given region
+pub fn region_tiles(region: Tile, size: u32) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    for y in (0..region.height).step_by(size as usize) {
+        for x in (0..region.width).step_by(size as usize) {
+            tiles.push(Tile::new(region.x + x,
+                                 region.y + y,
+                                 size.min(region.width - x),
+                                 size.min(region.height - y)));
+        }
+    }
+    tiles
+}
+
+impl<S: Shader + Sync> Raytracer<S> {
+    // The mean of each pixel's samples, with channels in [0, 1], over
+    // the region of the frame the options ask for
+    pub fn render_parallel(&self, camera: &Camera, scene: &Scene, options: &RenderOptions)
+                           -> Result<Framebuffer> {
+        self.render_parallel_with_progress(camera, scene, options, |_, _| ())
+    }
+
+    // As render_parallel, telling the callback how many tiles of how
+    // many are done as each finishes. It is called from the render
+    // threads, one at a time.
+    pub fn render_parallel_with_progress<F>(&self, camera: &Camera, scene: &Scene,
+                                            options: &RenderOptions, progress: F)
+                                            -> Result<Framebuffer>
+        where F: Fn(usize, usize) + Sync
+    {
+        let region = options.region.pixels(camera.width(), camera.height())?;
+        let pixels = self.render_samples_with_progress(camera, scene, options, region,
+                                                       0..options.samples.max(1), progress)?;
+        options.region.place(camera.width(), camera.height(), &pixels)
+    }
+
+    // The mean of the given range of each pixel's samples over a
+    // rectangle of the frame, ignoring the region in the options. The
+    // means of several ranges, weighted by their lengths, make up the
+    // image of all of them.
+    pub fn render_samples(&self, camera: &Camera, scene: &Scene, options: &RenderOptions,
+                          area: Tile, samples: Range<u32>)
+                          -> Result<Framebuffer> {
+        self.render_samples_with_progress(camera, scene, options, area, samples, |_, _| ())
+    }
+
+    fn render_samples_with_progress<F>(&self, camera: &Camera, scene: &Scene,
+                                       options: &RenderOptions, area: Tile,
+                                       samples: Range<u32>, progress: F)
+                                       -> Result<Framebuffer>
+        where F: Fn(usize, usize) + Sync
+    {
+        let batch: Vec<_> = region_tiles(area, options.tile_size)
+            .into_iter()
+            .map(|tile| (tile, samples.clone()))
+            .collect();
+        let rendered = self.render_batch(camera, scene, options, &batch, progress)?;
+
+        let mut image = Framebuffer::new(area.width, area.height, 3);
+        for (&(tile, _), pixels) in batch.iter().zip(rendered) {
+            for y in 0..tile.height {
+                for x in 0..tile.width {
+                    image.put(tile.x + x - area.x, tile.y + y - area.y, pixels.get(x, y));
+                }
+            }
+        }
+        Ok(image)
+    }
+
+    // The mean of each tile's own range of samples, rendering the tiles
+    // across threads, telling the callback how many of them are done.
+    // What the threads count is added to the counts of this one.
+    pub fn render_batch<F>(&self, camera: &Camera, scene: &Scene, options: &RenderOptions,
+                           batch: &[(Tile, Range<u32>)], progress: F)
+                           -> Result<Vec<Framebuffer>>
+        where F: Fn(usize, usize) + Sync
+    {
+        let sampler = options.sampler.build(options.seed, options.samples);
+        let next = AtomicUsize::new(0);
+        let done = Mutex::new(Vec::with_capacity(batch.len()));
+        let counted = Mutex::new(Counters::new());
+
+        thread::scope(|s| {
+            let (next, done, counted, progress) = (&next, &done, &counted, &progress);
+            for _ in 0..options.thread_count().min(batch.len()) {
+                let mut sampler = sampler.clone();
+                s.spawn(move || {
+                    loop {
+                        let i = next.fetch_add(1, Ordering::SeqCst);
+                        if i >= batch.len() {
+                            break;
+                        }
+                        let (tile, ref samples) = batch[i];
+                        let pixels = self.render_tile(camera, scene, options, &mut sampler, tile,
+                                                      samples.clone());
+                        let mut done = done.lock().unwrap();
+                        done.push((i, pixels));
+                        progress(done.len(), batch.len());
+                    }
+                    counted.lock().unwrap().add(&stats::take());
+                });
+            }
+        });
+        stats::add(&counted.into_inner().unwrap());
+
+        let mut done = done.into_inner().unwrap();
+        done.sort_by_key(|&(i, _)| i);
+        done.into_iter().map(|(_, pixels)| pixels).collect()
+    }
+
+    // Render the mean of a range of samples for the pixels of one tile,
+    // as render_samples does
+    pub fn render_tile<P: Sampler>(&self, camera: &Camera, scene: &Scene, options: &RenderOptions,
+                                   sampler: &mut P, tile: Tile, samples: Range<u32>)
+                                   -> Result<Framebuffer> {
+        // Samples are traced four at a time in packets, taken in pixel
+        // then index order so each pixel still sums them in order
+        let mut sums = vec![[0.0; 3]; tile.width as usize * tile.height as usize];
+        let mut batch = Vec::with_capacity(WIDTH);
+        for y in 0..tile.height {
+            for x in 0..tile.width {
+                let (px, py) = (tile.x + x, tile.y + y);
+                for i in samples.clone() {
+                    sampler.start(px, py, i);
+                    let (u, v) = sampler.next_2d();
+                    let t = sampler.next_1d();
+                    let ray = camera.get_ray_through_subpixel_at(px, py, u - 0.5, v - 0.5, t)?;
+                    batch.push(((y * tile.width + x) as usize, ray));
+                    if batch.len() == WIDTH {
+                        self.trace_batch(options.depth, &batch, scene, &mut sums);
+                        batch.clear();
+                    }
+                }
+            }
+        }
+        if !batch.is_empty() {
+            self.trace_batch(options.depth, &batch, scene, &mut sums);
+        }
+
+        let mut pixels = Framebuffer::new(tile.width, tile.height, 3);
+        let scale = 1.0 / (255.0 * samples.len().max(1) as Float);
+        for y in 0..tile.height {
+            for x in 0..tile.width {
+                let sum = sums[(y * tile.width + x) as usize];
+                pixels.put(x, y, &[sum[0] * scale, sum[1] * scale, sum[2] * scale]);
+            }
+        }
+        Ok(pixels)
+    }
+
+    // Trace a batch of rays, adding the colour each sees to the sum for
+    // its pixel. Only full batches go as a packet; the rest of a tile's
+    // rays are traced one by one, so that no work is wasted on padding
+    // and the counters match however the samples are split.
+    fn trace_batch(&self, depth: u8, batch: &[(usize, Ray)], scene: &Scene,
+                   sums: &mut [[Float; 3]]) {
+        let colours: Vec<Option<Rgb<u8>>> = if batch.len() == WIDTH {
+            let packet = RayPacket::new([batch[0].1, batch[1].1, batch[2].1, batch[3].1]);
+            self.trace_packet_to_depth(depth, &packet, Mask::splat(true), scene).to_vec()
+        } else {
+            batch.iter().map(|(_, ray)| self.trace_to_depth(depth, ray, scene)).collect()
+        };
+        for (&(at, _), colour) in batch.iter().zip(colours.iter()) {
+            if let Some(c) = *colour {
+                let c = to_floats(c);
+                for k in 0..3 {
+                    sums[at][k] += c[k];
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+    use vector3d::Vec3;
+    use camera::CameraBuilder;
+    use shapes::Sphere;
+    use shade::PhongShader;
+    use scene::{Light, AmbientLight};
+    use ray::Shadable;
+    use sampler::ALL_SAMPLERS;
+    #[cfg(feature = "stats")]
+    use stats::Primitive;
+
+    fn scene() -> Scene {
+        Scene {
+            ambient_light: AmbientLight { colour: Rgb([40; 3]) },
+            objects: vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 5.0), 1.5)) as Box<Shadable>,
+                          Box::new(Sphere::simple(Vec3(2.0, 1.0, 6.0), 1.0)) as Box<Shadable>],
+            lights: vec![Light::new(Vec3(2.0, -4.0, 0.0), Rgb([255; 3]))],
+            volumes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_tiles_cover_the_image_once() {
+        let tiles = tiles(10, 7, 4);
+        assert!(tiles.len() == 6);
+        assert!(tiles.iter().map(|t| t.width * t.height).sum::<u32>() == 70);
+        assert!(tiles[5] == Tile { x: 8, y: 4, width: 2, height: 3 });
+        assert!(region_tiles(Tile::new(5, 2, 3, 3), 2)[3] == Tile::new(7, 4, 1, 1));
+    }
+
+    #[test]
+    fn test_renders_do_not_depend_on_threads_or_tiles() {
+        let camera = CameraBuilder::new(16, 12, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let scene = scene();
+
+        for &kind in ALL_SAMPLERS.iter() {
+            let options = RenderOptions::new().with_samples(4).with_seed(9).with_sampler(kind);
+            let one = tracer.render_parallel(&camera, &scene, &options.with_threads(1)).unwrap();
+            let many = tracer.render_parallel(&camera, &scene,
+                                              &options.with_threads(4).with_tile_size(3))
+                .unwrap();
+            assert!(one == many);
+        }
+    }
+
+    #[test]
+    fn test_seed_changes_the_samples() {
+        let camera = CameraBuilder::new(16, 12, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let scene = scene();
+        let options = RenderOptions::new().with_samples(4).with_threads(2);
+
+        let a = tracer.render_parallel(&camera, &scene, &options.with_seed(1)).unwrap();
+        let b = tracer.render_parallel(&camera, &scene, &options.with_seed(1)).unwrap();
+        let c = tracer.render_parallel(&camera, &scene, &options.with_seed(2)).unwrap();
+        assert!(a == b);
+        assert!(a != c);
+    }
+
+    #[test]
+    fn test_regions_render_as_part_of_the_frame() {
+        let camera = CameraBuilder::new(16, 12, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let scene = scene();
+        let options = RenderOptions::new().with_samples(2).with_tile_size(5);
+        let region = Tile::new(3, 4, 9, 6);
+
+        let full = tracer.render_parallel(&camera, &scene, &options).unwrap();
+        let cropped = tracer.render_parallel(&camera, &scene,
+                                             &options.with_region(Region::Cropped(region)))
+            .unwrap();
+        let padded = tracer.render_parallel(&camera, &scene,
+                                            &options.with_region(Region::Padded(region)))
+            .unwrap();
+
+        assert!(cropped == full.crop(3, 4, 9, 6));
+        assert!(cropped == Region::Cropped(region).extract(&full).unwrap());
+        assert!(padded == Region::Padded(region).extract(&full).unwrap());
+        assert!(padded.width() == 16 && padded.height() == 12);
+        for y in 0..12 {
+            for x in 0..16 {
+                let inside = region.contains(&Tile::new(x, y, 1, 1));
+                assert!(padded.get(x, y) == if inside { full.get(x, y) } else { &[0.0; 3] });
+            }
+        }
+    }
+
+    #[test]
+    fn test_regions_must_lie_in_the_frame() {
+        let camera = CameraBuilder::new(16, 12, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let wrapping = [Tile::new(u32::MAX, 0, 2, 2), Tile::new(4, u32::MAX - 1, 3, 3)];
+        for &region in [Tile::new(10, 0, 7, 2), Tile::new(0, 0, 0, 5)].iter().chain(&wrapping) {
+            assert!(Region::Cropped(region).pixels(16, 12).is_err());
+            let options = RenderOptions::new().with_region(Region::Cropped(region));
+            match tracer.render_parallel(&camera, &scene(), &options) {
+                Err(Error::RegionOutOfFrame { .. }) => (),
+                _ => panic!("expected the region to be refused"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_progress_counts_every_tile() {
+        let camera = CameraBuilder::new(16, 12, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let seen = Mutex::new(Vec::new());
+        let options = RenderOptions::new().with_threads(3).with_tile_size(4);
+
+        tracer.render_parallel_with_progress(&camera, &scene(), &options,
+                                             |done, total| seen.lock().unwrap().push((done, total)))
+            .unwrap();
+
+        let seen = seen.into_inner().unwrap();
+        assert!(seen == (1..13).map(|n| (n, 12)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_sample_ranges_make_up_the_whole() {
+        let camera = CameraBuilder::new(16, 12, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let scene = scene();
+        let options = RenderOptions::new().with_samples(4).with_seed(3);
+        let area = Tile::new(2, 3, 7, 5);
+
+        let whole = tracer.render_parallel(&camera, &scene, &options).unwrap();
+        let first = tracer.render_samples(&camera, &scene, &options, area, 0..1).unwrap();
+        let rest = tracer.render_samples(&camera, &scene, &options, area, 1..4).unwrap();
+        for y in 0..5 {
+            for x in 0..7 {
+                for k in 0..3 {
+                    let mean = (first.get(x, y)[k] + 3.0 * rest.get(x, y)[k]) / 4.0;
+                    assert!((mean - whole.get(x + 2, y + 3)[k]).abs() < 1e-4);
+                }
+            }
+        }
+    }
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_threads_counts_reach_the_caller() {
+        let camera = CameraBuilder::new(16, 12, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let options = RenderOptions::new().with_samples(2).with_threads(3).with_depth(3);
+
+        stats::take();
+        tracer.render_parallel(&camera, &scene(), &options).unwrap();
+        let counted = stats::take();
+        assert!(counted.primary_rays == 16 * 12 * 2);
+        assert!(counted.tests_of(Primitive::Sphere) ==
+                2 * (counted.primary_rays + counted.secondary_rays + counted.shadow_rays));
+        assert!(counted.strikes > 0 && counted.secondary_rays <= counted.strikes);
+        assert!(counted.average_depth() > 0.0 && counted.average_depth() <= 3.0);
+    }
+}
@@ -0,0 +1,17 @@
+// precision - the floating point type used for geometry and colour
+//
+// Everything is computed in f64 by default. Building with the "f32"
+// feature halves the size of vectors, rays and materials, which helps
+// cache and SIMD throughput on large scenes at the cost of precision.
+
+#[cfg(not(feature = "f32"))]
+pub type Float = f64;
+
+#[cfg(not(feature = "f32"))]
+pub use std::f64 as float;
+
+#[cfg(feature = "f32")]
+pub type Float = f32;
+
+#[cfg(feature = "f32")]
+pub use std::f32 as float;
@@ -0,0 +1,203 @@
+// adaptive.rs - taking more samples only in the pixels which need them
+use image::{Rgb, RgbImage, ImageBuffer};
+use precision::{Float, float};
+use camera::Camera;
+use scene::Scene;
+use shade::{Shader, to_floats};
+use shade::debug::heat;
+use raytrace::Raytracer;
+use framebuffer::Framebuffer;
+use progressive::{sample_offset, sample_time};
+use error::Result;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AdaptiveSettings {
+    // Every pixel takes at least this many samples, to judge its noise
+    pub min_samples: u32,
+    pub max_samples: u32,
+    // Pixels stop taking samples once the standard error of the mean
+    // of their brightness, in [0, 1], falls to this
+    pub threshold: Float,
+}
+
+impl AdaptiveSettings {
+    pub fn new(min_samples: u32, max_samples: u32, threshold: Float) -> AdaptiveSettings {
+        let min_samples = min_samples.max(2);
+        AdaptiveSettings {
+            min_samples: min_samples,
+            max_samples: max_samples.max(min_samples),
+            threshold: threshold,
+        }
+    }
+}
+
+// Running totals for each pixel, from which the mean colour and the
+// noise in its brightness follow
+#[derive(Clone, Debug)]
+pub struct AdaptiveImage {
+    sum: Framebuffer,
+    // Brightness and its square, summed
+    moments: Framebuffer,
+    counts: Vec<u32>,
+    max_samples: u32,
+}
+
+impl AdaptiveImage {
+    fn new(width: u32, height: u32, max_samples: u32) -> AdaptiveImage {
+        AdaptiveImage {
+            sum: Framebuffer::new(width, height, 3),
+            moments: Framebuffer::new(width, height, 2),
+            counts: vec![0; width as usize * height as usize],
+            max_samples: max_samples,
+        }
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y * self.sum.width() + x) as usize
+    }
+
+    fn add(&mut self, x: u32, y: u32, colour: Rgb<u8>) {
+        let c = to_floats(colour);
+        let luma = (0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]) / 255.0;
+        {
+            let s = self.sum.get_mut(x, y);
+            for i in 0..3 {
+                s[i] += c[i] / 255.0;
+            }
+        }
+        {
+            let m = self.moments.get_mut(x, y);
+            m[0] += luma;
+            m[1] += luma * luma;
+        }
+        let i = self.index(x, y);
+        self.counts[i] += 1;
+    }
+
+    pub fn samples(&self, x: u32, y: u32) -> u32 {
+        self.counts[self.index(x, y)]
+    }
+
+    // The standard error of the pixel's mean brightness
+    pub fn error(&self, x: u32, y: u32) -> Float {
+        let n = self.samples(x, y) as Float;
+        if n < 2.0 {
+            return float::MAX;
+        }
+        let m = self.moments.get(x, y);
+        let variance = ((m[1] - m[0] * m[0] / n) / (n - 1.0)).max(0.0);
+        (variance / n).sqrt()
+    }
+
+    pub fn mean(&self) -> Framebuffer {
+        let mut mean = self.sum.clone();
+        for y in 0..mean.height() {
+            for x in 0..mean.width() {
+                let n = self.samples(x, y).max(1) as Float;
+                for c in mean.get_mut(x, y).iter_mut() {
+                    *c /= n;
+                }
+            }
+        }
+        mean
+    }
+
+    pub fn image(&self) -> RgbImage {
+        self.mean().to_rgb_image()
+    }
+
+    // The samples taken in each pixel, from blue for none to red for
+    // the most allowed
+    pub fn heatmap(&self) -> RgbImage {
+        ImageBuffer::from_fn(self.sum.width(), self.sum.height(), |x, y| {
+            heat(self.samples(x, y) as Float / self.max_samples.max(1) as Float)
+        })
+    }
+}
+
+impl<S: Shader> Raytracer<S> {
+    // Render with several samples per pixel, adding samples a round at
+    // a time to those pixels still too noisy, until each is smooth
+    // enough or has taken the most samples allowed
+    pub fn render_adaptive(&self, depth: u8, camera: &Camera, scene: &Scene,
+                           settings: AdaptiveSettings) -> Result<AdaptiveImage> {
+        let (width, height) = (camera.width(), camera.height());
+        let mut img = AdaptiveImage::new(width, height, settings.max_samples);
+
+        let sample = |img: &mut AdaptiveImage, x: u32, y: u32| -> Result<()> {
+            let n = img.samples(x, y);
+            let (dx, dy) = sample_offset(n);
+            let ray = camera.get_ray_through_subpixel_at(x, y, dx, dy, sample_time(n))?;
+            let colour = self.trace_to_depth(depth, &ray, scene).unwrap_or(Rgb([0, 0, 0]));
+            img.add(x, y, colour);
+            Ok(())
+        };
+
+        for _ in 0..settings.min_samples {
+            for y in 0..height {
+                for x in 0..width {
+                    sample(&mut img, x, y)?;
+                }
+            }
+        }
+
+        for _ in settings.min_samples..settings.max_samples {
+            let mut converged = true;
+            for y in 0..height {
+                for x in 0..width {
+                    if img.error(x, y) > settings.threshold {
+                        sample(&mut img, x, y)?;
+                        converged = false;
+                    }
+                }
+            }
+            if converged {
+                break;
+            }
+        }
+
+        Ok(img)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vector3d::Vec3;
+    use camera::CameraBuilder;
+    use shapes::Sphere;
+    use shade::PhongShader;
+    use scene::{Light, AmbientLight};
+    use ray::Shadable;
+
+    #[test]
+    fn test_samples_gather_at_edges() {
+        let scene = Scene {
+            ambient_light: AmbientLight { colour: Rgb([40; 3]) },
+            objects: vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 5.0), 1.5)) as Box<Shadable>],
+            lights: vec![Light::new(Vec3(2.0, -4.0, 0.0), Rgb([255; 3]))],
+            volumes: Vec::new(),
+        };
+        let camera = CameraBuilder::new(16, 12, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let settings = AdaptiveSettings::new(4, 32, 0.01);
+
+        let img = tracer.render_adaptive(2, &camera, &scene, settings).unwrap();
+
+        // The corner sees only empty space, so has nothing to refine
+        assert!(img.samples(0, 0) == 4);
+        assert!(img.error(0, 0) == 0.0);
+        let mut most = 0;
+        for y in 0..12 {
+            for x in 0..16 {
+                let n = img.samples(x, y);
+                assert!(n >= 4 && n <= 32);
+                assert!(n == 32 || img.error(x, y) <= 0.01);
+                most = most.max(n);
+            }
+        }
+        assert!(most > 4);
+        assert!(img.heatmap().get_pixel(0, 0) == &heat(4.0 / 32.0));
+    }
+}
@@ -0,0 +1,274 @@
+// progressive - rendering in passes which refine the image over time,
+// so that a preview can be shown and the render stopped at any point
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use image::{Rgb, RgbImage, ImageBuffer};
+use precision::Float;
+use camera::Camera;
+use scene::Scene;
+use shade::{Shader, to_floats};
+use raytrace::Raytracer;
+use framebuffer::Framebuffer;
+use error::Result;
+
+pub mod adaptive;
+
+pub use self::adaptive::{AdaptiveSettings, AdaptiveImage};
+
+// Shared between the render and whoever may want to stop it. Clones
+// refer to the same token.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// When to stop adding passes. Without a limit of either kind the
+// render runs until cancelled.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Budget {
+    pub samples: Option<u32>,
+    pub time: Option<Duration>,
+}
+
+impl Budget {
+    pub fn samples(n: u32) -> Budget {
+        Budget { samples: Some(n), time: None }
+    }
+
+    pub fn time(limit: Duration) -> Budget {
+        Budget { samples: None, time: Some(limit) }
+    }
+
+    pub fn and_time(self, limit: Duration) -> Budget {
+        Budget { time: Some(limit), ..self }
+    }
+}
+
+// The sum of every sample taken in each pixel so far
+#[derive(Clone, Debug)]
+pub struct Accumulator {
+    sum: Framebuffer,
+    samples: u32,
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32) -> Accumulator {
+        Accumulator {
+            sum: Framebuffer::new(width, height, 3),
+            samples: 0,
+        }
+    }
+
+    // Samples taken in each pixel
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    fn add(&mut self, pass: &RgbImage) {
+        for (x, y, p) in pass.enumerate_pixels() {
+            let (c, s) = (to_floats(*p), self.sum.get_mut(x, y));
+            for i in 0..3 {
+                s[i] += c[i];
+            }
+        }
+        self.samples += 1;
+    }
+
+    // The mean of the samples in each pixel, scaled to [0, 1]
+    pub fn mean(&self) -> Framebuffer {
+        let mut mean = self.sum.clone();
+        let scale = 1.0 / (255.0 * self.samples.max(1) as Float);
+        for y in 0..mean.height() {
+            for x in 0..mean.width() {
+                for c in mean.get_mut(x, y).iter_mut() {
+                    *c *= scale;
+                }
+            }
+        }
+        mean
+    }
+
+    pub fn image(&self) -> RgbImage {
+        self.mean().to_rgb_image()
+    }
+}
+
+// Where within pixel the nth sample is taken, relative to the point
+// a single sample would use. The offsets follow the R2 sequence, which
+// covers the pixel evenly however many are taken, beginning with no
+// offset at all.
+pub fn sample_offset(n: u32) -> (Float, Float) {
+    let (a1, a2) = (0.754877666246693, 0.569840290998053);
+    let wrap = |x: Float| x - x.floor() - 0.5;
+    (wrap(0.5 + a1 * n as Float), wrap(0.5 + a2 * n as Float))
+}
+
+// How far through the shutter interval the nth sample is taken. The
+// golden ratio sequence spreads samples evenly over it, beginning in
+// the middle, where a single sample is taken.
+pub fn sample_time(n: u32) -> Float {
+    let x = 0.5 + 0.618033988749895 * n as Float;
+    x - x.floor()
+}
+
+impl<S: Shader> Raytracer<S> {
+    // Render one sample per pixel per pass, handing the accumulated
+    // image to the callback after each pass. A pass is only begun if
+    // it looks set to finish within the time budget, judging by the
+    // last, and a cancelled pass is dropped part way. What has been
+    // accumulated is returned at the end.
+    pub fn render_progressive<F>(&self, depth: u8, camera: &Camera, scene: &Scene,
+                                 budget: Budget, cancel: &CancelToken, mut callback: F)
+                                 -> Result<Accumulator>
+        where F: FnMut(&Accumulator)
+    {
+        let (width, height) = (camera.width(), camera.height());
+        let mut acc = Accumulator::new(width, height);
+        let start = Instant::now();
+        let mut last_pass = Duration::from_secs(0);
+
+        while budget.samples.map_or(true, |n| acc.samples() < n) {
+            if let Some(limit) = budget.time {
+                if start.elapsed() + last_pass > limit {
+                    break;
+                }
+            }
+
+            let pass_start = Instant::now();
+            let (dx, dy) = sample_offset(acc.samples());
+            let time = sample_time(acc.samples());
+            let mut pass = ImageBuffer::new(width, height);
+            for y in 0..height {
+                if cancel.is_cancelled() {
+                    return Ok(acc);
+                }
+                for x in 0..width {
+                    let ray = camera.get_ray_through_subpixel_at(x, y, dx, dy, time)?;
+                    let colour = self.trace_to_depth(depth, &ray, scene);
+                    pass.put_pixel(x, y, colour.unwrap_or(Rgb([0, 0, 0])));
+                }
+            }
+
+            acc.add(&pass);
+            last_pass = pass_start.elapsed();
+            callback(&acc);
+        }
+
+        Ok(acc)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vector3d::Vec3;
+    use camera::CameraBuilder;
+    use shapes::Sphere;
+    use shade::PhongShader;
+    use scene::{Light, AmbientLight};
+    use ray::Shadable;
+
+    fn scene() -> Scene {
+        Scene {
+            ambient_light: AmbientLight { colour: Rgb([40; 3]) },
+            objects: vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 5.0), 1.5)) as Box<Shadable>],
+            lights: vec![Light::new(Vec3(2.0, -4.0, 0.0), Rgb([255; 3]))],
+            volumes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_first_sample_is_the_plain_render() {
+        let camera = CameraBuilder::new(8, 6, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let scene = scene();
+
+        let acc = tracer.render_progressive(2, &camera, &scene, Budget::samples(1),
+                                            &CancelToken::new(), |_| ())
+            .unwrap();
+        let image = tracer.render(2, &camera, &scene).unwrap();
+
+        assert!(sample_offset(0) == (0.0, 0.0) && sample_time(0) == 0.5);
+        assert!(acc.samples() == 1);
+        for (x, y, p) in image.enumerate_pixels() {
+            assert!(acc.image().get_pixel(x, y) == p);
+        }
+    }
+
+    #[test]
+    fn test_callback_sees_each_pass() {
+        let camera = CameraBuilder::new(4, 4, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let mut seen = Vec::new();
+
+        let acc = tracer.render_progressive(2, &camera, &scene(), Budget::samples(4),
+                                            &CancelToken::new(), |a| seen.push(a.samples()))
+            .unwrap();
+
+        assert!(acc.samples() == 4);
+        assert!(seen == vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_cancelling_stops_the_render() {
+        let camera = CameraBuilder::new(4, 4, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let cancel = CancelToken::new();
+        let watcher = cancel.clone();
+
+        // Without any budget, only cancelling ends the render
+        let acc = tracer.render_progressive(2, &camera, &scene(), Budget::default(), &cancel,
+                                            |a| if a.samples() == 3 { watcher.cancel() })
+            .unwrap();
+
+        assert!(acc.samples() == 3);
+    }
+
+    #[test]
+    fn test_time_budget_ends_the_render() {
+        let camera = CameraBuilder::new(4, 4, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+
+        let limit = Duration::from_millis(20);
+
+        // Neither budget sets a sample limit the render could reach
+        for &budget in [Budget::time(limit), Budget::samples(u32::MAX).and_time(limit)].iter() {
+            let start = Instant::now();
+            let acc = tracer.render_progressive(2, &camera, &scene(), budget,
+                                                &CancelToken::new(), |_| ())
+                .unwrap();
+            let took = start.elapsed();
+
+            // Passes over so few pixels are quick, so the render runs
+            // on until close to the limit and stops soon after
+            assert!(acc.samples() > 1);
+            assert!(took >= limit / 2 && took < limit + Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn test_sample_offsets_stay_within_the_pixel() {
+        for n in 0..64 {
+            let (dx, dy) = sample_offset(n);
+            assert!(dx >= -0.5 && dx < 0.5 && dy >= -0.5 && dy < 0.5);
+            assert!(sample_time(n) >= 0.0 && sample_time(n) < 1.0);
+        }
+    }
+}
@@ -3,19 +3,53 @@
 use vector3d::Vec3;
 use algebra::{InnerProductSpace, Real};
 use materials::{Material, HasMaterial};
+use packet::{RayPacket, PacketHits};
+use precision::Float;
+
+// Strikes closer than this to a ray's origin are ignored, so that
+// rays leaving a surface do not immediately hit it again
+pub const STRIKE_TOLERANCE: Float = 0.00001;
 
 #[derive(Clone,Copy,PartialEq)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
+    // When the ray is traced, in frames of the scene's animation.
+    // Moving shapes are struck where they are at this time.
+    pub time: Float,
 }
 
 // A ShadeCell contains all the point relevant information
 // we need to colour a point
-pub struct ShadeCell<'a>(pub Vec3, pub Vec3, pub Vec3, pub &'a Material);
+#[derive(Clone, Copy)]
+pub struct ShadeCell<'a>(pub Vec3, pub Vec3, pub Vec3, pub &'a Material, pub Strike);
+
+// Where and how a ray struck, beyond what is needed to colour the
+// point; mostly of use for inspecting renders
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Strike {
+    // The index of the object struck in the list traced against
+    pub object: usize,
+    // Distance from the ray's origin
+    pub distance: Float,
+    // Surface coordinates of the point on the object struck
+    pub uv: (Float, Float),
+    // Reflections traced before this strike; zero for primary rays
+    pub bounces: u8,
+    // The time of the ray, which the rays traced on from the strike
+    // keep
+    pub time: Float,
+}
 
 pub trait Intersectable {
     fn intersect(&self, ray: &Ray) -> Option<Vec3>;
+
+    // Intersect a packet of rays at once. The default traces each
+    // lane separately; shapes with a SIMD test should override this.
+    fn intersect_packet(&self, packet: &RayPacket) -> PacketHits {
+        let hit = |i: usize| self.intersect(&packet.ray(i));
+        [hit(0), hit(1), hit(2), hit(3)]
+    }
 }
 
 
@@ -23,16 +57,56 @@ pub trait Orientable {
     fn normal(&self, v: Vec3) -> Vec3;
 }
 
-pub trait Shadable: Intersectable + Orientable + HasMaterial {}
+// Surfaces with a parametrisation, giving coordinates (u, v) for
+// each point on them
+pub trait Mappable {
+    fn uv(&self, v: Vec3) -> (Float, Float);
+}
+
+// Surfaces of finite area which can be sampled uniformly, so that
+// they can stand in as lights when their material is emissive
+pub trait Sampleable {
+    fn area(&self) -> Float;
+
+    // Map a point (u, v) of the unit square onto the surface, giving
+    // the point and the outward normal there. Uniformly distributed
+    // (u, v) give points uniformly distributed by area.
+    fn sample_surface(&self, u: Float, v: Float) -> (Vec3, Vec3);
+}
+
+// Scenes are shared between the threads of a render, so their
+// objects must be safe to share
+pub trait Shadable: Intersectable + Orientable + Mappable + HasMaterial + Send + Sync {
+    // Shapes which can be sampled expose themselves here, so that
+    // emissive ones can be used as lights
+    fn surface(&self) -> Option<&Sampleable> {
+        None
+    }
+
+    // The normal and surface coordinates at a point struck at the
+    // given time. Only shapes which move need to override these.
+    fn normal_at(&self, v: Vec3, _time: Float) -> Vec3 {
+        self.normal(v)
+    }
+
+    fn uv_at(&self, v: Vec3, _time: Float) -> (Float, Float) {
+        self.uv(v)
+    }
+}
 
 impl Ray {
     pub fn new(o: Vec3, d: Vec3) -> Ray {
         Ray {
             origin: o,
             direction: d,
+            time: 0.0,
         }
     }
 
+    pub fn with_time(self, time: Float) -> Ray {
+        Ray { time: time, ..self }
+    }
+
     // intersection does dynamic dispatch over Intersectable objects
     pub fn intersection(&self, object: &Intersectable) -> Option<Vec3> {
         object.intersect(&self)
@@ -46,25 +120,38 @@ impl Ray {
         objects.into_iter()
             .map(|x| x.intersect(&self))
             .map(|x| Ray::adjust_for_tolerance(self.origin, x))
-            .zip(objects.into_iter())
+            .zip(objects.into_iter().enumerate())
             .filter(|x| x.0.is_some())
             .min_by_key(|x| Ray::measure_strike_distance(self.origin, x.0))
             .and_then(|strikes| {
                 match strikes.0 {
                     None => None,
                     Some(p) => {
-                        let n = strikes.1.normal(p);
-                        let view = self.direction.normalize();
-                        let mat = strikes.1.material();
-                        Some(ShadeCell(p, n, view, mat))
+                        let (index, object) = strikes.1;
+                        Some(self.shade_cell(p, index, object))
                     }
                 }
             })
     }
 
+    // Gather what the shaders need to know about a strike at p on the
+    // given object
+    pub fn shade_cell<'a>(&self, p: Vec3, index: usize, object: &'a Box<Shadable>)
+                          -> ShadeCell<'a> {
+        let strike = Strike {
+            object: index,
+            distance: (p - self.origin).norm(),
+            uv: object.uv_at(p, self.time),
+            bounces: 0,
+            time: self.time,
+        };
+        ShadeCell(p, object.normal_at(p, self.time), self.direction.normalize(), object.material(),
+                  strike)
+    }
+
     // Trace tolerance helper
     fn adjust_for_tolerance(p: Vec3, x: Option<Vec3>) -> Option<Vec3> {
-        let tolerance: Real = Real::from_float(0.00001).unwrap();
+        let tolerance: Real = Real::from_float(STRIKE_TOLERANCE).unwrap_or(Real::zero());
         if Ray::measure_strike_distance(p, x) < tolerance {
             None
         } else {
@@ -76,7 +163,7 @@ impl Ray {
     // Helper function to find the closest intersection
     fn measure_strike_distance(p: Vec3, x: Option<Vec3>) -> Real {
         match x {
-            Some(v) => Real::from_float((p - v).norm()).unwrap(),
+            Some(v) => Real::from_float((p - v).norm()).unwrap_or(Real::zero()),
             None => Real::zero(),
         }
     }
@@ -95,6 +182,7 @@ mod tests {
         let ray = Ray {
             origin: Vec3(2.0, 0.0, 0.0),
             direction: Vec3(-1.0, 0.0, 0.0),
+            time: 0.0,
         };
 
         assert!(ray.hits(&sphere));
@@ -107,23 +195,39 @@ mod tests {
         let ray = Ray {
             origin: Vec3(5.0, 0.0, 0.0),
             direction: Vec3(-1.0, 0.0, 0.0),
+            time: 0.0,
         };
 
         let objects = vec![sphere_1, sphere_2];
         let strike = ray.trace(&objects);
 
         // The following will assert if there is no collision
-        let ShadeCell(x, _, _, _) = strike.unwrap();
+        let ShadeCell(x, _, _, _, _) = strike.unwrap();
         // The following tests the collision is correct
         assert!(Vec3(4.0, 0.0, 0.0) == x);
     }
 
+    #[test]
+    fn test_ray_trace_records_the_strike() {
+        let sphere_1 = Box::new(Sphere::simple(Vec3(0.0, 0.0, 0.0), 1.0)) as Box<Shadable>;
+        let sphere_2 = Box::new(Sphere::simple(Vec3(3.0, 0.0, 0.0), 1.0)) as Box<Shadable>;
+        let ray = Ray::new(Vec3(5.0, 0.0, 0.0), Vec3(-2.0, 0.0, 0.0));
+
+        let objects = vec![sphere_1, sphere_2];
+        let ShadeCell(_, _, _, _, strike) = ray.trace(&objects).unwrap();
+
+        assert!(strike.object == 1);
+        assert!(strike.distance == 1.0);
+        assert!(strike.bounces == 0);
+    }
+
     #[test]
     fn test_ray_trace_copes_with_no_strike() {
         let sphere = Box::new(Sphere::simple(Vec3(0.0, 0.0, 0.0), 1.0)) as Box<Shadable>;
         let ray = Ray {
             origin: Vec3(5.0, 0.0, 0.0),
             direction: Vec3(0.0, 1.0, 0.0),
+            time: 0.0,
         };
 
         let objects = vec![sphere];
@@ -139,6 +243,7 @@ mod tests {
         let ray = Ray {
             origin: Vec3(0.0, 0.0, 0.0),
             direction: Vec3(0.0, 0.0, 1.0),
+            time: 0.0,
         };
 
         let objects = vec![sphere, obst];
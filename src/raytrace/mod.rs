@@ -1,9 +1,16 @@
 // raytrace.rs - generic raytracing functionality
 
-use shade::{Shader, red, green, blue};
+use shade::{Shader, EMITTER_GRID, red, green, blue, to_floats};
 use ray::{Ray, ShadeCell};
-use scene::Scene;
-use image::Rgb;
+use scene::{Scene, Light};
+use medium;
+use precision::Float;
+use camera::Camera;
+use packet::{RayPacket, Mask, WIDTH};
+use aov::{Pass, Sample, RenderPasses, LightGroups};
+use stats;
+use error::Result;
+use image::{Rgb, RgbImage, ImageBuffer};
 
 pub struct Raytracer<S> {
     shader: S,
@@ -15,38 +22,451 @@ impl<S: Shader> Raytracer<S> {
     }
 
     pub fn trace_to_depth(&self, depth: u8, ray: &Ray, scene: &Scene) -> Option<Rgb<u8>> {
-        let next_step = |sc : &ShadeCell| {
-            self.generate_next_rays_and_effect(sc).into_iter()
-                .map(|(i,r)| (i, self.trace_to_depth(depth-1, &r, scene)))
-                .filter(|&(i,op_col)| op_col.is_some())
-                .map(|(i, is_col)| (i, is_col.unwrap()))
-                .map(|(i, col)| Raytracer::<S>::adjust_intensity_piecewise(col, i))
-                .collect()
-        };
+        self.trace_after_bounces(depth, 0, ray, scene)
+    }
 
+    // As trace_to_depth, for a ray already reflected the given number
+    // of times on its way from the camera
+    fn trace_after_bounces(&self, depth: u8, bounces: u8, ray: &Ray, scene: &Scene)
+                           -> Option<Rgb<u8>> {
         if depth < 1 {
             None
         } else {
-            ray.trace(&scene.objects)
-                .map(|sc| self.shader.shade(&sc, scene, next_step(&sc)))
+            count_ray(bounces);
+            let struck = ray.trace(&scene.objects)
+                .map(|mut sc| {
+                    stats::record(|c| c.strikes += 1);
+                    sc.4.bounces = bounces;
+                    let influence = self.next_step(depth, &sc, scene);
+                    (sc.4.distance, self.shader.shade(&sc, scene, influence))
+                });
+            self.through_media(scene, ray, struck)
+        }
+    }
+
+    // Trace a packet of coherent rays together. Only the rays struck
+    // first are traced as a packet; secondary rays scatter, so they
+    // are traced one at a time. Lanes outside the live mask are padding:
+    // they are neither counted nor shaded, and see nothing.
+    pub fn trace_packet_to_depth(&self, depth: u8, packet: &RayPacket, live: Mask,
+                                 scene: &Scene)
+                                 -> [Option<Rgb<u8>>; WIDTH] {
+        let mut colours = [None; WIDTH];
+        if depth < 1 {
+            return colours;
+        }
+
+        stats::record(|c| c.primary_rays += live.bits().count_ones() as u64);
+        let mut cells = packet.trace(&scene.objects);
+        for (i, cell) in cells.iter_mut().enumerate() {
+            if !live.lane(i) {
+                *cell = None;
+            }
+        }
+        let struck: Vec<ShadeCell> = cells.iter().filter_map(|&sc| sc).collect();
+        stats::record(|c| c.strikes += struck.len() as u64);
+        let influences = struck.iter()
+            .map(|sc| self.next_step(depth, sc, scene))
+            .collect();
+
+        let mut shaded = self.shader.shade_packet(&struck, scene, influences).into_iter();
+        for i in (0..WIDTH).filter(|&i| live.lane(i)) {
+            let seen = cells[i].and_then(|sc| shaded.next().map(|col| (sc.4.distance, col)));
+            colours[i] = self.through_media(scene, &packet.ray(i), seen);
+        }
+        colours
+    }
+
+    // Render a full image as seen by the camera, tracing each primary
+    // ray to the given depth. Pixels whose rays escape are left black.
+    // Primary rays are traced in packets covering 2x2 blocks of pixels.
+    pub fn render(&self, depth: u8, camera: &Camera, scene: &Scene) -> Result<RgbImage> {
+        let (width, height) = (camera.width(), camera.height());
+        let mut img = ImageBuffer::new(width, height);
+        let on_canvas = |(x, y): (u32, u32)| x < width && y < height;
+
+        for y in (0..height).step_by(2) {
+            for x in (0..width).step_by(2) {
+                let block = [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)];
+
+                // Blocks overhanging the canvas are padded out with
+                // copies of their first pixel, masked off as they are traced
+                let pixels = block.map(|p| if on_canvas(p) { p } else { (x, y) });
+                let live = Mask::new(block.map(on_canvas));
+                let packet = camera.get_packet_through_pixels(pixels)?;
+                let colours = self.trace_packet_to_depth(depth, &packet, live, scene);
+
+                for (&(px, py), col) in block.iter().zip(colours.iter()) {
+                    if on_canvas((px, py)) {
+                        img.put_pixel(px, py, col.unwrap_or(Rgb([0 as u8, 0 as u8, 0 as u8])));
+                    }
+                }
+            }
+        }
+
+        Ok(img)
+    }
+
+    // Render the beauty image together with the given passes. Each
+    // pixel is traced on its own, as the passes need more from each
+    // strike than packet tracing keeps.
+    pub fn render_passes(&self, depth: u8, camera: &Camera, scene: &Scene, passes: &[Pass])
+                         -> Result<RenderPasses> {
+        let (width, height) = (camera.width(), camera.height());
+        let mut out = RenderPasses::new(width, height, passes);
+        if depth < 1 {
+            return Ok(out);
+        }
+
+        for y in 0..height {
+            for x in 0..width {
+                let ray = camera.get_ray_through_pixel(x, y)?;
+                count_ray(0);
+                if let Some(cell) = ray.trace(&scene.objects) {
+                    stats::record(|c| c.strikes += 1);
+                    let influence = self.next_step(depth, &cell, scene);
+                    let mut reflection = [0.0; 3];
+                    for c in influence.iter().map(|&c| to_floats(c)) {
+                        for i in 0..3 {
+                            reflection[i] += c[i];
+                        }
+                    }
+
+                    let sample = Sample {
+                        cell: cell,
+                        lighting: self.shader.lighting(&cell, scene),
+                        reflection: reflection,
+                    };
+                    let colour = self.shader.shade(&cell, scene, influence);
+                    let seen = self.through_media(scene, &ray, Some((cell.4.distance, colour)));
+                    out.beauty.put_pixel(x, y, seen.unwrap_or(colour));
+                    out.record(x, y, &sample);
+                } else if let Some(colour) = self.through_media(scene, &ray, None) {
+                    out.beauty.put_pixel(x, y, colour);
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    // Render the light from each light group separately. The light
+    // reflected off other surfaces is split up by group too, which
+    // assumes the shader simply adds the light it is given.
+    pub fn render_light_groups(&self, depth: u8, camera: &Camera, scene: &Scene)
+                               -> Result<LightGroups> {
+        let names = scene.light_groups();
+        let mut out = LightGroups::new(camera.width(), camera.height(), names.clone());
+
+        for y in 0..camera.height() {
+            for x in 0..camera.width() {
+                let ray = camera.get_ray_through_pixel(x, y)?;
+                if let Some(split) = self.trace_light_groups(depth, 0, &ray, scene, &names) {
+                    out.put(x, y, &split);
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    // The light returned along a ray from each named group, and then
+    // from everything else
+    fn trace_light_groups(&self, depth: u8, bounces: u8, ray: &Ray, scene: &Scene,
+                          names: &[String]) -> Option<Vec<[Float; 3]>> {
+        if depth < 1 {
+            return None;
+        }
+
+        count_ray(bounces);
+        let struck = ray.trace(&scene.objects).map(|mut sc| {
+            stats::record(|c| c.strikes += 1);
+            sc.4.bounces = bounces;
+            let mut visible = scene.visible_lights(sc.0, sc.4.time, EMITTER_GRID);
+            if self.shader.sees_media() {
+                visible = medium::dim_lights(&scene.volumes, sc.0, sc.4.time, visible);
+            }
+
+            let mut split: Vec<[Float; 3]> = names.iter()
+                .map(|name| {
+                    let lights: Vec<Light> = visible.iter()
+                        .filter(|l| l.is_in(name))
+                        .cloned()
+                        .collect();
+                    self.shader.lighting_from(&sc, &lights).total()
+                })
+                .collect();
+
+            // Whatever is not from a group is left over for the rest
+            let mut rest = self.shader.lighting(&sc, scene).total();
+            for c in split.iter() {
+                for i in 0..3 {
+                    rest[i] -= c[i];
+                }
+            }
+            split.push(rest);
+
+            for (reflectivity, r) in self.generate_next_rays_and_effect(&sc) {
+                let reflected = self.trace_light_groups(depth - 1, bounces + 1, &r, scene, names);
+                for (s, c) in split.iter_mut().zip(reflected.unwrap_or(Vec::new())) {
+                    for i in 0..3 {
+                        s[i] += reflectivity[i] * c[i];
+                    }
+                }
+            }
+            (sc.4.distance, split)
+        });
+        if self.shader.sees_media() {
+            medium::split_through_media(scene, ray, struck, names)
+        } else {
+            struck.map(|(_, split)| split)
         }
     }
 
-    fn generate_next_rays_and_effect(&self, shader_cell: &ShadeCell) -> Vec<([f64; 3], Ray)> {
-        let &ShadeCell(p, n, v, m) = shader_cell;
+    // What is seen along a ray through the scene's media, unless the
+    // shader looks straight through them
+    fn through_media(&self, scene: &Scene, ray: &Ray, struck: Option<(Float, Rgb<u8>)>)
+                     -> Option<Rgb<u8>> {
+        if self.shader.sees_media() {
+            medium::through_media(scene, ray, struck)
+        } else {
+            struck.map(|(_, colour)| colour)
+        }
+    }
+
+    fn next_step(&self, depth: u8, sc: &ShadeCell, scene: &Scene) -> Vec<Rgb<u8>> {
+        self.generate_next_rays_and_effect(sc).into_iter()
+            .map(|(i,r)| (i, self.trace_after_bounces(depth-1, sc.4.bounces + 1, &r, scene)))
+            .filter(|&(_,op_col)| op_col.is_some())
+            .map(|(i, is_col)| (i, is_col.unwrap()))
+            .map(|(i, col)| Raytracer::<S>::adjust_intensity_piecewise(col, i))
+            .collect()
+    }
+
+    fn generate_next_rays_and_effect(&self, shader_cell: &ShadeCell) -> Vec<([Float; 3], Ray)> {
+        let &ShadeCell(p, n, v, m, strike) = shader_cell;
         let reflection = Ray { origin : p,
-                               direction : n.reflect(v) };
+                               direction : n.reflect(v),
+                               time : strike.time };
         vec![(m.reflectivity(), reflection)]
     }
 
     // This is the same function as in the shader, and probably should
     // be factored out at some point into a more useful module
-    fn adjust_intensity_piecewise(c : Rgb<u8>, adjust_components : [f64 ; 3]) -> Rgb<u8> {
-        let approx = |col: f64| col.floor() as u8;
-        let adjust = |f: &Fn(Rgb<u8>) -> u8, a : f64| approx(a * (f(c) as f64));
+    fn adjust_intensity_piecewise(c : Rgb<u8>, adjust_components : [Float ; 3]) -> Rgb<u8> {
+        let approx = |col: Float| col.floor() as u8;
+        let adjust = |f: &Fn(Rgb<u8>) -> u8, a : Float| approx(a * (f(c) as Float));
 
         Rgb([adjust(&red, adjust_components[0]),
              adjust(&green, adjust_components[1]),
              adjust(&blue, adjust_components[2])])
     }
 }
+
+// Count a ray traced from the camera after the given number of
+// reflections
+fn count_ray(bounces: u8) {
+    if bounces == 0 {
+        stats::record(|c| c.primary_rays += 1)
+    } else {
+        stats::record(|c| c.secondary_rays += 1)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vector3d::Vec3;
+    use shapes::Sphere;
+    use materials::Material;
+    use shade::PhongShader;
+    use scene::AmbientLight;
+    use ray::Shadable;
+    use camera::CameraBuilder;
+    use aov::ALL_PASSES;
+    use medium::{Medium, Volume};
+    use precision::float;
+
+    #[test]
+    fn test_packet_tracing_agrees_with_single_rays() {
+        let light = Light::new(Vec3(0.0, -4.0, 0.0), Rgb([255 as u8; 3]));
+        let scene = Scene {
+            ambient_light: AmbientLight { colour: Rgb([40; 3]) },
+            objects: vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 5.0), 1.0)) as Box<Shadable>,
+                          Box::new(Sphere::simple(Vec3(1.5, 0.0, 6.0), 1.0)) as Box<Shadable>],
+            lights: vec![light],
+            volumes: Vec::new(),
+        };
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+        let o = Vec3::zero();
+        let rays = [Ray::new(o, Vec3(0.0, 0.0, 1.0)),
+                    Ray::new(o, Vec3(0.2, -0.1, 1.0)),
+                    Ray::new(o, Vec3(0.25, 0.0, 1.0)),
+                    Ray::new(o, Vec3(0.0, 1.0, 0.0))];
+
+        let colours = tracer.trace_packet_to_depth(2, &RayPacket::new(rays), Mask::splat(true),
+                                                   &scene);
+        for i in 0..WIDTH {
+            assert!(colours[i] == tracer.trace_to_depth(2, &rays[i], &scene));
+        }
+
+        let live = Mask::new([true, false, true, false]);
+        let colours = tracer.trace_packet_to_depth(2, &RayPacket::new(rays), live, &scene);
+        assert!(colours[0] == tracer.trace_to_depth(2, &rays[0], &scene));
+        assert!(colours[1].is_none() && colours[3].is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_renders_count_only_the_pixels_on_the_canvas() {
+        let scene = Scene::new(AmbientLight { colour: Rgb([40; 3]) },
+                               vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 5.0), 1.5))
+                                    as Box<Shadable>],
+                               vec![Light::new(Vec3(2.0, -4.0, 0.0), Rgb([255; 3]))]);
+        let camera = CameraBuilder::new(7, 5, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+
+        let mut hits = 0;
+        for y in 0..5 {
+            for x in 0..7 {
+                let ray = camera.get_ray_through_pixel(x, y).unwrap();
+                hits += ray.trace(&scene.objects).map_or(0, |_| 1);
+            }
+        }
+
+        stats::take();
+        tracer.render(1, &camera, &scene).unwrap();
+        let counted = stats::take();
+        assert!(counted.primary_rays == 7 * 5);
+        assert!(counted.strikes == hits);
+    }
+
+    #[test]
+    fn test_render_passes_agree_with_the_render() {
+        let light = Light::new(Vec3(2.0, -4.0, 0.0), Rgb([255 as u8; 3]));
+        let scene = Scene {
+            ambient_light: AmbientLight { colour: Rgb([40; 3]) },
+            // The same settings, given separately
+            objects: vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 5.0), 1.5)) as Box<Shadable>,
+                          Box::new(Sphere::new(Vec3(2.5, 0.0, 6.0), 1.0,
+                                               Material::plain().with_id(4)))
+                              as Box<Shadable>],
+            lights: vec![light],
+            volumes: Vec::new(),
+        };
+        let camera = CameraBuilder::new(8, 6, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+
+        let image = tracer.render(2, &camera, &scene).unwrap();
+        let passes = tracer.render_passes(2, &camera, &scene, &ALL_PASSES).unwrap();
+        let depth = passes.get(Pass::Depth).unwrap();
+        let ids = passes.get(Pass::ObjectId).unwrap();
+        let materials = passes.get(Pass::MaterialId).unwrap();
+        let normals = passes.get(Pass::Normal).unwrap();
+
+        for y in 0..6 {
+            for x in 0..8 {
+                assert!(passes.beauty.get_pixel(x, y) == image.get_pixel(x, y));
+                match camera.get_ray_through_pixel(x, y).unwrap().trace(&scene.objects) {
+                    Some(ShadeCell(_, n, _, _, strike)) => {
+                        assert!(depth.get(x, y)[0] == strike.distance);
+                        assert!(ids.get(x, y)[0] == (strike.object + 1) as Float);
+                        assert!(materials.get(x, y)[0] == [1.0, 5.0][strike.object]);
+                        assert!(normals.get(x, y) == &[n.i(), n.j(), n.k()]);
+                    }
+                    None => {
+                        assert!(depth.get(x, y)[0] == float::INFINITY);
+                        assert!(ids.get(x, y)[0] == 0.0);
+                        assert!(materials.get(x, y)[0] == 0.0);
+                    }
+                }
+            }
+        }
+        assert!(materials.get(4, 3)[0] == 1.0);
+        assert!((0..8).any(|x| materials.get(x, 3)[0] == 5.0));
+    }
+
+    #[test]
+    fn test_light_groups_sum_to_the_beauty_image() {
+        let light = |x: Float, colour: Rgb<u8>| {
+            Light::new(Vec3(x, -4.0, 0.0), colour)
+        };
+        let mirror = Material::new([0.2; 3], [0.3; 3], [0.1; 3], [0.5; 3], 5.0);
+        let scene = |lights: Vec<Light>| {
+            Scene {
+                ambient_light: AmbientLight { colour: Rgb([30; 3]) },
+                objects: vec![Box::new(Sphere::new(Vec3(0.0, 0.0, 5.0), 1.5, mirror))
+                                  as Box<Shadable>,
+                              Box::new(Sphere::simple(Vec3(2.5, 0.0, 6.0), 1.0))
+                                  as Box<Shadable>],
+                lights: lights,
+                volumes: Vec::new(),
+            }
+        };
+        let key = light(3.0, Rgb([200, 180, 150])).in_group("key");
+        let rim = light(-3.0, Rgb([60, 60, 120])).in_group("rim");
+        let fill = light(0.0, Rgb([40; 3]));
+        let lit = scene(vec![key.clone(), rim.clone(), fill.clone()]);
+        let camera = CameraBuilder::new(8, 6, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+
+        let groups = tracer.render_light_groups(3, &camera, &lit).unwrap();
+        assert!(groups.names() == &["key".to_string(), "rim".to_string()]);
+
+        // Shading rounds each term down, so allow for a little drift
+        let beauty = groups.beauty();
+        let image = tracer.render(3, &camera, &lit).unwrap();
+        for (x, y, p) in image.enumerate_pixels() {
+            for i in 0..3 {
+                let (a, b) = (p.data[i] as i32, beauty.get_pixel(x, y).data[i] as i32);
+                assert!((a - b).abs() <= 8);
+            }
+        }
+
+        // Dimming a group matches rendering without it
+        let without_key = tracer.render_light_groups(3, &camera, &scene(vec![rim, fill]))
+            .unwrap()
+            .relight(|_| [1.0; 3]);
+        let dimmed = groups.relight(|name| if name == "key" { [0.0; 3] } else { [1.0; 3] });
+        let tolerance = float::EPSILON.sqrt();
+        for y in 0..6 {
+            for x in 0..8 {
+                for i in 0..3 {
+                    assert!((dimmed.get(x, y)[i] - without_key.get(x, y)[i]).abs() < tolerance);
+                }
+            }
+        }
+        assert!(groups.get("key").unwrap().get(4, 3)[0] > 0.0);
+    }
+
+    #[test]
+    fn test_light_groups_sum_to_the_beauty_image_in_fog() {
+        let haze = Medium {
+            absorption: [0.02; 3],
+            scattering: [0.05, 0.05, 0.08],
+            anisotropy: 0.3,
+        };
+        let mut scene = Scene::new(AmbientLight { colour: Rgb([20; 3]) },
+                                   vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 5.0), 1.5))
+                                            as Box<Shadable>],
+                                   vec![Light::new(Vec3(3.0, -4.0, 2.0), Rgb([200, 180, 150]))
+                                            .in_group("key"),
+                                        Light::new(Vec3(-3.0, -4.0, 2.0), Rgb([60; 3]))]);
+        scene.volumes.push(Volume::everywhere(haze));
+        let camera = CameraBuilder::new(8, 6, 90.0).unwrap().build();
+        let tracer = Raytracer::from_shader(PhongShader::instance());
+
+        let groups = tracer.render_light_groups(2, &camera, &scene).unwrap();
+        let beauty = groups.beauty();
+        let image = tracer.render(2, &camera, &scene).unwrap();
+        for (x, y, p) in image.enumerate_pixels() {
+            for i in 0..3 {
+                let (a, b) = (p.data[i] as i32, beauty.get_pixel(x, y).data[i] as i32);
+                assert!((a - b).abs() <= 8);
+            }
+        }
+        // The fog lit by the key light shows in its group off the sphere
+        assert!(groups.get("key").unwrap().get(0, 0)[0] > 0.0);
+    }
+}
@@ -0,0 +1,252 @@
+// blue_noise.rs - sampling whose error is spread like blue noise over
+// the image, with neighbouring pixels erring in opposite directions,
+// which the eye (and a denoiser) finds far easier to smooth away than
+// white noise
+use std::sync::Arc;
+
+use precision::Float;
+use sampler::{Sampler, Rng, hash, mix, owen_scramble, sobol, to_unit, wrap};
+
+// A square tile of values in which each of (k + 0.5) / n, for n the
+// number of cells, appears once, arranged so that similar values lie
+// far apart. It is built by Ulichney's void-and-cluster method, and
+// repeats seamlessly when tiled.
+#[derive(Clone, Debug)]
+pub struct BlueNoiseMask {
+    size: u32,
+    values: Vec<Float>,
+}
+
+// Spread of the filter which judges how crowded each cell is
+const SIGMA: Float = 1.5;
+
+// How crowded each cell of a binary pattern is: the sum of a Gaussian
+// about each set cell, wrapping around the edges of the tile
+#[derive(Clone)]
+struct Energy {
+    size: usize,
+    kernel: Vec<Float>,
+    energy: Vec<Float>,
+}
+
+impl Energy {
+    fn new(size: usize) -> Energy {
+        let mut kernel = vec![0.0; size * size];
+        for dy in 0..size {
+            for dx in 0..size {
+                let (x, y) = (dx.min(size - dx) as Float, dy.min(size - dy) as Float);
+                kernel[dy * size + dx] = (-(x * x + y * y) / (2.0 * SIGMA * SIGMA)).exp();
+            }
+        }
+        Energy {
+            size: size,
+            kernel: kernel,
+            energy: vec![0.0; size * size],
+        }
+    }
+
+    fn toggle(&mut self, cell: usize, sign: Float) {
+        let size = self.size;
+        let (cx, cy) = (cell % size, cell / size);
+        for y in 0..size {
+            let dy = (y + size - cy) % size;
+            for x in 0..size {
+                let dx = (x + size - cx) % size;
+                self.energy[y * size + x] += sign * self.kernel[dy * size + dx];
+            }
+        }
+    }
+
+    // The most crowded set cell
+    fn tightest_cluster(&self, pattern: &[bool]) -> usize {
+        self.extreme(pattern, true, |a, b| a > b)
+    }
+
+    // The least crowded unset cell
+    fn largest_void(&self, pattern: &[bool]) -> usize {
+        self.extreme(pattern, false, |a, b| a < b)
+    }
+
+    fn extreme<F>(&self, pattern: &[bool], set: bool, better: F) -> usize
+        where F: Fn(Float, Float) -> bool
+    {
+        let mut best = None;
+        for (i, (&p, &e)) in pattern.iter().zip(self.energy.iter()).enumerate() {
+            if p == set && best.map_or(true, |b: usize| better(e, self.energy[b])) {
+                best = Some(i);
+            }
+        }
+        best.expect("pattern has no cell of the kind sought")
+    }
+}
+
+impl BlueNoiseMask {
+    pub fn new(size: u32, seed: u32) -> BlueNoiseMask {
+        let size = size.max(2);
+        let n = (size * size) as usize;
+        let mut energy = Energy::new(size as usize);
+        let mut pattern = vec![false; n];
+
+        // Begin with a tenth of the cells set at random
+        let mut rng = Rng::new(seed as u64, 0);
+        let ones = (n / 10).max(1);
+        let mut placed = 0;
+        while placed < ones {
+            let i = rng.next_u32() as usize % n;
+            if !pattern[i] {
+                pattern[i] = true;
+                energy.toggle(i, 1.0);
+                placed += 1;
+            }
+        }
+
+        // Even them out, moving the most crowded into the emptiest
+        // space until that would put it back where it was
+        for _ in 0..n {
+            let cluster = energy.tightest_cluster(&pattern);
+            pattern[cluster] = false;
+            energy.toggle(cluster, -1.0);
+            let void = energy.largest_void(&pattern);
+            pattern[void] = true;
+            energy.toggle(void, 1.0);
+            if void == cluster {
+                break;
+            }
+        }
+
+        let mut rank = vec![0; n];
+
+        // The initial cells are ranked by removing the most crowded in
+        // turn, so those left are always evenly spread...
+        let (mut removing, mut removed) = (pattern.clone(), energy.clone());
+        for r in (0..ones).rev() {
+            let cluster = removed.tightest_cluster(&removing);
+            removing[cluster] = false;
+            removed.toggle(cluster, -1.0);
+            rank[cluster] = r;
+        }
+
+        // ...and the rest by filling the emptiest space in turn. Past
+        // half full this is the same as taking the most crowded of the
+        // unset cells, since the energies of set and unset cells sum
+        // to a constant.
+        for r in ones..n {
+            let void = energy.largest_void(&pattern);
+            pattern[void] = true;
+            energy.toggle(void, 1.0);
+            rank[void] = r;
+        }
+
+        BlueNoiseMask {
+            size: size,
+            values: rank.into_iter().map(|r| (r as Float + 0.5) / n as Float).collect(),
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    // The value at a cell, with the tile repeating in both directions
+    pub fn get(&self, x: u32, y: u32) -> Float {
+        let (x, y) = (x % self.size, y % self.size);
+        self.values[(y * self.size + x) as usize]
+    }
+}
+
+// Every pixel takes the same scrambled Sobol points, but shifted by
+// values from a blue noise mask (a Cranley-Patterson rotation), so
+// that the error in neighbouring pixels differs as much as it can.
+// Each dimension reads the mask at its own offset into the tile.
+#[derive(Clone, Debug)]
+pub struct BlueNoiseSampler {
+    seed: u32,
+    mask: Arc<BlueNoiseMask>,
+    pixel: (u32, u32),
+    index: u32,
+    dimension: u32,
+}
+
+pub const MASK_SIZE: u32 = 64;
+
+impl BlueNoiseSampler {
+    pub fn new(seed: u32) -> BlueNoiseSampler {
+        BlueNoiseSampler::with_mask(seed, BlueNoiseMask::new(MASK_SIZE, seed))
+    }
+
+    pub fn with_mask(seed: u32, mask: BlueNoiseMask) -> BlueNoiseSampler {
+        BlueNoiseSampler {
+            seed: seed,
+            mask: Arc::new(mask),
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn next_point(&mut self) -> (Float, Float, Float, Float) {
+        let d = self.dimension;
+        self.dimension += 1;
+        let seed = hash(&[self.seed, d]);
+        let (a, b) = sobol(owen_scramble(self.index, seed));
+        let shift = |k: u32| {
+            let offset = hash(&[seed, k]);
+            self.mask.get(self.pixel.0.wrapping_add(offset),
+                          self.pixel.1.wrapping_add(offset >> 16))
+        };
+        (to_unit(owen_scramble(a, mix(seed))),
+         to_unit(owen_scramble(b, mix(seed ^ 1))),
+         shift(0),
+         shift(1))
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = (x, y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> Float {
+        let (u, _, du, _) = self.next_point();
+        wrap(u + du)
+    }
+
+    fn next_2d(&mut self) -> (Float, Float) {
+        let (u, v, du, dv) = self.next_point();
+        (wrap(u + du), wrap(v + dv))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_holds_each_value_once() {
+        let mask = BlueNoiseMask::new(16, 3);
+        let mut ranks: Vec<u32> = (0..16 * 16)
+            .map(|i| (mask.get(i % 16, i / 16) * 256.0) as u32)
+            .collect();
+        ranks.sort();
+        assert!(ranks == (0..256).collect::<Vec<u32>>());
+        assert!(mask.get(17, 35) == mask.get(1, 3));
+    }
+
+    #[test]
+    fn test_mask_neighbours_differ() {
+        // In blue noise, neighbouring values differ by more than the
+        // third of the range they would in white noise
+        let mask = BlueNoiseMask::new(16, 3);
+        let mut total = 0.0;
+        for y in 0..16 {
+            for x in 0..16 {
+                total += (mask.get(x, y) - mask.get(x + 1, y)).abs();
+                total += (mask.get(x, y) - mask.get(x, y + 1)).abs();
+            }
+        }
+        assert!(total / 512.0 > 0.4);
+    }
+}
@@ -0,0 +1,551 @@
+// sampler - sources of points in the unit square for Monte Carlo
+// estimates. Every sampler is deterministic: what it draws depends
+// only on its seed, the pixel, the sample index and how many values
+// have been drawn since the sample began, never on the order in which
+// pixels are visited.
+use precision::{Float, float};
+
+pub mod blue_noise;
+
+pub use self::blue_noise::{BlueNoiseMask, BlueNoiseSampler};
+
+pub trait Sampler {
+    // Begin the given sample of a pixel, back at its first dimension
+    fn start(&mut self, x: u32, y: u32, index: u32);
+    // Values in [0, 1), each call taking the next dimension
+    fn next_1d(&mut self) -> Float;
+    fn next_2d(&mut self) -> (Float, Float);
+}
+
+const ONE_MINUS_EPSILON: Float = 1.0 - float::EPSILON;
+
+// The top bits of an integer as a fraction in [0, 1). Only 24 are
+// kept, so the result stays below one in single precision.
+pub fn to_unit(bits: u32) -> Float {
+    (bits >> 8) as Float / 16777216.0
+}
+
+// The fractional part, kept below one
+fn wrap(x: Float) -> Float {
+    (x - x.floor()).min(ONE_MINUS_EPSILON)
+}
+
+fn mix(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^ (x >> 16)
+}
+
+// Combine several integers into one well mixed hash
+pub fn hash(values: &[u32]) -> u32 {
+    values.iter().fold(0x9e3779b9, |h, &v| mix(h ^ v.wrapping_add(0x9e3779b9)
+        .wrapping_add(h << 6)
+        .wrapping_add(h >> 2)))
+}
+
+// O'Neill's PCG32 generator: small, fast and statistically sound
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+    increment: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64, stream: u64) -> Rng {
+        let mut rng = Rng { state: 0, increment: (stream << 1) | 1 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(6364136223846793005).wrapping_add(self.increment);
+        let shifted = (((old >> 18) ^ old) >> 27) as u32;
+        shifted.rotate_right((old >> 59) as u32)
+    }
+
+    pub fn next_float(&mut self) -> Float {
+        to_unit(self.next_u32())
+    }
+}
+
+
+// Plain uniform random numbers, with nothing spreading them out
+#[derive(Clone, Debug)]
+pub struct IndependentSampler {
+    seed: u32,
+    rng: Rng,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u32) -> IndependentSampler {
+        IndependentSampler {
+            seed: seed,
+            rng: Rng::new(seed as u64, 0),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.rng = Rng::new(hash(&[self.seed, x, y, index]) as u64, self.seed as u64);
+    }
+
+    fn next_1d(&mut self) -> Float {
+        self.rng.next_float()
+    }
+
+    fn next_2d(&mut self) -> (Float, Float) {
+        let u = self.rng.next_float();
+        (u, self.rng.next_float())
+    }
+}
+
+
+// Divides each dimension into as many strata as there are samples per
+// pixel and puts one sample in each, jittered within it. The strata
+// are shuffled separately for each dimension and pixel, so that the
+// dimensions are not correlated. Sample indices past the last stratum
+// begin another round.
+#[derive(Clone, Debug)]
+pub struct StratifiedSampler {
+    seed: u32,
+    per_axis: u32,
+    pixel: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl StratifiedSampler {
+    // For per_axis * per_axis samples per pixel
+    pub fn new(seed: u32, per_axis: u32) -> StratifiedSampler {
+        StratifiedSampler {
+            seed: seed,
+            per_axis: per_axis.max(1),
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn stratum(&mut self) -> (u32, u32) {
+        let n = self.per_axis * self.per_axis;
+        let round = self.index / n;
+        let s = permute(self.index % n, n, hash(&[self.pixel, self.dimension, round]));
+        let jitter = hash(&[self.pixel, self.dimension, self.index]);
+        self.dimension += 1;
+        (s, jitter)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = hash(&[self.seed, x, y]);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> Float {
+        let n = self.per_axis * self.per_axis;
+        let (s, jitter) = self.stratum();
+        (s as Float + to_unit(jitter)) / n as Float
+    }
+
+    fn next_2d(&mut self) -> (Float, Float) {
+        let k = self.per_axis as Float;
+        let (s, jitter) = self.stratum();
+        let (sx, sy) = (s % self.per_axis, s / self.per_axis);
+        ((sx as Float + to_unit(jitter)) / k,
+         (sy as Float + to_unit(mix(jitter))) / k)
+    }
+}
+
+// Kensler's hashed permutation: the position of i in a shuffle of
+// 0..l chosen by p, found without storing the shuffle
+pub fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+
+const PRIMES: [u32; 32] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61,
+                           67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131];
+
+// The digits of i in the given base, mirrored about the point
+pub fn radical_inverse(base: u32, mut i: u32) -> Float {
+    let inverse = 1.0 / base as Float;
+    let (mut scale, mut result) = (inverse, 0.0);
+    while i > 0 {
+        result += (i % base) as Float * scale;
+        i /= base;
+        scale *= inverse;
+    }
+    result.min(ONE_MINUS_EPSILON)
+}
+
+// The Halton sequence, with dimension d using the dth prime as its
+// base. Each pixel shifts every dimension by its own random amount
+// (a Cranley-Patterson rotation), so that pixels do not share their
+// errors. Past the table of primes, dimensions are plain random.
+#[derive(Clone, Debug)]
+pub struct HaltonSampler {
+    seed: u32,
+    pixel: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u32) -> HaltonSampler {
+        HaltonSampler {
+            seed: seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = hash(&[self.seed, x, y]);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> Float {
+        let d = self.dimension;
+        self.dimension += 1;
+        match PRIMES.get(d as usize) {
+            Some(&base) => {
+                wrap(radical_inverse(base, self.index) + to_unit(hash(&[self.pixel, d])))
+            }
+            None => to_unit(hash(&[self.pixel, d, self.index])),
+        }
+    }
+
+    fn next_2d(&mut self) -> (Float, Float) {
+        let u = self.next_1d();
+        (u, self.next_1d())
+    }
+}
+
+
+// The first two dimensions of the Sobol sequence: the van der Corput
+// sequence, and the one given by Pascal's triangle taken mod two
+fn sobol(i: u32) -> (u32, u32) {
+    let (mut bits, mut v, mut i2) = (0, 1 << 31, i);
+    while i2 != 0 {
+        if i2 & 1 != 0 {
+            bits ^= v;
+        }
+        i2 >>= 1;
+        v ^= v >> 1;
+    }
+    (i.reverse_bits(), bits)
+}
+
+// Laine and Karras' hash, with Burley's constants, in which each bit
+// depends only on those below it
+fn laine_karras(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^ x.wrapping_mul(0x8d22f6e6)
+}
+
+// Owen's nested uniform scramble of a binary fraction: each digit is
+// flipped or not according to the digits before it
+pub fn owen_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras(x.reverse_bits(), seed).reverse_bits()
+}
+
+// Owen scrambled Sobol points, following Burley. Each pair of
+// dimensions is its own two dimensional Sobol sequence, decorrelated
+// from the rest by shuffling the order its points are taken in, and
+// each pixel scrambles every dimension differently. The first n
+// samples of a pixel, for n a power of two, lie one to each cell of
+// every grid of n cells in the square.
+#[derive(Clone, Debug)]
+pub struct SobolSampler {
+    seed: u32,
+    pixel: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u32) -> SobolSampler {
+        SobolSampler {
+            seed: seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn next_point(&mut self) -> (u32, u32, u32) {
+        let d = self.dimension;
+        self.dimension += 1;
+        let shuffled = owen_scramble(self.index, hash(&[self.pixel, d]));
+        let (a, b) = sobol(shuffled);
+        (a, b, hash(&[self.pixel, d, 1]))
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = hash(&[self.seed, x, y]);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> Float {
+        let (a, _, seed) = self.next_point();
+        to_unit(owen_scramble(a, seed))
+    }
+
+    fn next_2d(&mut self) -> (Float, Float) {
+        let (a, b, seed) = self.next_point();
+        (to_unit(owen_scramble(a, seed)), to_unit(owen_scramble(b, mix(seed))))
+    }
+}
+
+
+// The samplers by name, for choosing one in the render options
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+    BlueNoise,
+}
+
+pub const ALL_SAMPLERS: [SamplerKind; 5] = [SamplerKind::Independent, SamplerKind::Stratified,
+                                            SamplerKind::Halton, SamplerKind::Sobol,
+                                            SamplerKind::BlueNoise];
+
+impl SamplerKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SamplerKind::Independent => "independent",
+            SamplerKind::Stratified => "stratified",
+            SamplerKind::Halton => "halton",
+            SamplerKind::Sobol => "sobol",
+            SamplerKind::BlueNoise => "blue-noise",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SamplerKind> {
+        ALL_SAMPLERS.iter().cloned().find(|k| k.name() == name)
+    }
+
+    // A sampler of this kind suited to taking the given number of
+    // samples in each pixel
+    pub fn build(&self, seed: u32, samples: u32) -> AnySampler {
+        match *self {
+            SamplerKind::Independent => AnySampler::Independent(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => {
+                let per_axis = (samples as Float).sqrt().ceil() as u32;
+                AnySampler::Stratified(StratifiedSampler::new(seed, per_axis))
+            }
+            SamplerKind::Halton => AnySampler::Halton(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => AnySampler::Sobol(SobolSampler::new(seed)),
+            SamplerKind::BlueNoise => AnySampler::BlueNoise(BlueNoiseSampler::new(seed)),
+        }
+    }
+}
+
+// Any one of the samplers, as a concrete type which can be cloned for
+// each thread of a render
+#[derive(Clone, Debug)]
+pub enum AnySampler {
+    Independent(IndependentSampler),
+    Stratified(StratifiedSampler),
+    Halton(HaltonSampler),
+    Sobol(SobolSampler),
+    BlueNoise(BlueNoiseSampler),
+}
+
+impl AnySampler {
+    fn inner(&mut self) -> &mut Sampler {
+        match *self {
+            AnySampler::Independent(ref mut s) => s,
+            AnySampler::Stratified(ref mut s) => s,
+            AnySampler::Halton(ref mut s) => s,
+            AnySampler::Sobol(ref mut s) => s,
+            AnySampler::BlueNoise(ref mut s) => s,
+        }
+    }
+}
+
+impl Sampler for AnySampler {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.inner().start(x, y, index)
+    }
+
+    fn next_1d(&mut self) -> Float {
+        self.inner().next_1d()
+    }
+
+    fn next_2d(&mut self) -> (Float, Float) {
+        self.inner().next_2d()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samplers() -> Vec<Box<Sampler>> {
+        vec![Box::new(IndependentSampler::new(7)),
+             Box::new(StratifiedSampler::new(7, 4)),
+             Box::new(HaltonSampler::new(7)),
+             Box::new(SobolSampler::new(7)),
+             Box::new(BlueNoiseSampler::with_mask(7, BlueNoiseMask::new(16, 7)))]
+    }
+
+    fn draw(sampler: &mut Sampler, x: u32, y: u32, index: u32) -> Vec<Float> {
+        sampler.start(x, y, index);
+        let mut values = Vec::new();
+        for _ in 0..40 {
+            let (u, v) = sampler.next_2d();
+            values.push(u);
+            values.push(v);
+            values.push(sampler.next_1d());
+        }
+        values
+    }
+
+    #[test]
+    fn test_samples_are_repeatable_and_in_range() {
+        for sampler in samplers().iter_mut() {
+            let first = draw(&mut **sampler, 3, 5, 2);
+            // Drawing from elsewhere in between changes nothing
+            draw(&mut **sampler, 9, 1, 6);
+            assert!(draw(&mut **sampler, 3, 5, 2) == first);
+            assert!(draw(&mut **sampler, 4, 5, 2) != first);
+            assert!(draw(&mut **sampler, 3, 5, 3) != first);
+            assert!(first.iter().all(|&u| u >= 0.0 && u < 1.0));
+        }
+    }
+
+    // Whether the points lie one to each cell of every grid of
+    // 2^a by 2^b cells with a + b = bits
+    fn is_net(points: &[(Float, Float)], bits: u32) -> bool {
+        (0..bits + 1).all(|a| {
+            let (nx, ny) = (1 << a, 1 << (bits - a));
+            let mut cells = vec![false; points.len()];
+            points.iter().all(|&(u, v)| {
+                let cell = (v * ny as Float) as usize * nx + (u * nx as Float) as usize;
+                !std::mem::replace(&mut cells[cell], true)
+            })
+        })
+    }
+
+    #[test]
+    fn test_sobol_points_form_nets() {
+        let mut sampler = SobolSampler::new(11);
+        for &(x, y) in [(0, 0), (5, 2)].iter() {
+            for dimension in 0..3 {
+                let points: Vec<(Float, Float)> = (0..16)
+                    .map(|i| {
+                        sampler.start(x, y, i);
+                        for _ in 0..dimension {
+                            sampler.next_2d();
+                        }
+                        sampler.next_2d()
+                    })
+                    .collect();
+                assert!(is_net(&points, 4));
+            }
+        }
+    }
+
+    #[test]
+    fn test_stratified_samples_fill_every_stratum() {
+        let mut sampler = StratifiedSampler::new(3, 4);
+        for dimension in 0..3 {
+            let mut cells = [false; 16];
+            for i in 0..16 {
+                sampler.start(1, 2, i);
+                for _ in 0..dimension {
+                    sampler.next_2d();
+                }
+                let (u, v) = sampler.next_2d();
+                cells[(v * 4.0) as usize * 4 + (u * 4.0) as usize] = true;
+            }
+            assert!(cells.iter().all(|&c| c));
+        }
+    }
+
+    #[test]
+    fn test_halton_is_evenly_spread() {
+        assert!(radical_inverse(2, 6) == 0.375);
+        assert!((radical_inverse(3, 5) - 7.0 / 9.0).abs() < 1e-6);
+
+        // A rotated van der Corput sequence still has one point in
+        // each of n equal intervals, for n a power of two
+        let mut sampler = HaltonSampler::new(5);
+        let mut cells = [false; 8];
+        for i in 0..8 {
+            sampler.start(2, 2, i);
+            cells[(sampler.next_1d() * 8.0) as usize] = true;
+        }
+        assert!(cells.iter().all(|&c| c));
+    }
+
+    #[test]
+    fn test_permutations_are_permutations() {
+        for &l in [1, 5, 16, 33].iter() {
+            let mut seen = vec![false; l as usize];
+            for i in 0..l {
+                seen[permute(i, l, 0xdeadbeef) as usize] = true;
+            }
+            assert!(seen.iter().all(|&s| s));
+        }
+    }
+
+    #[test]
+    fn test_sampler_names_round_trip() {
+        for &kind in ALL_SAMPLERS.iter() {
+            assert!(SamplerKind::from_name(kind.name()) == Some(kind));
+        }
+        assert!(SamplerKind::from_name("random") == None);
+    }
+}
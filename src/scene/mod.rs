@@ -1,13 +1,33 @@
 // scene.rs - definitions for scenes
+use std::sync::Arc;
+
 use image::Rgb;
+use precision::Float;
 use vector3d::Vec3;
-use ray::{Ray, Shadable, ShadeCell};
+use algebra::InnerProductSpace;
+use ray::{Ray, Shadable, ShadeCell, STRIKE_TOLERANCE};
+use packet::{RayPacket, WIDTH};
+use medium::Volume;
+use stats;
 
+// Lights may be tagged with the name of a group, so that the light
+// from each group can be rendered separately. The name is shared, so
+// that copying lights as they are shaded costs no allocation.
+#[derive(Clone)]
 pub struct Light {
     pub position : Vec3,
-    pub colour : Rgb<u8>
+    pub colour : Rgb<u8>,
+    // A factor on each channel of the colour, kept as floats so that
+    // faint or bright lights are neither rounded away nor clipped
+    // before shading, and so that media can tint what reaches a point
+    pub intensity : [Float; 3],
+    pub group : Option<Arc<str>>
 }
 
+// Stand-in lights sit this far off emissive surfaces, so that shadow
+// rays toward them are not blocked by the surface itself
+const EMITTER_LIGHT_OFFSET : Float = 0.0001;
+
 pub struct AmbientLight {
     pub colour : Rgb<u8>
 }
@@ -16,14 +36,145 @@ pub struct AmbientLight {
 pub struct Scene {
     pub ambient_light : AmbientLight,
     pub objects : Vec<Box<Shadable>>,
-    pub lights : Vec<Light>
+    pub lights : Vec<Light>,
+    // Fog, haze and the like which rays pass through on their way
+    pub volumes : Vec<Volume>
+}
+
+impl Scene {
+    // A scene without media, which may be added to volumes
+    pub fn new(ambient_light : AmbientLight, objects : Vec<Box<Shadable>>, lights : Vec<Light>)
+               -> Scene {
+        Scene {
+            ambient_light : ambient_light,
+            objects : objects,
+            lights : lights,
+            volumes : Vec::new()
+        }
+    }
+
+    pub fn emitters(&self) -> Vec<&Shadable> {
+        self.objects
+            .iter()
+            .map(|o| &**o)
+            .filter(|o| o.material().is_emissive() && o.surface().is_some())
+            .collect()
+    }
+
+    // Point lights standing in for the emissive surfaces, as seen from
+    // p. Each surface is sampled on a grid of n by n points, and each
+    // sample carries its share of the light the surface emits toward
+    // p, falling off with the square of the distance. The colour of a
+    // stand-in is the surface's tint at full brightness, and its
+    // intensity how much of that reaches p.
+    pub fn emitter_lights(&self, p : Vec3, n : u32) -> Vec<Light> {
+        let mut lights = Vec::new();
+        let cells = (n * n) as Float;
+
+        for o in self.emitters() {
+            let surface = match o.surface() {
+                Some(s) => s,
+                None => continue
+            };
+            let emission = o.material().emission();
+            let peak = emission.iter().cloned().fold(0.0, Float::max);
+            if !(peak > 0.0) {
+                continue;
+            }
+            let tint = |e : Float| (255.0 * e.max(0.0) / peak).round() as u8;
+            let colour = Rgb([tint(emission[0]), tint(emission[1]), tint(emission[2])]);
+            let share = surface.area() / cells;
+
+            for i in 0..n {
+                for j in 0..n {
+                    let (u, v) = ((i as Float + 0.5) / n as Float, (j as Float + 0.5) / n as Float);
+                    let (q, normal) = surface.sample_surface(u, v);
+                    let to_p = p - q;
+                    let d2 = to_p.dot(to_p);
+                    let cos = normal.dot(to_p) / d2.sqrt();
+                    if d2 == 0.0 || !(cos > 0.0) {
+                        continue;
+                    }
+
+                    lights.push(Light::new(q + EMITTER_LIGHT_OFFSET * normal, colour)
+                        .with_intensity(peak * share * cos / d2));
+                }
+            }
+        }
+        lights
+    }
+
+    // The lights which reach p unobstructed at the given time,
+    // including stand-ins for emissive surfaces sampled on an n by n
+    // grid
+    pub fn visible_lights(&self, p : Vec3, time : Float, n : u32) -> Vec<Light> {
+        let mut visible : Vec<Light> = self.lights
+            .iter()
+            .filter(|l| l.illuminates(p, time, &self.objects))
+            .cloned()
+            .collect();
+        visible.extend(self.emitter_lights(p, n)
+            .into_iter()
+            .filter(|l| l.illuminates(p, time, &self.objects)));
+        visible
+    }
+
+    // The names of the light groups in the scene, in the order they
+    // first appear
+    pub fn light_groups(&self) -> Vec<String> {
+        let mut groups : Vec<String> = Vec::new();
+        for name in self.lights.iter().filter_map(|l| l.group.as_ref()) {
+            if !groups.iter().any(|g| **g == **name) {
+                groups.push(name.to_string());
+            }
+        }
+        groups
+    }
 }
 
 impl Light {
-    pub fn illuminates(&self, p : Vec3, objects : &Vec<Box<Shadable>>) -> bool {
-        let strike = Ray::new(p, self.position - p).trace(objects);
+    pub fn new(position : Vec3, colour : Rgb<u8>) -> Light {
+        Light {
+            position : position,
+            colour : colour,
+            intensity : [1.0; 3],
+            group : None
+        }
+    }
+
+    pub fn with_intensity(self, intensity : Float) -> Light {
+        Light { intensity : [intensity; 3], ..self }
+    }
+
+    // The light with each channel scaled by the given factor, as by
+    // the media it passes through
+    pub fn dimmed(self, by : [Float; 3]) -> Light {
+        let i = self.intensity;
+        Light { intensity : [i[0] * by[0], i[1] * by[1], i[2] * by[2]], ..self }
+    }
+
+    pub fn in_group(self, name : &str) -> Light {
+        Light { group : Some(Arc::from(name)), ..self }
+    }
+
+    // The light given off, on the scale of colour channels
+    pub fn radiance(&self) -> [Float; 3] {
+        let (Rgb { data : [r, g, b] }, i) = (self.colour, self.intensity);
+        [r as Float * i[0], g as Float * i[1], b as Float * i[2]]
+    }
+
+    // Whether the light belongs to the named group
+    pub fn is_in(&self, group : &str) -> bool {
+        self.group.as_ref().map_or(false, |g| &**g == group)
+    }
+
+    // Whether the light reaches p unobstructed, with anything moving
+    // where it is at the given time
+    pub fn illuminates(&self, p : Vec3, time : Float, objects : &Vec<Box<Shadable>>) -> bool {
+        stats::record(|c| c.shadow_rays += 1);
+        let strike = Ray::new(p, self.position - p).with_time(time).trace(objects);
         match strike {
-            Some(ShadeCell(x,_,_,_)) => if (x - p).norm() < (p - self.position).norm() {
+            Some(ShadeCell(x,_,_,_,_)) => if (x - p).norm() < (p - self.position).norm() {
                 false
             }
             else {
@@ -32,6 +183,32 @@ impl Light {
             None => true
         }
     }
+
+    // The same test for several points at once, casting the shadow
+    // rays toward the light as a packet
+    pub fn illuminates_packet(&self, points: [Vec3; WIDTH], times: [Float; WIDTH],
+                              objects: &Vec<Box<Shadable>>)
+                              -> [bool; WIDTH] {
+        let to_light = |i: usize| {
+            Ray::new(points[i], self.position - points[i]).with_time(times[i])
+        };
+        let packet = RayPacket::new([to_light(0), to_light(1), to_light(2), to_light(3)]);
+        stats::record(|c| c.shadow_rays += WIDTH as u64);
+        let mut lit = [true; WIDTH];
+
+        for object in objects.iter() {
+            let hits = object.intersect_packet(&packet);
+            for i in 0..WIDTH {
+                if let Some(x) = hits[i] {
+                    let d = (x - points[i]).norm();
+                    if d >= STRIKE_TOLERANCE && d < (points[i] - self.position).norm() {
+                        lit[i] = false;
+                    }
+                }
+            }
+        }
+        lit
+    }
 }
 
 #[cfg(test)]
@@ -41,54 +218,63 @@ mod tests {
     use image::Rgb;
     use shapes::Sphere;
     use ray::Shadable;
+    use shapes::{Plane, Quad};
+    use materials::Material;
+
+    fn scene_with_panel() -> Scene {
+        let panel = Material::plain().with_emission([1.0; 3], 2.0);
+        let light_panel = Box::new(Quad::new(Vec3(-1.0, -1.0, 4.0), Vec3(2.0, 0.0, 0.0),
+                                             Vec3(0.0, 2.0, 0.0), panel).unwrap())
+            as Box<Shadable>;
+        let floor = Box::new(Plane::new(Vec3(1.0, 0.0, 0.0), Vec3(0.0, 1.0, 0.0),
+                                        Material::plain()).unwrap()) as Box<Shadable>;
+        Scene {
+            ambient_light : AmbientLight { colour : Rgb([0; 3]) },
+            objects : vec!(floor, light_panel),
+            lights : vec!(),
+            volumes : vec!()
+        }
+    }
 
     #[test]
     fn test_can_detect_interfering_object() {
-        let light = Light {
-            position : Vec3(0.0, 0.0, 5.0),
-            colour : Rgb([255 as u8 ; 3]) };
+        let light = Light::new(Vec3(0.0, 0.0, 5.0), Rgb([255 as u8 ; 3]));
 
         let sphere = Box::new(Sphere::simple(Vec3(0.0, 0.0, 0.0), 1.0)) as Box<Shadable>;
         let objects = vec!(sphere);
 
         let point = Vec3(0.0, 0.0, -5.0);
 
-        assert!(!light.illuminates(point, &objects));
+        assert!(!light.illuminates(point, 0.0, &objects));
     }
 
     #[test]
     fn test_can_detect_non_interfering_object() {
-        let light = Light {
-            position : Vec3(0.0, 0.0, 5.0),
-            colour : Rgb([255 as u8 ; 3]) };
+        let light = Light::new(Vec3(0.0, 0.0, 5.0), Rgb([255 as u8 ; 3]));
 
         let sphere = Box::new(Sphere::simple(Vec3(0.0, 0.0, 0.0), 1.0)) as Box<Shadable>;
         let objects = vec!(sphere);
 
         let point = Vec3(3.0, 0.0, -5.0);
 
-        assert!(light.illuminates(point, &objects));
+        assert!(light.illuminates(point, 0.0, &objects));
     }
 
     #[test]
     fn test_light_detects_interference_in_correct_portion_of_ray() {
-        let light = Light {
-            position : Vec3(2.0, 0.0, 0.0),
-            colour : Rgb([255 as u8 ; 3]) };
+        let light = Light::new(Vec3(2.0, 0.0, 0.0), Rgb([255 as u8 ; 3]));
 
         let sphere = Box::new(Sphere::simple(Vec3(0.0, 0.0, 0.0), 1.0)) as Box<Shadable>;
         let objects = vec!(sphere);
 
         let point = Vec3(3.0, 0.0, 0.0);
 
-        assert!(light.illuminates(point, &objects));
+        assert!(light.illuminates(point, 0.0, &objects));
     }
 
     #[test]
     fn test_light_illumination_is_not_confused_by_two_objects() {
-        let light = Light {
-            position : Vec3(2.0, 0.0, 0.0),
-            colour : Rgb([255 as u8 ; 3]) };
+        let light = Light::new(Vec3(2.0, 0.0, 0.0), Rgb([255 as u8 ; 3]));
 
         let sphere = Box::new(Sphere::simple(Vec3(0.0, 0.0, 0.0), 1.0)) as Box<Shadable>;
         let hidden = Box::new(Sphere::simple(Vec3(-5.0, 0.0, 0.0), 1.0)) as Box<Shadable>;
@@ -96,7 +282,82 @@ mod tests {
 
         let point = Vec3(1.0, 0.0, 0.0);
 
-        assert!(light.illuminates(point, &objects));
+        assert!(light.illuminates(point, 0.0, &objects));
+    }
+
+    #[test]
+    fn test_light_groups_are_listed_once_in_order() {
+        let light = |name : Option<&str>| {
+            let l = Light::new(Vec3::zero(), Rgb([255 as u8 ; 3]));
+            match name {
+                Some(n) => l.in_group(n),
+                None => l
+            }
+        };
+        let scene = Scene {
+            ambient_light : AmbientLight { colour : Rgb([0; 3]) },
+            objects : vec!(),
+            lights : vec!(light(Some("key")), light(None), light(Some("rim")), light(Some("key"))),
+            volumes : vec!()
+        };
+
+        assert!(scene.light_groups() == vec!("key".to_string(), "rim".to_string()));
+    }
+
+    #[test]
+    fn test_only_emissive_surfaces_are_emitters() {
+        let scene = scene_with_panel();
+        assert!(scene.emitters().len() == 1);
+    }
+
+    #[test]
+    fn test_emitters_deliver_their_light_at_every_distance() {
+        // Far off, the 2 by 2 panel of strength 2 delivers about
+        // 255 * 2 * 4 / d^2, however faint each stand-in is
+        let scene = scene_with_panel();
+        let total = |p : Vec3| -> Float {
+            scene.emitter_lights(p, 4).iter().map(|l| l.radiance()[0]).sum()
+        };
+        for &d in [5.0, 10.0, 40.0].iter() {
+            let expected = 255.0 * 2.0 * 4.0 / (d * d);
+            let delivered = total(Vec3(0.0, 0.0, 4.0 + d));
+            assert!((delivered / expected - 1.0).abs() < 0.05, "{} at {}", delivered, d);
+        }
+
+        // Up close, the stand-in just below gives far more than full
+        // white, which the others only add to
+        let near = 255.0 * 2.0 * 0.25 / (0.1 * 0.1);
+        let delivered = total(Vec3(0.25, 0.25, 4.1));
+        assert!(delivered > near && delivered < 1.1 * near);
+    }
+
+    #[test]
+    fn test_emitters_light_the_side_they_face() {
+        let scene = scene_with_panel();
+        let below = scene.emitter_lights(Vec3(0.0, 0.0, 0.0), 2);
+        let above = scene.emitter_lights(Vec3(0.0, 0.0, 8.0), 2);
+
+        assert!(below.is_empty());
+        assert!(above.len() == 4);
+        assert!(above.iter().all(|l| l.illuminates(Vec3(0.0, 0.0, 8.0), 0.0, &scene.objects)));
+    }
+
+    #[test]
+    fn test_packet_illumination_agrees_with_single_rays() {
+        let light = Light::new(Vec3(2.0, 0.0, 0.0), Rgb([255 as u8 ; 3]));
+
+        let sphere = Box::new(Sphere::simple(Vec3(0.0, 0.0, 0.0), 1.0)) as Box<Shadable>;
+        let hidden = Box::new(Sphere::simple(Vec3(-5.0, 0.0, 0.0), 1.0)) as Box<Shadable>;
+        let objects = vec!(sphere, hidden);
+
+        let points = [Vec3(1.0, 0.0, 0.0), Vec3(-3.0, 0.0, 0.0),
+                      Vec3(0.0, 3.0, 0.0), Vec3(-7.0, 0.0, 0.0)];
+        let lit = light.illuminates_packet(points, [0.0; WIDTH], &objects);
+
+        for i in 0..WIDTH {
+            assert!(lit[i] == light.illuminates(points[i], 0.0, &objects));
+        }
+        assert!(lit == [true, false, true, false]);
     }
-        
+
 }
@@ -0,0 +1,701 @@
+// scene_file - reading scenes from text files, so that scenes can be
+// changed without rebuilding the renderer.
+//
+// Each line gives one thing, named by its first word, followed by its
+// values. Positions and colours are three numbers apiece, and anything
+// after a # is a comment. For example:
+//
+//     camera position 0 -2 0 rotate 0.47 0 0 fov 45 shutter 0.5
+//     resolution 800 600
+//     ambient 70 70 70
+//     light 4 -4 0 colour 255 255 200 group key name sun
+//     material slate phong specular 0.01 0.01 0.01 diffuse 0.03 0.03 0.03
+//     material gold pbr base 1 0.8 0.3 metallic 1 roughness 0.3
+//     material lamp pbr base 1 1 1 emission 1 0.9 0.8 strength 4
+//     plane 1 0 0  0 0 1 material slate
+//     sphere 0 -1 5 radius 1 material gold name ball
+//     sphere 2 -1 5 radius 0.5 move 0.1 0 0
+//     triangle 0 0 0  1 0 0  0 1 0
+//     quad 0 0 0  1 0 0  0 1 0 material lamp
+//     medium haze absorb 0.01 0.01 0.01 scatter 0.04 0.05 0.06 phase 0.6
+//     medium smoke absorb 0.5 0.5 0.5 scatter 0.2 0.2 0.2
+//     fog haze
+//     sphere 0 3 5 radius 1 fill smoke
+//
+// Shapes without a material take the plain one. Materials must be
+// given before the shapes which use them, and are numbered from one in
+// the order given. A shape which moves travels
+// the given distance each frame, from where it is given at frame zero.
+// The camera's shutter stays open for the given fraction of each
+// frame, blurring anything which moves meanwhile; by default it is
+// open for an instant.
+//
+// Media absorb and scatter the given fraction of light per unit
+// distance, in each colour channel, and scatter it onward (phase
+// toward 1), back (toward -1) or evenly (0, by default). Fog fills
+// all space with a medium, and a shape filled with one is not drawn
+// but bounds it, so it must be closed and can be neither named nor
+// moved. Media must be given before they are used.
+//
+// Scenes may be animated by keyframe tracks, each giving the frames
+// of its keys and the values at them:
+//
+//     frames 1 48
+//     track camera position bezier at 1 0 -2 0 at 48 2 -2 0
+//     track camera rotate slerp at 1 0.47 0 0 at 48 0.47 0 0.3
+//     track camera fov linear at 1 45 at 48 30
+//     track light sun colour linear at 1 255 255 200 at 48 255 120 40
+//     track object ball translate bezier at 1 0 0 0 at 24 0 0 1 at 48 0 0 0
+//     track object ball rotate slerp at 1 0 0 0 at 48 0 0 3.14
+//
+// Lights may be tracked by position and colour, and the camera by
+// position, rotate and fov. Objects may be tracked by translate,
+// rotate and scale, which move them as a whole, scaling and turning
+// them about the origin. Rotations are angles about the x, y and z
+// axes, interpolated by slerp or bezier; everything else by linear or
+// bezier. Lights and objects must be named before they are tracked.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use image::Rgb;
+use precision::Float;
+use vector3d::Vec3;
+use space_algebra::{EulerOrder, UnitQuaternion};
+use camera::{Camera, CameraSpec};
+use materials::Material;
+use medium::{Medium, Volume};
+use shapes::{Sphere, Plane, Triangle, Quad, Transformed, Moving};
+use animation::{Animation, Animatable, Interpolation, Track, CameraTracks, CameraPath};
+use scene::{Scene, Light, AmbientLight};
+use ray::Shadable;
+use error::{Error, Result};
+
+pub struct SceneFile {
+    pub camera: CameraSpec,
+    // The size to render at, unless told otherwise
+    pub resolution: Option<(u32, u32)>,
+    pub scene: Scene,
+    pub animation: Animation,
+    // The first and last frames to render, unless told otherwise
+    pub frames: Option<(u32, u32)>,
+    // When the shutter opens and closes, and how the camera moves
+    // meanwhile, once posed at a frame
+    pub shutter: (Float, Float),
+    pub camera_motion: Option<CameraTracks>,
+}
+
+impl SceneFile {
+    // The scene as it stands at a frame of its animation, with nothing
+    // left to animate. While the shutter is open, tracked objects and
+    // the camera keep moving through the interval it is open for.
+    pub fn at_frame(mut self, frame: Float) -> SceneFile {
+        let animation = ::std::mem::replace(&mut self.animation, Animation::default());
+        let open = self.camera.shutter > 0.0;
+        self.shutter = (frame, frame + self.camera.shutter);
+        if open && animation.camera != CameraTracks::default() {
+            self.camera_motion = Some(animation.camera.clone());
+        }
+        self.camera = animation.camera.pose(&self.camera, frame);
+        for &(i, ref tracks) in animation.lights.iter() {
+            self.scene.lights[i] = tracks.pose(&self.scene.lights[i], frame);
+        }
+
+        let objects = ::std::mem::replace(&mut self.scene.objects, Vec::new());
+        self.scene.objects = objects.into_iter()
+            .enumerate()
+            .map(|(i, object)| match animation.objects.iter().find(|o| o.0 == i) {
+                Some(&(_, ref tracks)) if open => {
+                    Box::new(Moving::new(object, Box::new(tracks.clone()), frame)) as Box<Shadable>
+                }
+                Some(&(_, ref tracks)) => {
+                    Box::new(Transformed::new(object, tracks.transform(frame))) as Box<Shadable>
+                }
+                None => object,
+            })
+            .collect();
+        self
+    }
+
+    // The camera at the given size, with its shutter and motion
+    pub fn build_camera(&self, width: u32, height: u32) -> Result<Camera> {
+        let (open, close) = self.shutter;
+        let camera = self.camera.build(width, height)?.with_shutter(open, close);
+        Ok(match self.camera_motion {
+            Some(ref tracks) => {
+                camera.with_motion(Box::new(CameraPath {
+                    camera: self.camera,
+                    tracks: tracks.clone(),
+                }))
+            }
+            None => camera,
+        })
+    }
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<SceneFile> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    parse(&text)
+}
+
+pub fn parse(text: &str) -> Result<SceneFile> {
+    let mut file = SceneFile {
+        camera: CameraSpec::default(),
+        resolution: None,
+        scene: Scene::new(AmbientLight { colour: Rgb([0; 3]) }, Vec::new(), Vec::new()),
+        animation: Animation::default(),
+        frames: None,
+        shutter: (0.0, 0.0),
+        camera_motion: None,
+    };
+    let (mut materials, mut media) = (HashMap::new(), HashMap::new());
+    let mut numbered = 0;
+    // The places of named lights and objects in the scene's lists
+    let (mut lights, mut objects) = (HashMap::new(), HashMap::new());
+
+    for (i, line) in text.lines().enumerate() {
+        let content = line.split('#').next().unwrap_or("");
+        let mut words = Words {
+            line: i + 1,
+            words: content.split_whitespace().collect(),
+            next: 0,
+        };
+        if words.words.is_empty() {
+            continue;
+        }
+        match words.word()? {
+            "camera" => {
+                while let Some(key) = words.key() {
+                    match key {
+                        "position" => file.camera.position = words.vector()?,
+                        "rotate" => file.camera.orientation = words.rotation()?,
+                        "fov" => file.camera.fov = words.float()?,
+                        "shutter" => file.camera.shutter = words.shutter()?,
+                        _ => return Err(words.unknown(key)),
+                    }
+                }
+            }
+            "resolution" => file.resolution = Some((words.integer()?, words.integer()?)),
+            "ambient" => file.scene.ambient_light = AmbientLight { colour: words.colour()? },
+            "light" => {
+                let mut light = Light::new(words.vector()?, Rgb([255; 3]));
+                while let Some(key) = words.key() {
+                    match key {
+                        "colour" => light.colour = words.colour()?,
+                        "group" => light = light.in_group(words.word()?),
+                        "name" => words.name(&mut lights, file.scene.lights.len())?,
+                        _ => return Err(words.unknown(key)),
+                    }
+                }
+                file.scene.lights.push(light);
+            }
+            "frames" => {
+                let (first, last) = (words.integer()?, words.integer()?);
+                if last < first {
+                    return Err(words.error("the last frame comes before the first"));
+                }
+                file.frames = Some((first, last));
+            }
+            "track" => words.track(&mut file, &lights, &objects)?,
+            "material" => {
+                let name = words.word()?.to_string();
+                let material = words.material()?;
+                numbered += 1;
+                materials.insert(name, material.with_id(numbered));
+            }
+            "medium" => {
+                let name = words.word()?.to_string();
+                let medium = words.medium()?;
+                media.insert(name, medium);
+            }
+            "fog" => {
+                let medium = words.medium_named(&media)?;
+                file.scene.volumes.push(Volume::everywhere(medium));
+            }
+            "sphere" => {
+                let centre = words.vector()?;
+                let radius = match words.key() {
+                    Some("radius") => words.float()?,
+                    _ => return Err(words.error("sphere needs a radius")),
+                };
+                let (m, fill) = words.shape_settings(&materials, &media, &mut objects, &mut file)?;
+                add_shape(&mut file, Box::new(Sphere::new(centre, radius, m)), fill);
+            }
+            "plane" => {
+                let (d1, d2) = (words.vector()?, words.vector()?);
+                let (m, fill) = words.shape_settings(&materials, &media, &mut objects, &mut file)?;
+                add_shape(&mut file, Box::new(Plane::new(d1, d2, m)?), fill);
+            }
+            "triangle" => {
+                let (a, b, c) = (words.vector()?, words.vector()?, words.vector()?);
+                let (m, fill) = words.shape_settings(&materials, &media, &mut objects, &mut file)?;
+                add_shape(&mut file, Box::new(Triangle::new(a, b, c, m)?), fill);
+            }
+            "quad" => {
+                let (corner, e1, e2) = (words.vector()?, words.vector()?, words.vector()?);
+                let (m, fill) = words.shape_settings(&materials, &media, &mut objects, &mut file)?;
+                add_shape(&mut file, Box::new(Quad::new(corner, e1, e2, m)?), fill);
+            }
+            other => return Err(words.error(&format!("unknown item '{}'", other))),
+        }
+        if words.next < words.words.len() {
+            return Err(words.error(&format!("unexpected '{}'", words.words[words.next])));
+        }
+    }
+
+    Ok(file)
+}
+
+// The words of one line, read from the front
+struct Words<'a> {
+    line: usize,
+    words: Vec<&'a str>,
+    next: usize,
+}
+
+impl<'a> Words<'a> {
+    fn error(&self, message: &str) -> Error {
+        Error::SceneFile { line: self.line, message: message.to_string() }
+    }
+
+    fn unknown(&self, key: &str) -> Error {
+        self.error(&format!("unknown setting '{}'", key))
+    }
+
+    fn word(&mut self) -> Result<&'a str> {
+        match self.words.get(self.next) {
+            Some(&w) => {
+                self.next += 1;
+                Ok(w)
+            }
+            None => Err(self.error("line ends too soon")),
+        }
+    }
+
+    // The next word, if any is left
+    fn key(&mut self) -> Option<&'a str> {
+        self.word().ok()
+    }
+
+    fn float(&mut self) -> Result<Float> {
+        let w = self.word()?;
+        w.parse().map_err(|_| self.error(&format!("expected a number, found '{}'", w)))
+    }
+
+    fn integer(&mut self) -> Result<u32> {
+        let w = self.word()?;
+        w.parse().map_err(|_| self.error(&format!("expected a whole number, found '{}'", w)))
+    }
+
+    fn vector(&mut self) -> Result<Vec3> {
+        Ok(Vec3(self.float()?, self.float()?, self.float()?))
+    }
+
+    fn triple(&mut self) -> Result<[Float; 3]> {
+        Ok([self.float()?, self.float()?, self.float()?])
+    }
+
+    // Eight bit colour channels, as for lights
+    fn colour(&mut self) -> Result<Rgb<u8>> {
+        let mut c = [0; 3];
+        for channel in c.iter_mut() {
+            let w = self.word()?;
+            *channel = w.parse()
+                .map_err(|_| self.error(&format!("expected a channel in 0-255, found '{}'", w)))?;
+        }
+        Ok(Rgb(c))
+    }
+
+    fn material(&mut self) -> Result<Material> {
+        let mut material = match self.word()? {
+            "phong" => {
+                let m = Material::plain();
+                let (mut spec, mut diff, mut amb, mut refl, mut shine) =
+                    (m.specular_reflectiviy(), m.diffusive_reflectivity(),
+                     m.ambient_refletivity(), m.reflectivity(), m.shine());
+                while let Some(key) = self.peek_setting(&["specular", "diffuse", "ambient",
+                                                          "reflect", "shine"]) {
+                    match key {
+                        "specular" => spec = self.triple()?,
+                        "diffuse" => diff = self.triple()?,
+                        "ambient" => amb = self.triple()?,
+                        "reflect" => refl = self.triple()?,
+                        _ => shine = self.float()?,
+                    }
+                }
+                Material::new(spec, diff, amb, refl, shine)
+            }
+            "pbr" => {
+                let (mut base, mut metallic, mut roughness, mut specular) =
+                    ([0.8; 3], 0.0, 0.5, 0.5);
+                while let Some(key) = self.peek_setting(&["base", "metallic", "roughness",
+                                                          "specular"]) {
+                    match key {
+                        "base" => base = self.triple()?,
+                        "metallic" => metallic = self.float()?,
+                        "roughness" => roughness = self.float()?,
+                        _ => specular = self.float()?,
+                    }
+                }
+                Material::metallic_roughness(base, metallic, roughness, specular)
+            }
+            other => return Err(self.error(&format!("unknown material kind '{}'", other))),
+        };
+
+        let (mut emission, mut strength) = (None, 1.0);
+        while let Some(key) = self.peek_setting(&["emission", "strength"]) {
+            match key {
+                "emission" => emission = Some(self.triple()?),
+                _ => strength = self.float()?,
+            }
+        }
+        if let Some(colour) = emission {
+            material = material.with_emission(colour, strength);
+        }
+        Ok(material)
+    }
+
+    // The next word, taken only if it is one of the given settings
+    fn peek_setting(&mut self, settings: &[&str]) -> Option<&'a str> {
+        match self.words.get(self.next) {
+            Some(&w) if settings.contains(&w) => {
+                self.next += 1;
+                Some(w)
+            }
+            _ => None,
+        }
+    }
+
+    // Angles about the x, y and z axes, in radians, applied in turn
+    fn rotation(&mut self) -> Result<UnitQuaternion> {
+        let (x, y, z) = (self.float()?, self.float()?, self.float()?);
+        Ok(UnitQuaternion::from_euler(EulerOrder::XYZ, x, y, z))
+    }
+
+    // Give the thing at the given place in its list a name
+    fn name(&mut self, names: &mut HashMap<String, usize>, place: usize) -> Result<()> {
+        let name = self.word()?;
+        if names.insert(name.to_string(), place).is_some() {
+            return Err(self.error(&format!("'{}' is named twice", name)));
+        }
+        Ok(())
+    }
+
+    // The optional trailing "material <name>", "name <name>",
+    // "move <x> <y> <z>" and "fill <medium>" of the next shape in the
+    // file, giving its material and the medium it is filled with
+    fn shape_settings(&mut self, materials: &HashMap<String, Material>,
+                      media: &HashMap<String, Medium>, names: &mut HashMap<String, usize>,
+                      file: &mut SceneFile)
+                      -> Result<(Material, Option<Medium>)> {
+        let place = file.scene.objects.len();
+        let (mut material, mut fill, mut placed) = (Material::plain(), None, false);
+        while let Some(key) = self.key() {
+            match key {
+                "material" => {
+                    let name = self.word()?;
+                    material = materials.get(name)
+                        .cloned()
+                        .ok_or_else(|| self.error(&format!("no material named '{}'", name)))?;
+                }
+                "name" => {
+                    self.name(names, place)?;
+                    placed = true;
+                }
+                "move" => {
+                    tracks_of(&mut file.animation.objects, place).velocity = Some(self.vector()?);
+                    placed = true;
+                }
+                "fill" => fill = Some(self.medium_named(media)?),
+                _ => return Err(self.unknown(key)),
+            }
+        }
+        if fill.is_some() && placed {
+            return Err(self.error("a shape filled with a medium cannot be named or moved"));
+        }
+        Ok((material, fill))
+    }
+
+    fn medium(&mut self) -> Result<Medium> {
+        let mut medium = Medium {
+            absorption: [0.0; 3],
+            scattering: [0.0; 3],
+            anisotropy: 0.0,
+        };
+        while let Some(key) = self.peek_setting(&["absorb", "scatter", "phase"]) {
+            match key {
+                "absorb" => medium.absorption = self.triple()?,
+                "scatter" => medium.scattering = self.triple()?,
+                _ => medium.anisotropy = self.float()?,
+            }
+        }
+        let coefficients = medium.absorption.iter().chain(medium.scattering.iter());
+        if coefficients.cloned().any(|c| !(c >= 0.0)) {
+            return Err(self.error("a medium cannot absorb or scatter less than nothing"));
+        }
+        if !(medium.anisotropy.abs() < 1.0) {
+            return Err(self.error("phase must lie between -1 and 1"));
+        }
+        Ok(medium)
+    }
+
+    fn medium_named(&mut self, media: &HashMap<String, Medium>) -> Result<Medium> {
+        let name = self.word()?;
+        media.get(name).cloned().ok_or_else(|| self.error(&format!("no medium named '{}'", name)))
+    }
+
+    // "track <target> <property> <interpolation>" followed by its keys
+    fn track(&mut self, file: &mut SceneFile, lights: &HashMap<String, usize>,
+             objects: &HashMap<String, usize>)
+             -> Result<()> {
+        let animation = &mut file.animation;
+        match self.word()? {
+            "camera" => {
+                let tracks = &mut animation.camera;
+                match self.word()? {
+                    "position" => tracks.position = Some(self.keys(Words::vector)?),
+                    "rotate" => tracks.rotation = Some(self.keys(Words::rotation)?),
+                    "fov" => tracks.fov = Some(self.keys(Words::float)?),
+                    key => return Err(self.unknown(key)),
+                }
+            }
+            "light" => {
+                let place = self.named(lights)?;
+                let tracks = tracks_of(&mut animation.lights, place);
+                match self.word()? {
+                    "position" => tracks.position = Some(self.keys(Words::vector)?),
+                    "colour" => tracks.colour = Some(self.keys(Words::channels)?),
+                    key => return Err(self.unknown(key)),
+                }
+            }
+            "object" => {
+                let place = self.named(objects)?;
+                let tracks = tracks_of(&mut animation.objects, place);
+                match self.word()? {
+                    "translate" => tracks.translation = Some(self.keys(Words::vector)?),
+                    "rotate" => tracks.rotation = Some(self.keys(Words::rotation)?),
+                    "scale" => tracks.scale = Some(self.keys(Words::scale)?),
+                    key => return Err(self.unknown(key)),
+                }
+            }
+            other => return Err(self.error(&format!("cannot track '{}'", other))),
+        }
+        Ok(())
+    }
+
+    // The place of the thing named by the next word
+    fn named(&mut self, names: &HashMap<String, usize>) -> Result<usize> {
+        let name = self.word()?;
+        names.get(name).cloned().ok_or_else(|| self.error(&format!("nothing named '{}'", name)))
+    }
+
+    // An interpolation, then "at <frame> <value>" for each key
+    fn keys<T: Animatable>(&mut self, value: fn(&mut Words<'a>) -> Result<T>)
+                           -> Result<Track<T>> {
+        let word = self.word()?;
+        let interpolation = Interpolation::by_name(word)
+            .ok_or_else(|| self.error(&format!("unknown interpolation '{}'", word)))?;
+        let mut keys = Vec::new();
+        while let Some(_) = self.peek_setting(&["at"]) {
+            keys.push((self.float()?, value(self)?));
+        }
+        Track::new(interpolation, keys).map_err(|e| self.error(&e.to_string()))
+    }
+
+    // A light's colour, allowing any number for each channel in 0-255
+    fn channels(&mut self) -> Result<Vec3> {
+        let c = self.vector()?;
+        let Vec3(r, g, b) = c;
+        if [r, g, b].iter().any(|&x| x < 0.0 || x > 255.0) {
+            return Err(self.error("colour channels must lie in 0-255"));
+        }
+        Ok(c)
+    }
+
+    fn shutter(&mut self) -> Result<Float> {
+        let s = self.float()?;
+        if !(s >= 0.0 && s <= 1.0) {
+            return Err(self.error(&format!("shutter must be open for 0-1 frames, not {}", s)));
+        }
+        Ok(s)
+    }
+
+    fn scale(&mut self) -> Result<Float> {
+        let s = self.float()?;
+        if !(s > 0.0) {
+            return Err(self.error(&format!("scale must be positive, not {}", s)));
+        }
+        Ok(s)
+    }
+}
+
+// Add a shape to the scene, or the volume it bounds if it is filled
+// with a medium
+fn add_shape(file: &mut SceneFile, shape: Box<Shadable>, fill: Option<Medium>) {
+    match fill {
+        Some(medium) => file.scene.volumes.push(Volume::inside(medium, shape)),
+        None => file.scene.objects.push(shape),
+    }
+}
+
+// The tracks kept for the thing at the given place, made empty if
+// there are none yet
+fn tracks_of<T: Default>(list: &mut Vec<(usize, T)>, place: usize) -> &mut T {
+    let i = match list.iter().position(|t| t.0 == place) {
+        Some(i) => i,
+        None => {
+            list.push((place, T::default()));
+            list.len() - 1
+        }
+    };
+    &mut list[i].1
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use algebra::approx_eq;
+    use ray::Ray;
+
+    const EXAMPLE: &'static str = "
+        # A ground plane and two balls
+        camera position 0 -2 0 rotate 0.47 0 0 fov 45
+        resolution 80 60
+        ambient 70 70 70
+        light 4 -4 0 colour 255 255 200
+        light -4 -4 0 group rim     # a second light
+
+        material slate phong specular 0.01 0.01 0.01 diffuse 0.03 0.03 0.03 shine 2
+        material gold pbr base 1 0.8 0.3 metallic 1 roughness 0.3
+        material lamp pbr base 1 1 1 emission 1 1 1 strength 4
+
+        plane 1 0 0  0 0 1 material slate
+        sphere 0 -1 5 radius 1 material gold
+        sphere -2 -0.5 4 radius 0.5
+        quad -1 -3 6  2 0 0  0 0 1 material lamp
+    ";
+
+    #[test]
+    fn test_example_scene_is_read() {
+        let file = parse(EXAMPLE).unwrap();
+        assert!(file.resolution == Some((80, 60)));
+        assert!(file.camera.orientation == UnitQuaternion::from_euler(EulerOrder::XYZ, 0.47, 0.0,
+                                                                       0.0));
+        assert!(file.camera.position == Vec3(0.0, -2.0, 0.0));
+        assert!(file.scene.ambient_light.colour == Rgb([70; 3]));
+        assert!(file.scene.lights.len() == 2);
+        assert!(file.scene.lights[0].colour == Rgb([255, 255, 200]));
+        assert!(file.scene.light_groups() == vec!["rim".to_string()]);
+        assert!(file.scene.objects.len() == 4);
+        assert!(file.scene.objects[1].material().pbr().metallic == 1.0);
+        assert!(*file.scene.objects[2].material() == Material::plain());
+        let ids: Vec<u32> = file.scene.objects.iter().map(|o| o.material().id()).collect();
+        assert!(ids == vec![1, 2, 0, 3]);
+        assert!(file.scene.emitters().len() == 1);
+        assert!(file.camera.build(80, 60).is_ok());
+    }
+
+    #[test]
+    fn test_mistakes_give_their_line() {
+        let line = |text: &str| match parse(text) {
+            Err(Error::SceneFile { line, .. }) => line,
+            _ => 0,
+        };
+        assert!(line("ambient 1 2 3\nsphere 0 0 0 radius") == 2);
+        assert!(line("\n\nsphere 0 0 0 radius 1 material missing") == 3);
+        assert!(line("cube 0 0 0") == 1);
+        assert!(line("light 0 0 0 colour 300 0 0") == 1);
+        assert!(line("camera fov 45 zoom 2") == 1);
+        assert!(line("camera shutter 2") == 1);
+        assert!(line("sphere 0 0 0 radius 1 move 1 1") == 1);
+        assert!(line("fog haze") == 1);
+        assert!(line("medium haze scatter -1 0 0") == 1);
+        assert!(line("medium haze phase 1") == 1);
+        assert!(line("medium haze\nsphere 0 0 0 radius 1 fill haze name a") == 2);
+        assert!(line("resolution 80 60 40") == 1);
+        assert!(line("sphere 0 0 0 radius 1 name a\nsphere 0 0 0 radius 1 name a") == 2);
+        assert!(line("track object a translate linear at 0 1 1 1") == 1);
+        assert!(line("light 0 0 0 name a\ntrack light a position slerp at 0 1 1 1") == 2);
+        assert!(line("track camera fov linear at 2 40 at 1 30") == 1);
+        assert!(line("frames 10 2") == 1);
+    }
+
+    const ANIMATED: &'static str = "
+        camera position 0 -2 0
+        light 4 -4 0 colour 0 0 0 name sun
+        sphere 0 0 0 radius 1 name ball
+        sphere 0 0 5 radius 1
+        frames 1 9
+        track camera position linear at 1 0 -2 0 at 9 8 -2 0
+        track camera fov bezier at 1 40 at 9 30
+        track light sun colour linear at 1 0 0 0 at 9 200 100 0
+        track object ball translate linear at 1 0 0 4 at 9 0 0 8
+        track object ball scale linear at 1 1 at 9 2
+    ";
+
+    #[test]
+    fn test_animated_scene_is_posed() {
+        let file = parse(ANIMATED).unwrap();
+        assert!(file.frames == Some((1, 9)));
+        assert!(!file.animation.is_empty());
+
+        let posed = parse(ANIMATED).unwrap().at_frame(5.0);
+        assert!(posed.animation.is_empty());
+        assert!(posed.camera.position == Vec3(4.0, -2.0, 0.0));
+        assert!(approx_eq(posed.camera.fov, 35.0, 0.0001));
+        assert!(posed.scene.lights[0].colour == Rgb([100, 50, 0]));
+
+        // The ball has moved to z = 6 and grown to radius 1.5
+        let ray = Ray::new(Vec3::zero(), Vec3(0.0, 0.0, 1.0));
+        let hit = posed.scene.objects[0].intersect(&ray).unwrap();
+        assert!(hit.approx_eq(Vec3(0.0, 0.0, 4.5), 0.0001));
+        assert!(posed.scene.objects[1].intersect(&ray) == Some(Vec3(0.0, 0.0, 4.0)));
+        assert!(parse(ANIMATED).unwrap().at_frame(1.0).scene.objects[0].intersect(&ray) ==
+                Some(Vec3(0.0, 0.0, 3.0)));
+    }
+
+    #[test]
+    fn test_media_fill_space_and_shapes() {
+        let file = parse("
+            medium haze absorb 0.01 0.01 0.01 scatter 0.04 0.05 0.06 phase 0.6
+            medium smoke absorb 0.5 0.5 0.5
+            fog haze
+            sphere 0 3 5 radius 1 fill smoke
+            sphere 0 0 5 radius 1 name ball
+        ")
+            .unwrap();
+        assert!(file.scene.objects.len() == 1 && file.scene.volumes.len() == 2);
+        let haze = &file.scene.volumes[0];
+        assert!(haze.bounds.is_none());
+        assert!(haze.medium.scattering == [0.04, 0.05, 0.06] && haze.medium.anisotropy == 0.6);
+        let smoke = &file.scene.volumes[1];
+        assert!(smoke.bounds.is_some() && smoke.medium.scattering == [0.0; 3]);
+    }
+
+    #[test]
+    fn test_moving_shapes_blur_while_the_shutter_is_open() {
+        let text = "
+            camera fov 90 shutter 0.5
+            track camera position linear at 0 0 0 0 at 10 10 0 0
+            sphere 0 0 5 radius 1 move 2 0 0
+        ";
+        let posed = parse(text).unwrap().at_frame(1.0);
+        assert!(posed.shutter == (1.0, 1.5));
+        let camera = posed.build_camera(16, 12).unwrap();
+        let ray = camera.get_ray_through_subpixel_at(8, 6, 0.0, 0.0, 1.0).unwrap();
+        assert!(ray.time == 1.5 && ray.origin == Vec3(1.5, 0.0, 0.0));
+
+        // The sphere is at x = 2 as the shutter opens and x = 3 as it
+        // closes, so a ray along x = 3.5 only strikes it late
+        let ray = Ray::new(Vec3(3.5, 0.0, 0.0), Vec3(0.0, 0.0, 1.0));
+        assert!(posed.scene.objects[0].intersect(&ray.with_time(1.0)).is_none());
+        assert!(posed.scene.objects[0].intersect(&ray.with_time(1.5)).is_some());
+
+        // With the shutter shut the sphere is simply moved to x = 2
+        let still = parse("sphere 0 0 5 radius 1 move 2 0 0").unwrap().at_frame(1.0);
+        assert!(still.scene.objects[0].intersect(&ray.with_time(1.5)).is_none());
+    }
+}
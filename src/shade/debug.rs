@@ -0,0 +1,300 @@
+// debug.rs - shaders for inspecting what rays strike, rather than for
+// making pretty pictures. They show the surfaces as they are, through
+// any fog in front of them.
+use image::Rgb;
+use precision::Float;
+use vector3d::Vec3;
+use algebra::InnerProductSpace;
+use scene::Scene;
+use ray::ShadeCell;
+use shade::Shader;
+
+// Scale a value in [0, 1] to a colour channel
+fn channel(x: Float) -> u8 {
+    (255.0 * x.max(0.0).min(1.0)).round() as u8
+}
+
+fn grey(x: Float) -> Rgb<u8> {
+    let c = channel(x);
+    Rgb([c, c, c])
+}
+
+// A ramp from blue through green to red, for x in [0, 1]
+pub fn heat(x: Float) -> Rgb<u8> {
+    Rgb([channel(2.0 * x - 1.0), channel(1.0 - (2.0 * x - 1.0).abs()), channel(1.0 - 2.0 * x)])
+}
+
+
+// The normal as a colour, mapping each component from [-1, 1] to the
+// full range of a channel
+pub struct NormalShader {}
+
+impl NormalShader {
+    pub fn instance() -> NormalShader {
+        NormalShader {}
+    }
+}
+
+impl Shader for NormalShader {
+    fn sees_media(&self) -> bool {
+        false
+    }
+
+    fn shade(&self, shade_cell: &ShadeCell, _: &Scene, _: Vec<Rgb<u8>>) -> Rgb<u8> {
+        let &ShadeCell(_, n, _, _, _) = shade_cell;
+        let Vec3(x, y, z) = 0.5 * (n + Vec3(1.0, 1.0, 1.0));
+        Rgb([channel(x), channel(y), channel(z)])
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DepthScale {
+    Linear,
+    Logarithmic,
+}
+
+// Distance from the ray's origin as shades of grey, from white up
+// close fading to black at the far distance. The logarithmic scale
+// keeps detail near the camera in deep scenes.
+pub struct DepthShader {
+    scale: DepthScale,
+    far: Float,
+}
+
+impl DepthShader {
+    pub fn linear(far: Float) -> DepthShader {
+        DepthShader { scale: DepthScale::Linear, far: far }
+    }
+
+    pub fn logarithmic(far: Float) -> DepthShader {
+        DepthShader { scale: DepthScale::Logarithmic, far: far }
+    }
+
+    // How far towards the far distance d is, in [0, 1]
+    pub fn depth(&self, d: Float) -> Float {
+        let x = match self.scale {
+            DepthScale::Linear => d / self.far,
+            DepthScale::Logarithmic => d.ln_1p() / self.far.ln_1p(),
+        };
+        x.max(0.0).min(1.0)
+    }
+}
+
+impl Shader for DepthShader {
+    fn sees_media(&self) -> bool {
+        false
+    }
+
+    fn shade(&self, shade_cell: &ShadeCell, _: &Scene, _: Vec<Rgb<u8>>) -> Rgb<u8> {
+        let &ShadeCell(_, _, _, _, strike) = shade_cell;
+        grey(1.0 - self.depth(strike.distance))
+    }
+}
+
+
+// A checkerboard laid over each surface's coordinates, tinted by the
+// coordinates themselves so that their direction can be read off
+pub struct UvShader {
+    tiles: Float,
+}
+
+impl UvShader {
+    pub fn checker(tiles: u32) -> UvShader {
+        UvShader { tiles: tiles.max(1) as Float }
+    }
+}
+
+impl Shader for UvShader {
+    fn sees_media(&self) -> bool {
+        false
+    }
+
+    fn shade(&self, shade_cell: &ShadeCell, _: &Scene, _: Vec<Rgb<u8>>) -> Rgb<u8> {
+        let &ShadeCell(_, _, _, _, strike) = shade_cell;
+        let (u, v) = strike.uv;
+        let parity = ((u * self.tiles).floor() + (v * self.tiles).floor()) as i64 % 2 == 0;
+        let k = if parity { 1.0 } else { 0.5 };
+        Rgb([channel(k * u.fract().abs()), channel(k * v.fract().abs()), channel(k)])
+    }
+}
+
+
+// Each object in its own arbitrary but repeatable colour
+pub struct ObjectIdShader {}
+
+impl ObjectIdShader {
+    pub fn instance() -> ObjectIdShader {
+        ObjectIdShader {}
+    }
+
+    pub fn colour(object: usize) -> Rgb<u8> {
+        // Scramble the index so that neighbours differ widely
+        let mut h = (object as u32).wrapping_add(1).wrapping_mul(0x9e3779b9);
+        h ^= h >> 16;
+        h = h.wrapping_mul(0x85ebca6b);
+        h ^= h >> 13;
+        Rgb([h as u8 | 0x20, (h >> 8) as u8 | 0x20, (h >> 16) as u8 | 0x20])
+    }
+}
+
+impl Shader for ObjectIdShader {
+    fn sees_media(&self) -> bool {
+        false
+    }
+
+    fn shade(&self, shade_cell: &ShadeCell, _: &Scene, _: Vec<Rgb<u8>>) -> Rgb<u8> {
+        let &ShadeCell(_, _, _, _, strike) = shade_cell;
+        ObjectIdShader::colour(strike.object)
+    }
+}
+
+
+// How squarely each surface faces the viewer: white when seen head
+// on, black when seen edge on
+pub struct FacingRatioShader {}
+
+impl FacingRatioShader {
+    pub fn instance() -> FacingRatioShader {
+        FacingRatioShader {}
+    }
+}
+
+impl Shader for FacingRatioShader {
+    fn sees_media(&self) -> bool {
+        false
+    }
+
+    fn shade(&self, shade_cell: &ShadeCell, _: &Scene, _: Vec<Rgb<u8>>) -> Rgb<u8> {
+        let &ShadeCell(_, n, v, _, _) = shade_cell;
+        grey(n.dot(v).abs())
+    }
+}
+
+
+// The number of reflections traced to reach each point, stepping up
+// through a ramp of colours from blue for primary strikes. Reflected
+// colours come back weighted by the reflecting surface's reflectivity,
+// so deeper bounces only show through where surfaces reflect.
+pub struct BounceShader {
+    max_bounces: u8,
+}
+
+impl BounceShader {
+    pub fn new(max_bounces: u8) -> BounceShader {
+        BounceShader { max_bounces: max_bounces.max(1) }
+    }
+
+    pub fn colour(&self, bounces: u8) -> Rgb<u8> {
+        heat(bounces.min(self.max_bounces) as Float / self.max_bounces as Float)
+    }
+}
+
+impl Shader for BounceShader {
+    fn sees_media(&self) -> bool {
+        false
+    }
+
+    fn shade(&self, shade_cell: &ShadeCell, _: &Scene, influence: Vec<Rgb<u8>>) -> Rgb<u8> {
+        let &ShadeCell(_, _, _, _, strike) = shade_cell;
+        influence.into_iter().fold(self.colour(strike.bounces), |a, b| {
+            Rgb([a.data[0].max(b.data[0]), a.data[1].max(b.data[1]), a.data[2].max(b.data[2])])
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ray::{Ray, Shadable};
+    use shapes::Sphere;
+    use materials::Material;
+    use raytrace::Raytracer;
+    use scene::AmbientLight;
+    use medium::{Medium, Volume};
+
+    fn two_spheres() -> Scene {
+        Scene {
+            ambient_light: AmbientLight { colour: Rgb([0; 3]) },
+            objects: vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 5.0), 1.0)) as Box<Shadable>,
+                          Box::new(Sphere::simple(Vec3(3.0, 0.0, 5.0), 1.0)) as Box<Shadable>],
+            lights: vec![],
+            volumes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_normal_shader_maps_components_to_channels() {
+        let scene = two_spheres();
+        let cell = Ray::new(Vec3::zero(), Vec3(0.0, 0.0, 1.0)).trace(&scene.objects).unwrap();
+        assert!(NormalShader::instance().shade(&cell, &scene, vec![]) == Rgb([128, 128, 0]));
+    }
+
+    #[test]
+    fn test_depth_shader_fades_with_distance() {
+        let linear = DepthShader::linear(8.0);
+        let log = DepthShader::logarithmic(8.0);
+        assert!(linear.depth(4.0) == 0.5);
+        assert!(log.depth(4.0) > linear.depth(4.0));
+        assert!(linear.depth(100.0) == 1.0 && log.depth(0.0) == 0.0);
+
+        let scene = two_spheres();
+        let cell = Ray::new(Vec3::zero(), Vec3(0.0, 0.0, 1.0)).trace(&scene.objects).unwrap();
+        assert!(linear.shade(&cell, &scene, vec![]) == grey(0.5));
+    }
+
+    #[test]
+    fn test_object_id_shader_tells_objects_apart() {
+        let scene = two_spheres();
+        let shader = ObjectIdShader::instance();
+        let a = Ray::new(Vec3::zero(), Vec3(0.0, 0.0, 1.0)).trace(&scene.objects).unwrap();
+        let b = Ray::new(Vec3(3.0, 0.0, 0.0), Vec3(0.0, 0.0, 1.0)).trace(&scene.objects).unwrap();
+
+        assert!(shader.shade(&a, &scene, vec![]) == ObjectIdShader::colour(0));
+        assert!(shader.shade(&b, &scene, vec![]) == ObjectIdShader::colour(1));
+        assert!(ObjectIdShader::colour(0) != ObjectIdShader::colour(1));
+    }
+
+    #[test]
+    fn test_facing_ratio_is_one_head_on() {
+        let scene = two_spheres();
+        let cell = Ray::new(Vec3::zero(), Vec3(0.0, 0.0, 1.0)).trace(&scene.objects).unwrap();
+        assert!(FacingRatioShader::instance().shade(&cell, &scene, vec![]) == Rgb([255; 3]));
+    }
+
+    #[test]
+    fn test_bounce_shader_sees_reflections() {
+        let mirror = Material::new([0.0; 3], [0.0; 3], [0.0; 3], [1.0; 3], 1.0);
+        let scene = Scene {
+            ambient_light: AmbientLight { colour: Rgb([0; 3]) },
+            objects: vec![Box::new(Sphere::new(Vec3(0.0, 0.0, 5.0), 1.0, mirror))
+                              as Box<Shadable>,
+                          Box::new(Sphere::new(Vec3(0.0, 0.0, -5.0), 1.0, mirror))
+                              as Box<Shadable>],
+            lights: vec![],
+            volumes: Vec::new(),
+        };
+        let shader = BounceShader::new(2);
+        let tracer = Raytracer::from_shader(BounceShader::new(2));
+        let ray = Ray::new(Vec3::zero(), Vec3(0.0, 0.0, 1.0));
+
+        assert!(tracer.trace_to_depth(1, &ray, &scene) == Some(shader.colour(0)));
+        assert!(tracer.trace_to_depth(3, &ray, &scene) == Some(Rgb([255, 255, 255])));
+    }
+
+    #[test]
+    fn test_debug_shaders_see_through_fog() {
+        let fog = Medium {
+            absorption: [0.2; 3],
+            scattering: [0.2; 3],
+            anisotropy: 0.0,
+        };
+        let foggy = Scene { volumes: vec![Volume::everywhere(fog)], ..two_spheres() };
+        let tracer = Raytracer::from_shader(NormalShader::instance());
+        let ray = Ray::new(Vec3::zero(), Vec3(0.0, 0.0, 1.0));
+        assert!(tracer.trace_to_depth(1, &ray, &foggy) == Some(Rgb([128, 128, 0])));
+        assert!(tracer.trace_to_depth(1, &Ray::new(Vec3::zero(), Vec3(0.0, 1.0, 0.0)), &foggy)
+            .is_none());
+    }
+}
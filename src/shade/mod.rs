@@ -1,10 +1,26 @@
 // shade.rs - things that can be shaded, and how to shade them
 use image::{Rgb, Primitive};
+use precision::Float;
 use vector3d::Vec3;
 use algebra::InnerProductSpace;
 use shapes::Sphere;
 use scene::{Scene, AmbientLight, Light};
 use ray::{Ray, ShadeCell, Shadable};
+use packet::WIDTH;
+use medium;
+
+pub mod pbr;
+pub mod occlusion;
+pub mod debug;
+
+pub use self::pbr::CookTorranceShader;
+pub use self::occlusion::{AmbientOcclusion, AmbientOcclusionShader};
+pub use self::debug::{NormalShader, DepthShader, UvShader, ObjectIdShader, FacingRatioShader,
+                      BounceShader};
+
+// Emissive surfaces are sampled on a grid of this many points a side
+// when shading, each standing in as a point light
+pub const EMITTER_GRID: u32 = 4;
 use std::ops::{Add, Mul};
 
 pub fn red<T: Primitive>(c: Rgb<T>) -> T {
@@ -20,19 +36,140 @@ pub fn blue<T: Primitive>(c: Rgb<T>) -> T {
 }
 
 
+// The light leaving a point towards the viewer, split up by where it
+// came from. Values are on the scale of colour channels, so that 255
+// is full white, but may run past it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Lighting {
+    pub emission: [Float; 3],
+    pub ambient: [Float; 3],
+    pub diffuse: [Float; 3],
+    pub specular: [Float; 3],
+}
+
+impl Lighting {
+    pub fn dark() -> Lighting {
+        Lighting {
+            emission: [0.0; 3],
+            ambient: [0.0; 3],
+            diffuse: [0.0; 3],
+            specular: [0.0; 3],
+        }
+    }
+
+    pub fn total(&self) -> [Float; 3] {
+        let sum = |i: usize| self.emission[i] + self.ambient[i] + self.diffuse[i] + self.specular[i];
+        [sum(0), sum(1), sum(2)]
+    }
+}
+
+pub fn to_floats(c: Rgb<u8>) -> [Float; 3] {
+    [red(c) as Float, green(c) as Float, blue(c) as Float]
+}
+
 pub trait Shader {
     fn shade(&self, shade_cell : &ShadeCell, scene: &Scene, influence : Vec<Rgb<u8>>)
              -> Rgb<u8>;
+
+    // The local lighting at a cell, as for shade without influence,
+    // split into its parts. Shaders which cannot tell the parts apart
+    // report it all as diffuse.
+    fn lighting(&self, shade_cell: &ShadeCell, scene: &Scene) -> Lighting {
+        Lighting { diffuse: to_floats(self.shade(shade_cell, scene, vec![])), ..Lighting::dark() }
+    }
+
+    // The part of the lighting due to the given lights alone, taking
+    // them to be unobstructed. Shaders which do not model lights give
+    // no light here.
+    fn lighting_from(&self, _shade_cell: &ShadeCell, _lights: &[Light]) -> Lighting {
+        Lighting::dark()
+    }
+
+    // Whether fog and other media between the viewer and what a ray
+    // strikes dim and add to its colour
+    fn sees_media(&self) -> bool {
+        true
+    }
+
+    // Shade the cells struck by a packet of coherent rays (at most
+    // packet::WIDTH of them) together. Shaders which cast shadow rays
+    // can override this to cast them as packets.
+    fn shade_packet(&self, shade_cells: &[ShadeCell], scene: &Scene,
+                    influences: Vec<Vec<Rgb<u8>>>) -> Vec<Rgb<u8>> {
+        shade_cells.iter()
+            .zip(influences.into_iter())
+            .map(|(sc, influence)| self.shade(sc, scene, influence))
+            .collect()
+    }
 }
 
-pub struct PhongShader {}
+// Shaders chosen while running, as by name, shade as the one they hold
+impl<S: Shader + ?Sized> Shader for Box<S> {
+    fn shade(&self, shade_cell: &ShadeCell, scene: &Scene, influence: Vec<Rgb<u8>>)
+             -> Rgb<u8> {
+        (**self).shade(shade_cell, scene, influence)
+    }
+
+    fn lighting(&self, shade_cell: &ShadeCell, scene: &Scene) -> Lighting {
+        (**self).lighting(shade_cell, scene)
+    }
+
+    fn lighting_from(&self, shade_cell: &ShadeCell, lights: &[Light]) -> Lighting {
+        (**self).lighting_from(shade_cell, lights)
+    }
+
+    fn sees_media(&self) -> bool {
+        (**self).sees_media()
+    }
+
+    fn shade_packet(&self, shade_cells: &[ShadeCell], scene: &Scene,
+                    influences: Vec<Vec<Rgb<u8>>>) -> Vec<Rgb<u8>> {
+        (**self).shade_packet(shade_cells, scene, influences)
+    }
+}
+
+pub const SHADER_NAMES: [&'static str; 9] =
+    ["phong", "pbr", "ao", "normal", "depth", "uv", "object-id", "facing", "bounces"];
+
+// A shader by its name in SHADER_NAMES, with the settings the command
+// line uses. The bounce shader counts up to max_bounces.
+pub fn by_name(name: &str, max_bounces: u8) -> Option<Box<Shader + Send + Sync>> {
+    Some(match name {
+        "phong" => Box::new(PhongShader::instance()),
+        "pbr" => Box::new(CookTorranceShader::instance()),
+        "ao" => Box::new(AmbientOcclusionShader::new(AmbientOcclusion::new(16, 10.0))),
+        "normal" => Box::new(NormalShader::instance()),
+        "depth" => Box::new(DepthShader::linear(20.0)),
+        "uv" => Box::new(UvShader::checker(8)),
+        "object-id" => Box::new(ObjectIdShader::instance()),
+        "facing" => Box::new(FacingRatioShader::instance()),
+        "bounces" => Box::new(BounceShader::new(max_bounces)),
+        _ => return None,
+    })
+}
+
+pub struct PhongShader {
+    occlusion: Option<AmbientOcclusion>,
+}
 
 impl PhongShader {
     pub fn instance() -> PhongShader {
-        return PhongShader {}
+        return PhongShader { occlusion: None }
+    }
+
+    // Darken the ambient term where nearby geometry blocks it
+    pub fn with_ambient_occlusion(occlusion: AmbientOcclusion) -> PhongShader {
+        PhongShader { occlusion: Some(occlusion) }
+    }
+
+    fn ambient_visibility(&self, shade_cell: &ShadeCell, scene: &Scene) -> Float {
+        let &ShadeCell(p, n, v, _, strike) = shade_cell;
+        self.occlusion.map_or(1.0, |ao| {
+            ao.visibility(p, occlusion::facing_normal(n, v), strike.time, &scene.objects)
+        })
     }
     
-    fn dot(x: Vec3, y: Vec3) -> f64 {
+    fn dot(x: Vec3, y: Vec3) -> Float {
         x.dot(y).max(0.0)
     }
 
@@ -40,64 +177,83 @@ impl PhongShader {
         &s.ambient_light
     }
 
-    fn diffuse_at_shade_cell(shade_cell: &ShadeCell, scene: &Scene) -> Vec<Rgb<u8>> {
-        let &ShadeCell(p, n, _, _) = shade_cell;
-        scene.lights
-            .iter()
-            .filter(|l| l.illuminates(p, &scene.objects))
-            .map(|l| {
-                PhongShader::adjust_intensity(l.colour,
-                    PhongShader::dot((l.position - p).normalize(), n))
-            })
-            .collect()
+    // The lights reaching a cell, dimmed by any media on the way
+    fn visible_lights(&self, shade_cell: &ShadeCell, scene: &Scene) -> Vec<Light> {
+        let &ShadeCell(p, _, _, _, strike) = shade_cell;
+        let lights = scene.visible_lights(p, strike.time, EMITTER_GRID);
+        if self.sees_media() {
+            medium::dim_lights(&scene.volumes, p, strike.time, lights)
+        } else {
+            lights
+        }
     }
 
-    fn specular_at_shade_cell(shade_cell: &ShadeCell, scene: &Scene) -> Vec<Rgb<u8>> {
-        let &ShadeCell(p, n, v, m) = shade_cell;
-        let shininess = m.shine();
+    // The diffuse and specular light from the given lights, summed as
+    // floats so that many faint lights are not each rounded away
+    fn light_from_lights(shade_cell: &ShadeCell, lights: &[Light]) -> Lighting {
+        let &ShadeCell(p, n, v, m, _) = shade_cell;
+        let mut lighting = Lighting::dark();
+        lighting.diffuse = PhongShader::sum_lights(&lights, |l| {
+            PhongShader::dot((l.position - p).normalize(), n)
+        }, m.diffusive_reflectivity());
+        lighting.specular = PhongShader::sum_lights(&lights, |l| {
+            PhongShader::dot(n.reflect((p - l.position).normalize()), -v).powf(m.shine())
+        }, m.specular_reflectiviy());
+        lighting
+    }
 
-        let from = |light: &Light| (p - light.position).normalize();
-        let reflection_from = |light: &Light| n.reflect(from(light));
+    // Sum the light from each light, weighted by a factor depending on
+    // the light and by the material's reflectivity
+    fn sum_lights<F>(lights: &[Light], weight: F, reflectivity: [Float; 3]) -> [Float; 3]
+        where F: Fn(&Light) -> Float
+    {
+        let mut total = [0.0; 3];
+        for light in lights.iter() {
+            let w = weight(light);
+            let c = light.radiance();
+            for i in 0..3 {
+                total[i] += w * c[i] * reflectivity[i];
+            }
+        }
+        total
+    }
 
-        scene.lights
-            .iter()
-            .filter(|l| l.illuminates(p, &scene.objects))
-            .map(|l| {
-                PhongShader::adjust_intensity(l.colour,
-                    PhongShader::dot(reflection_from(l), -1.0 * v).powf(shininess))
-            })
-            .collect()
+    fn local_shade(&self, shade_cell : &ShadeCell, scene: &Scene) -> Rgb<u8> {
+        PhongShader::local_shade_with_lights(shade_cell, scene,
+                                             &self.visible_lights(shade_cell, scene),
+                                             self.ambient_visibility(shade_cell, scene))
     }
 
-    fn local_shade(shade_cell : &ShadeCell, scene: &Scene) -> Rgb<u8> {
-        let &ShadeCell(_,_,_,m) = shade_cell;
+    fn local_shade_with_lights(shade_cell : &ShadeCell, scene: &Scene, lights: &[Light],
+                               ambient_visibility: Float) -> Rgb<u8> {
+        let &ShadeCell(_,_,_,m,_) = shade_cell;
         let ambience = m.ambient_refletivity();
-        let diffusivity = m.diffusive_reflectivity();
-        let specularity = m.specular_reflectiviy();
-
-        let a : Rgb<u8> = PhongShader::adjust_intensity_piecewise(
-            PhongShader::ambient_light(scene).colour, ambience);
 
-        let b = PhongShader::diffuse_at_shade_cell(shade_cell, scene).into_iter()
-            .map(|c| PhongShader::adjust_intensity_piecewise(c, diffusivity))
-            .fold(a, PhongShader::add_illumination);
-
-        PhongShader::specular_at_shade_cell(shade_cell, scene).into_iter()
-            .map(|c| PhongShader::adjust_intensity_piecewise(c, specularity))
-            .fold(b, PhongShader::add_illumination)
+        let a : Rgb<u8> = PhongShader::add_illumination(
+            PhongShader::adjust_intensity_piecewise(
+                PhongShader::adjust_intensity(PhongShader::ambient_light(scene).colour,
+                                              ambient_visibility),
+                ambience),
+            PhongShader::adjust_intensity_piecewise(Rgb([255; 3]), m.emission()));
+
+        let lit = PhongShader::light_from_lights(shade_cell, lights);
+        let channel = |i: usize| {
+            (lit.diffuse[i] + lit.specular[i]).round().max(0.0).min(255.0) as u8
+        };
+        PhongShader::add_illumination(a, Rgb([channel(0), channel(1), channel(2)]))
     }
 
 
-    fn adjust_intensity_piecewise(c : Rgb<u8>, adjust_components : [f64 ; 3]) -> Rgb<u8> {
-        let approx = |col: f64| col.floor() as u8;
-        let adjust = |f: &Fn(Rgb<u8>) -> u8, a : f64| approx(a * (f(c) as f64));
+    fn adjust_intensity_piecewise(c : Rgb<u8>, adjust_components : [Float ; 3]) -> Rgb<u8> {
+        let approx = |col: Float| col.floor() as u8;
+        let adjust = |f: &Fn(Rgb<u8>) -> u8, a : Float| approx(a * (f(c) as Float));
 
         Rgb([adjust(&red, adjust_components[0]),
              adjust(&green, adjust_components[1]),
              adjust(&blue, adjust_components[2])])
     }
 
-    fn adjust_intensity(c: Rgb<u8>, i: f64) -> Rgb<u8> {
+    fn adjust_intensity(c: Rgb<u8>, i: Float) -> Rgb<u8> {
         let adjust_components = [i ; 3];
         PhongShader::adjust_intensity_piecewise(c, adjust_components)
     }
@@ -113,10 +269,79 @@ impl PhongShader {
 impl Shader for PhongShader {
     fn shade(&self, shade_cell : &ShadeCell, scene: &Scene, influence : Vec<Rgb<u8>>)
                  -> Rgb<u8> {
-        let i = PhongShader::local_shade(shade_cell, scene);
+        let i = self.local_shade(shade_cell, scene);
         influence.into_iter()
             .fold(i, PhongShader::add_illumination)
     }
+
+    fn lighting(&self, shade_cell: &ShadeCell, scene: &Scene) -> Lighting {
+        let &ShadeCell(_, _, _, m, _) = shade_cell;
+        let lights = self.visible_lights(shade_cell, scene);
+        let visibility = self.ambient_visibility(shade_cell, scene);
+        let ambient = to_floats(PhongShader::ambient_light(scene).colour);
+        let (ambience, emission) = (m.ambient_refletivity(), m.emission());
+
+        let mut lighting = self.lighting_from(shade_cell, &lights);
+        for i in 0..3 {
+            lighting.emission[i] = 255.0 * emission[i];
+            lighting.ambient[i] = ambient[i] * visibility * ambience[i];
+        }
+        lighting
+    }
+
+    fn lighting_from(&self, shade_cell: &ShadeCell, lights: &[Light]) -> Lighting {
+        PhongShader::light_from_lights(shade_cell, lights)
+    }
+
+    // Test every cell against each light with a single shadow packet.
+    // A partly filled packet is shaded cell by cell instead, so that no
+    // shadow rays are cast for padding.
+    fn shade_packet(&self, shade_cells: &[ShadeCell], scene: &Scene,
+                    influences: Vec<Vec<Rgb<u8>>>) -> Vec<Rgb<u8>> {
+        if shade_cells.len() < WIDTH {
+            return shade_cells.iter()
+                .zip(influences)
+                .map(|(sc, influence)| self.shade(sc, scene, influence))
+                .collect();
+        }
+
+        let cell = |i: usize| shade_cells[i];
+        let points = [cell(0).0, cell(1).0, cell(2).0, cell(3).0];
+        let times = [cell(0).4.time, cell(1).4.time, cell(2).4.time, cell(3).4.time];
+
+        let mut visible: Vec<Vec<Light>> = vec![Vec::new(); shade_cells.len()];
+        for light in scene.lights.iter() {
+            let lit = light.illuminates_packet(points, times, &scene.objects);
+            for (i, v) in visible.iter_mut().enumerate().take(WIDTH) {
+                if lit[i] {
+                    v.push(light.clone());
+                }
+            }
+        }
+
+        // Stand-in lights for emissive surfaces differ from point to
+        // point, so their shadow rays are cast singly
+        for (i, v) in visible.iter_mut().enumerate() {
+            let (p, time) = (points[i], times[i]);
+            v.extend(scene.emitter_lights(p, EMITTER_GRID)
+                .into_iter()
+                .filter(|l| l.illuminates(p, time, &scene.objects)));
+            if self.sees_media() {
+                *v = medium::dim_lights(&scene.volumes, p, time, v.split_off(0));
+            }
+        }
+
+        shade_cells.iter()
+            .zip(visible.iter())
+            .zip(influences.into_iter())
+            .map(|((sc, lights), influence)| {
+                influence.into_iter()
+                    .fold(PhongShader::local_shade_with_lights(
+                              sc, scene, lights, self.ambient_visibility(sc, scene)),
+                          PhongShader::add_illumination)
+            })
+            .collect()
+    }
 }
 
 
@@ -153,29 +378,24 @@ fn test_phong_dot_returns_zero_on_negative() {
 #[test]
 fn test_gets_diffuse_at_shade_cell() {
     let a_colour = Rgb([255 as u8; 3]);
-    let light = Light {
-        position: Vec3(2.0, 0.0, 0.0),
-        colour: a_colour,
-    };
+    let light = Light::new(Vec3(2.0, 0.0, 0.0), a_colour);
 
     let sphere = Box::new(Sphere::simple(Vec3(0.0, 0.0, 0.0), 1.0)) as Box<Shadable>;
     let scene_objects = vec![sphere];
 
     let ambient = AmbientLight { colour: a_colour };
 
-    let scene = Scene {
-        ambient_light: ambient,
-        objects: scene_objects,
-        lights: vec![light],
-    };
+    let scene = Scene::new(ambient, scene_objects, vec![light]);
 
     let shade_cell = Ray::new(Vec3(2.0, 0.0, 0.0), Vec3(-1.0, 0.0, 0.0))
         .trace(&scene.objects)
         .unwrap();
 
-    let diffuse = PhongShader::diffuse_at_shade_cell(&shade_cell, &scene);
+    let lights = PhongShader::instance().visible_lights(&shade_cell, &scene);
+    let diffuse = PhongShader::light_from_lights(&shade_cell, &lights).diffuse;
     print!("{:?}", diffuse);
-    let expected = vec![a_colour];
+    let d = shade_cell.3.diffusive_reflectivity();
+    let expected = [255.0 * d[0], 255.0 * d[1], 255.0 * d[2]];
 
     assert!(expected == diffuse);
 }
@@ -186,5 +406,134 @@ mod tests {
     use super::*;
     use image::Rgb;
     use vector3d::Vec3;
+    use shapes::Quad;
+    use materials::Material;
+    use medium::{Medium, Volume};
+
+    #[test]
+    fn test_ambient_occlusion_darkens_the_ambient_term() {
+        let flat = Material::new([0.0; 3], [0.0; 3], [1.0; 3], [0.0; 3], 1.0);
+        let floor = Box::new(Quad::new(Vec3(-5.0, -5.0, 0.0), Vec3(10.0, 0.0, 0.0),
+                                       Vec3(0.0, 10.0, 0.0), flat).unwrap()) as Box<Shadable>;
+        let wall = Box::new(Quad::new(Vec3(-5.0, 0.1, 0.0), Vec3(10.0, 0.0, 0.0),
+                                      Vec3(0.0, 0.0, 5.0), flat).unwrap()) as Box<Shadable>;
+        let scene = Scene {
+            ambient_light: AmbientLight { colour: Rgb([200; 3]) },
+            objects: vec![floor, wall],
+            lights: vec![],
+            volumes: Vec::new(),
+        };
+        let cell = Ray::new(Vec3(0.0, 0.0, 1.0), Vec3(0.0, 0.0, -1.0))
+            .trace(&scene.objects)
+            .unwrap();
+
+        let plain = PhongShader::instance().shade(&cell, &scene, vec![]);
+        let occluded = PhongShader::with_ambient_occlusion(AmbientOcclusion::new(64, 1.0))
+            .shade(&cell, &scene, vec![]);
+
+        assert!(plain == Rgb([200; 3]));
+        assert!(red(occluded) < red(plain));
+    }
 
+    #[test]
+    fn test_lighting_parts_add_up_to_the_shade() {
+        let light = Light::new(Vec3(3.0, 1.0, 0.0), Rgb([200, 150, 100]));
+        let scene = Scene {
+            ambient_light: AmbientLight { colour: Rgb([30; 3]) },
+            objects: vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 0.0), 1.0)) as Box<Shadable>],
+            lights: vec![light],
+            volumes: Vec::new(),
+        };
+        let cell = Ray::new(Vec3(3.0, 0.5, 0.0), Vec3(-1.0, 0.0, 0.0))
+            .trace(&scene.objects)
+            .unwrap();
+
+        let shader = PhongShader::instance();
+        let l = shader.lighting(&cell, &scene);
+        let shaded = to_floats(shader.shade(&cell, &scene, vec![]));
+        assert!(l.specular[0] > 0.0 && l.diffuse[0] > 0.0);
+        for i in 0..3 {
+            let total = l.emission[i] + l.ambient[i] + l.diffuse[i] + l.specular[i];
+            // The ambient and emitted parts are rounded down when
+            // shading, and the light from lights to the nearest
+            assert!(shaded[i] <= total + 0.5 && total < shaded[i] + 2.5);
+        }
+    }
+
+    #[test]
+    fn test_media_dim_the_light_reaching_surfaces() {
+        let light = Light::new(Vec3(3.0, 1.0, 0.0), Rgb([200, 150, 100]));
+        let clear = Scene::new(AmbientLight { colour: Rgb([0; 3]) },
+                               vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 0.0), 1.0))
+                                    as Box<Shadable>],
+                               vec![light.clone()]);
+        let murk = Medium { absorption: [0.1, 0.2, 0.3], scattering: [0.0; 3], anisotropy: 0.0 };
+        let foggy = Scene { volumes: vec![Volume::everywhere(murk)], ..clear };
+        let cell = Ray::new(Vec3(3.0, 0.5, 0.0), Vec3(-1.0, 0.0, 0.0))
+            .trace(&foggy.objects)
+            .unwrap();
+        let through = murk.transmittance((light.position - cell.0).norm());
+
+        let packed = vec![cell; WIDTH];
+        let unfogged = PhongShader::light_from_lights(&cell, &[light]);
+        let shaders: [Box<Shader>; 2] = [Box::new(PhongShader::instance()),
+                                         Box::new(CookTorranceShader::instance())];
+        for shader in shaders.iter() {
+            let fogged = shader.lighting(&cell, &foggy);
+            let plain = shader.lighting_from(&cell, &[foggy.lights[0].clone()]);
+            for i in 0..3 {
+                assert!((fogged.diffuse[i] - plain.diffuse[i] * through[i]).abs() < 1e-3);
+            }
+        }
+        let single = PhongShader::instance().shade(&cell, &foggy, vec![]);
+        let shaded = PhongShader::instance().shade_packet(&packed, &foggy, vec![vec![]; WIDTH]);
+        assert!(shaded.iter().all(|&c| c == single));
+        assert!(to_floats(single)[0] < unfogged.diffuse[0] + unfogged.specular[0]);
+    }
+
+    #[test]
+    fn test_emissive_surfaces_glow_and_light_others() {
+        let black = Material::new([0.0; 3], [0.0; 3], [0.0; 3], [0.0; 3], 1.0);
+        let panel = Box::new(Quad::new(Vec3(-1.0, -1.0, 0.0), Vec3(2.0, 0.0, 0.0),
+                                       Vec3(0.0, 2.0, 0.0),
+                                       black.with_emission([1.0, 0.0, 0.0], 1.0)).unwrap())
+            as Box<Shadable>;
+        let floor = Box::new(Quad::new(Vec3(-5.0, -5.0, 2.0), Vec3(0.0, 10.0, 0.0),
+                                       Vec3(10.0, 0.0, 0.0), Material::plain()).unwrap())
+            as Box<Shadable>;
+        let scene = Scene {
+            ambient_light: AmbientLight { colour: Rgb([0; 3]) },
+            objects: vec![panel, floor],
+            lights: vec![],
+            volumes: Vec::new(),
+        };
+        let shader = PhongShader::instance();
+
+        let glow = Ray::new(Vec3(0.0, 0.0, -1.0), Vec3(0.0, 0.0, 1.0)).trace(&scene.objects).unwrap();
+        assert!(shader.shade(&glow, &scene, vec![]) == Rgb([255, 0, 0]));
+
+        let lit = Ray::new(Vec3(0.0, 0.0, 5.0), Vec3(0.0, 0.0, -1.0)).trace(&scene.objects).unwrap();
+        let colour = shader.shade(&lit, &scene, vec![]);
+        assert!(red(colour) > 0 && green(colour) == 0 && blue(colour) == 0);
+    }
+
+    #[test]
+    fn test_shaders_by_name_shade_as_themselves() {
+        let scene = Scene {
+            ambient_light: AmbientLight { colour: Rgb([60; 3]) },
+            objects: vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 5.0), 1.0)) as Box<Shadable>],
+            lights: vec![Light::new(Vec3(0.0, -3.0, 0.0), Rgb([255; 3]))],
+            volumes: Vec::new(),
+        };
+        let cell = Ray::new(Vec3(0.0, 0.0, 0.0), Vec3(0.0, 0.0, 1.0))
+            .trace(&scene.objects)
+            .unwrap();
+        let phong = by_name("phong", 2).unwrap();
+        assert!(phong.shade(&cell, &scene, vec![]) ==
+                PhongShader::instance().shade(&cell, &scene, vec![]));
+        for name in SHADER_NAMES.iter() {
+            assert!(by_name(name, 2).is_some());
+        }
+        assert!(by_name("toon", 2).is_none());
+    }
 }
@@ -0,0 +1,151 @@
+// occlusion.rs - ray traced ambient occlusion
+use image::Rgb;
+use precision::Float;
+use vector3d::Vec3;
+use algebra::InnerProductSpace;
+use scene::Scene;
+use ray::{Ray, ShadeCell, Shadable, STRIKE_TOLERANCE};
+use warp::square_to_cosine_hemisphere;
+use shade::Shader;
+use sampler::{hash, radical_inverse, to_unit};
+use stats;
+
+// Estimates how much of the hemisphere above a point is open, by
+// casting cosine weighted rays and counting those which travel the
+// maximum distance without striking anything
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AmbientOcclusion {
+    samples: u32,
+    max_distance: Float,
+}
+
+impl AmbientOcclusion {
+    pub fn new(samples: u32, max_distance: Float) -> AmbientOcclusion {
+        AmbientOcclusion {
+            samples: samples.max(1),
+            max_distance: max_distance,
+        }
+    }
+
+    // The unoccluded fraction of the hemisphere about n at p, with
+    // one meaning fully open, with anything moving where it is at the
+    // given time
+    pub fn visibility(&self, p: Vec3, n: Vec3, time: Float, objects: &Vec<Box<Shadable>>)
+                      -> Float {
+        let (t, b) = n.orthonormal_basis();
+
+        // The sample pattern is fixed (a Hammersley set), but shifted
+        // by an amount depending on the point so that neighbouring
+        // points do not share their errors
+        let (du, dv) = AmbientOcclusion::shift(p);
+        let open = (0..self.samples)
+            .filter(|&i| {
+                let u = ((i as Float + 0.5) / self.samples as Float + du).fract();
+                let v = (radical_inverse(2, i) + dv).fract();
+                let Vec3(x, y, z) = square_to_cosine_hemisphere(u, v);
+                let ray = Ray::new(p, x * t + y * b + z * n).with_time(time);
+                !self.occluded(&ray, objects)
+            })
+            .count();
+
+        open as Float / self.samples as Float
+    }
+
+    fn occluded(&self, ray: &Ray, objects: &Vec<Box<Shadable>>) -> bool {
+        stats::record(|c| c.shadow_rays += 1);
+        objects.iter().any(|o| {
+            o.intersect(ray).map_or(false, |x| {
+                let d = (x - ray.origin).norm();
+                d >= STRIKE_TOLERANCE && d < self.max_distance
+            })
+        })
+    }
+
+    fn shift(p: Vec3) -> (Float, Float) {
+        let bits = |x: Float| (x as f32).to_bits();
+        let h = hash(&[bits(p.i()), bits(p.j()), bits(p.k())]);
+        (to_unit(h), to_unit(hash(&[h])))
+    }
+}
+
+// Orient a surface normal to face back along the incoming ray, since
+// only that side of the surface can be seen
+pub fn facing_normal(n: Vec3, view: Vec3) -> Vec3 {
+    if n.dot(view) > 0.0 { -n } else { n }
+}
+
+
+// A clay render: every surface is the same colour, darkened where
+// its surroundings block the sky
+pub struct AmbientOcclusionShader {
+    occlusion: AmbientOcclusion,
+    colour: Rgb<u8>,
+}
+
+impl AmbientOcclusionShader {
+    pub fn new(occlusion: AmbientOcclusion) -> AmbientOcclusionShader {
+        AmbientOcclusionShader {
+            occlusion: occlusion,
+            colour: Rgb([255; 3]),
+        }
+    }
+
+    pub fn with_colour(self, colour: Rgb<u8>) -> AmbientOcclusionShader {
+        AmbientOcclusionShader { colour: colour, ..self }
+    }
+}
+
+impl Shader for AmbientOcclusionShader {
+    // Reflections are ignored; clay does not shine
+    fn shade(&self, shade_cell: &ShadeCell, scene: &Scene, _: Vec<Rgb<u8>>) -> Rgb<u8> {
+        let &ShadeCell(p, n, v, _, strike) = shade_cell;
+        let k = self.occlusion.visibility(p, facing_normal(n, v), strike.time, &scene.objects);
+        let scale = |c: u8| (k * c as Float).floor() as u8;
+        let Rgb { data: [r, g, b] } = self.colour;
+        Rgb([scale(r), scale(g), scale(b)])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shapes::{Plane, Sphere};
+    use materials::Material;
+
+    #[test]
+    fn test_open_plane_is_unoccluded() {
+        let ao = AmbientOcclusion::new(64, 10.0);
+        let objects = vec![Box::new(Plane::new(Vec3(1.0, 0.0, 0.0), Vec3(0.0, 1.0, 0.0),
+                                               Material::plain()).unwrap()) as Box<Shadable>];
+        assert!(ao.visibility(Vec3::zero(), Vec3(0.0, 0.0, 1.0), 0.0, &objects) == 1.0);
+    }
+
+    #[test]
+    fn test_crease_is_darker_than_open_ground() {
+        let ao = AmbientOcclusion::new(256, 10.0);
+        let objects = vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 1.0), 1.0)) as Box<Shadable>];
+        let up = Vec3(0.0, 0.0, 1.0);
+        let near = ao.visibility(Vec3(1.0, 0.0, 0.0), up, 0.0, &objects);
+        let far = ao.visibility(Vec3(5.0, 0.0, 0.0), up, 0.0, &objects);
+
+        assert!(near < far);
+        assert!(near > 0.0);
+    }
+
+    #[test]
+    fn test_occlusion_respects_max_distance() {
+        let ao = AmbientOcclusion::new(64, 0.5);
+        let objects = vec![Box::new(Sphere::simple(Vec3(0.0, 0.0, 3.0), 1.0)) as Box<Shadable>];
+        assert!(ao.visibility(Vec3::zero(), Vec3(0.0, 0.0, 1.0), 0.0, &objects) == 1.0);
+    }
+
+    #[test]
+    fn test_cosine_hemisphere_directions_are_unit_and_upward() {
+        for &(u, v) in [(0.0, 0.0), (0.5, 0.5), (0.99, 0.1)].iter() {
+            let d = square_to_cosine_hemisphere(u, v);
+            assert!((d.norm() - 1.0).abs() < 1e-6);
+            assert!(d.k() >= 0.0);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+// pbr.rs - physically based shading with a microfacet BRDF
+use image::Rgb;
+use precision::Float;
+use precision::float::consts::PI;
+use vector3d::Vec3;
+use algebra::InnerProductSpace;
+use materials::MetallicRoughness;
+use scene::{Scene, Light};
+use ray::ShadeCell;
+use shade::{Shader, Lighting, EMITTER_GRID, to_floats};
+use medium;
+
+// A Cook-Torrance shader using the GGX normal distribution, Smith
+// masking-shadowing and Schlick's Fresnel approximation. Diffuse
+// light is Lambertian, weighted by the energy not reflected
+// specularly.
+//
+// Light colours are taken as the irradiance facing the light, so
+// that a white Lambertian surface matches the Phong shader.
+pub struct CookTorranceShader {}
+
+impl CookTorranceShader {
+    pub fn instance() -> CookTorranceShader {
+        CookTorranceShader {}
+    }
+
+    // GGX (Trowbridge-Reitz) distribution of microfacet normals
+    fn distribution(n_dot_h: Float, alpha: Float) -> Float {
+        let a2 = alpha * alpha;
+        let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+        a2 / (PI * d * d)
+    }
+
+    // Smith's separable masking-shadowing for GGX, per direction
+    fn smith_g1(n_dot_x: Float, alpha: Float) -> Float {
+        let a2 = alpha * alpha;
+        2.0 * n_dot_x / (n_dot_x + (a2 + (1.0 - a2) * n_dot_x * n_dot_x).sqrt())
+    }
+
+    fn fresnel(f0: Float, v_dot_h: Float) -> Float {
+        f0 + (1.0 - f0) * (1.0 - v_dot_h).max(0.0).powi(5)
+    }
+
+    // Outgoing radiance towards the viewer per unit of light colour,
+    // for each channel, with light arriving from direction l
+    #[cfg(test)]
+    fn reflectance(params: &MetallicRoughness, n: Vec3, v: Vec3, l: Vec3) -> [Float; 3] {
+        let (diffuse, specular) = CookTorranceShader::reflectance_parts(params, n, v, l);
+        [diffuse[0] + specular[0], diffuse[1] + specular[1], diffuse[2] + specular[2]]
+    }
+
+    // The diffuse and specular parts of the reflectance
+    fn reflectance_parts(params: &MetallicRoughness, n: Vec3, v: Vec3, l: Vec3)
+                         -> ([Float; 3], [Float; 3]) {
+        let n_dot_l = n.dot(l);
+        let n_dot_v = n.dot(v);
+        if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+            return ([0.0; 3], [0.0; 3]);
+        }
+
+        let h = (l + v).normalize();
+        let n_dot_h = n.dot(h).max(0.0);
+        let v_dot_h = v.dot(h).max(0.0);
+
+        // Perfectly smooth surfaces make the distribution singular
+        let alpha = (params.roughness * params.roughness).max(0.002);
+        let d = CookTorranceShader::distribution(n_dot_h, alpha);
+        let g = CookTorranceShader::smith_g1(n_dot_l, alpha) *
+                CookTorranceShader::smith_g1(n_dot_v, alpha);
+
+        let f0 = params.f0();
+        let diffuse = params.diffuse_colour();
+        let mut parts = ([0.0; 3], [0.0; 3]);
+        for i in 0..3 {
+            let f = CookTorranceShader::fresnel(f0[i], v_dot_h);
+            let specular = d * g * f / (4.0 * n_dot_l * n_dot_v);
+            let lambert = (1.0 - f) * diffuse[i] / PI;
+            (parts.0)[i] = PI * lambert * n_dot_l;
+            (parts.1)[i] = PI * specular * n_dot_l;
+        }
+        parts
+    }
+
+    fn to_colour(c: [Float; 3]) -> Rgb<u8> {
+        let approx = |x: Float| x.max(0.0).min(255.0).floor() as u8;
+        Rgb([approx(c[0]), approx(c[1]), approx(c[2])])
+    }
+}
+
+impl Shader for CookTorranceShader {
+    fn shade(&self, shade_cell: &ShadeCell, scene: &Scene, influence: Vec<Rgb<u8>>) -> Rgb<u8> {
+        let lighting = self.lighting(shade_cell, scene);
+        let mut total = [0.0; 3];
+        for i in 0..3 {
+            total[i] = lighting.emission[i] + lighting.ambient[i] + lighting.diffuse[i] +
+                       lighting.specular[i];
+        }
+
+        for c in influence.into_iter().map(to_floats) {
+            for i in 0..3 {
+                total[i] += c[i];
+            }
+        }
+
+        CookTorranceShader::to_colour(total)
+    }
+
+    fn lighting(&self, shade_cell: &ShadeCell, scene: &Scene) -> Lighting {
+        let &ShadeCell(p, _, _, m, strike) = shade_cell;
+        let params = m.pbr();
+
+        let ambient = to_floats(scene.ambient_light.colour);
+        let emission = m.emission();
+        let mut lights = scene.visible_lights(p, strike.time, EMITTER_GRID);
+        if self.sees_media() {
+            lights = medium::dim_lights(&scene.volumes, p, strike.time, lights);
+        }
+        let mut lighting = self.lighting_from(shade_cell, &lights);
+        for i in 0..3 {
+            lighting.emission[i] = 255.0 * emission[i];
+            lighting.ambient[i] = ambient[i] * params.base_colour[i];
+        }
+        lighting
+    }
+
+    fn lighting_from(&self, shade_cell: &ShadeCell, lights: &[Light]) -> Lighting {
+        let &ShadeCell(p, n, view, m, _) = shade_cell;
+        let params = m.pbr();
+        let v = -view;
+
+        let mut lighting = Lighting::dark();
+        for light in lights.iter() {
+            let l = (light.position - p).normalize();
+            let (diffuse, specular) = CookTorranceShader::reflectance_parts(&params, n, v, l);
+            let c = light.radiance();
+            for i in 0..3 {
+                lighting.diffuse[i] += diffuse[i] * c[i];
+                lighting.specular[i] += specular[i] * c[i];
+            }
+        }
+
+        lighting
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use materials::Material;
+
+    #[test]
+    fn test_ggx_distribution_is_normalised() {
+        // The projected area of the microfacets should integrate to
+        // one over the hemisphere
+        let alpha = 0.3;
+        let steps = 2000;
+        let dtheta = PI / 2.0 / (steps as Float);
+        let integral = (0..steps).fold(0.0, |acc, i| {
+            let theta = (i as Float + 0.5) * dtheta;
+            let d = CookTorranceShader::distribution(theta.cos(), alpha);
+            acc + d * theta.cos() * theta.sin() * dtheta * 2.0 * PI
+        });
+        assert!((integral - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_schlick_fresnel_limits() {
+        assert!(CookTorranceShader::fresnel(0.04, 1.0) == 0.04);
+        assert!(CookTorranceShader::fresnel(0.04, 0.0) == 1.0);
+    }
+
+    #[test]
+    fn test_reflectance_conserves_energy() {
+        // Integrating over every direction light could arrive from,
+        // even a white surface must not reflect more than it receives
+        let n = Vec3(0.0, 0.0, 1.0);
+        let v = Vec3(0.5, 0.0, (0.75 as Float).sqrt());
+        let (steps_theta, steps_phi) = (200, 400);
+        let dtheta = PI / 2.0 / (steps_theta as Float);
+        let dphi = 2.0 * PI / (steps_phi as Float);
+
+        for &roughness in [0.3, 0.6, 1.0].iter() {
+            let m = Material::metallic_roughness([1.0; 3], 0.0, roughness, 0.5);
+            let params = m.pbr();
+            let mut albedo = 0.0;
+            for i in 0..steps_theta {
+                let theta = (i as Float + 0.5) * dtheta;
+                for j in 0..steps_phi {
+                    let phi = (j as Float + 0.5) * dphi;
+                    let l = Vec3(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                    let r = CookTorranceShader::reflectance(&params, n, v, l);
+                    albedo += r[0] / PI * theta.sin() * dtheta * dphi;
+                }
+            }
+            assert!(albedo > 0.5 && albedo <= 1.0);
+        }
+    }
+
+    #[test]
+    fn test_no_light_from_below_the_surface() {
+        let n = Vec3(0.0, 0.0, 1.0);
+        let params = Material::plain().pbr();
+        let r = CookTorranceShader::reflectance(&params, n, n, Vec3(0.0, 0.0, -1.0));
+        assert!(r == [0.0; 3]);
+    }
+}
@@ -1,24 +1,35 @@
 // shapes.rs - descriptions of basic shapes
 use image::Rgb;
 
+use precision::{Float, float};
+use precision::float::consts::PI;
 use vector3d::Vec3;
 use algebra::InnerProductSpace;
-use ray::{Ray, Intersectable, Orientable, Shadable};
+use ray::{Ray, Intersectable, Orientable, Mappable, Shadable, Sampleable};
+use packet::{Lanes, RayPacket, PacketHits, Vec3Lanes, WIDTH};
+use bounds::Aabb;
 use materials::{Material, HasMaterial};
+use warp::{square_to_sphere, square_to_triangle};
+use stats::{self, Primitive};
+use error::{Error, Result};
+
+pub mod transformed;
+
+pub use self::transformed::{Transform, Transformed, Motion, Moving};
 
 pub struct Sphere {
     pub centre: Vec3,
-    pub radius: f64,
+    pub radius: Float,
     pub material: Material,
 }
 
 impl Sphere {
     // temporarily public - really only for testing
-    pub fn simple(c: Vec3, r: f64) -> Sphere {
+    pub fn simple(c: Vec3, r: Float) -> Sphere {
         Sphere::new(c, r, Material::plain())
     }
 
-    pub fn new(c: Vec3, r: f64, m: Material) -> Sphere {
+    pub fn new(c: Vec3, r: Float, m: Material) -> Sphere {
         Sphere {
             centre: c,
             radius: r,
@@ -30,12 +41,13 @@ impl Sphere {
 
 impl Intersectable for Sphere {
     fn intersect(&self, ray: &Ray) -> Option<Vec3> {
+        stats::count_tests(Primitive::Sphere, 1);
         let dir = ray.direction;
         let dist_origin = ray.origin - self.centre;
 
         let a = dir.dot(dir);
         let b = dir.dot(dist_origin);
-        let c = dist_origin.dot(dist_origin) - self.radius;
+        let c = dist_origin.dot(dist_origin) - self.radius * self.radius;
 
         let discriminant = b * b - a * c;
 
@@ -58,13 +70,39 @@ impl Intersectable for Sphere {
             None
         }
     }
+
+    // The same computation as above, four rays at a time
+    fn intersect_packet(&self, packet: &RayPacket) -> PacketHits {
+        stats::count_tests(Primitive::Sphere, WIDTH as u64);
+        let zero = Lanes::splat(0.0);
+        let dir = packet.direction;
+        let dist_origin = packet.origin.sub(Vec3Lanes::splat(self.centre));
+
+        let a = dir.dot(dir);
+        let b = dir.dot(dist_origin);
+        let c = dist_origin.dot(dist_origin) - Lanes::splat(self.radius * self.radius);
+
+        let discriminant = b * b - a * c;
+        let root = discriminant.max(zero).sqrt();
+        let t = Lanes::select((-b).gt(root), (-b - root) / a, (-b + root) / a);
+
+        packet.points_at(t, discriminant.ge(zero).and(t.gt(zero)))
+    }
 }
 
 
 impl Orientable for Sphere {
     fn normal(&self, v: Vec3) -> Vec3 {
         let w = v - self.centre;
-        1.0 / w.norm() * w
+        w.normalize()
+    }
+}
+
+// Longitude about the z axis, then angle down from the north pole
+impl Mappable for Sphere {
+    fn uv(&self, v: Vec3) -> (Float, Float) {
+        let Vec3(x, y, z) = self.normal(v);
+        (0.5 + y.atan2(x) / (2.0 * PI), z.max(-1.0).min(1.0).acos() / PI)
     }
 }
 
@@ -74,7 +112,23 @@ impl HasMaterial for Sphere {
     }
 }
 
-impl Shadable for Sphere {}
+impl Sampleable for Sphere {
+    fn area(&self) -> Float {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    // Archimedes: height along the axis is uniform by area
+    fn sample_surface(&self, u: Float, v: Float) -> (Vec3, Vec3) {
+        let n = square_to_sphere(u, v);
+        (self.centre + self.radius * n, n)
+    }
+}
+
+impl Shadable for Sphere {
+    fn surface(&self) -> Option<&Sampleable> {
+        Some(self)
+    }
+}
 
 
 pub struct Plane {
@@ -84,15 +138,22 @@ pub struct Plane {
 }
 
 impl Plane {
-    pub fn new(direction1: Vec3, direction2: Vec3, mat: Material) -> Plane {
+    // The directions must be finite and far enough from parallel to
+    // give the plane a normal
+    pub fn new(direction1: Vec3, direction2: Vec3, mat: Material) -> Result<Plane> {
         if direction1.norm() == 0.0 || direction2.norm() == 0.0 {
-            panic!("Plane defined with zero vectors!");
+            return Err(Error::DegeneratePlane);
         }
-        Plane {
-            d1: direction1.normalize(),
-            d2: direction2.normalize(),
-            material: mat,
+        let (d1, d2) = (direction1.normalize(), direction2.normalize());
+        let n = d1.cross(d2).norm();
+        if !(n.is_finite() && n > float::EPSILON.sqrt()) {
+            return Err(Error::DegeneratePlane);
         }
+        Ok(Plane {
+            d1: d1,
+            d2: d2,
+            material: mat,
+        })
     }
 }
 
@@ -104,14 +165,22 @@ impl Orientable for Plane {
 
 impl Intersectable for Plane {
     fn intersect(&self, ray : &Ray) -> Option<Vec3> {
+        stats::count_tests(Primitive::Plane, 1);
         let n = self.normal(Vec3::zero());
         let a = ray.origin;
         let b = ray.direction;
-        let t = -1.0 * a.dot(n) / b.dot(n);
+        let t = -a.dot(n) / b.dot(n);
         if t > 0.0 { Some(a + t*b) } else { None }
     }
 }
 
+// Distances along each of the plane's directions
+impl Mappable for Plane {
+    fn uv(&self, v: Vec3) -> (Float, Float) {
+        edge_coordinates(v, self.d1, self.d2)
+    }
+}
+
 impl HasMaterial for Plane {
     fn material(&self) -> &Material {
         &self.material
@@ -121,6 +190,388 @@ impl HasMaterial for Plane {
 impl Shadable for Plane {}
 
 
+pub struct Triangle {
+    a: Vec3,
+    e1: Vec3,
+    e2: Vec3,
+    normal: Vec3,
+    material: Material,
+}
+
+impl Triangle {
+    // Vertices are taken anticlockwise about the normal
+    pub fn new(a: Vec3, b: Vec3, c: Vec3, mat: Material) -> Result<Triangle> {
+        let (e1, e2) = (b - a, c - a);
+        let n = e1.cross(e2);
+        if n.norm() == 0.0 {
+            return Err(Error::DegenerateTriangle);
+        }
+        Ok(Triangle {
+            a: a,
+            e1: e1,
+            e2: e2,
+            normal: n.normalize(),
+            material: mat,
+        })
+    }
+
+    pub fn vertices(&self) -> (Vec3, Vec3, Vec3) {
+        (self.a, self.a + self.e1, self.a + self.e2)
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let (a, b, c) = self.vertices();
+        Aabb::new(a.min(b).min(c), a.max(b).max(c))
+    }
+
+    // Barycentric coordinates (u, v) of the projection of p onto the
+    // plane of the triangle, so that p is near a + u e1 + v e2
+    pub fn barycentric(&self, p: Vec3) -> (Float, Float) {
+        edge_coordinates(p - self.a, self.e1, self.e2)
+    }
+}
+
+// Coordinates (u, v) of the projection of w onto the plane spanned by
+// e1 and e2, so that w is near u e1 + v e2
+fn edge_coordinates(w: Vec3, e1: Vec3, e2: Vec3) -> (Float, Float) {
+    let (d11, d12, d22) = (e1.dot(e1), e1.dot(e2), e2.dot(e2));
+    let (w1, w2) = (w.dot(e1), w.dot(e2));
+    let det = d11 * d22 - d12 * d12;
+    ((d22 * w1 - d12 * w2) / det, (d11 * w2 - d12 * w1) / det)
+}
+
+impl Orientable for Triangle {
+    fn normal(&self, _: Vec3) -> Vec3 {
+        self.normal
+    }
+}
+
+impl Intersectable for Triangle {
+    // Moller-Trumbore: solve for the barycentric coordinates (u, v)
+    // of the strike and the distance t along the ray together
+    fn intersect(&self, ray: &Ray) -> Option<Vec3> {
+        stats::count_tests(Primitive::Triangle, 1);
+        let p = ray.direction.cross(self.e2);
+        let det = self.e1.dot(p);
+        if det.abs() < float::EPSILON {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = ray.origin - self.a;
+        let u = s.dot(p) * inv_det;
+        if u < 0.0 || u > 1.0 {
+            return None;
+        }
+
+        let q = s.cross(self.e1);
+        let v = ray.direction.dot(q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let t = self.e2.dot(q) * inv_det;
+        if t > 0.0 { Some(ray.origin + t * ray.direction) } else { None }
+    }
+
+    fn intersect_packet(&self, packet: &RayPacket) -> PacketHits {
+        stats::count_tests(Primitive::Triangle, WIDTH as u64);
+        let (zero, one) = (Lanes::splat(0.0), Lanes::splat(1.0));
+        let e1 = Vec3Lanes::splat(self.e1);
+        let e2 = Vec3Lanes::splat(self.e2);
+
+        let p = packet.direction.cross(e2);
+        let det = e1.dot(p);
+        let inv_det = one / det;
+        let s = packet.origin.sub(Vec3Lanes::splat(self.a));
+        let u = s.dot(p) * inv_det;
+        let q = s.cross(e1);
+        let v = packet.direction.dot(q) * inv_det;
+        let t = e2.dot(q) * inv_det;
+
+        let hit = det.abs().ge(Lanes::splat(float::EPSILON))
+            .and(u.ge(zero))
+            .and(u.le(one))
+            .and(v.ge(zero))
+            .and((u + v).le(one))
+            .and(t.gt(zero));
+        packet.points_at(t, hit)
+    }
+}
+
+impl Mappable for Triangle {
+    fn uv(&self, v: Vec3) -> (Float, Float) {
+        self.barycentric(v)
+    }
+}
+
+impl HasMaterial for Triangle {
+    fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
+impl Sampleable for Triangle {
+    fn area(&self) -> Float {
+        self.e1.cross(self.e2).norm() / 2.0
+    }
+
+    // Folding the square onto the triangle with a square root keeps
+    // the samples uniform
+    fn sample_surface(&self, u: Float, v: Float) -> (Vec3, Vec3) {
+        let (b1, b2) = square_to_triangle(u, v);
+        (self.a + b1 * self.e1 + b2 * self.e2, self.normal)
+    }
+}
+
+impl Shadable for Triangle {
+    fn surface(&self) -> Option<&Sampleable> {
+        Some(self)
+    }
+}
+
+
+// A parallelogram with one corner at the given point, spanned by two
+// edges. Its normal follows the right hand rule from edge1 to edge2.
+pub struct Quad {
+    corner: Vec3,
+    e1: Vec3,
+    e2: Vec3,
+    normal: Vec3,
+    material: Material,
+}
+
+impl Quad {
+    pub fn new(corner: Vec3, edge1: Vec3, edge2: Vec3, mat: Material) -> Result<Quad> {
+        let n = edge1.cross(edge2);
+        if n.norm() == 0.0 {
+            return Err(Error::DegenerateQuad);
+        }
+        Ok(Quad {
+            corner: corner,
+            e1: edge1,
+            e2: edge2,
+            normal: n.normalize(),
+            material: mat,
+        })
+    }
+}
+
+impl Orientable for Quad {
+    fn normal(&self, _: Vec3) -> Vec3 {
+        self.normal
+    }
+}
+
+impl Intersectable for Quad {
+    // As for triangles, but the coordinates along each edge may
+    // independently run the full length of the edge
+    fn intersect(&self, ray: &Ray) -> Option<Vec3> {
+        stats::count_tests(Primitive::Quad, 1);
+        let p = ray.direction.cross(self.e2);
+        let det = self.e1.dot(p);
+        if det.abs() < float::EPSILON {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = ray.origin - self.corner;
+        let u = s.dot(p) * inv_det;
+        let q = s.cross(self.e1);
+        let v = ray.direction.dot(q) * inv_det;
+        if u < 0.0 || u > 1.0 || v < 0.0 || v > 1.0 {
+            return None;
+        }
+
+        let t = self.e2.dot(q) * inv_det;
+        if t > 0.0 { Some(ray.origin + t * ray.direction) } else { None }
+    }
+}
+
+// Fractions of the way along each edge
+impl Mappable for Quad {
+    fn uv(&self, v: Vec3) -> (Float, Float) {
+        edge_coordinates(v - self.corner, self.e1, self.e2)
+    }
+}
+
+impl HasMaterial for Quad {
+    fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
+impl Sampleable for Quad {
+    fn area(&self) -> Float {
+        self.e1.cross(self.e2).norm()
+    }
+
+    fn sample_surface(&self, u: Float, v: Float) -> (Vec3, Vec3) {
+        (self.corner + u * self.e1 + v * self.e2, self.normal)
+    }
+}
+
+impl Shadable for Quad {
+    fn surface(&self) -> Option<&Sampleable> {
+        Some(self)
+    }
+}
+
+
+// A triangle mesh sharing a single material. Faces index into the
+// vertex list, and are wound anticlockwise about their normals.
+pub struct Mesh {
+    triangles: Vec<Triangle>,
+    // Running totals of face area, for sampling faces by area
+    cumulative_area: Vec<Float>,
+    // The box around every face, which rays must enter to strike any
+    bounds: Option<Aabb>,
+    material: Material,
+}
+
+impl Mesh {
+    pub fn new(vertices: Vec<Vec3>, faces: Vec<[usize; 3]>, mat: Material) -> Result<Mesh> {
+        let mut triangles = Vec::with_capacity(faces.len());
+        for face in faces.iter() {
+            for &index in face.iter() {
+                if index >= vertices.len() {
+                    return Err(Error::MeshIndex(index));
+                }
+            }
+            triangles.push(Triangle::new(vertices[face[0]], vertices[face[1]], vertices[face[2]],
+                                         mat)?);
+        }
+
+        let cumulative_area = triangles.iter()
+            .scan(0.0, |total, t| {
+                *total += t.area();
+                Some(*total)
+            })
+            .collect();
+        let bounds = triangles.iter().map(Triangle::bounds).fold(None, |acc, b| {
+            Some(acc.map_or(b, |a: Aabb| a.union(b)))
+        });
+
+        Ok(Mesh {
+            triangles: triangles,
+            cumulative_area: cumulative_area,
+            bounds: bounds,
+            material: mat,
+        })
+    }
+
+    pub fn triangles(&self) -> &[Triangle] {
+        &self.triangles
+    }
+
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.bounds
+    }
+
+    // The nearer to the origin of the best hit so far and another
+    fn nearer(origin: Vec3, best: Option<Vec3>, hit: Option<Vec3>) -> Option<Vec3> {
+        match (best, hit) {
+            (Some(q), Some(p)) if (p - origin).norm() < (q - origin).norm() => Some(p),
+            (None, hit) => hit,
+            (best, _) => best,
+        }
+    }
+
+    // The face whose plane passes closest to the point, among the
+    // faces containing it
+    fn face_at(&self, v: Vec3) -> Option<&Triangle> {
+        let tolerance = 0.0001;
+        let distance = |t: &Triangle| (v - t.a).dot(t.normal).abs();
+        self.triangles
+            .iter()
+            .filter(|t| {
+                let (a, b) = t.barycentric(v);
+                a >= -tolerance && b >= -tolerance && a + b <= 1.0 + tolerance
+            })
+            .fold(None, |best: Option<&Triangle>, t| {
+                match best {
+                    Some(b) if distance(b) <= distance(t) => Some(b),
+                    _ => Some(t),
+                }
+            })
+    }
+}
+
+impl Orientable for Mesh {
+    fn normal(&self, v: Vec3) -> Vec3 {
+        self.face_at(v).map_or(Vec3(0.0, 0.0, 1.0), |t| t.normal)
+    }
+}
+
+// Meshes carry no texture coordinates, so each face is mapped on its
+// own by barycentric coordinates
+impl Mappable for Mesh {
+    fn uv(&self, v: Vec3) -> (Float, Float) {
+        self.face_at(v).map_or((0.0, 0.0), |t| t.barycentric(v))
+    }
+}
+
+impl Intersectable for Mesh {
+    // Rays which miss the mesh's box are not tested against its faces
+    fn intersect(&self, ray: &Ray) -> Option<Vec3> {
+        if self.bounds.map_or(true, |b| b.intersect(ray).is_none()) {
+            return None;
+        }
+        self.triangles.iter()
+            .fold(None, |best, t| Mesh::nearer(ray.origin, best, t.intersect(ray)))
+    }
+
+    fn intersect_packet(&self, packet: &RayPacket) -> PacketHits {
+        let live = match self.bounds {
+            Some(b) => b.intersect_packet(packet),
+            None => return [None; WIDTH],
+        };
+        if !live.any() {
+            return [None; WIDTH];
+        }
+        self.triangles.iter().fold([None; WIDTH], |mut best, t| {
+            let hits = t.intersect_packet(packet);
+            for (i, b) in best.iter_mut().enumerate().filter(|&(i, _)| live.lane(i)) {
+                *b = Mesh::nearer(packet.origin.lane(i), *b, hits[i]);
+            }
+            best
+        })
+    }
+}
+
+impl HasMaterial for Mesh {
+    fn material(&self) -> &Material {
+        &self.material
+    }
+}
+
+impl Sampleable for Mesh {
+    fn area(&self) -> Float {
+        self.cumulative_area.last().cloned().unwrap_or(0.0)
+    }
+
+    // Pick a face with probability proportional to its area, then
+    // stretch u back over the unit interval to sample within it
+    fn sample_surface(&self, u: Float, v: Float) -> (Vec3, Vec3) {
+        let target = u * self.area();
+        let i = self.cumulative_area
+            .iter()
+            .position(|&a| a >= target)
+            .unwrap_or(self.triangles.len() - 1);
+        let start = if i == 0 { 0.0 } else { self.cumulative_area[i - 1] };
+        let width = self.cumulative_area[i] - start;
+        let u_face = if width > 0.0 { ((target - start) / width).max(0.0).min(1.0) } else { 0.0 };
+        self.triangles[i].sample_surface(u_face, v)
+    }
+}
+
+impl Shadable for Mesh {
+    fn surface(&self) -> Option<&Sampleable> {
+        if self.triangles.is_empty() { None } else { Some(self) }
+    }
+}
+
+
 #[test]
 fn test_sphere_orientability() {
     let s = Sphere::simple(Vec3::zero(), 1.0);
@@ -134,6 +585,7 @@ fn test_intersection_of_ray_and_sphere() {
     let ray = Ray {
         origin: Vec3::zero(),
         direction: Vec3(1.0, 0.0, 0.0),
+        time: 0.0,
     };
     let expected = Some(Vec3(1.0, 0.0, 0.0));
     assert!(expected == sphere.intersect(&ray));
@@ -145,6 +597,7 @@ fn test_intersection_of_ray_and_sphere_behind() {
     let ray = Ray {
         origin: Vec3(2.0, 0.0, 0.0),
         direction: Vec3(1.0, 0.0, 0.0),
+        time: 0.0,
     };
     let expected = None;
     assert!(expected == sphere.intersect(&ray));
@@ -152,7 +605,7 @@ fn test_intersection_of_ray_and_sphere_behind() {
 
 #[test]
 fn test_orientability_of_plane() {
-    let plane = Plane::new(Vec3(2.0, 0.0, 0.0), Vec3(0.0, 2.0, 0.0), Material::plain());
+    let plane = Plane::new(Vec3(2.0, 0.0, 0.0), Vec3(0.0, 2.0, 0.0), Material::plain()).unwrap();
     let arbitrary_vector = Vec3(9.9, 23.2, 141.2);
     let expected = Vec3(0.0, 0.0, 1.0);
     assert!(expected == plane.normal(arbitrary_vector));
@@ -160,9 +613,148 @@ fn test_orientability_of_plane() {
 
 #[test]
 fn test_plane_intesectability() {
-    let plane = Plane::new(Vec3(2.0, 0.0, 0.0), Vec3(0.0, 2.0, 0.0), Material::plain());
+    let plane = Plane::new(Vec3(2.0, 0.0, 0.0), Vec3(0.0, 2.0, 0.0), Material::plain()).unwrap();
     let ray = Ray { origin : Vec3(0.0, 1.0, 1.0),
-                    direction : Vec3(0.0, -1.0, -1.0) };
+                    direction : Vec3(0.0, -1.0, -1.0),
+                    time : 0.0 };
     let expected = Some(Vec3::zero());
     assert!(expected == plane.intersect(&ray));
 }
+
+#[test]
+fn test_plane_rejects_zero_vectors() {
+    let plane = Plane::new(Vec3::zero(), Vec3(0.0, 2.0, 0.0), Material::plain());
+    assert!(plane.is_err());
+    assert!(Plane::new(Vec3(1.0, 0.0, 0.0), Vec3(-3.0, 0.0, 0.0), Material::plain()).is_err());
+    let nan = 0.0 / 0.0;
+    assert!(Plane::new(Vec3(1.0, nan, 0.0), Vec3(0.0, 1.0, 0.0), Material::plain()).is_err());
+}
+
+#[test]
+fn test_intersection_of_ray_and_non_unit_sphere() {
+    let sphere = Sphere::simple(Vec3::zero(), 2.0);
+    let ray = Ray {
+        origin: Vec3(5.0, 0.0, 0.0),
+        direction: Vec3(-1.0, 0.0, 0.0),
+        time: 0.0,
+    };
+    assert!(Some(Vec3(2.0, 0.0, 0.0)) == sphere.intersect(&ray));
+}
+
+// Spheres reach as far as their radius rather than its square root,
+// whether rays are traced one by one or in packets
+#[test]
+fn test_sphere_edges_lie_at_the_radius() {
+    let along_z = |x: Float| Ray::new(Vec3(x, 0.0, -5.0), Vec3(0.0, 0.0, 1.0));
+    let cases = [(2.0, 1.9, true), (2.0, 2.1, false), (0.5, 0.45, true), (0.5, 0.6, false)];
+    for &(radius, x, hit) in cases.iter() {
+        let sphere = Sphere::simple(Vec3::zero(), radius);
+        let rays = [along_z(x); WIDTH];
+        assert!(sphere.intersect(&rays[0]).is_some() == hit);
+        assert!(sphere.intersect_packet(&RayPacket::new(rays))[0].is_some() == hit);
+    }
+}
+
+#[test]
+fn test_intersection_of_ray_and_triangle() {
+    let triangle = Triangle::new(Vec3(0.0, 0.0, 1.0), Vec3(1.0, 0.0, 1.0), Vec3(0.0, 1.0, 1.0),
+                                 Material::plain()).unwrap();
+    let hit = Ray::new(Vec3(0.25, 0.25, 0.0), Vec3(0.0, 0.0, 1.0));
+    let miss = Ray::new(Vec3(0.75, 0.75, 0.0), Vec3(0.0, 0.0, 1.0));
+
+    assert!(triangle.intersect(&hit) == Some(Vec3(0.25, 0.25, 1.0)));
+    assert!(triangle.intersect(&miss).is_none());
+    assert!(triangle.normal(Vec3::zero()) == Vec3(0.0, 0.0, 1.0));
+}
+
+#[test]
+fn test_triangle_rejects_collinear_vertices() {
+    let triangle = Triangle::new(Vec3::zero(), Vec3(1.0, 1.0, 1.0), Vec3(2.0, 2.0, 2.0),
+                                 Material::plain());
+    assert!(triangle.is_err());
+}
+
+#[test]
+fn test_intersection_of_ray_and_quad() {
+    let quad = Quad::new(Vec3(0.0, 0.0, 1.0), Vec3(2.0, 0.0, 0.0), Vec3(0.0, 1.0, 0.0),
+                         Material::plain()).unwrap();
+    let hit = Ray::new(Vec3(1.5, 0.75, 0.0), Vec3(0.0, 0.0, 1.0));
+    let miss = Ray::new(Vec3(2.5, 0.5, 0.0), Vec3(0.0, 0.0, 1.0));
+
+    assert!(quad.intersect(&hit) == Some(Vec3(1.5, 0.75, 1.0)));
+    assert!(quad.intersect(&miss).is_none());
+    assert!(quad.area() == 2.0);
+}
+
+#[test]
+fn test_surface_samples_lie_on_shapes() {
+    let tolerance = ::precision::float::EPSILON.sqrt();
+    let sphere = Sphere::simple(Vec3(1.0, 2.0, 3.0), 2.0);
+    let triangle = Triangle::new(Vec3(0.0, 0.0, 1.0), Vec3(1.0, 0.0, 1.0), Vec3(0.0, 1.0, 1.0),
+                                 Material::plain()).unwrap();
+
+    for &(u, v) in [(0.0, 0.0), (0.3, 0.9), (1.0, 1.0), (0.5, 0.25)].iter() {
+        let (p, n) = sphere.sample_surface(u, v);
+        assert!(((p - sphere.centre).norm() - 2.0).abs() < tolerance);
+        assert!(n.approx_eq(sphere.normal(p), tolerance));
+
+        let (q, m) = triangle.sample_surface(u, v);
+        let (a, b) = triangle.barycentric(q);
+        assert!(a >= -tolerance && b >= -tolerance && a + b <= 1.0 + tolerance);
+        assert!(m == triangle.normal(q));
+    }
+}
+
+#[test]
+fn test_mesh_intersects_nearest_face() {
+    let vertices = vec![Vec3(0.0, 0.0, 1.0), Vec3(1.0, 0.0, 1.0), Vec3(0.0, 1.0, 1.0),
+                        Vec3(0.0, 0.0, 2.0), Vec3(0.0, 1.0, 2.0), Vec3(1.0, 0.0, 2.0)];
+    let mesh = Mesh::new(vertices, vec![[3, 4, 5], [0, 1, 2]], Material::plain()).unwrap();
+    let ray = Ray::new(Vec3(0.25, 0.25, 0.0), Vec3(0.0, 0.0, 1.0));
+    let p = mesh.intersect(&ray).unwrap();
+
+    assert!(p == Vec3(0.25, 0.25, 1.0));
+    assert!(mesh.normal(p) == Vec3(0.0, 0.0, 1.0));
+    assert!(mesh.normal(Vec3(0.25, 0.25, 2.0)) == Vec3(0.0, 0.0, -1.0));
+    assert!(mesh.area() == 1.0);
+}
+
+#[test]
+fn test_mesh_packets_agree_with_single_rays() {
+    let vertices = vec![Vec3(0.0, 0.0, 1.0), Vec3(1.0, 0.0, 1.0), Vec3(0.0, 1.0, 1.0),
+                        Vec3(0.0, 0.0, 2.0), Vec3(0.0, 1.0, 2.0), Vec3(1.0, 0.0, 2.0)];
+    let mesh = Mesh::new(vertices, vec![[3, 4, 5], [0, 1, 2]], Material::plain()).unwrap();
+    let ray = |x: Float, y: Float| Ray::new(Vec3(x, y, 0.0), Vec3(0.0, 0.0, 1.0));
+
+    let rays = [ray(0.25, 0.25), ray(0.75, 0.75), ray(3.0, 0.5), ray(0.1, 0.6)];
+    let hits = mesh.intersect_packet(&RayPacket::new(rays));
+    for i in 0..WIDTH {
+        assert!(hits[i] == mesh.intersect(&rays[i]));
+    }
+    assert!(hits[0] == Some(Vec3(0.25, 0.25, 1.0)) && hits[1].is_none());
+
+    // Packets missing the mesh's box entirely strike nothing
+    let away = [ray(3.0, 0.0), ray(3.0, 1.0), ray(4.0, 0.0), ray(-2.0, 0.5)];
+    assert!(mesh.intersect_packet(&RayPacket::new(away)) == [None; WIDTH]);
+}
+
+#[test]
+fn test_mesh_rejects_bad_indices() {
+    let vertices = vec![Vec3(0.0, 0.0, 1.0), Vec3(1.0, 0.0, 1.0), Vec3(0.0, 1.0, 1.0)];
+    assert!(Mesh::new(vertices, vec![[0, 1, 3]], Material::plain()).is_err());
+}
+
+#[test]
+fn test_surface_coordinates() {
+    let tolerance = float::EPSILON.sqrt();
+    let sphere = Sphere::simple(Vec3(0.0, 0.0, 0.0), 2.0);
+    let (u, v) = sphere.uv(Vec3(0.0, 0.0, 2.0));
+    assert!(v.abs() < tolerance && u >= 0.0 && u <= 1.0);
+    let (u, v) = sphere.uv(Vec3(-2.0, 0.0, 0.0));
+    assert!((u - 1.0).abs() < tolerance && (v - 0.5).abs() < tolerance);
+
+    let quad = Quad::new(Vec3(1.0, 1.0, 0.0), Vec3(2.0, 0.0, 0.0), Vec3(0.0, 4.0, 0.0),
+                         Material::plain()).unwrap();
+    let (u, v) = quad.uv(Vec3(2.0, 4.0, 0.0));
+    assert!((u - 0.5).abs() < tolerance && (v - 0.75).abs() < tolerance);
+}
@@ -0,0 +1,270 @@
+// transformed.rs - shapes moved, turned and scaled as a whole, so that
+// they can be posed at each frame of an animation without rebuilding
+// them, or moved while the camera's shutter is open
+use precision::Float;
+use vector3d::Vec3;
+use space_algebra::UnitQuaternion;
+use ray::{Ray, Intersectable, Orientable, Mappable, Shadable, Sampleable};
+use materials::{Material, HasMaterial};
+
+// Scale about the origin, then rotate about it, then translate. Only
+// uniform scales are allowed, so that normals turn with the shape.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: UnitQuaternion,
+    // Must be positive
+    pub scale: Float,
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            translation: Vec3::zero(),
+            rotation: UnitQuaternion::identity(),
+            scale: 1.0,
+        }
+    }
+
+    pub fn apply(&self, p: Vec3) -> Vec3 {
+        self.translation + self.rotation * (self.scale * p)
+    }
+
+    pub fn apply_inverse(&self, p: Vec3) -> Vec3 {
+        (self.rotation.conjugate() * (p - self.translation)) / self.scale
+    }
+
+    // A ray carried into the space the transform is applied to, keeping
+    // its time
+    pub fn carry_in(&self, ray: &Ray) -> Ray {
+        Ray::new(self.apply_inverse(ray.origin),
+                 (self.rotation.conjugate() * ray.direction).normalize())
+            .with_time(ray.time)
+    }
+}
+
+// How a shape is posed at each time
+pub trait Motion: Send + Sync {
+    fn transform_at(&self, time: Float) -> Transform;
+}
+
+pub struct Transformed {
+    object: Box<Shadable>,
+    transform: Transform,
+}
+
+impl Transformed {
+    pub fn new(object: Box<Shadable>, transform: Transform) -> Transformed {
+        Transformed {
+            object: object,
+            transform: transform,
+        }
+    }
+
+    pub fn transform(&self) -> Transform {
+        self.transform
+    }
+}
+
+// Rays are carried into the shape's own space and their strikes
+// carried back out, so the shape never knows it has been moved
+impl Intersectable for Transformed {
+    fn intersect(&self, ray: &Ray) -> Option<Vec3> {
+        let t = &self.transform;
+        self.object.intersect(&t.carry_in(ray)).map(|p| t.apply(p))
+    }
+}
+
+impl Orientable for Transformed {
+    fn normal(&self, v: Vec3) -> Vec3 {
+        self.transform.rotation * self.object.normal(self.transform.apply_inverse(v))
+    }
+}
+
+impl Mappable for Transformed {
+    fn uv(&self, v: Vec3) -> (Float, Float) {
+        self.object.uv(self.transform.apply_inverse(v))
+    }
+}
+
+impl HasMaterial for Transformed {
+    fn material(&self) -> &Material {
+        self.object.material()
+    }
+}
+
+impl Sampleable for Transformed {
+    fn area(&self) -> Float {
+        let s = self.transform.scale;
+        self.object.surface().map_or(0.0, |surface| surface.area() * s * s)
+    }
+
+    fn sample_surface(&self, u: Float, v: Float) -> (Vec3, Vec3) {
+        let (p, n) = self.object.surface().map_or((Vec3::zero(), Vec3(0.0, 0.0, 1.0)),
+                                                  |surface| surface.sample_surface(u, v));
+        (self.transform.apply(p), self.transform.rotation * n)
+    }
+}
+
+impl Shadable for Transformed {
+    fn surface(&self) -> Option<&Sampleable> {
+        self.object.surface().map(|_| self as &Sampleable)
+    }
+
+    fn normal_at(&self, v: Vec3, time: Float) -> Vec3 {
+        let t = &self.transform;
+        t.rotation * self.object.normal_at(t.apply_inverse(v), time)
+    }
+
+    fn uv_at(&self, v: Vec3, time: Float) -> (Float, Float) {
+        self.object.uv_at(self.transform.apply_inverse(v), time)
+    }
+}
+
+// A shape posed afresh for each ray, at the ray's time, so that it is
+// blurred along its path. Questions which come without a time, and
+// sampling its surface for lighting, find it where it is at rest.
+pub struct Moving {
+    object: Box<Shadable>,
+    motion: Box<Motion>,
+    rest: Float,
+}
+
+impl Moving {
+    pub fn new(object: Box<Shadable>, motion: Box<Motion>, rest: Float) -> Moving {
+        Moving {
+            object: object,
+            motion: motion,
+            rest: rest,
+        }
+    }
+
+    pub fn transform_at(&self, time: Float) -> Transform {
+        self.motion.transform_at(time)
+    }
+}
+
+impl Intersectable for Moving {
+    fn intersect(&self, ray: &Ray) -> Option<Vec3> {
+        let t = self.transform_at(ray.time);
+        self.object.intersect(&t.carry_in(ray)).map(|p| t.apply(p))
+    }
+}
+
+impl Orientable for Moving {
+    fn normal(&self, v: Vec3) -> Vec3 {
+        self.normal_at(v, self.rest)
+    }
+}
+
+impl Mappable for Moving {
+    fn uv(&self, v: Vec3) -> (Float, Float) {
+        self.uv_at(v, self.rest)
+    }
+}
+
+impl HasMaterial for Moving {
+    fn material(&self) -> &Material {
+        self.object.material()
+    }
+}
+
+impl Sampleable for Moving {
+    fn area(&self) -> Float {
+        let s = self.transform_at(self.rest).scale;
+        self.object.surface().map_or(0.0, |surface| surface.area() * s * s)
+    }
+
+    fn sample_surface(&self, u: Float, v: Float) -> (Vec3, Vec3) {
+        let t = self.transform_at(self.rest);
+        let (p, n) = self.object.surface().map_or((Vec3::zero(), Vec3(0.0, 0.0, 1.0)),
+                                                  |surface| surface.sample_surface(u, v));
+        (t.apply(p), t.rotation * n)
+    }
+}
+
+impl Shadable for Moving {
+    fn surface(&self) -> Option<&Sampleable> {
+        self.object.surface().map(|_| self as &Sampleable)
+    }
+
+    fn normal_at(&self, v: Vec3, time: Float) -> Vec3 {
+        let t = self.transform_at(time);
+        t.rotation * self.object.normal_at(t.apply_inverse(v), time)
+    }
+
+    fn uv_at(&self, v: Vec3, time: Float) -> (Float, Float) {
+        self.object.uv_at(self.transform_at(time).apply_inverse(v), time)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use precision::float;
+    use precision::float::consts::PI;
+    use shapes::{Sphere, Quad};
+
+    #[test]
+    fn test_transformed_sphere_moves_and_grows() {
+        let tolerance = float::EPSILON.sqrt();
+        let transform = Transform {
+            translation: Vec3(0.0, 0.0, 5.0),
+            rotation: UnitQuaternion::from_axis_angle(Vec3(0.0, 1.0, 0.0), 1.0).unwrap(),
+            scale: 2.0,
+        };
+        let sphere = Transformed::new(Box::new(Sphere::simple(Vec3::zero(), 1.0)), transform);
+        let hit = sphere.intersect(&Ray::new(Vec3::zero(), Vec3(0.0, 0.0, 1.0))).unwrap();
+
+        assert!(hit.approx_eq(Vec3(0.0, 0.0, 3.0), tolerance));
+        assert!(sphere.normal(hit).approx_eq(Vec3(0.0, 0.0, -1.0), tolerance));
+        assert!((sphere.area() - 16.0 * PI).abs() < tolerance);
+        assert!(transform.apply_inverse(transform.apply(Vec3(1.0, 2.0, 3.0)))
+            .approx_eq(Vec3(1.0, 2.0, 3.0), tolerance));
+    }
+
+    #[test]
+    fn test_transformed_quad_turns() {
+        let tolerance = float::EPSILON.sqrt();
+        let quad = Quad::new(Vec3(-1.0, -1.0, 0.0), Vec3(2.0, 0.0, 0.0), Vec3(0.0, 2.0, 0.0),
+                             Material::plain()).unwrap();
+        let transform = Transform {
+            translation: Vec3(0.0, 0.0, 4.0),
+            rotation: UnitQuaternion::from_axis_angle(Vec3(1.0, 0.0, 0.0), PI / 2.0).unwrap(),
+            scale: 1.0,
+        };
+        let turned = Transformed::new(Box::new(quad), transform);
+
+        // Turned edge on to rays along z, so they miss it
+        assert!(turned.intersect(&Ray::new(Vec3(0.5, 0.5, 0.0), Vec3(0.0, 0.0, 1.0))).is_none());
+        let hit = turned.intersect(&Ray::new(Vec3(0.5, -3.0, 4.5), Vec3(0.0, 1.0, 0.0))).unwrap();
+        assert!(hit.approx_eq(Vec3(0.5, 0.0, 4.5), tolerance));
+        let (p, _) = turned.sample_surface(0.5, 0.5);
+        assert!(p.approx_eq(Vec3(0.0, 0.0, 4.0), tolerance));
+    }
+
+    struct Sliding;
+
+    impl Motion for Sliding {
+        fn transform_at(&self, time: Float) -> Transform {
+            Transform { translation: Vec3(time, 0.0, 5.0), ..Transform::identity() }
+        }
+    }
+
+    #[test]
+    fn test_moving_sphere_is_struck_where_it_is_at_the_ray_time() {
+        let tolerance = float::EPSILON.sqrt();
+        let sphere = Moving::new(Box::new(Sphere::simple(Vec3::zero(), 1.0)),
+                                 Box::new(Sliding),
+                                 0.0);
+        let ray = Ray::new(Vec3(2.0, 0.0, 0.0), Vec3(0.0, 0.0, 1.0));
+
+        assert!(sphere.intersect(&ray).is_none());
+        let hit = sphere.intersect(&ray.with_time(2.0)).unwrap();
+        assert!(hit.approx_eq(Vec3(2.0, 0.0, 4.0), tolerance));
+        assert!(sphere.normal_at(hit, 2.0).approx_eq(Vec3(0.0, 0.0, -1.0), tolerance));
+        let (p, _) = sphere.sample_surface(0.0, 0.0);
+        assert!(((p - Vec3(0.0, 0.0, 5.0)).norm() - 1.0).abs() < tolerance);
+    }
+}
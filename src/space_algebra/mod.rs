@@ -1,19 +1,23 @@
 // space-algebra - algebraic structure used for manipulating
 // things in space
 
-use vector3d::{Vec3, Matrix3};
+use precision::Float;
+use vector3d::{Vec3, Matrix3, Matrix4};
 use std::ops::Mul;
-use algebra::Group;
+use algebra::{Group, GroupAction, InnerProductSpace};
+#[cfg(test)]
+use algebra::approx_eq;
+use error::{Error, Result};
 
 use std::vec::Vec;
-use std::f64;
+use precision::float;
 
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 enum SO3Gen {
-    RotationX(f64),
-    RotationY(f64),
-    RotationZ(f64),
+    RotationX(Float),
+    RotationY(Float),
+    RotationZ(Float),
 }
 
 impl SO3Gen {
@@ -36,16 +40,39 @@ impl SO3Gen {
 }
 
 
+// The order in which elementary axis rotations are applied when
+// building a rotation from Euler angles. XYZ rotates about x first,
+// then y, then z.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EulerOrder {
+    XYZ,
+    XZY,
+    YXZ,
+    YZX,
+    ZXY,
+    ZYX,
+}
+
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct SO3(Matrix3);
 
 
+// A rotation represented as a unit quaternion w + xi + yj + zk,
+// stored as the scalar part and the vector part (x, y, z)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct UnitQuaternion {
+    w: Float,
+    v: Vec3,
+}
+
+
 impl SO3 {
     pub fn identity() -> SO3 {
         SO3(Matrix3::identity())
     }
 
-    pub fn rotation_x(theta: f64) -> SO3 {
+    pub fn rotation_x(theta: Float) -> SO3 {
         if theta == 0.0 {
             SO3::identity()
         } else {
@@ -53,7 +80,7 @@ impl SO3 {
         }
     }
 
-    pub fn rotation_y(theta: f64) -> SO3 {
+    pub fn rotation_y(theta: Float) -> SO3 {
         if theta == 0.0 {
             SO3::identity()
         } else {
@@ -61,13 +88,130 @@ impl SO3 {
         }
     }
 
-    pub fn rotation_z(theta: f64) -> SO3 {
+    pub fn rotation_z(theta: Float) -> SO3 {
         if theta == 0.0 {
             SO3::identity()
         } else {
             SO3(SO3Gen::RotationZ(theta).to_matrix())
         }
     }
+
+    pub fn from_axis_angle(axis: Vec3, theta: Float) -> Result<SO3> {
+        UnitQuaternion::from_axis_angle(axis, theta).map(SO3::from)
+    }
+
+    // Compose the elementary rotations about each axis in the given
+    // order, so that SO3::from_euler(EulerOrder::XYZ, a, 0.0, 0.0)
+    // agrees with SO3::rotation_x(a), and so on
+    pub fn from_euler(order: EulerOrder, x: Float, y: Float, z: Float) -> SO3 {
+        let (rx, ry, rz) = (SO3::rotation_x(x), SO3::rotation_y(y), SO3::rotation_z(z));
+        match order {
+            EulerOrder::XYZ => rz * ry * rx,
+            EulerOrder::XZY => ry * rz * rx,
+            EulerOrder::YXZ => rz * rx * ry,
+            EulerOrder::YZX => rx * rz * ry,
+            EulerOrder::ZXY => ry * rx * rz,
+            EulerOrder::ZYX => rx * ry * rz,
+        }
+    }
+
+    pub fn transpose(self) -> SO3 {
+        let SO3(m) = self;
+        SO3(m.transpose())
+    }
+
+    pub fn matrix(&self) -> Matrix3 {
+        let &SO3(m) = self;
+        m
+    }
+}
+
+
+impl UnitQuaternion {
+    pub fn identity() -> UnitQuaternion {
+        UnitQuaternion {
+            w: 1.0,
+            v: Vec3::zero(),
+        }
+    }
+
+    pub fn from_axis_angle(axis: Vec3, theta: Float) -> Result<UnitQuaternion> {
+        let length = axis.norm();
+        if length == 0.0 || !length.is_finite() {
+            Err(Error::DegenerateAxis)
+        } else {
+            let half = theta / 2.0;
+            Ok(UnitQuaternion {
+                w: half.cos(),
+                v: (half.sin() / length) * axis,
+            })
+        }
+    }
+
+    pub fn from_euler(order: EulerOrder, x: Float, y: Float, z: Float) -> UnitQuaternion {
+        UnitQuaternion::from(SO3::from_euler(order, x, y, z))
+    }
+
+    // Recover a unit axis and an angle in [0, 2pi]; the identity
+    // has no well defined axis, so we report the x axis
+    pub fn to_axis_angle(self) -> (Vec3, Float) {
+        let theta = 2.0 * self.w.max(-1.0).min(1.0).acos();
+        let s = self.v.norm();
+        if s < float::EPSILON {
+            (Vec3(1.0, 0.0, 0.0), theta)
+        } else {
+            (self.v / s, theta)
+        }
+    }
+
+    pub fn conjugate(self) -> UnitQuaternion {
+        UnitQuaternion {
+            w: self.w,
+            v: -self.v,
+        }
+    }
+
+    // Spherical linear interpolation, taking the shorter arc between
+    // the two rotations
+    pub fn slerp(self, other: UnitQuaternion, t: Float) -> UnitQuaternion {
+        let cos_theta = self.dot4(other);
+        let (target, cos_theta) = if cos_theta < 0.0 {
+            (other.negate(), -cos_theta)
+        } else {
+            (other, cos_theta)
+        };
+
+        // Nearly parallel quaternions interpolate linearly to avoid
+        // dividing by a vanishing sine
+        let (a, b) = if cos_theta > 0.9995 {
+            (1.0 - t, t)
+        } else {
+            let theta = cos_theta.acos();
+            let sin_theta = theta.sin();
+            (((1.0 - t) * theta).sin() / sin_theta, (t * theta).sin() / sin_theta)
+        };
+
+        UnitQuaternion::normalized(a * self.w + b * target.w, a * self.v + b * target.v)
+    }
+
+    fn dot4(self, other: UnitQuaternion) -> Float {
+        self.w * other.w + self.v.dot(other.v)
+    }
+
+    fn negate(self) -> UnitQuaternion {
+        UnitQuaternion {
+            w: -self.w,
+            v: -self.v,
+        }
+    }
+
+    fn normalized(w: Float, v: Vec3) -> UnitQuaternion {
+        let n = (w * w + v.dot(v)).sqrt();
+        UnitQuaternion {
+            w: w / n,
+            v: v / n,
+        }
+    }
 }
 
 
@@ -89,6 +233,102 @@ impl Mul<Vec3> for SO3 {
     }
 }
 
+// Rotations are orthogonal, so the inverse is the transpose
+impl Group for SO3 {
+    fn identity() -> SO3 {
+        SO3::identity()
+    }
+
+    fn inverse(self) -> SO3 {
+        self.transpose()
+    }
+}
+
+impl GroupAction<Vec3> for SO3 {}
+
+
+impl Mul for UnitQuaternion {
+    type Output = UnitQuaternion;
+
+    // The Hamilton product; we renormalise to stop rounding errors
+    // accumulating over long chains of rotations
+    fn mul(self, rhs: UnitQuaternion) -> UnitQuaternion {
+        UnitQuaternion::normalized(self.w * rhs.w - self.v.dot(rhs.v),
+                                   self.w * rhs.v + rhs.w * self.v + self.v.cross(rhs.v))
+    }
+}
+
+impl Mul<Vec3> for UnitQuaternion {
+    type Output = Vec3;
+
+    fn mul(self, rhs: Vec3) -> Vec3 {
+        let t = 2.0 * self.v.cross(rhs);
+        rhs + self.w * t + self.v.cross(t)
+    }
+}
+
+impl Group for UnitQuaternion {
+    fn identity() -> UnitQuaternion {
+        UnitQuaternion::identity()
+    }
+
+    fn inverse(self) -> UnitQuaternion {
+        self.conjugate()
+    }
+}
+
+impl GroupAction<Vec3> for UnitQuaternion {}
+
+
+impl From<SO3> for Matrix4 {
+    fn from(r: SO3) -> Matrix4 {
+        Matrix4::affine(r.matrix(), Vec3::zero())
+    }
+}
+
+impl From<UnitQuaternion> for SO3 {
+    fn from(q: UnitQuaternion) -> SO3 {
+        let (w, Vec3(x, y, z)) = (q.w, q.v);
+        SO3(Matrix3::with_columns(Vec3(1.0 - 2.0 * (y * y + z * z),
+                                       2.0 * (x * y + w * z),
+                                       2.0 * (x * z - w * y)),
+                                  Vec3(2.0 * (x * y - w * z),
+                                       1.0 - 2.0 * (x * x + z * z),
+                                       2.0 * (y * z + w * x)),
+                                  Vec3(2.0 * (x * z + w * y),
+                                       2.0 * (y * z - w * x),
+                                       1.0 - 2.0 * (x * x + y * y))))
+    }
+}
+
+impl From<SO3> for UnitQuaternion {
+    // Shepperd's method: pivot on the largest of the diagonal terms
+    // to keep the square root well away from zero
+    fn from(r: SO3) -> UnitQuaternion {
+        let SO3(m) = r;
+        let (Vec3(m00, m10, m20), Vec3(m01, m11, m21), Vec3(m02, m12, m22)) = m.columns();
+        let trace = m00 + m11 + m22;
+
+        if trace > 0.0 {
+            let s = 2.0 * (trace + 1.0).sqrt();
+            UnitQuaternion::normalized(s / 4.0,
+                                       Vec3((m21 - m12) / s, (m02 - m20) / s, (m10 - m01) / s))
+        } else if m00 > m11 && m00 > m22 {
+            let s = 2.0 * (1.0 + m00 - m11 - m22).sqrt();
+            UnitQuaternion::normalized((m21 - m12) / s,
+                                       Vec3(s / 4.0, (m01 + m10) / s, (m02 + m20) / s))
+        } else if m11 > m22 {
+            let s = 2.0 * (1.0 + m11 - m00 - m22).sqrt();
+            UnitQuaternion::normalized((m02 - m20) / s,
+                                       Vec3((m01 + m10) / s, s / 4.0, (m12 + m21) / s))
+        } else {
+            let s = 2.0 * (1.0 + m22 - m00 - m11).sqrt();
+            UnitQuaternion::normalized((m10 - m01) / s,
+                                       Vec3((m02 + m20) / s, (m12 + m21) / s, s / 4.0))
+        }
+    }
+}
+
 
 #[test]
 fn test_SO3_multiplication() {
@@ -105,3 +345,59 @@ fn test_SO3_multiply_vector() {
 
     assert!(s * v == expected);
 }
+
+#[test]
+fn test_SO3_inverse_is_transpose() {
+    let tolerance = 0.0000001;
+    let r = SO3::rotation_x(0.3) * SO3::rotation_y(1.2) * SO3::rotation_z(-0.7);
+    let SO3(m) = r * r.inverse();
+    assert!(Matrix3::dist(m, Matrix3::identity()) < tolerance);
+}
+
+#[test]
+fn test_quaternion_agrees_with_SO3() {
+    let tolerance = float::EPSILON.sqrt();
+    let r = SO3::from_euler(EulerOrder::ZXY, 0.4, -1.1, 2.5);
+    let q = UnitQuaternion::from(r);
+    let v = Vec3(0.3, -2.0, 1.5);
+    assert!((r * v).approx_eq(q * v, tolerance));
+
+    let SO3(m) = SO3::from(q);
+    let SO3(n) = r;
+    assert!(m.approx_eq(n, tolerance));
+}
+
+#[test]
+fn test_euler_angles_compose_in_order() {
+    let tolerance = 0.0000001;
+    let SO3(m) = SO3::from_euler(EulerOrder::XYZ, 0.5, 0.2, 0.0);
+    let SO3(n) = SO3::rotation_y(0.2) * SO3::rotation_x(0.5);
+    assert!(Matrix3::dist(m, n) < tolerance);
+}
+
+#[test]
+fn test_quaternion_axis_angle() {
+    let tolerance = 0.0000001;
+    let q = UnitQuaternion::from_axis_angle(Vec3(0.0, 0.0, 2.0), float::consts::PI / 2.0).unwrap();
+    assert!((q * Vec3(1.0, 0.0, 0.0)).approx_eq(Vec3(0.0, 1.0, 0.0), tolerance));
+
+    let (axis, theta) = q.to_axis_angle();
+    assert!(axis.approx_eq(Vec3(0.0, 0.0, 1.0), tolerance));
+    assert!(approx_eq(theta, float::consts::PI / 2.0, tolerance));
+
+    assert!(UnitQuaternion::from_axis_angle(Vec3::zero(), 1.0).is_err());
+}
+
+#[test]
+fn test_quaternion_slerp() {
+    let tolerance = 0.0000001;
+    let axis = Vec3(0.0, 1.0, 0.0);
+    let a = UnitQuaternion::identity();
+    let b = UnitQuaternion::from_axis_angle(axis, 1.0).unwrap();
+    let halfway = UnitQuaternion::from_axis_angle(axis, 0.5).unwrap();
+    let q = a.slerp(b, 0.5);
+
+    assert!((q.dot4(halfway) - 1.0).abs() < tolerance);
+    assert!((a.slerp(b, 0.0).dot4(a) - 1.0).abs() < tolerance);
+    assert!((a.slerp(b, 1.0).dot4(b) - 1.0).abs() < tolerance);
+}
@@ -0,0 +1,259 @@
+// stats - counting what a render does, and timing its phases, to see
+// where its time goes. Each thread counts for itself, so counting
+// costs no locking; renders which spawn threads add what those threads
+// counted to the thread which asked for the render, where take finds
+// it once the render returns.
+//
+// Every ray is tested against every object in the scene, as there is
+// no bounding volume hierarchy, so the intersection tests counted here
+// are all there are.
+//
+// Counting is left out of builds without the stats feature, which
+// then count nothing, and so pay nothing for it.
+#[cfg(feature = "stats")]
+use std::cell::RefCell;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use precision::Float;
+use error::{Error, Result};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Primitive {
+    Sphere,
+    Plane,
+    Triangle,
+    Quad,
+}
+
+pub const ALL_PRIMITIVES: [Primitive; 4] =
+    [Primitive::Sphere, Primitive::Plane, Primitive::Triangle, Primitive::Quad];
+
+impl Primitive {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Primitive::Sphere => "sphere",
+            Primitive::Plane => "plane",
+            Primitive::Triangle => "triangle",
+            Primitive::Quad => "quad",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Counters {
+    // Rays from the camera
+    pub primary_rays: u64,
+    // Rays reflected from the surfaces struck
+    pub secondary_rays: u64,
+    // Rays testing whether a light, or the sky, can be seen
+    pub shadow_rays: u64,
+    // Surfaces struck by primary and secondary rays
+    pub strikes: u64,
+    // Intersection tests of rays with each kind of primitive, in the
+    // order of ALL_PRIMITIVES. Meshes are counted by their triangles.
+    pub tests: [u64; 4],
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters::default()
+    }
+
+    pub fn tests_of(&self, primitive: Primitive) -> u64 {
+        self.tests[primitive as usize]
+    }
+
+    pub fn total_tests(&self) -> u64 {
+        self.tests.iter().sum()
+    }
+
+    // The mean number of surfaces struck on the way from the camera,
+    // counting primary rays which strike nothing as reaching no depth
+    pub fn average_depth(&self) -> Float {
+        self.strikes as Float / self.primary_rays.max(1) as Float
+    }
+
+    pub fn add(&mut self, other: &Counters) {
+        self.primary_rays += other.primary_rays;
+        self.secondary_rays += other.secondary_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.strikes += other.strikes;
+        for (t, &u) in self.tests.iter_mut().zip(other.tests.iter()) {
+            *t += u;
+        }
+    }
+
+    // The counters as words on a line, for sending to another process
+    pub fn to_words(&self) -> String {
+        format!("{} {} {} {} {} {} {} {}", self.primary_rays, self.secondary_rays,
+                self.shadow_rays, self.strikes, self.tests[0], self.tests[1], self.tests[2],
+                self.tests[3])
+    }
+
+    pub fn from_words(words: &str) -> Result<Counters> {
+        let n = words.split_whitespace()
+            .map(|w| w.parse().map_err(|_| Error::Protocol(format!("bad counters '{}'", words))))
+            .collect::<Result<Vec<u64>>>()?;
+        match n.as_slice() {
+            &[primary, secondary, shadow, strikes, t0, t1, t2, t3] => {
+                Ok(Counters {
+                    primary_rays: primary,
+                    secondary_rays: secondary,
+                    shadow_rays: shadow,
+                    strikes: strikes,
+                    tests: [t0, t1, t2, t3],
+                })
+            }
+            _ => Err(Error::Protocol(format!("bad counters '{}'", words))),
+        }
+    }
+}
+
+#[cfg(feature = "stats")]
+thread_local! {
+    static COUNTERS: RefCell<Counters> = RefCell::new(Counters::new());
+}
+
+// Count something on this thread
+#[cfg(feature = "stats")]
+pub fn record<F: FnOnce(&mut Counters)>(f: F) {
+    COUNTERS.with(|c| f(&mut c.borrow_mut()))
+}
+
+#[cfg(not(feature = "stats"))]
+#[inline(always)]
+pub fn record<F: FnOnce(&mut Counters)>(_: F) {}
+
+pub fn count_tests(primitive: Primitive, n: u64) {
+    record(|c| c.tests[primitive as usize] += n)
+}
+
+// What this thread has counted since it was last taken, starting
+// again from nothing
+#[cfg(feature = "stats")]
+pub fn take() -> Counters {
+    COUNTERS.with(|c| ::std::mem::replace(&mut *c.borrow_mut(), Counters::new()))
+}
+
+#[cfg(not(feature = "stats"))]
+pub fn take() -> Counters {
+    Counters::new()
+}
+
+// Count on this thread what another thread counted
+pub fn add(counters: &Counters) {
+    record(|c| c.add(counters))
+}
+
+// The counters from a render and the time each phase of it took
+#[derive(Clone, PartialEq, Debug)]
+pub struct Report {
+    pub counters: Counters,
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report {
+            counters: Counters::new(),
+            phases: Vec::new(),
+        }
+    }
+
+    // Run a phase of the render, timing it, and taking what it counted.
+    // Phases run more than once, as for each frame of an animation, add
+    // up their times.
+    pub fn time<T, F: FnOnce() -> T>(&mut self, phase: &'static str, f: F) -> T {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+        match self.phases.iter_mut().find(|p| p.0 == phase) {
+            Some(p) => p.1 += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+        self.counters.add(&take());
+        result
+    }
+
+    pub fn phase(&self, phase: &str) -> Option<Duration> {
+        self.phases.iter().find(|p| p.0 == phase).map(|p| p.1)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let c = &self.counters;
+        for &(phase, time) in self.phases.iter() {
+            writeln!(f, "{:<20}{:>12.3} s", phase, time.as_secs_f64())?;
+        }
+        if !cfg!(feature = "stats") {
+            return Ok(());
+        }
+        writeln!(f, "{:<20}{:>12}", "primary rays", c.primary_rays)?;
+        writeln!(f, "{:<20}{:>12}", "secondary rays", c.secondary_rays)?;
+        writeln!(f, "{:<20}{:>12}", "shadow rays", c.shadow_rays)?;
+        for &p in ALL_PRIMITIVES.iter() {
+            writeln!(f, "{:<20}{:>12}", format!("{} tests", p.name()), c.tests_of(p))?;
+        }
+        writeln!(f, "{:<20}{:>12.3}", "average depth", c.average_depth())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "stats")]
+    use std::thread;
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_counts_are_kept_by_thread() {
+        take();
+        count_tests(Primitive::Quad, 3);
+        record(|c| c.primary_rays += 2);
+        let other = thread::spawn(|| {
+                count_tests(Primitive::Quad, 5);
+                take()
+            })
+            .join()
+            .unwrap();
+        assert!(other.tests_of(Primitive::Quad) == 5);
+
+        add(&other);
+        let mine = take();
+        assert!(mine.tests_of(Primitive::Quad) == 8 && mine.total_tests() == 8);
+        assert!(mine.primary_rays == 2);
+        assert!(take() == Counters::new());
+    }
+
+    #[test]
+    fn test_counters_cross_the_wire() {
+        let counters = Counters {
+            primary_rays: 1,
+            secondary_rays: 2,
+            shadow_rays: 3,
+            strikes: 4,
+            tests: [5, 6, 7, 8],
+        };
+        assert!(Counters::from_words(&counters.to_words()).unwrap() == counters);
+        assert!(Counters::from_words("1 2 3").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn test_reports_time_phases() {
+        let mut report = Report::new();
+        let n = report.time("load", || {
+            record(|c| c.strikes += 3);
+            7
+        });
+        assert!(n == 7);
+        assert!(report.phase("load").is_some() && report.phase("write").is_none());
+        assert!(report.counters.strikes == 3);
+        report.time("load", || ());
+        assert!(report.phases.len() == 1);
+        assert!(format!("{}", report).contains("average depth"));
+    }
+}
@@ -0,0 +1,51 @@
+// tonemap - bringing rendered light into the range a display can show.
+// Shading works in 8 bit colour, so a render reaches here already
+// within [0, 1], anything brighter than white having been cut off on
+// the way. With no highlights left to compress, the only mapping worth
+// making is a change of exposure followed by clamping.
+use precision::Float;
+use framebuffer::Framebuffer;
+
+// Brighten or darken by the given number of stops, leaving the values
+// unbounded, as for writing to float images
+pub fn expose(image: &Framebuffer, stops: Float) -> Framebuffer {
+    let scale = (2.0 as Float).powf(stops);
+    let mut out = image.clone();
+    for y in 0..out.height() {
+        for x in 0..out.width() {
+            for c in out.get_mut(x, y).iter_mut() {
+                *c *= scale;
+            }
+        }
+    }
+    out
+}
+
+// Scale by the exposure, in stops, then cut each channel off at black
+// and white
+pub fn apply(image: &Framebuffer, exposure: Float) -> Framebuffer {
+    let mut out = expose(image, exposure);
+    for y in 0..out.height() {
+        for x in 0..out.width() {
+            for c in out.get_mut(x, y).iter_mut() {
+                *c = c.clamp(0.0, 1.0);
+            }
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exposure_is_in_stops() {
+        let image = Framebuffer::filled(2, 2, 3, 0.25);
+        assert!(apply(&image, 1.0).get(1, 1) == &[0.5; 3]);
+        assert!(apply(&image, -2.0).get(0, 0) == &[0.0625; 3]);
+        assert!(apply(&image, 3.0).get(0, 1) == &[1.0; 3]);
+        assert!(expose(&image, 3.0).get(0, 1) == &[2.0; 3]);
+    }
+}
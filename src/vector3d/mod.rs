@@ -1,30 +1,123 @@
 // vector3d -- 3d vectors for working in space
-use std::ops::{Add, Mul, Sub};
-use algebra::{VectorSpace, InnerProductSpace, Group};
+use precision::Float;
+use std::ops::{Add, Mul, Sub, Neg, Div, Index, IndexMut};
+use algebra::{VectorSpace, InnerProductSpace, Group, approx_eq};
 
 #[derive(Clone, Copy, PartialEq, Debug)]
-pub struct Vec3(pub f64, pub f64, pub f64);
+pub struct Vec3(pub Float, pub Float, pub Float);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Matrix3(Vec3, Vec3, Vec3);
 
+// An affine transformation of space in homogeneous coordinates. The
+// bottom row is always (0, 0, 0, 1), so we store only the linear
+// part and the translation.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Matrix4 {
+    linear: Matrix3,
+    translation: Vec3,
+}
+
 
 impl Vec3 {
-    pub fn i(&self) -> f64 {
+    pub fn i(&self) -> Float {
         self.0
     }
 
-    pub fn j(&self) -> f64 {
+    pub fn j(&self) -> Float {
         self.1
     }
 
-    pub fn k(&self) -> f64 {
+    pub fn k(&self) -> Float {
         self.2
     }
 
     pub fn zero() -> Vec3 {
         Vec3(0.0, 0.0, 0.0)
     }
+
+    pub fn component_mul(self, rhs: Vec3) -> Vec3 {
+        Vec3(self.i() * rhs.i(), self.j() * rhs.j(), self.k() * rhs.k())
+    }
+
+    pub fn min(self, rhs: Vec3) -> Vec3 {
+        Vec3(self.i().min(rhs.i()), self.j().min(rhs.j()), self.k().min(rhs.k()))
+    }
+
+    pub fn max(self, rhs: Vec3) -> Vec3 {
+        Vec3(self.i().max(rhs.i()), self.j().max(rhs.j()), self.k().max(rhs.k()))
+    }
+
+    pub fn abs(self) -> Vec3 {
+        Vec3(self.i().abs(), self.j().abs(), self.k().abs())
+    }
+
+    pub fn lerp(self, rhs: Vec3, t: Float) -> Vec3 {
+        self + t * (rhs - self)
+    }
+
+    pub fn max_component(self) -> Float {
+        self.i().max(self.j()).max(self.k())
+    }
+
+    pub fn min_component(self) -> Float {
+        self.i().min(self.j()).min(self.k())
+    }
+
+    pub fn approx_eq(self, rhs: Vec3, tolerance: Float) -> bool {
+        approx_eq(self.i(), rhs.i(), tolerance) &&
+        approx_eq(self.j(), rhs.j(), tolerance) &&
+        approx_eq(self.k(), rhs.k(), tolerance)
+    }
+}
+
+impl Neg for Vec3 {
+    type Output = Vec3;
+    fn neg(self) -> Vec3 {
+        Vec3(-self.i(), -self.j(), -self.k())
+    }
+}
+
+impl Mul<Float> for Vec3 {
+    type Output = Vec3;
+    fn mul(self, rhs: Float) -> Vec3 {
+        rhs * self
+    }
+}
+
+impl Div<Float> for Vec3 {
+    type Output = Vec3;
+    fn div(self, rhs: Float) -> Vec3 {
+        Vec3(self.i() / rhs, self.j() / rhs, self.k() / rhs)
+    }
+}
+
+impl Index<Axis> for Vec3 {
+    type Output = Float;
+    fn index(&self, axis: Axis) -> &Float {
+        match axis {
+            Axis::X => &self.0,
+            Axis::Y => &self.1,
+            Axis::Z => &self.2,
+        }
+    }
+}
+
+impl IndexMut<Axis> for Vec3 {
+    fn index_mut(&mut self, axis: Axis) -> &mut Float {
+        match axis {
+            Axis::X => &mut self.0,
+            Axis::Y => &mut self.1,
+            Axis::Z => &mut self.2,
+        }
+    }
 }
 
 // We give Vec3 a vector space structure
@@ -36,7 +129,7 @@ impl Add for Vec3 {
     }
 }
 
-impl Mul<Vec3> for f64 {
+impl Mul<Vec3> for Float {
     type Output = Vec3;
     fn mul(self, rhs: Vec3) -> Vec3 {
         Vec3(self * rhs.i(), self * rhs.j(), self * rhs.k())
@@ -46,34 +139,34 @@ impl Mul<Vec3> for f64 {
 impl Sub<Vec3> for Vec3 {
     type Output = Vec3;
     fn sub(self, rhs: Vec3) -> Vec3 {
-        self + (-1.0) * rhs
+        self + -rhs
     }
 }
 
 impl VectorSpace for Vec3 {
-    type Field = f64;
+    type Field = Float;
 }
 
 // And we also give it an inner product
 
 impl InnerProductSpace for Vec3 {
-    fn dot(self, rhs: Vec3) -> f64 {
+    fn dot(self, rhs: Vec3) -> Float {
         self.i() * rhs.i() + self.j() * rhs.j() + self.k() * rhs.k()
     }
 }
 
 // From the inner product space structure we can define a norm
 impl Vec3 {
-    pub fn norm(self) -> f64 {
+    pub fn norm(self) -> Float {
         self.dot(self).sqrt()
     }
 
     pub fn normalize(self) -> Vec3 {
-        (1.0 / self.norm()) * self
+        self / self.norm()
     }
 
     pub fn reflect(self, v : Vec3) -> Vec3 {
-        -1.0 * (2.0 * v.dot(self) * self - v)
+        -(2.0 * v.dot(self) * self - v)
     }
 
     pub fn cross(self, v : Vec3) -> Vec3 {
@@ -81,6 +174,16 @@ impl Vec3 {
              v.i()*self.k() - self.i()*v.k(),
              self.i()*v.j() - v.i()*self.j())
     }
+
+    // Complete a unit vector to a right handed orthonormal basis
+    // (t, b, self), following Duff et al. (2017)
+    pub fn orthonormal_basis(self) -> (Vec3, Vec3) {
+        let sign = (1.0 as Float).copysign(self.k());
+        let a = -1.0 / (sign + self.k());
+        let b = self.i() * self.j() * a;
+        (Vec3(1.0 + sign * self.i() * self.i() * a, sign * b, -sign * self.i()),
+         Vec3(b, sign + self.j() * self.j() * a, -self.j()))
+    }
 }
 
 // Now we give Vec3 an (additive) group structure
@@ -98,7 +201,7 @@ impl Group for Vec3 {
     }
 
     fn inverse(self) -> Vec3 {
-        (-1.0) * self
+        -self
     }
 }
 
@@ -106,7 +209,7 @@ impl Group for Vec3 {
 // Matrix operations
 
 impl Matrix3 {
-    fn row1(&self) -> Vec3 {
+    pub fn row1(&self) -> Vec3 {
         let &Matrix3(x, y, z) = self;
         let Vec3(a, _, _) = x;
         let Vec3(b, _, _) = y;
@@ -115,7 +218,7 @@ impl Matrix3 {
         Vec3(a, b, c)
     }
 
-    fn row2(&self) -> Vec3 {
+    pub fn row2(&self) -> Vec3 {
         let &Matrix3(x, y, z) = self;
         let Vec3(_, a, _) = x;
         let Vec3(_, b, _) = y;
@@ -124,7 +227,7 @@ impl Matrix3 {
         Vec3(a, b, c)
     }
 
-    fn row3(&self) -> Vec3 {
+    pub fn row3(&self) -> Vec3 {
         let &Matrix3(x, y, z) = self;
         let Vec3(_, _, a) = x;
         let Vec3(_, _, b) = y;
@@ -143,7 +246,54 @@ impl Matrix3 {
         Matrix3(col1, col2, col3)
     }
 
-    pub fn dist(m1: Matrix3, m2: Matrix3) -> f64 {
+    pub fn columns(&self) -> (Vec3, Vec3, Vec3) {
+        let &Matrix3(x, y, z) = self;
+        (x, y, z)
+    }
+
+    pub fn scaling(s: Vec3) -> Matrix3 {
+        Matrix3(Vec3(s.i(), 0.0, 0.0),
+                Vec3(0.0, s.j(), 0.0),
+                Vec3(0.0, 0.0, s.k()))
+    }
+
+    // A shear where, for instance, xy is the amount x is displaced
+    // per unit of y
+    pub fn shear(xy: Float, xz: Float, yx: Float, yz: Float, zx: Float, zy: Float) -> Matrix3 {
+        Matrix3(Vec3(1.0, yx, zx),
+                Vec3(xy, 1.0, zy),
+                Vec3(xz, yz, 1.0))
+    }
+
+    pub fn transpose(self) -> Matrix3 {
+        Matrix3(self.row1(), self.row2(), self.row3())
+    }
+
+    pub fn determinant(&self) -> Float {
+        let &Matrix3(x, y, z) = self;
+        x.dot(y.cross(z))
+    }
+
+    // The rows of the inverse are the cross products of pairs of
+    // columns, scaled by the determinant
+    pub fn inverse(&self) -> Option<Matrix3> {
+        let &Matrix3(x, y, z) = self;
+        let det = self.determinant();
+        if det == 0.0 || !det.is_finite() {
+            None
+        } else {
+            let r = 1.0 / det;
+            Some(Matrix3(r * y.cross(z), r * z.cross(x), r * x.cross(y)).transpose())
+        }
+    }
+
+    pub fn approx_eq(self, rhs: Matrix3, tolerance: Float) -> bool {
+        let Matrix3(x, y, z) = self;
+        let Matrix3(r, s, t) = rhs;
+        x.approx_eq(r, tolerance) && y.approx_eq(s, tolerance) && z.approx_eq(t, tolerance)
+    }
+
+    pub fn dist(m1: Matrix3, m2: Matrix3) -> Float {
         let Matrix3(x, y, z) = m1;
         let Matrix3(r, s, t) = m2;
 
@@ -174,6 +324,81 @@ impl Mul for Matrix3 {
 }
 
 
+impl Matrix4 {
+    pub fn identity() -> Matrix4 {
+        Matrix4::affine(Matrix3::identity(), Vec3::zero())
+    }
+
+    pub fn affine(linear: Matrix3, translation: Vec3) -> Matrix4 {
+        Matrix4 {
+            linear: linear,
+            translation: translation,
+        }
+    }
+
+    pub fn translation(v: Vec3) -> Matrix4 {
+        Matrix4::affine(Matrix3::identity(), v)
+    }
+
+    pub fn scaling(s: Vec3) -> Matrix4 {
+        Matrix4::affine(Matrix3::scaling(s), Vec3::zero())
+    }
+
+    pub fn linear_part(&self) -> Matrix3 {
+        self.linear
+    }
+
+    pub fn translation_part(&self) -> Vec3 {
+        self.translation
+    }
+
+    pub fn rows(&self) -> [[Float; 4]; 4] {
+        let t = self.translation;
+        let (r1, r2, r3) = (self.linear.row1(), self.linear.row2(), self.linear.row3());
+        [[r1.i(), r1.j(), r1.k(), t.i()],
+         [r2.i(), r2.j(), r2.k(), t.j()],
+         [r3.i(), r3.j(), r3.k(), t.k()],
+         [0.0, 0.0, 0.0, 1.0]]
+    }
+
+    // Points have homogeneous coordinate 1 and are translated...
+    pub fn transform_point(&self, p: Vec3) -> Vec3 {
+        self.linear * p + self.translation
+    }
+
+    // ...whereas directions have homogeneous coordinate 0 and are not
+    pub fn transform_direction(&self, d: Vec3) -> Vec3 {
+        self.linear * d
+    }
+
+    // Normals transform by the inverse transpose of the linear part
+    // so that they stay perpendicular to transformed surfaces
+    pub fn normal_matrix(&self) -> Option<Matrix3> {
+        self.linear.inverse().map(Matrix3::transpose)
+    }
+
+    pub fn transform_normal(&self, n: Vec3) -> Option<Vec3> {
+        self.normal_matrix().map(|m| (m * n).normalize())
+    }
+
+    pub fn determinant(&self) -> Float {
+        self.linear.determinant()
+    }
+
+    pub fn inverse(&self) -> Option<Matrix4> {
+        self.linear.inverse().map(|m| Matrix4::affine(m, -(m * self.translation)))
+    }
+}
+
+impl Mul for Matrix4 {
+    type Output = Matrix4;
+
+    fn mul(self, rhs: Matrix4) -> Matrix4 {
+        Matrix4::affine(self.linear * rhs.linear, self.transform_point(rhs.translation))
+    }
+}
+
+
 // Unit tests
 
 #[test]
@@ -312,3 +537,123 @@ fn test_matrix_distance() {
 
     assert!(Matrix3::dist(m1, m2) == 3.0);
 }
+
+#[test]
+fn test_matrix_transpose() {
+    let m = Matrix3(Vec3(1.0, 2.0, 3.0), Vec3(4.0, 5.0, 6.0), Vec3(7.0, 8.0, 9.0));
+    let expected = Matrix3(Vec3(1.0, 4.0, 7.0), Vec3(2.0, 5.0, 8.0), Vec3(3.0, 6.0, 9.0));
+
+    assert!(m.transpose() == expected);
+    assert!(m.transpose().transpose() == m);
+}
+
+#[test]
+fn test_matrix_determinant() {
+    let m = Matrix3(Vec3(2.0, 0.0, 0.0), Vec3(1.0, 3.0, 0.0), Vec3(5.0, 7.0, 4.0));
+    assert!(m.determinant() == 24.0);
+    assert!(Matrix3::scaling(Vec3(1.0, 0.0, 1.0)).determinant() == 0.0);
+}
+
+#[test]
+fn test_matrix_inverse() {
+    let tolerance = 0.00000001;
+    let m = Matrix3(Vec3(2.0, 1.0, 0.0), Vec3(1.0, 3.0, 1.0), Vec3(0.0, 1.0, 4.0));
+    let n = m.inverse().unwrap();
+
+    assert!(Matrix3::dist(m * n, Matrix3::identity()) < tolerance);
+    assert!(Matrix3::dist(n * m, Matrix3::identity()) < tolerance);
+    assert!(Matrix3::scaling(Vec3(1.0, 0.0, 1.0)).inverse().is_none());
+}
+
+#[test]
+fn test_matrix_shear() {
+    let m = Matrix3::shear(2.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+    assert!(m * Vec3(0.0, 1.0, 0.0) == Vec3(2.0, 1.0, 0.0));
+    assert!(m.determinant() == 1.0);
+}
+
+#[test]
+fn test_affine_points_and_directions() {
+    let m = Matrix4::translation(Vec3(1.0, 2.0, 3.0)) * Matrix4::scaling(Vec3(2.0, 2.0, 2.0));
+    let v = Vec3(1.0, 1.0, 1.0);
+
+    assert!(m.transform_point(v) == Vec3(3.0, 4.0, 5.0));
+    assert!(m.transform_direction(v) == Vec3(2.0, 2.0, 2.0));
+    assert!(m.rows()[0] == [2.0, 0.0, 0.0, 1.0]);
+}
+
+#[test]
+fn test_affine_inverse() {
+    let tolerance = 0.00000001;
+    let m = Matrix4::translation(Vec3(1.0, -2.0, 3.0)) *
+            Matrix4::affine(Matrix3::shear(0.5, 0.0, 0.0, 1.0, 0.0, 0.0), Vec3::zero());
+    let p = Vec3(0.3, 0.7, -1.1);
+    let q = m.inverse().unwrap().transform_point(m.transform_point(p));
+
+    assert!((p - q).dot(p - q) < tolerance);
+}
+
+#[test]
+fn test_normals_stay_perpendicular_under_scaling() {
+    let tolerance = 0.00000001;
+    let m = Matrix4::scaling(Vec3(4.0, 1.0, 1.0));
+    let tangent = Vec3(1.0, -1.0, 0.0);
+    let normal = Vec3(1.0, 1.0, 0.0);
+    let n = m.transform_normal(normal).unwrap();
+
+    assert!(m.transform_direction(tangent).dot(n).abs() < tolerance);
+}
+
+#[test]
+fn test_vec3_arithmetic_operators() {
+    let v = Vec3(1.0, -2.0, 4.0);
+
+    assert!(-v == Vec3(-1.0, 2.0, -4.0));
+    assert!(v * 2.0 == 2.0 * v);
+    assert!(v / 2.0 == Vec3(0.5, -1.0, 2.0));
+    assert!(v.component_mul(Vec3(2.0, 3.0, 0.5)) == Vec3(2.0, -6.0, 2.0));
+}
+
+#[test]
+fn test_vec3_componentwise_utilities() {
+    let v = Vec3(1.0, -2.0, 4.0);
+    let w = Vec3(0.0, 3.0, 5.0);
+
+    assert!(v.min(w) == Vec3(0.0, -2.0, 4.0));
+    assert!(v.max(w) == Vec3(1.0, 3.0, 5.0));
+    assert!(v.abs() == Vec3(1.0, 2.0, 4.0));
+    assert!(v.lerp(w, 0.5) == Vec3(0.5, 0.5, 4.5));
+    assert!(v.max_component() == 4.0);
+    assert!(v.min_component() == -2.0);
+}
+
+#[test]
+fn test_vec3_index_by_axis() {
+    let mut v = Vec3(1.0, 2.0, 3.0);
+    v[Axis::Y] = 5.0;
+
+    assert!(v[Axis::X] == 1.0);
+    assert!(v[Axis::Y] == 5.0);
+    assert!(v[Axis::Z] == 3.0);
+}
+
+#[test]
+fn test_orthonormal_basis() {
+    let tolerance = ::precision::float::EPSILON.sqrt();
+    for &n in [Vec3(0.0, 0.0, 1.0), Vec3(0.0, 0.0, -1.0), Vec3(1.0, 2.0, -3.0).normalize()].iter() {
+        let (t, b) = n.orthonormal_basis();
+        assert!(approx_eq(t.norm(), 1.0, tolerance));
+        assert!(approx_eq(b.norm(), 1.0, tolerance));
+        assert!(approx_eq(t.dot(n), 0.0, tolerance));
+        assert!(approx_eq(b.dot(n), 0.0, tolerance));
+        assert!(t.cross(b).approx_eq(n, tolerance));
+    }
+}
+
+#[test]
+fn test_vec3_approximate_equality() {
+    let v = Vec3(1.0, 2.0, 3.0);
+
+    assert!(v.approx_eq(v + Vec3(0.0, 1e-12, 0.0), 1e-9));
+    assert!(!v.approx_eq(v + Vec3(0.0, 1e-3, 0.0), 1e-9));
+}
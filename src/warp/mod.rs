@@ -0,0 +1,147 @@
+// warp - maps from uniform points in the unit square to points spread
+// over other shapes, each with the density it leaves them in. Together
+// with a sampler these turn well spread points in the square into well
+// spread directions and positions.
+use precision::Float;
+use precision::float::consts::PI;
+use vector3d::Vec3;
+
+// Uniformly over the unit disc, by Shirley's concentric map, which
+// keeps nearby points in the square nearby on the disc
+pub fn square_to_disc(u: Float, v: Float) -> (Float, Float) {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+pub fn disc_pdf() -> Float {
+    1.0 / PI
+}
+
+// Uniformly over the hemisphere about the z axis
+pub fn square_to_hemisphere(u: Float, v: Float) -> Vec3 {
+    let z = u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vec3(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn hemisphere_pdf() -> Float {
+    1.0 / (2.0 * PI)
+}
+
+// Over the hemisphere about the z axis with density cos(theta), by
+// lifting uniform points on the disc
+pub fn square_to_cosine_hemisphere(u: Float, v: Float) -> Vec3 {
+    let (x, y) = square_to_disc(u, v);
+    Vec3(x, y, (1.0 - x * x - y * y).max(0.0).sqrt())
+}
+
+pub fn cosine_hemisphere_pdf(cos_theta: Float) -> Float {
+    cos_theta.max(0.0) / PI
+}
+
+// Uniformly over the unit sphere
+pub fn square_to_sphere(u: Float, v: Float) -> Vec3 {
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vec3(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn sphere_pdf() -> Float {
+    1.0 / (4.0 * PI)
+}
+
+// Uniformly over the directions within a cone about the z axis, given
+// the cosine of its half angle
+pub fn square_to_cone(u: Float, v: Float, cos_max: Float) -> Vec3 {
+    let z = 1.0 - u * (1.0 - cos_max);
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vec3(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn cone_pdf(cos_max: Float) -> Float {
+    1.0 / (2.0 * PI * (1.0 - cos_max))
+}
+
+// Uniformly over a triangle, as the weights of its second and third
+// corners; the first takes what is left
+pub fn square_to_triangle(u: Float, v: Float) -> (Float, Float) {
+    let su = u.sqrt();
+    (su * (1.0 - v), su * v)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use algebra::approx_eq;
+    use precision::float;
+
+    fn grid() -> Vec<(Float, Float)> {
+        let n = 16;
+        let mut points = Vec::new();
+        for j in 0..n + 1 {
+            for i in 0..n + 1 {
+                points.push((i as Float / n as Float, j as Float / n as Float));
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn test_warps_land_on_their_shapes() {
+        let tolerance = float::EPSILON.sqrt();
+        let cos_max = (0.3 as Float).cos();
+        for &(u, v) in grid().iter() {
+            let (x, y) = square_to_disc(u, v);
+            assert!(x * x + y * y <= 1.0 + tolerance);
+
+            for w in [square_to_hemisphere(u, v), square_to_cosine_hemisphere(u, v),
+                      square_to_sphere(u, v), square_to_cone(u, v, cos_max)].iter() {
+                assert!(approx_eq(w.norm(), 1.0, tolerance));
+            }
+            assert!(square_to_hemisphere(u, v).k() >= 0.0);
+            assert!(square_to_cosine_hemisphere(u, v).k() >= 0.0);
+            assert!(square_to_cone(u, v, cos_max).k() >= cos_max - tolerance);
+
+            let (b1, b2) = square_to_triangle(u, v);
+            assert!(b1 >= 0.0 && b2 >= 0.0 && b1 + b2 <= 1.0 + tolerance);
+        }
+    }
+
+    #[test]
+    fn test_disc_map_keeps_the_centre_and_edges() {
+        assert!(square_to_disc(0.5, 0.5) == (0.0, 0.0));
+        let (x, y) = square_to_disc(1.0, 0.5);
+        let tolerance = float::EPSILON.sqrt();
+        assert!(approx_eq(x, 1.0, tolerance) && approx_eq(y, 0.0, tolerance));
+    }
+
+    #[test]
+    fn test_cosine_weighted_directions_favour_the_pole() {
+        // The mean of cos(theta) is 2/3 under a cosine density, and
+        // 1/2 under a uniform one
+        let points: Vec<(Float, Float)> = grid()
+            .into_iter()
+            .filter(|&(u, v)| u < 1.0 && v < 1.0)
+            .map(|(u, v)| (u + 1.0 / 32.0, v + 1.0 / 32.0))
+            .collect();
+        let mean = |f: &Fn(Float, Float) -> Vec3| {
+            points.iter().map(|&(u, v)| f(u, v).k()).sum::<Float>() / points.len() as Float
+        };
+        assert!((mean(&square_to_cosine_hemisphere) - 2.0 / 3.0).abs() < 0.02);
+        assert!((mean(&square_to_hemisphere) - 0.5).abs() < 0.02);
+        assert!(approx_eq(cosine_hemisphere_pdf(1.0), 1.0 / PI, float::EPSILON));
+        assert!(approx_eq(cone_pdf(-1.0), sphere_pdf(), float::EPSILON));
+    }
+}